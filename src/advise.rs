@@ -0,0 +1,256 @@
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::compare::{BackendKind, Comparison};
+use crate::file_store::{FileStore, Serializer, WritePolicy};
+use crate::load_test::{self, random_value, LoadParams};
+use crate::store::Store;
+
+/// Keys per shard the first file count guess aims for.
+const KEYS_PER_FILE: u64 = 8192;
+
+/// Most files a recommendation will shard across.
+const MAX_FILE_COUNT: usize = 256;
+
+/// Write policies calibrated for every serializer and file count: persisting on
+/// every write, batching a millisecond of writes, and handing writes to a queue.
+const CALIBRATED_POLICIES: [&str; 3] = ["sync:0", "sync:1000", "async:1024"];
+
+/// Write period while populating a candidate store, long enough that every key is
+/// written once at the closing flush.
+const POPULATE_WRITE_PERIOD: Duration = Duration::from_secs(3600);
+
+/// What the calibration aims for.
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub p99_latency: Duration,
+}
+
+/// The calibrated configurations, fastest first among those meeting the target.
+#[derive(Debug)]
+pub struct Advice {
+    pub target: Target,
+    /// Every candidate's results, in the order they ran.
+    pub comparison: Comparison,
+    pub candidates: Vec<BackendKind>,
+    /// Bytes on disk of each candidate's populated store, in the same order.
+    pub store_bytes: Vec<u64>,
+    /// Index of the recommended candidate.
+    pub recommended: usize,
+    /// Whether the recommended candidate meets the target; if none does, the one
+    /// with the lowest p99 latency is recommended instead.
+    pub meets_target: bool,
+}
+
+/// File counts to calibrate: the power of two that puts about `KEYS_PER_FILE` keys
+/// in each shard, and a quarter and four times as many.
+fn file_counts(key_count: u64) -> Vec<usize> {
+    let guess = (key_count / KEYS_PER_FILE).max(1).next_power_of_two() as usize;
+    let mut counts: Vec<usize> = [guess / 4, guess, guess * 4]
+        .into_iter()
+        .map(|count| count.clamp(1, MAX_FILE_COUNT))
+        .collect();
+    counts.dedup();
+    counts
+}
+
+/// Run `load_params`' workload for a short while against file stores of every
+/// candidate serializer, file count and write policy, each populated with all
+/// `key_count` keys first so that reads hit and shards are their real size, and
+/// recommend the fastest that meets `target`. Stores are created in temporary
+/// directories, removed once calibrated.
+pub fn advise(load_params: &LoadParams, target: Target) -> Result<Advice> {
+    let policies: Vec<WritePolicy> = CALIBRATED_POLICIES
+        .iter()
+        .map(|policy| policy.parse())
+        .collect::<Result<_>>()?;
+    let mut runs = Vec::new();
+    let mut candidates = Vec::new();
+    let mut store_bytes = Vec::new();
+    for serializer in [Serializer::Json, Serializer::Cbor] {
+        for file_count in file_counts(load_params.key_count) {
+            let dir = tempfile::tempdir()?;
+            log::info!(
+                "Populating {} keys in {} {:?} files.",
+                load_params.key_count,
+                file_count,
+                serializer
+            );
+            populate(dir.path(), file_count, serializer.clone(), load_params)?;
+            let bytes = dir_bytes(dir.path())?;
+            for write_policy in &policies {
+                let candidate = BackendKind::File {
+                    serializer: serializer.clone(),
+                    write_policy: write_policy.clone(),
+                    file_count,
+                };
+                let label = candidate.file_args().join(" ");
+                log::info!("Calibrating {}", label);
+                let mut store = FileStore::open(dir.path(), write_policy)?;
+                let probe = store.spawn()?;
+                let all_stats = load_test::load_test(store, load_params.clone())?;
+                // Drain the writers before the next policy reopens the store.
+                probe.flush()?;
+                runs.push((label, all_stats));
+                candidates.push(candidate);
+                store_bytes.push(bytes);
+            }
+        }
+    }
+    let comparison = Comparison::new(runs)?;
+    let max_p99_us = target.p99_latency.as_micros() as u64;
+    let meeting = comparison
+        .backends
+        .iter()
+        .enumerate()
+        .filter(|(_, result)| result.p99_latency_us <= max_p99_us && result.error_rate == 0.0)
+        .max_by(|(_, a), (_, b)| a.ops_per_sec.total_cmp(&b.ops_per_sec));
+    let (recommended, meets_target) = match meeting {
+        Some((index, _)) => (index, true),
+        None => {
+            let lowest = comparison
+                .backends
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, result)| result.p99_latency_us)
+                .map(|(index, _)| index)
+                .unwrap_or_default();
+            (lowest, false)
+        }
+    };
+    Ok(Advice {
+        target,
+        comparison,
+        candidates,
+        store_bytes,
+        recommended,
+        meets_target,
+    })
+}
+
+/// Create a store at `dir` holding every one of `load_params`' keys, with values
+/// drawn as the workload draws them.
+fn populate(
+    dir: &Path,
+    file_count: usize,
+    serializer: Serializer,
+    load_params: &LoadParams,
+) -> Result<()> {
+    let mut store = FileStore::create(
+        dir,
+        file_count,
+        &WritePolicy::Synchronous {
+            write_period: POPULATE_WRITE_PERIOD,
+        },
+        serializer,
+        Default::default(),
+        Default::default(),
+    )?;
+    let mut rng = match load_params.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    for index in 0..load_params.key_count {
+        let key = format!("{}{}", load_params.key_prefix, index);
+        store.put(&key, random_value(&mut rng, load_params))?;
+    }
+    store.flush()
+}
+
+fn dir_bytes(dir: &Path) -> Result<u64> {
+    let mut bytes = 0;
+    for entry in std::fs::read_dir(dir)? {
+        bytes += entry?.metadata()?.len();
+    }
+    Ok(bytes)
+}
+
+impl Advice {
+    pub fn recommendation(&self) -> &BackendKind {
+        &self.candidates[self.recommended]
+    }
+
+    /// The calibration table, with the recommended row marked, and the
+    /// recommendation.
+    pub fn text_lines(&self) -> Vec<String> {
+        let mut lines = self.comparison.text_lines();
+        let recommended = &self.comparison.backends[self.recommended];
+        if let Some(line) = lines.get_mut(self.recommended + 1) {
+            line.push_str(" <-");
+        }
+        let target_ms = self.target.p99_latency.as_secs_f64() * 1000.0;
+        lines.push(if self.meets_target {
+            format!(
+                "Recommended: file {} ({:.2} ops/sec, p99 {}us, within the {}ms target)",
+                recommended.backend, recommended.ops_per_sec, recommended.p99_latency_us, target_ms
+            )
+        } else {
+            format!(
+                "No configuration met the {}ms p99 target; the lowest p99 was file {} ({}us)",
+                target_ms, recommended.backend, recommended.p99_latency_us
+            )
+        });
+        lines.push(format!(
+            "The store takes {} bytes on disk, and the file store holds all of it in memory.",
+            self.store_bytes[self.recommended]
+        ));
+        lines
+    }
+
+    /// Write the recommendation as a shell script that runs the calibrated workload
+    /// against the recommended configuration, passing on its own arguments as
+    /// global options; the calibration table is kept in comments.
+    pub fn write_script(&self, load_params: &LoadParams, mut out: impl Write) -> Result<()> {
+        writeln!(out, "#!/bin/sh")?;
+        writeln!(
+            out,
+            "# Written by key_value_store advise, build {}.",
+            self.comparison.build
+        )?;
+        for line in self.text_lines() {
+            writeln!(out, "# {}", line)?;
+        }
+        let mut args = vec![
+            "--key-count".to_string(),
+            load_params.key_count.to_string(),
+            "--value-size-bytes".to_string(),
+            load_params.value_size.to_string(),
+            "--value-shape".to_string(),
+            load_params.value_shape.to_string().to_lowercase(),
+            "--value-depth".to_string(),
+            load_params.value_depth.to_string(),
+            "--read-percent".to_string(),
+            load_params.read_percent.to_string(),
+            "--durable-percent".to_string(),
+            load_params.durable_percent.to_string(),
+            "--scan-percent".to_string(),
+            load_params.scan_percent.to_string(),
+            "--key-distribution".to_string(),
+            load_params.key_distribution.to_string(),
+            "--threads".to_string(),
+            load_params.threads.to_string(),
+        ];
+        if !load_params.key_prefix.is_empty() {
+            args.extend(["--key-prefix".to_string(), load_params.key_prefix.clone()]);
+        }
+        let quoted = |args: Vec<String>| {
+            args.into_iter()
+                .map(|arg| format!("'{}'", arg.replace('\'', "'\\''")))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        writeln!(
+            out,
+            "exec key_value_store {} \"$@\" file {}",
+            quoted(args),
+            quoted(self.recommendation().file_args())
+        )?;
+        out.flush()?;
+        Ok(())
+    }
+}
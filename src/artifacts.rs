@@ -0,0 +1,240 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use tempfile::TempDir;
+use zip::write::SimpleFileOptions;
+
+use crate::build_info::BuildInfo;
+use crate::file_store::{HealthHandle, JournalHandle};
+use crate::load_test::Progress;
+use crate::manifest::Manifest;
+use crate::store::StoreError;
+
+/// Number of most recent log lines kept for a failure bundle.
+pub const LOG_TAIL_CAPACITY: usize = 1000;
+
+/// Ring buffer of the most recently logged lines.
+pub struct LogTail {
+    lines: Mutex<VecDeque<String>>,
+    capacity: usize,
+}
+
+impl LogTail {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    fn push(&self, line: String) {
+        if let Ok(mut lines) = self.lines.lock() {
+            if lines.len() == self.capacity {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+    }
+
+    pub fn lines(&self) -> Result<Vec<String>> {
+        let lines = self.lines.lock().map_err(|_| StoreError::LockError)?;
+        Ok(lines.iter().cloned().collect())
+    }
+}
+
+/// Logs through `env_logger`, keeping a copy of every emitted line in a `LogTail`.
+struct TeeLogger {
+    inner: env_logger::Logger,
+    tail: Arc<LogTail>,
+}
+
+impl log::Log for TeeLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        self.inner.log(record);
+        self.tail.push(format!(
+            "[{} {} {}] {}",
+            chrono::Local::now().to_rfc3339(),
+            record.level(),
+            record.target(),
+            record.args()
+        ));
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Install `builder`'s logger globally, copying its output into `tail`.
+pub fn init_logger(mut builder: env_logger::Builder, tail: Arc<LogTail>) -> Result<()> {
+    let inner = builder.build();
+    log::set_max_level(inner.filter());
+    log::set_boxed_logger(Box::new(TeeLogger { inner, tail }))?;
+    Ok(())
+}
+
+/// Everything needed to describe a run if it fails: collected as the run goes,
+/// and written out as a zip bundle by `write_bundle`.
+pub struct FailureContext {
+    config: String,
+    bundle_dir: PathBuf,
+    progress: Arc<Progress>,
+    log_tail: Arc<LogTail>,
+    /// The file store's directory, plus its temporary directory if it has one so
+    /// that it isn't removed before the bundle is written.
+    store_dir: Mutex<Option<(PathBuf, Option<TempDir>)>>,
+    journal: Mutex<Option<JournalHandle>>,
+    health: Mutex<Option<HealthHandle>>,
+    /// Path of the bundle, once written. Only the first failure (e.g. a panic, then
+    /// the error it turns into) is bundled.
+    bundle: Mutex<Option<PathBuf>>,
+}
+
+impl FailureContext {
+    pub fn new(
+        config: String,
+        bundle_dir: PathBuf,
+        progress: Arc<Progress>,
+        log_tail: Arc<LogTail>,
+    ) -> Self {
+        Self {
+            config,
+            bundle_dir,
+            progress,
+            log_tail,
+            store_dir: Mutex::new(None),
+            journal: Mutex::new(None),
+            health: Mutex::new(None),
+            bundle: Mutex::new(None),
+        }
+    }
+
+    /// Include metadata about the file store at `dir` in any bundle, keeping
+    /// `tmp_dir` alive until this context is dropped.
+    pub fn track_store_dir(&self, dir: PathBuf, tmp_dir: Option<TempDir>) -> Result<()> {
+        *self.store_dir.lock().map_err(|_| StoreError::LockError)? = Some((dir, tmp_dir));
+        Ok(())
+    }
+
+    /// Include the file store's operation journal in any bundle.
+    pub fn track_journal(&self, journal: JournalHandle) -> Result<()> {
+        *self.journal.lock().map_err(|_| StoreError::LockError)? = Some(journal);
+        Ok(())
+    }
+
+    /// Include the file store's background error policy and errors in any bundle.
+    pub fn track_health(&self, health: HealthHandle) -> Result<()> {
+        *self.health.lock().map_err(|_| StoreError::LockError)? = Some(health);
+        Ok(())
+    }
+
+    /// Write a bundle describing `failure` and return its path, or return the path
+    /// of the bundle already written for an earlier failure.
+    pub fn write_bundle(&self, failure: &str) -> Result<PathBuf> {
+        let mut bundle = self.bundle.lock().map_err(|_| StoreError::LockError)?;
+        if let Some(path) = &*bundle {
+            return Ok(path.clone());
+        }
+        std::fs::create_dir_all(&self.bundle_dir)?;
+        let path = self.bundle_dir.join(format!(
+            "kv-failure-{}.zip",
+            chrono::Local::now().format("%Y%m%dT%H%M%S%.3f")
+        ));
+        let mut zip = zip::ZipWriter::new(File::create(&path)?);
+        let options = SimpleFileOptions::default();
+
+        zip.start_file("failure.txt", options)?;
+        writeln!(zip, "{}", failure)?;
+
+        zip.start_file("config.txt", options)?;
+        writeln!(zip, "build: {}", BuildInfo::current())?;
+        writeln!(zip, "{}", self.config)?;
+
+        zip.start_file("stats.txt", options)?;
+        for (thread, stats) in self.progress.snapshot()?.iter().enumerate() {
+            writeln!(
+                zip,
+                "thread {}: {} ops ({} reads, {} writes, {} errors, {} misses) in {:?} ({:.2} ops/sec)",
+                thread,
+                stats.ops.0,
+                stats.reads,
+                stats.writes,
+                stats.errors,
+                stats.misses,
+                stats.runtime,
+                stats.ops_per_sec().0
+            )?;
+        }
+
+        zip.start_file("slow_ops.txt", options)?;
+        for slow_op in self.progress.slow_ops()? {
+            writeln!(
+                zip,
+                "{:?} thread {} {} {:?} took {:?}",
+                slow_op.at, slow_op.thread, slow_op.op, slow_op.key, slow_op.latency
+            )?;
+        }
+
+        if let Some((dir, _)) = &*self.store_dir.lock().map_err(|_| StoreError::LockError)? {
+            zip.start_file("shards.txt", options)?;
+            write_shard_metadata(&mut zip, dir)?;
+        }
+
+        if let Some(journal) = &*self.journal.lock().map_err(|_| StoreError::LockError)? {
+            zip.start_file("journal.txt", options)?;
+            journal.dump(&mut zip)?;
+        }
+
+        if let Some(health) = &*self.health.lock().map_err(|_| StoreError::LockError)? {
+            zip.start_file("health.txt", options)?;
+            health.health()?.write(&mut zip)?;
+        }
+
+        zip.start_file("log.txt", options)?;
+        for line in self.log_tail.lines()? {
+            writeln!(zip, "{}", line)?;
+        }
+
+        zip.finish()?;
+        *bundle = Some(path.clone());
+        Ok(path)
+    }
+}
+
+/// The store's manifest, followed by the size of every file in its directory.
+fn write_shard_metadata(out: &mut impl Write, dir: &Path) -> Result<()> {
+    writeln!(out, "store: {:?}", dir)?;
+    match Manifest::load(dir) {
+        Ok(manifest) => writeln!(out, "manifest: {:#?}", manifest)?,
+        Err(err) => writeln!(out, "manifest: unreadable ({:#})", err)?,
+    }
+    let entries = std::fs::read_dir(dir).and_then(|entries| entries.collect());
+    let mut entries: Vec<std::fs::DirEntry> = match entries {
+        Ok(entries) => entries,
+        Err(err) => {
+            writeln!(out, "files: unreadable ({})", err)?;
+            return Ok(());
+        }
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        writeln!(
+            out,
+            "{:?}: {} bytes",
+            entry.file_name(),
+            entry.metadata()?.len()
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use std::fmt;
+use std::io::Write;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::load_test::OutputFormat;
+
+/// The binary that produced a report or store, as embedded by `build.rs`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct BuildInfo {
+    pub version: String,
+    /// Abbreviated commit hash, suffixed with "-dirty" if tracked files had
+    /// uncommitted changes, or "unknown" if not built from a git checkout.
+    pub git_commit: String,
+    /// Cargo profile: "release" or "debug".
+    pub profile: String,
+    /// Cargo features enabled, sorted.
+    pub features: Vec<String>,
+}
+
+impl BuildInfo {
+    /// This binary's.
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: env!("KVS_GIT_COMMIT").to_string(),
+            profile: env!("KVS_BUILD_PROFILE").to_string(),
+            features: env!("KVS_FEATURES")
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .map(String::from)
+                .collect(),
+        }
+    }
+
+    /// One line per field, for the `version` subcommand.
+    pub fn text_lines(&self) -> Vec<String> {
+        vec![
+            format!("key_value_store {}", self.version),
+            format!("git_commit: {}", self.git_commit),
+            format!("profile: {}", self.profile),
+            format!("features: {}", self.features_list()),
+        ]
+    }
+
+    pub fn write(&self, format: OutputFormat, mut out: impl Write) -> Result<()> {
+        match format {
+            OutputFormat::Text => {
+                for line in self.text_lines() {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+            OutputFormat::Json => {
+                serde_json::to_writer_pretty(&mut out, self)?;
+                writeln!(out)?;
+            }
+            OutputFormat::Csv => {
+                writeln!(out, "version,git_commit,profile,features")?;
+                writeln!(
+                    out,
+                    "{},{},{},{}",
+                    self.version,
+                    self.git_commit,
+                    self.profile,
+                    self.features_list()
+                )?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    fn features_list(&self) -> String {
+        if self.features.is_empty() {
+            "none".to_string()
+        } else {
+            self.features.join("+")
+        }
+    }
+}
+
+/// On one line, with no commas, so that it fits a CSV column.
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} features={}",
+            self.version,
+            self.git_commit,
+            self.profile,
+            self.features_list()
+        )
+    }
+}
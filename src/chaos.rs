@@ -0,0 +1,606 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::load_test::Sample;
+use crate::store::{Blob, Store, StoreError};
+
+/// Width of the windows the timeline report buckets operations into.
+const TIMELINE_WINDOW: Duration = Duration::from_secs(1);
+
+/// A sample is back to steady state once its p99 is within this factor of the
+/// run's median sample p99.
+const STEADY_STATE_P99_FACTOR: f64 = 1.5;
+
+/// A fault injected into every operation while its event is in effect.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    /// Fail this fraction (0.0-1.0) of operations with an I/O error.
+    IoErrors { rate: f64 },
+    /// Delay every operation by this many milliseconds.
+    Latency { ms: u64 },
+}
+
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::IoErrors { rate } => write!(f, "io_errors {}%", rate * 100.0),
+            Self::Latency { ms } => write!(f, "latency {}ms", ms),
+        }
+    }
+}
+
+/// One entry of a chaos schedule: inject `fault` from `at_sec` seconds into the
+/// run, for `duration_sec` seconds or until the end of the run.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ChaosEvent {
+    pub at_sec: u64,
+    #[serde(default)]
+    pub duration_sec: Option<u64>,
+    pub fault: Fault,
+}
+
+impl ChaosEvent {
+    fn is_active(&self, elapsed: Duration) -> bool {
+        let start = Duration::from_secs(self.at_sec);
+        let end = self.duration_sec.map(|d| start + Duration::from_secs(d));
+        elapsed >= start && end.map(|end| elapsed < end).unwrap_or(true)
+    }
+
+    /// Whether the event is in effect at any point in `[from, from + width)`.
+    fn overlaps(&self, from: Duration, width: Duration) -> bool {
+        let start = Duration::from_secs(self.at_sec);
+        let end = self.duration_sec.map(|d| start + Duration::from_secs(d));
+        start < from + width && end.map(|end| end > from).unwrap_or(true)
+    }
+}
+
+/// Faults to inject over the course of a run, read from a JSON list of
+/// `ChaosEvent`s, e.g.
+///
+/// ```json
+/// [
+///     {"at_sec": 60, "duration_sec": 30, "fault": {"io_errors": {"rate": 0.05}}},
+///     {"at_sec": 180, "fault": {"latency": {"ms": 20}}}
+/// ]
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(transparent)]
+pub struct ChaosSchedule {
+    pub events: Vec<ChaosEvent>,
+}
+
+impl ChaosSchedule {
+    pub fn load(path: &Path) -> Result<Self> {
+        let schedule: Self = serde_json::from_reader(File::open(path)?)?;
+        for event in &schedule.events {
+            if let Fault::IoErrors { rate } = event.fault {
+                if !(0.0..=1.0).contains(&rate) {
+                    bail!("io_errors rate must be between 0 and 1, got {}", rate);
+                }
+            }
+        }
+        Ok(schedule)
+    }
+}
+
+#[derive(Default)]
+struct Window {
+    ops: AtomicU64,
+    errors: AtomicU64,
+    injected: AtomicU64,
+}
+
+/// Per-window operation counts, for overlaying the schedule on what happened.
+pub struct ChaosTimeline {
+    schedule: ChaosSchedule,
+    windows: Vec<Window>,
+}
+
+impl ChaosTimeline {
+    fn window(&self, elapsed: Duration) -> &Window {
+        let index = (elapsed.as_secs_f64() / TIMELINE_WINDOW.as_secs_f64()) as usize;
+        &self.windows[index.min(self.windows.len() - 1)]
+    }
+
+    /// Log one line per window: operations, errors (of which injected), and the
+    /// events in effect.
+    pub fn report(&self) {
+        for (index, window) in self.windows.iter().enumerate() {
+            let ops = window.ops.load(Ordering::Relaxed);
+            if ops == 0 {
+                continue;
+            }
+            let from = TIMELINE_WINDOW * index as u32;
+            let events: Vec<String> = self
+                .schedule
+                .events
+                .iter()
+                .filter(|event| event.overlaps(from, TIMELINE_WINDOW))
+                .map(|event| event.fault.to_string())
+                .collect();
+            log::info!(
+                "chaos_timeline: t={:>4}s ops={} errors={} injected={}{}",
+                from.as_secs(),
+                ops,
+                window.errors.load(Ordering::Relaxed),
+                window.injected.load(Ordering::Relaxed),
+                if events.is_empty() {
+                    String::new()
+                } else {
+                    format!(" [{}]", events.join(", "))
+                }
+            );
+        }
+    }
+}
+
+struct Shared {
+    started: Instant,
+    timeline: Arc<ChaosTimeline>,
+}
+
+impl Shared {
+    /// Apply the faults in effect now, returning the window to record the
+    /// operation's outcome in, or the injected error.
+    fn inject(&self) -> Result<&Window, StoreError> {
+        let timeline = &self.timeline;
+        let elapsed = self.started.elapsed();
+        let window = timeline.window(elapsed);
+        window.ops.fetch_add(1, Ordering::Relaxed);
+        for event in timeline.schedule.events.iter() {
+            if !event.is_active(elapsed) {
+                continue;
+            }
+            match event.fault {
+                Fault::Latency { ms } => std::thread::sleep(Duration::from_millis(ms)),
+                Fault::IoErrors { rate } => {
+                    if rand::thread_rng().gen::<f64>() < rate {
+                        window.errors.fetch_add(1, Ordering::Relaxed);
+                        window.injected.fetch_add(1, Ordering::Relaxed);
+                        return Err(std::io::Error::other("injected I/O error").into());
+                    }
+                }
+            }
+        }
+        Ok(window)
+    }
+}
+
+/// Wraps a store, injecting the faults of a `ChaosSchedule` into its operations
+/// and recording a `ChaosTimeline`.
+pub struct ChaosStore<S: Store> {
+    inner: S,
+    shared: Arc<Shared>,
+}
+
+impl<S: Store> ChaosStore<S> {
+    /// `horizon` is the expected length of the run; later operations are counted
+    /// in the timeline's last window.
+    pub fn new(inner: S, schedule: ChaosSchedule, horizon: Duration) -> Self {
+        let window_count = (horizon.as_secs_f64() / TIMELINE_WINDOW.as_secs_f64()).ceil() as usize;
+        let timeline = ChaosTimeline {
+            schedule,
+            windows: (0..window_count.max(1))
+                .map(|_| Window::default())
+                .collect(),
+        };
+        Self {
+            inner,
+            shared: Arc::new(Shared {
+                started: Instant::now(),
+                timeline: Arc::new(timeline),
+            }),
+        }
+    }
+
+    pub fn timeline(&self) -> Arc<ChaosTimeline> {
+        Arc::clone(&self.shared.timeline)
+    }
+}
+
+/// Count a genuine (non-injected) failure.
+fn record<T>(window: &Window, result: Result<T, StoreError>) -> Result<T, StoreError> {
+    if result.is_err() {
+        window.errors.fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
+
+impl<S: Store> Store for ChaosStore<S> {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        let window = self.shared.inject()?;
+        record(window, self.inner.get(key))
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let window = self.shared.inject()?;
+        record(window, self.inner.put(key, value))
+    }
+
+    fn put_durable(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let window = self.shared.inject()?;
+        record(window, self.inner.put_durable(key, value))
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        let window = self.shared.inject()?;
+        record(window, self.inner.delete(key))
+    }
+
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        let window = self.shared.inject()?;
+        record(window, self.inner.range(range))
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(Self {
+            inner: self.inner.spawn()?,
+            shared: Arc::clone(&self.shared),
+        })
+    }
+}
+
+/// A store that can act as if its process had been killed: it stops persisting at
+/// once, losing whatever it acknowledged but hadn't written to disk, and fails
+/// every later write. Reopening it from disk then shows what survived.
+pub trait Crash: Store {
+    fn crash(&self) -> Result<(), StoreError>;
+
+    /// Read `key` as a restart's survival check does. Stores whose gets have side
+    /// effects that would skew the run after the restart, such as filling a cache,
+    /// avoid them here.
+    fn recovered(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        self.get(key)
+    }
+}
+
+/// The last write to a key a handle had acknowledged since the last restart, and
+/// so expects to survive it; a value of None marks a delete.
+#[derive(Clone, Debug)]
+struct Acked {
+    value: Option<Blob>,
+    durable: bool,
+}
+
+type AckLog = Arc<Mutex<HashMap<String, Acked>>>;
+
+/// How many acknowledged writes survived restarts, over a whole run.
+#[derive(Clone, Debug, Default)]
+pub struct RestartStats {
+    pub restarts: u64,
+    pub acked: u64,
+    pub survived: u64,
+    /// Of `acked` and `survived`, durable puts, which should always survive.
+    pub durable_acked: u64,
+    pub durable_survived: u64,
+    /// When each restart began, for lining restarts up with the load's samples.
+    pub restarted_at: Vec<Instant>,
+}
+
+struct Restarts<S> {
+    /// Held for reading by every operation and for writing by a restart, so that
+    /// restarts fall between operations and no acknowledgement is in flight.
+    gate: RwLock<()>,
+    /// Bumped by every restart, so that handles know to switch to `current`.
+    generation: AtomicU64,
+    current: Mutex<S>,
+    reopen: Box<dyn Fn() -> Result<S> + Send + Sync>,
+    /// Every handle's acknowledged writes, checked and cleared by each restart.
+    ack_logs: Mutex<Vec<AckLog>>,
+    stats: Mutex<RestartStats>,
+}
+
+impl<S: Store + Crash> Restarts<S> {
+    /// Crash the current store, reopen it, and count which acknowledged writes
+    /// made it. Only exact when each key is written by one handle, as under
+    /// `LoadParams::validate`; otherwise another handle's later write can make an
+    /// earlier one look lost.
+    fn restart(&self) -> Result<()> {
+        let started = Instant::now();
+        let _gate = self.gate.write().map_err(|_| StoreError::LockError)?;
+        let mut current = self.current.lock().map_err(|_| StoreError::LockError)?;
+        current.crash()?;
+        *current = (self.reopen)()?;
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        let reopened = started.elapsed();
+
+        let mut round = RestartStats {
+            restarts: 1,
+            ..RestartStats::default()
+        };
+        for log in self
+            .ack_logs
+            .lock()
+            .map_err(|_| StoreError::LockError)?
+            .iter()
+        {
+            for (key, acked) in log.lock().map_err(|_| StoreError::LockError)?.drain() {
+                let survived = current.recovered(&key)? == acked.value;
+                round.acked += 1;
+                round.survived += u64::from(survived);
+                if acked.durable {
+                    round.durable_acked += 1;
+                    round.durable_survived += u64::from(survived);
+                }
+            }
+        }
+        log::info!(
+            "chaos_restart: reopened in {:.1}ms; {} of {} keys' acknowledged writes survived ({} of {} durable)",
+            reopened.as_secs_f64() * 1000.0,
+            round.survived,
+            round.acked,
+            round.durable_survived,
+            round.durable_acked
+        );
+        if round.durable_survived < round.durable_acked {
+            log::error!(
+                "{} durable writes were lost in a restart",
+                round.durable_acked - round.durable_survived
+            );
+        }
+        let mut stats = self.stats.lock().map_err(|_| StoreError::LockError)?;
+        stats.restarts += 1;
+        stats.acked += round.acked;
+        stats.survived += round.survived;
+        stats.durable_acked += round.durable_acked;
+        stats.durable_survived += round.durable_survived;
+        stats.restarted_at.push(started);
+        Ok(())
+    }
+}
+
+/// Log how long the load's p99 took to settle after each restart: the time from
+/// the restart to the start of the first sample before the next restart whose p99
+/// is within `STEADY_STATE_P99_FACTOR` of the median sample's. The search starts
+/// at the sample the restart falls in, since operations it stalled count in the
+/// sample they started in, and skips samples without operations. `origin` is
+/// when the samples' offsets count from.
+pub fn log_recovery(series: &[Sample], origin: Instant, restarted_at: &[Instant]) {
+    let series: Vec<&Sample> = series.iter().filter(|sample| sample.ops > 0).collect();
+    let mut p99s: Vec<u64> = series.iter().map(|sample| sample.p99_latency_us).collect();
+    p99s.sort_unstable();
+    let Some(&median) = p99s.get(p99s.len() / 2) else {
+        return;
+    };
+    let steady = median as f64 * STEADY_STATE_P99_FACTOR;
+    log::info!(
+        "chaos_restart_steady_p99_us: {} (median sample p99 {}us)",
+        steady as u64,
+        median
+    );
+    let mut settled = Vec::new();
+    for (index, restart) in restarted_at.iter().enumerate() {
+        let from = restart.saturating_duration_since(origin);
+        let until = restarted_at
+            .get(index + 1)
+            .map(|next| next.saturating_duration_since(origin));
+        let after: Vec<&Sample> = series
+            .iter()
+            .copied()
+            .filter(|sample| {
+                sample.start + sample.elapsed > from
+                    && until.is_none_or(|until| sample.start < until)
+            })
+            .collect();
+        let Some(first) = after.first() else {
+            continue;
+        };
+        match after
+            .iter()
+            .find(|sample| sample.p99_latency_us as f64 <= steady)
+        {
+            Some(sample) => {
+                let took = sample.start.saturating_sub(from);
+                settled.push(took);
+                log::info!(
+                    "chaos_restart {}: first sample p99 {}us; steady after {:.2}s",
+                    index + 1,
+                    first.p99_latency_us,
+                    took.as_secs_f64()
+                );
+            }
+            None => log::info!(
+                "chaos_restart {}: first sample p99 {}us; not steady before the {}",
+                index + 1,
+                first.p99_latency_us,
+                if until.is_some() {
+                    "next restart"
+                } else {
+                    "run ended"
+                }
+            ),
+        }
+    }
+    if let Some(max) = settled.iter().max() {
+        let mean = settled.iter().sum::<Duration>() / settled.len() as u32;
+        log::info!(
+            "chaos_restart_time_to_steady_sec: mean {:.2}, max {:.2} ({} of {} restarts settled)",
+            mean.as_secs_f64(),
+            max.as_secs_f64(),
+            settled.len(),
+            restarted_at.len()
+        );
+    }
+}
+
+/// Wraps a store that `Restarter` periodically crashes and reopens from disk while
+/// the load carries on, recording what each handle had acknowledged so that
+/// every restart can count how much of it survived.
+pub struct RestartingStore<S> {
+    restarts: Arc<Restarts<S>>,
+    /// This handle's store and the generation it belongs to, replaced on the first
+    /// operation after a restart.
+    local: RefCell<(u64, S)>,
+    acked: AckLog,
+}
+
+impl<S: Store + Crash + 'static> RestartingStore<S> {
+    /// `reopen` opens the store from disk again after a crash.
+    pub fn new(
+        mut store: S,
+        reopen: impl Fn() -> Result<S> + Send + Sync + 'static,
+    ) -> Result<Self, StoreError> {
+        let local = store.spawn()?;
+        let acked = AckLog::default();
+        Ok(Self {
+            restarts: Arc::new(Restarts {
+                gate: RwLock::new(()),
+                generation: AtomicU64::new(0),
+                current: Mutex::new(store),
+                reopen: Box::new(reopen),
+                ack_logs: Mutex::new(vec![Arc::clone(&acked)]),
+                stats: Mutex::new(RestartStats::default()),
+            }),
+            local: RefCell::new((0, local)),
+            acked,
+        })
+    }
+
+    /// Restart the store every `interval`, until dropped. Stops restarting if a
+    /// restart fails, leaving the store crashed.
+    pub fn spawn_restarter(&self, interval: Duration) -> Restarter {
+        let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+        let restarts = Arc::clone(&self.restarts);
+        let handle = std::thread::spawn(move || {
+            while let Err(crossbeam_channel::RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(interval)
+            {
+                if let Err(err) = restarts.restart() {
+                    log::error!("Restarting the store failed: {:?}", err);
+                    return;
+                }
+            }
+        });
+        Restarter {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// A handle on the store as of the latest restart.
+    pub fn current(&self) -> Result<S, StoreError> {
+        self.restarts
+            .current
+            .lock()
+            .map_err(|_| StoreError::LockError)?
+            .spawn()
+    }
+
+    pub fn stats(&self) -> Result<RestartStats, StoreError> {
+        Ok(self
+            .restarts
+            .stats
+            .lock()
+            .map_err(|_| StoreError::LockError)?
+            .clone())
+    }
+}
+
+impl<S: Store> RestartingStore<S> {
+    /// Run `op` on this handle's store, switching to the current one first if a
+    /// restart happened since the last operation.
+    fn with_store<T>(
+        &self,
+        op: impl FnOnce(&mut S) -> Result<T, StoreError>,
+    ) -> Result<T, StoreError> {
+        let _gate = self
+            .restarts
+            .gate
+            .read()
+            .map_err(|_| StoreError::LockError)?;
+        let generation = self.restarts.generation.load(Ordering::Relaxed);
+        let mut local = self.local.borrow_mut();
+        if local.0 != generation {
+            let store = self
+                .restarts
+                .current
+                .lock()
+                .map_err(|_| StoreError::LockError)?
+                .spawn()?;
+            *local = (generation, store);
+        }
+        op(&mut local.1)
+    }
+
+    fn ack(&self, key: &str, value: Option<Blob>, durable: bool) -> Result<(), StoreError> {
+        self.acked
+            .lock()
+            .map_err(|_| StoreError::LockError)?
+            .insert(key.to_string(), Acked { value, durable });
+        Ok(())
+    }
+}
+
+impl<S: Store> Store for RestartingStore<S> {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        self.with_store(|store| store.get(key))
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        self.with_store(|store| {
+            store.put(key, value.clone())?;
+            self.ack(key, Some(value), false)
+        })
+    }
+
+    fn put_durable(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        self.with_store(|store| {
+            store.put_durable(key, value.clone())?;
+            self.ack(key, Some(value), true)
+        })
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        self.with_store(|store| {
+            store.delete(key)?;
+            self.ack(key, None, false)
+        })
+    }
+
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        self.with_store(|store| store.range(range))
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        let local = self.with_store(|store| store.spawn())?;
+        let generation = self.local.borrow().0;
+        let acked = AckLog::default();
+        self.restarts
+            .ack_logs
+            .lock()
+            .map_err(|_| StoreError::LockError)?
+            .push(Arc::clone(&acked));
+        Ok(Self {
+            restarts: Arc::clone(&self.restarts),
+            local: RefCell::new((generation, local)),
+            acked,
+        })
+    }
+}
+
+/// Background thread restarting a `RestartingStore`; stops when dropped.
+pub struct Restarter {
+    stop: Option<crossbeam_channel::Sender<()>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for Restarter {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the thread up.
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
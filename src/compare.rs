@@ -0,0 +1,392 @@
+use std::fmt;
+use std::io::Write;
+
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+
+use crate::build_info::BuildInfo;
+use crate::file_store::{FileStore, Serializer, WritePolicy};
+use crate::load_test::{self, all_latencies, LoadParams, OutputFormat, Stats, Summary};
+use crate::mem_store::{
+    ConcurrentMemoryStore, MemoryImpl, MemoryLock, MemoryStore, RwLockMemoryStore,
+    ShardedMemoryStore,
+};
+use crate::store::Store;
+
+/// Shards of each file store that doesn't name its own count.
+const DEFAULT_FILE_COUNT: usize = 8;
+
+/// One backend configuration to compare: "memory[:SHARDS]", "memory:rwlock",
+/// "memory:dashmap", or
+/// "file:SERIALIZER:sync:WRITE_PERIOD_US" / "file:SERIALIZER:async:QUEUE_DEPTH" /
+/// "file:SERIALIZER:hybrid:SNAPSHOT_INTERVAL_MS", optionally followed by
+/// ":FILE_COUNT".
+#[derive(Clone, Debug)]
+pub struct BackendSpec {
+    /// As given, to label its row.
+    pub label: String,
+    pub backend: BackendKind,
+}
+
+#[derive(Clone, Debug)]
+pub enum BackendKind {
+    /// A `MemoryStore`, a `ShardedMemoryStore` with more than one shard, a
+    /// `RwLockMemoryStore`, or a `ConcurrentMemoryStore` for dashmap.
+    Memory {
+        shards: usize,
+        lock: MemoryLock,
+        implementation: MemoryImpl,
+    },
+    File {
+        serializer: Serializer,
+        write_policy: WritePolicy,
+        file_count: usize,
+    },
+}
+
+impl std::str::FromStr for BackendSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(':').map(str::trim).collect();
+        let backend = match parts.as_slice() {
+            ["memory"] => BackendKind::Memory {
+                shards: 1,
+                lock: MemoryLock::Mutex,
+                implementation: MemoryImpl::Hashmap,
+            },
+            ["memory", option] => {
+                let lock: Option<MemoryLock> = option.parse().ok();
+                let implementation: Option<MemoryImpl> = option.parse().ok();
+                BackendKind::Memory {
+                    shards: match (lock, implementation) {
+                        (None, None) => option.parse()?,
+                        _ => 1,
+                    },
+                    lock: lock.unwrap_or(MemoryLock::Mutex),
+                    implementation: implementation.unwrap_or(MemoryImpl::Hashmap),
+                }
+            }
+            ["file", serializer, kind, value, rest @ ..] if rest.len() <= 1 => BackendKind::File {
+                serializer: serializer.parse().map_err(|err: String| anyhow!(err))?,
+                write_policy: format!("{}:{}", kind, value).parse()?,
+                file_count: match rest {
+                    [count] => count.parse()?,
+                    _ => DEFAULT_FILE_COUNT,
+                },
+            },
+            _ => bail!(
+                "Expected memory[:SHARDS], memory:rwlock, memory:dashmap, file:SERIALIZER:sync:WRITE_PERIOD_US[:FILE_COUNT], file:SERIALIZER:async:QUEUE_DEPTH[:FILE_COUNT] or file:SERIALIZER:hybrid:SNAPSHOT_INTERVAL_MS[:FILE_COUNT], got {:?}",
+                s
+            ),
+        };
+        if let BackendKind::File { file_count: 0, .. } = backend {
+            bail!("{:?} must have at least one file", s);
+        }
+        if let BackendKind::Memory { shards: 0, .. } = backend {
+            bail!("{:?} must have at least one shard", s);
+        }
+        Ok(Self {
+            label: s.to_string(),
+            backend,
+        })
+    }
+}
+
+impl BackendKind {
+    /// The `file` subcommand's options for this configuration; none for memory.
+    pub fn file_args(&self) -> Vec<String> {
+        let BackendKind::File {
+            serializer,
+            write_policy,
+            file_count,
+        } = self
+        else {
+            return Vec::new();
+        };
+        let mut args = vec!["--file-count".to_string(), file_count.to_string()];
+        match write_policy {
+            WritePolicy::Synchronous { write_period } => args.extend([
+                "--write-period-us".to_string(),
+                write_period.as_micros().to_string(),
+            ]),
+            WritePolicy::Asynchronous { queue_depth } => {
+                args.extend(["--queue-depth".to_string(), queue_depth.to_string()])
+            }
+            WritePolicy::Hybrid {
+                wal,
+                snapshot_interval,
+            } => {
+                args.extend([
+                    "--snapshot-interval-ms".to_string(),
+                    snapshot_interval.as_millis().to_string(),
+                ]);
+                if !wal {
+                    args.push("--no-wal".to_string());
+                }
+            }
+        }
+        args.extend([
+            "--serializer".to_string(),
+            serializer.to_string().to_lowercase(),
+        ]);
+        args
+    }
+}
+
+impl fmt::Display for BackendSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.label)
+    }
+}
+
+/// Throughput and latency of one backend's run, and how they compare with the
+/// first backend's: the baseline every delta is relative to.
+#[derive(Debug, Serialize)]
+pub struct BackendResult {
+    pub backend: String,
+    pub ops: i64,
+    pub ops_per_sec: f64,
+    /// Throughput change from the baseline, as a fraction (0.1 is 10% faster).
+    pub ops_per_sec_delta: f64,
+    pub error_rate: f64,
+    pub p50_latency_us: u64,
+    pub p50_latency_delta: f64,
+    pub p99_latency_us: u64,
+    pub p99_latency_delta: f64,
+    pub p99_9_latency_us: u64,
+    pub p99_9_latency_delta: f64,
+}
+
+/// The result of every backend, baseline first.
+#[derive(Debug, Serialize)]
+pub struct Comparison {
+    /// The binary that ran every backend.
+    pub build: BuildInfo,
+    pub backends: Vec<BackendResult>,
+}
+
+/// Run the same workload against a fresh store of each backend in turn, one
+/// after the other so that they don't compete for the machine. Every file store
+/// is created in its own temporary directory, removed once its run is over.
+///
+/// `load_params` should carry a seed, so every run draws the same keys, values
+/// and operations; with `total_ops`, every run also does the same number of them.
+pub fn run(specs: &[BackendSpec], load_params: &LoadParams) -> Result<Comparison> {
+    if load_params.durable_percent > 0.0
+        && specs
+            .iter()
+            .any(|spec| matches!(spec.backend, BackendKind::Memory { .. }))
+    {
+        bail!(
+            "durable_percent needs a file store to persist to; it cannot be compared with memory"
+        );
+    }
+    let mut runs = Vec::with_capacity(specs.len());
+    for spec in specs {
+        log::info!("Running the workload against {}", spec);
+        let all_stats = match &spec.backend {
+            BackendKind::Memory {
+                implementation: MemoryImpl::Dashmap,
+                ..
+            } => load_test::load_test(ConcurrentMemoryStore::new(), load_params.clone())?,
+            BackendKind::Memory {
+                lock: MemoryLock::Rwlock,
+                ..
+            } => load_test::load_test(RwLockMemoryStore::new(), load_params.clone())?,
+            BackendKind::Memory { shards: 1, .. } => {
+                load_test::load_test(MemoryStore::new(), load_params.clone())?
+            }
+            BackendKind::Memory { shards, .. } => {
+                load_test::load_test(ShardedMemoryStore::new(*shards), load_params.clone())?
+            }
+            BackendKind::File {
+                serializer,
+                write_policy,
+                file_count,
+            } => {
+                let dir = tempfile::tempdir()?;
+                let mut store = FileStore::create(
+                    dir.path(),
+                    *file_count,
+                    write_policy,
+                    serializer.clone(),
+                    Default::default(),
+                    Default::default(),
+                )?;
+                let probe = store.spawn()?;
+                let all_stats = load_test::load_test(store, load_params.clone())?;
+                // Drain the writers before their directory is removed.
+                probe.flush()?;
+                all_stats
+            }
+        };
+        runs.push((spec.label.clone(), all_stats));
+    }
+    Comparison::new(runs)
+}
+
+impl Comparison {
+    /// Compare labelled runs, the first being the baseline.
+    pub fn new(runs: Vec<(String, Vec<Stats>)>) -> Result<Self> {
+        let runs = runs
+            .into_iter()
+            .map(|(label, all_stats)| Ok((label, Run::new(&all_stats)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let Some((_, baseline)) = runs.first() else {
+            bail!("Nothing to compare");
+        };
+        let baseline = baseline.clone();
+        let backends = runs
+            .into_iter()
+            .map(|(backend, run)| BackendResult {
+                backend,
+                ops: run.ops,
+                ops_per_sec: run.ops_per_sec,
+                ops_per_sec_delta: delta(run.ops_per_sec, baseline.ops_per_sec),
+                error_rate: run.error_rate,
+                p50_latency_us: run.p50,
+                p50_latency_delta: latency_delta(run.p50, baseline.p50),
+                p99_latency_us: run.p99,
+                p99_latency_delta: latency_delta(run.p99, baseline.p99),
+                p99_9_latency_us: run.p99_9,
+                p99_9_latency_delta: latency_delta(run.p99_9, baseline.p99_9),
+            })
+            .collect();
+        Ok(Self {
+            build: BuildInfo::current(),
+            backends,
+        })
+    }
+}
+
+/// The numbers compared from one run.
+#[derive(Clone, Debug)]
+struct Run {
+    ops: i64,
+    ops_per_sec: f64,
+    error_rate: f64,
+    p50: u64,
+    p99: u64,
+    p99_9: u64,
+}
+
+impl Run {
+    fn new(all_stats: &[Stats]) -> Result<Self> {
+        let total = Summary::new(all_stats)?.total;
+        let latencies = all_latencies(all_stats)?;
+        Ok(Self {
+            ops: total.ops,
+            ops_per_sec: total.ops_per_sec,
+            error_rate: total.error_rate,
+            p50: latencies.value_at_percentile(50.0),
+            p99: latencies.value_at_percentile(99.0),
+            p99_9: latencies.value_at_percentile(99.9),
+        })
+    }
+}
+
+/// Change from `baseline` to `value`, as a fraction of `baseline`.
+fn delta(value: f64, baseline: f64) -> f64 {
+    if baseline > 0.0 {
+        (value - baseline) / baseline
+    } else {
+        0.0
+    }
+}
+
+/// Latency change, counting anything under a microsecond (the histograms'
+/// resolution) as one, so that a sub-microsecond baseline still compares.
+fn latency_delta(value_us: u64, baseline_us: u64) -> f64 {
+    delta(value_us.max(1) as f64, baseline_us.max(1) as f64)
+}
+
+impl Comparison {
+    /// A table with a row per backend, deltas as percentages of the baseline's.
+    pub fn text_lines(&self) -> Vec<String> {
+        let width = self
+            .backends
+            .iter()
+            .map(|result| result.backend.len())
+            .chain(["backend".len()])
+            .max()
+            .unwrap_or_default();
+        let mut lines = vec![format!(
+            "{:<width$} {:>12} {:>9} {:>8} {:>8} {:>10} {:>8} {:>10} {:>9} {:>10} {:>8}",
+            "backend",
+            "ops/sec",
+            "vs_base",
+            "error%",
+            "p50_us",
+            "vs_base",
+            "p99_us",
+            "vs_base",
+            "p99.9_us",
+            "vs_base",
+            "ops",
+            width = width
+        )];
+        for result in &self.backends {
+            lines.push(format!(
+                "{:<width$} {:>12.2} {:>+8.1}% {:>7.4}% {:>8} {:>+9.1}% {:>8} {:>+9.1}% {:>9} {:>+9.1}% {:>8}",
+                result.backend,
+                result.ops_per_sec,
+                result.ops_per_sec_delta * 100.0,
+                result.error_rate * 100.0,
+                result.p50_latency_us,
+                result.p50_latency_delta * 100.0,
+                result.p99_latency_us,
+                result.p99_latency_delta * 100.0,
+                result.p99_9_latency_us,
+                result.p99_9_latency_delta * 100.0,
+                result.ops,
+                width = width
+            ));
+        }
+        lines
+    }
+
+    pub fn write_csv(&self, mut out: impl Write) -> Result<()> {
+        writeln!(
+            out,
+            "backend,ops,ops_per_sec,ops_per_sec_delta,error_rate,p50_latency_us,p50_latency_delta,p99_latency_us,p99_latency_delta,p99_9_latency_us,p99_9_latency_delta,build"
+        )?;
+        for result in &self.backends {
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{},{},{},{},{},{}",
+                result.backend,
+                result.ops,
+                result.ops_per_sec,
+                result.ops_per_sec_delta,
+                result.error_rate,
+                result.p50_latency_us,
+                result.p50_latency_delta,
+                result.p99_latency_us,
+                result.p99_latency_delta,
+                result.p99_9_latency_us,
+                result.p99_9_latency_delta,
+                self.build
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, format: OutputFormat, mut out: impl Write) -> Result<()> {
+        match format {
+            OutputFormat::Text => {
+                writeln!(out, "build: {}", self.build)?;
+                for line in self.text_lines() {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+            OutputFormat::Json => {
+                serde_json::to_writer_pretty(&mut out, self)?;
+                writeln!(out)?;
+            }
+            OutputFormat::Csv => self.write_csv(&mut out)?,
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
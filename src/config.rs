@@ -0,0 +1,591 @@
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use structopt::clap::{App, ArgMatches, ErrorKind};
+
+/// A config file option, as the arguments it stands for.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    /// A string or number, passed as the option's value.
+    Scalar(String),
+    /// `true` passes a flag; `false` leaves it out.
+    Bool(bool),
+    /// Each element is passed as another occurrence of the option.
+    Array(Vec<String>),
+}
+
+/// Options named as their flags are, without the leading dashes, in file order.
+pub type Options = Vec<(String, Value)>;
+
+/// A TOML file of command-line options: global options at the top, and the
+/// backend with its options in a `[backend]` table, e.g.
+///
+/// ```toml
+/// threads = 8
+/// read-percent = 90
+/// key-distribution = "zipfian:0.99"
+///
+/// [backend]
+/// kind = "file"
+/// file-count = 16
+/// policy-switch = ["20:async:1024", "40:sync:100000"]
+/// ```
+///
+/// Only a subset of TOML is read: tables, bare or quoted keys, strings,
+/// numbers, booleans and arrays of strings and numbers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    pub globals: Options,
+    /// The command the `[backend]` table's `kind` names, and its options.
+    pub backend: Option<(String, Options)>,
+    /// Each `[[scenario]]` table's `name` and options, in file order.
+    pub scenarios: Vec<(String, Options)>,
+}
+
+/// A table of a config file as it's read.
+struct Table {
+    header: &'static str,
+    /// The key that names the table, if it's named.
+    name_key: Option<&'static str>,
+    name: Option<String>,
+    options: Options,
+}
+
+impl Table {
+    fn new(header: &'static str, name_key: Option<&'static str>) -> Self {
+        Self {
+            header,
+            name_key,
+            name: None,
+            options: Vec::new(),
+        }
+    }
+
+    /// The name, which named tables need.
+    fn into_named(self) -> Result<(String, Options)> {
+        match (self.name, self.name_key) {
+            (Some(name), _) => Ok((name, self.options)),
+            (None, key) => bail!("{} needs a {}", self.header, key.unwrap_or("name")),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+        Self::parse(&text).with_context(|| format!("Invalid config file {:?}", path))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parser = Parser {
+            chars: text.chars().collect(),
+            pos: 0,
+        };
+        let mut globals = Table::new("the top of the file", None);
+        // Every table after the first header, in file order.
+        let mut tables: Vec<Table> = Vec::new();
+        loop {
+            parser.skip_blank_lines();
+            let Some(c) = parser.peek() else {
+                break;
+            };
+            let line = parser.line();
+            if c == '[' {
+                parser.pos += 1;
+                let array = parser.peek() == Some('[');
+                if array {
+                    parser.pos += 1;
+                }
+                parser.skip_spaces();
+                let name = parser.key()?;
+                parser.skip_spaces();
+                parser.expect(']')?;
+                if array {
+                    parser.expect(']')?;
+                }
+                parser.end_of_line()?;
+                let table = match (name.as_str(), array) {
+                    ("backend", false) => Table::new("[backend]", Some("kind")),
+                    ("scenario", true) => Table::new("[[scenario]]", Some("name")),
+                    _ => bail!(
+                        "line {}: unknown table {:?}; only [backend] and [[scenario]] are",
+                        line,
+                        name
+                    ),
+                };
+                if table.header == "[backend]" && tables.iter().any(|t| t.header == table.header) {
+                    bail!("line {}: [backend] is defined twice", line);
+                }
+                tables.push(table);
+                continue;
+            }
+            let key = parser.key()?;
+            parser.skip_spaces();
+            parser.expect('=')?;
+            parser.skip_spaces();
+            let value = parser.value()?;
+            parser.end_of_line()?;
+            let table = tables.last_mut().unwrap_or(&mut globals);
+            if table.name_key == Some(key.as_str()) {
+                let Value::Scalar(name) = value else {
+                    bail!("line {}: {} must be a string", line, key);
+                };
+                if table.name.replace(name).is_some() {
+                    bail!("line {}: {} is defined twice", line, key);
+                }
+                continue;
+            }
+            if flag_name(&key) == "config" {
+                bail!("line {}: config files can't name other config files", line);
+            }
+            if table
+                .options
+                .iter()
+                .any(|(defined, _)| flag_name(defined) == flag_name(&key))
+            {
+                bail!("line {}: {} is defined twice", line, key);
+            }
+            table.options.push((key, value));
+        }
+        let mut config = Self {
+            globals: globals.options,
+            ..Self::default()
+        };
+        for table in tables {
+            if table.header == "[backend]" {
+                config.backend = Some(table.into_named()?);
+                continue;
+            }
+            let scenario = table.into_named()?;
+            if config.scenarios.iter().any(|(name, _)| *name == scenario.0) {
+                bail!("There are two scenarios named {:?}", scenario.0);
+            }
+            config.scenarios.push(scenario);
+        }
+        Ok(config)
+    }
+}
+
+/// Keys may use underscores, as the flags' descriptions do, for dashes.
+fn flag_name(key: &str) -> String {
+    key.replace('_', "-")
+}
+
+fn push_args(options: &Options, args: &mut Vec<OsString>) {
+    for (key, value) in options {
+        let flag = format!("--{}", flag_name(key));
+        match value {
+            // Joined with `=`, so that values starting with a dash aren't taken for
+            // flags.
+            Value::Scalar(value) => args.push(format!("{}={}", flag, value).into()),
+            Value::Bool(true) => args.push(flag.into()),
+            Value::Bool(false) => {}
+            Value::Array(values) => args.extend(
+                values
+                    .iter()
+                    .map(|value| format!("{}={}", flag, value).into()),
+            ),
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    /// The line `pos` is on, counted from 1.
+    fn line(&self) -> usize {
+        1 + self.chars[..self.pos.min(self.chars.len())]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count()
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.pos += 1;
+            }
+        }
+    }
+
+    /// Past whitespace, comments and line breaks.
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n' | '\r') => self.pos += 1,
+                _ => return,
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        if self.peek() != Some(expected) {
+            bail!("line {}: expected {:?}", self.line(), expected);
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn end_of_line(&mut self) -> Result<()> {
+        self.skip_spaces();
+        self.skip_comment();
+        match self.peek() {
+            None | Some('\n' | '\r') => Ok(()),
+            Some(c) => bail!("line {}: unexpected {:?} after the value", self.line(), c),
+        }
+    }
+
+    fn key(&mut self) -> Result<String> {
+        match self.peek() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            _ => {
+                let key = self.bare_token();
+                if key.is_empty() || key.contains(['.', '+', ':']) {
+                    bail!("line {}: expected a key, e.g. threads", self.line());
+                }
+                Ok(key)
+            }
+        }
+    }
+
+    /// Up to the next space, `=`, `,`, `]`, `#` or line break.
+    fn bare_token(&mut self) -> String {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || "_-+.:".contains(c)) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some('"') => Ok(Value::Scalar(self.basic_string()?)),
+            Some('\'') => Ok(Value::Scalar(self.literal_string()?)),
+            Some('[') => self.array(),
+            _ => self.bare_value(),
+        }
+    }
+
+    fn bare_value(&mut self) -> Result<Value> {
+        let line = self.line();
+        let token = self.bare_token();
+        match token.as_str() {
+            "true" => return Ok(Value::Bool(true)),
+            "false" => return Ok(Value::Bool(false)),
+            _ => {}
+        }
+        let number = token.replace('_', "");
+        let is_number = number.parse::<i64>().is_ok()
+            || (number.contains(|c: char| c.is_ascii_digit()) && number.parse::<f64>().is_ok());
+        if !is_number {
+            bail!(
+                "line {}: {:?} isn't a string, number, boolean or array; strings need quotes",
+                line,
+                token
+            );
+        }
+        Ok(Value::Scalar(number))
+    }
+
+    fn array(&mut self) -> Result<Value> {
+        let line = self.line();
+        self.expect('[')?;
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank_lines();
+            if self.peek() == Some(']') {
+                break;
+            }
+            match self.value()? {
+                Value::Scalar(value) => values.push(value),
+                _ => bail!(
+                    "line {}: arrays can only hold strings and numbers",
+                    self.line()
+                ),
+            }
+            self.skip_blank_lines();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => break,
+                None => bail!("line {}: unterminated array", line),
+                Some(c) => bail!("line {}: expected ',' or ']', not {:?}", self.line(), c),
+            }
+        }
+        self.pos += 1;
+        Ok(Value::Array(values))
+    }
+
+    fn literal_string(&mut self) -> Result<String> {
+        let line = self.line();
+        self.expect('\'')?;
+        let mut string = String::new();
+        loop {
+            match self.peek() {
+                Some('\'') => break,
+                None | Some('\n') => bail!("line {}: unterminated string", line),
+                Some(c) => string.push(c),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        Ok(string)
+    }
+
+    fn basic_string(&mut self) -> Result<String> {
+        let line = self.line();
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            let c = match self.peek() {
+                Some('"') => break,
+                None | Some('\n') => bail!("line {}: unterminated string", line),
+                Some(c) => c,
+            };
+            self.pos += 1;
+            if c != '\\' {
+                string.push(c);
+                continue;
+            }
+            let escape = self.peek();
+            self.pos += 1;
+            string.push(match escape {
+                Some('b') => '\u{8}',
+                Some('t') => '\t',
+                Some('n') => '\n',
+                Some('f') => '\u{c}',
+                Some('r') => '\r',
+                Some('"') => '"',
+                Some('\\') => '\\',
+                Some(u @ ('u' | 'U')) => {
+                    let len = if u == 'u' { 4 } else { 8 };
+                    let digits: String = self.chars.iter().skip(self.pos).take(len).collect();
+                    self.pos += len;
+                    u32::from_str_radix(&digits, 16)
+                        .ok()
+                        .filter(|_| digits.len() == len)
+                        .and_then(char::from_u32)
+                        .with_context(|| format!("line {}: bad escape \\{}{}", line, u, digits))?
+                }
+                _ => bail!("line {}: unknown escape in string", line),
+            });
+        }
+        self.pos += 1;
+        Ok(string)
+    }
+}
+
+/// A command line, with the options of the config file it names with --config
+/// merged in.
+#[derive(Debug)]
+pub struct Invocation {
+    args: Vec<OsString>,
+    /// Index in `args` of the command, if there is one.
+    command: Option<usize>,
+    config: Option<(PathBuf, Config)>,
+}
+
+impl Invocation {
+    /// Splits `args` as `app` would, without validating them.
+    pub fn new(app: &App, args: Vec<OsString>) -> Result<Self> {
+        let mut invocation = Self {
+            args,
+            command: None,
+            config: None,
+        };
+        if !invocation
+            .args
+            .iter()
+            .any(|arg| arg.to_string_lossy().starts_with("--config"))
+        {
+            return Ok(invocation);
+        }
+        invocation.command = command_index(app, &invocation.args);
+        let globals = &invocation.args[1..invocation.command.unwrap_or(invocation.args.len())];
+        let mut path = None;
+        for (i, arg) in globals.iter().enumerate() {
+            let arg = arg.to_string_lossy();
+            if arg == "--config" {
+                path = globals.get(i + 1).map(PathBuf::from);
+            } else if let Some(value) = arg.strip_prefix("--config=") {
+                path = Some(PathBuf::from(value));
+            }
+        }
+        if let Some(path) = path {
+            let config = Config::load(&path)?;
+            invocation.config = Some((path, config));
+        }
+        Ok(invocation)
+    }
+
+    /// The arguments to run with: the file's global options, then the command
+    /// line's, so that they override the file's, then the command line's command
+    /// or, if it has none, the file's backend.
+    pub fn args(&self) -> Vec<OsString> {
+        let Some((_, config)) = &self.config else {
+            return self.args.clone();
+        };
+        let mut args = self.args[..1].to_vec();
+        push_args(&config.globals, &mut args);
+        self.push_command_line(config, &mut args);
+        args
+    }
+
+    /// `args`, but with the file's backend in place of the command line's
+    /// command; None if the file has no backend.
+    pub fn file_backend_args(&self) -> Option<Vec<OsString>> {
+        let (_, config) = self.config.as_ref()?;
+        config.backend.as_ref()?;
+        let mut args = self.globals(config);
+        push_backend(config, &mut args);
+        Some(args)
+    }
+
+    /// The arguments of each of the file's scenarios, named: `args`, with the
+    /// scenario's options after the file's global options, and so also overridden
+    /// by the command line's. Scenarios may only set the options in `allowed`.
+    pub fn scenario_args(&self, allowed: &[&str]) -> Result<Vec<(String, Vec<OsString>)>> {
+        let Some((_, config)) = &self.config else {
+            return Ok(Vec::new());
+        };
+        let mut scenarios = Vec::with_capacity(config.scenarios.len());
+        for (name, options) in &config.scenarios {
+            if let Some((key, _)) = options
+                .iter()
+                .find(|(key, _)| !allowed.contains(&flag_name(key).as_str()))
+            {
+                bail!(
+                    "Scenario {:?} sets {}, which applies to the whole run; set it at the top of the file",
+                    name,
+                    key
+                );
+            }
+            let mut args = self.args[..1].to_vec();
+            push_args(&config.globals, &mut args);
+            push_args(options, &mut args);
+            self.push_command_line(config, &mut args);
+            scenarios.push((name.clone(), args));
+        }
+        Ok(scenarios)
+    }
+
+    fn globals(&self, config: &Config) -> Vec<OsString> {
+        let mut args = self.args[..1].to_vec();
+        push_args(&config.globals, &mut args);
+        let command = self.command.unwrap_or(self.args.len());
+        args.extend_from_slice(&self.args[1..command]);
+        args
+    }
+
+    /// The command line's global options and command, or the file's backend.
+    fn push_command_line(&self, config: &Config, args: &mut Vec<OsString>) {
+        let command = self.command.unwrap_or(self.args.len());
+        args.extend_from_slice(&self.args[1..]);
+        if command == self.args.len() {
+            push_backend(config, args);
+        }
+    }
+}
+
+fn push_backend(config: &Config, args: &mut Vec<OsString>) {
+    if let Some((kind, options)) = &config.backend {
+        args.push(kind.into());
+        push_args(options, args);
+    }
+}
+
+/// The index of the first argument that `app` would parse as a command, found by
+/// putting clap's own `help` command there: if the arguments before it leave it
+/// to be parsed as a command, rather than as an option's value, clap shows help.
+fn command_index(app: &App, args: &[OsString]) -> Option<usize> {
+    (1..args.len()).find(|&i| {
+        if args[i].to_string_lossy().starts_with('-') {
+            return false;
+        }
+        let probe = args[..i]
+            .iter()
+            .map(OsString::as_os_str)
+            .chain([OsStr::new("help")]);
+        matches!(
+            app.clone().get_matches_from_safe(probe),
+            Err(err) if err.kind == ErrorKind::HelpDisplayed
+        )
+    })
+}
+
+/// `matches` as a config file, with every option set, including to its default,
+/// and with `matches`' command as the `[backend]` table if `backend`.
+pub fn render(matches: &ArgMatches, backend: bool) -> String {
+    let mut text = String::new();
+    render_options(matches, &mut text, |name| name != "config");
+    if let (true, (kind, Some(options))) = (backend, matches.subcommand()) {
+        let _ = writeln!(text, "\n[backend]\nkind = {}", toml_value(kind));
+        render_options(options, &mut text, |_| true);
+    }
+    text
+}
+
+/// A `[[scenario]]` table of `matches`' options in `options`, for `render`'s
+/// output.
+pub fn render_scenario(name: &str, matches: &ArgMatches, options: &[&str]) -> String {
+    let mut text = format!("\n[[scenario]]\nname = {}\n", toml_value(name));
+    render_options(matches, &mut text, |name| options.contains(&name));
+    text
+}
+
+fn render_options(matches: &ArgMatches, text: &mut String, keep: impl Fn(&str) -> bool) {
+    // clap 2 has no public way to list the arguments it matched; `args` is
+    // public but hidden from its docs, and clap 2 no longer changes.
+    let mut names: Vec<&str> = matches
+        .args
+        .keys()
+        .copied()
+        .filter(|&name| keep(name))
+        .collect();
+    names.sort_unstable();
+    for name in names {
+        let values: Vec<String> = matches
+            .values_of_lossy(name)
+            .unwrap_or_default()
+            .iter()
+            .map(|value| toml_value(value))
+            .collect();
+        let _ = match values.as_slice() {
+            [] => writeln!(text, "{} = true", name),
+            [value] => writeln!(text, "{} = {}", name, value),
+            values => writeln!(text, "{} = [{}]", name, values.join(", ")),
+        };
+    }
+}
+
+/// Numbers bare, if they'd be read back as the same text, and anything else
+/// as a string.
+fn toml_value(value: &str) -> String {
+    let bare = value.parse::<i64>().is_ok_and(|n| n.to_string() == value)
+        || value
+            .parse::<f64>()
+            .is_ok_and(|n| n.is_finite() && value.contains('.') && n.to_string() == value);
+    if bare {
+        value.to_string()
+    } else {
+        serde_json::Value::from(value).to_string()
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::Result;
+
+use crate::store::{Blob, StoreError};
+
+/// Most search steps spent on one key before its history is reported as
+/// inconclusive rather than checked.
+const MAX_STEPS_PER_KEY: u64 = 10_000_000;
+
+/// Most operations of a violating key that `Report::log` prints.
+const MAX_LOGGED_OPS: usize = 50;
+
+/// What an operation did to a key, and for gets, what it saw.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OpKind {
+    Get(Option<Blob>),
+    Put(Blob),
+    Delete,
+}
+
+/// One completed operation, with when it was invoked and when it returned, in
+/// nanoseconds since the history's origin.
+#[derive(Clone, Debug)]
+pub struct Operation {
+    pub thread: usize,
+    pub key: String,
+    pub kind: OpKind,
+    pub invoked: u64,
+    pub returned: u64,
+    /// Whether the store returned an error. A failed write may or may not have
+    /// taken effect, so histories with one aren't checked.
+    pub failed: bool,
+}
+
+/// Global history of a load test's operations, for `check`. Threads record into
+/// their own buffers and add them here when they finish, so recording doesn't
+/// serialize them.
+#[derive(Debug)]
+pub struct History {
+    origin: Instant,
+    ops: Mutex<Vec<Operation>>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+            ops: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Nanoseconds from the history's origin to `at`.
+    pub fn offset_ns(&self, at: Instant) -> u64 {
+        at.saturating_duration_since(self.origin).as_nanos() as u64
+    }
+
+    pub fn extend(&self, ops: Vec<Operation>) -> Result<()> {
+        self.ops
+            .lock()
+            .map_err(|_| StoreError::LockError)?
+            .extend(ops);
+        Ok(())
+    }
+
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.ops.lock().map_err(|_| StoreError::LockError)?.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Outcome of checking a history, key by key.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub keys_checked: usize,
+    pub ops_checked: usize,
+    /// Keys whose operations can't be put in any order consistent with both
+    /// their timing and a single register, with their operations.
+    pub violations: Vec<(String, Vec<Operation>)>,
+    /// Keys whose search ran out of steps.
+    pub inconclusive: Vec<String>,
+    /// Keys skipped because a write to them failed.
+    pub skipped: Vec<String>,
+}
+
+impl Report {
+    pub fn log(&self) {
+        log::info!(
+            "linearizability: {} operations on {} keys checked, {} violating keys, {} inconclusive, {} skipped after failed writes",
+            self.ops_checked,
+            self.keys_checked,
+            self.violations.len(),
+            self.inconclusive.len(),
+            self.skipped.len()
+        );
+        if let Some((key, ops)) = self.violations.first() {
+            log::error!(
+                "History of {:?}, the first key that isn't linearizable ({} operations; times in us):",
+                key,
+                ops.len()
+            );
+            for op in ops.iter().take(MAX_LOGGED_OPS) {
+                log::error!(
+                    "  thread {} {:.1}-{:.1}: {}",
+                    op.thread,
+                    op.invoked as f64 / 1000.0,
+                    op.returned as f64 / 1000.0,
+                    describe(&op.kind)
+                );
+            }
+        }
+        for key in &self.inconclusive {
+            log::warn!(
+                "linearizability: gave up on {:?}; record a shorter history",
+                key
+            );
+        }
+    }
+}
+
+fn describe(kind: &OpKind) -> String {
+    let value = |value: Option<&Blob>| match value {
+        Some(value) => serde_json::Value::from(value.clone()).to_string(),
+        None => "(nil)".to_string(),
+    };
+    match kind {
+        OpKind::Get(seen) => format!("get -> {}", value(seen.as_ref())),
+        OpKind::Put(written) => format!("put {}", value(Some(written))),
+        OpKind::Delete => "delete".to_string(),
+    }
+}
+
+/// Check `history` for linearizability against a key-value store in which every
+/// key is a register. Keys are independent, so each is checked on its own, with
+/// an unknown initial value (the store may have held data before the run).
+pub fn check(history: &History) -> Result<Report> {
+    let ops = std::mem::take(&mut *history.ops.lock().map_err(|_| StoreError::LockError)?);
+    let mut by_key: BTreeMap<String, Vec<Operation>> = BTreeMap::new();
+    for op in ops {
+        // A failed get observed nothing.
+        if op.failed && matches!(op.kind, OpKind::Get(_)) {
+            continue;
+        }
+        by_key.entry(op.key.clone()).or_default().push(op);
+    }
+    let mut report = Report::default();
+    for (key, ops) in by_key {
+        if ops.iter().any(|op| op.failed) {
+            report.skipped.push(key);
+            continue;
+        }
+        report.keys_checked += 1;
+        report.ops_checked += ops.len();
+        match check_register(&ops) {
+            Some(true) => {}
+            Some(false) => report.violations.push((key, ops)),
+            None => report.inconclusive.push(key),
+        }
+    }
+    Ok(report)
+}
+
+/// Register state during the search: unknown until the first write or read, then
+/// the index of the value held (None if deleted).
+type State = Option<Option<usize>>;
+
+/// Wing and Gong's search, with Lowe's memoization of (linearized set, state)
+/// pairs: walk the history's calls and returns in time order, tentatively
+/// linearizing each call whose effect is consistent with the register, and
+/// backtracking when an operation returns without having been linearized.
+/// Returns None if the search exceeded its step budget.
+fn check_register(ops: &[Operation]) -> Option<bool> {
+    // Each operation as whether it writes, and the index among the distinct values
+    // of what it wrote or saw, so that states are small and cheap to compare.
+    let mut values: Vec<&Blob> = Vec::new();
+    let effects: Vec<(bool, Option<usize>)> = ops
+        .iter()
+        .map(|op| match &op.kind {
+            OpKind::Get(seen) => (false, seen.as_ref().map(|v| intern(&mut values, v))),
+            OpKind::Put(written) => (true, Some(intern(&mut values, written))),
+            OpKind::Delete => (true, None),
+        })
+        .collect();
+    let apply = |state: State, op: usize| -> Option<State> {
+        match effects[op] {
+            (true, value) => Some(Some(value)),
+            (false, seen) => match state {
+                None => Some(Some(seen)),
+                Some(held) if held == seen => Some(state),
+                Some(_) => None,
+            },
+        }
+    };
+
+    // Calls and returns in time order, calls first on ties so that operations
+    // that might have overlapped are treated as concurrent. Entry 2i is op i's
+    // call and 2i+1 its return; the list is threaded through `next` and `prev`,
+    // with a sentinel at `head`.
+    let mut events: Vec<(u64, bool, usize)> = Vec::with_capacity(ops.len() * 2);
+    for (i, op) in ops.iter().enumerate() {
+        events.push((op.invoked, false, 2 * i));
+        events.push((op.returned, true, 2 * i + 1));
+    }
+    events.sort_unstable();
+    let head = ops.len() * 2;
+    let mut next = vec![usize::MAX; head + 1];
+    let mut prev = vec![usize::MAX; head + 1];
+    let mut last = head;
+    for &(_, _, entry) in &events {
+        next[last] = entry;
+        prev[entry] = last;
+        last = entry;
+    }
+
+    let mut linearized = vec![0u64; ops.len().div_ceil(64)];
+    let mut seen: HashSet<(Vec<u64>, State)> = HashSet::new();
+    let mut stack: Vec<(usize, State)> = Vec::new();
+    let mut state: State = None;
+    let mut entry = next[head];
+    let mut steps = 0;
+    while next[head] != usize::MAX {
+        steps += 1;
+        if steps > MAX_STEPS_PER_KEY {
+            return None;
+        }
+        let op = entry / 2;
+        if entry % 2 == 0 {
+            if let Some(new_state) = apply(state, op) {
+                linearized[op / 64] |= 1 << (op % 64);
+                if seen.insert((linearized.clone(), new_state)) {
+                    stack.push((entry, state));
+                    state = new_state;
+                    // Remove the call and its return; the return must go first so
+                    // that relinking in reverse order restores the list.
+                    unlink(&mut next, &mut prev, entry + 1);
+                    unlink(&mut next, &mut prev, entry);
+                    entry = next[head];
+                    continue;
+                }
+                linearized[op / 64] &= !(1 << (op % 64));
+            }
+            entry = next[entry];
+        } else {
+            // `op` returned before it could be linearized: undo the last choice.
+            let Some((call, previous)) = stack.pop() else {
+                return Some(false);
+            };
+            let undone = call / 2;
+            linearized[undone / 64] &= !(1 << (undone % 64));
+            state = previous;
+            relink(&mut next, &mut prev, call);
+            relink(&mut next, &mut prev, call + 1);
+            entry = next[call];
+        }
+    }
+    Some(true)
+}
+
+fn intern<'a>(values: &mut Vec<&'a Blob>, value: &'a Blob) -> usize {
+    match values.iter().position(|v| *v == value) {
+        Some(index) => index,
+        None => {
+            values.push(value);
+            values.len() - 1
+        }
+    }
+}
+
+/// Remove `entry` from the list, leaving its own links for `relink`.
+fn unlink(next: &mut [usize], prev: &mut [usize], entry: usize) {
+    let (p, n) = (prev[entry], next[entry]);
+    next[p] = n;
+    if n != usize::MAX {
+        prev[n] = p;
+    }
+}
+
+fn relink(next: &mut [usize], prev: &mut [usize], entry: usize) {
+    let (p, n) = (prev[entry], next[entry]);
+    next[p] = entry;
+    if n != usize::MAX {
+        prev[n] = entry;
+    }
+}
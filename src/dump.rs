@@ -0,0 +1,259 @@
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use structopt::clap::arg_enum;
+
+use crate::store::{Blob, Store};
+
+arg_enum! {
+    /// How a dump or import file is laid out.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum DumpFormat {
+        // One `{"key": ..., "value": ...}` object per line.
+        Ndjson,
+        // A header row naming `key` and `value` columns, then one row per key.
+        Csv,
+    }
+}
+
+impl DumpFormat {
+    /// CSV for paths ending in .csv, NDJSON otherwise.
+    pub fn of_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("csv") => Self::Csv,
+            _ => Self::Ndjson,
+        }
+    }
+
+    /// Whether `path` looks like a dump rather than a Redis file.
+    pub fn is_dump_path(path: &Path) -> bool {
+        path.extension().is_some_and(|extension| {
+            ["ndjson", "jsonl", "csv"]
+                .iter()
+                .any(|known| extension.eq_ignore_ascii_case(known))
+        })
+    }
+}
+
+/// How values are written in a dump, or read from an import.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueEncoding {
+    /// `Blob`'s own serde representation, e.g. `{"Str": "x"}`, as the file store's
+    /// JSON shards hold. Every value round-trips.
+    Tagged,
+    /// Plain JSON, converted as `Blob`'s `serde_json::Value` conversions do.
+    /// Booleans become integers, and bytes become lists of integers.
+    Json,
+    /// The value is a string, taken as it is.
+    String,
+}
+
+impl FromStr for ValueEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "tagged" => Ok(Self::Tagged),
+            "json" => Ok(Self::Json),
+            "string" => Ok(Self::String),
+            _ => bail!("expected tagged, json or string, not {:?}", s),
+        }
+    }
+}
+
+impl ValueEncoding {
+    /// Tagged for NDJSON, which a dump writes; strings for CSV, whose cells
+    /// usually are.
+    pub fn default_for(format: DumpFormat) -> Self {
+        match format {
+            DumpFormat::Ndjson => Self::Tagged,
+            DumpFormat::Csv => Self::String,
+        }
+    }
+
+    fn encode(self, value: Blob) -> Result<serde_json::Value> {
+        Ok(match self {
+            Self::Tagged => serde_json::to_value(value)?,
+            Self::Json => value.into(),
+            Self::String => match value {
+                Blob::Str(s) => serde_json::Value::String(s),
+                _ => bail!("only strings can be written with the string encoding"),
+            },
+        })
+    }
+
+    fn decode(self, value: serde_json::Value) -> Result<Blob> {
+        Ok(match self {
+            Self::Tagged => serde_json::from_value(value)
+                .context("not a tagged value, e.g. {\"Str\": \"x\"}")?,
+            Self::Json => value.into(),
+            Self::String => match value {
+                serde_json::Value::String(s) => Blob::Str(s),
+                _ => bail!("not a string"),
+            },
+        })
+    }
+
+    /// A CSV cell holds JSON for the tagged and JSON encodings.
+    fn decode_cell(self, cell: String) -> Result<Blob> {
+        match self {
+            Self::String => Ok(Blob::Str(cell)),
+            _ => self.decode(serde_json::from_str(&cell).context("not JSON")?),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Record {
+    key: String,
+    value: serde_json::Value,
+}
+
+/// Write `rows` as NDJSON, in key order.
+pub fn write_ndjson(
+    rows: &mut [(String, Blob)],
+    encoding: ValueEncoding,
+    mut out: impl Write,
+) -> Result<()> {
+    rows.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (key, value) in rows.iter() {
+        let record = Record {
+            key: key.clone(),
+            value: encoding
+                .encode(value.clone())
+                .with_context(|| format!("key {:?}", key))?,
+        };
+        serde_json::to_writer(&mut out, &record)?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Put every key of a dump in `input` into `store`, returning how many there
+/// were. Blank NDJSON lines are skipped. Later duplicates of a key overwrite
+/// earlier ones; a malformed record stops the import, with its line number.
+pub fn import<S: Store>(
+    input: impl BufRead,
+    format: DumpFormat,
+    encoding: ValueEncoding,
+    store: &mut S,
+) -> Result<u64> {
+    match format {
+        DumpFormat::Ndjson => import_ndjson(input, encoding, store),
+        DumpFormat::Csv => import_csv(input, encoding, store),
+    }
+}
+
+fn import_ndjson<S: Store>(
+    input: impl BufRead,
+    encoding: ValueEncoding,
+    store: &mut S,
+) -> Result<u64> {
+    let mut imported = 0;
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let context = || format!("line {}", index + 1);
+        let record: Record = serde_json::from_str(&line).with_context(context)?;
+        let value = encoding.decode(record.value).with_context(context)?;
+        store.put(&record.key, value).with_context(context)?;
+        imported += 1;
+    }
+    Ok(imported)
+}
+
+fn import_csv<S: Store>(
+    input: impl BufRead,
+    encoding: ValueEncoding,
+    store: &mut S,
+) -> Result<u64> {
+    let mut rows = CsvRows::new(input);
+    let Some((_, header)) = rows.next_row()? else {
+        bail!("CSV input is empty; it needs a header naming key and value columns");
+    };
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|column| column.trim().eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("CSV header {:?} has no {} column", header, name))
+    };
+    let (key_column, value_column) = (column("key")?, column("value")?);
+    let mut imported = 0;
+    while let Some((line, mut row)) = rows.next_row()? {
+        if row.len() == 1 && row[0].is_empty() {
+            continue;
+        }
+        if row.len() != header.len() {
+            bail!(
+                "line {}: {} fields, but the header has {}",
+                line,
+                row.len(),
+                header.len()
+            );
+        }
+        let context = || format!("line {}", line);
+        let value = encoding
+            .decode_cell(std::mem::take(&mut row[value_column]))
+            .with_context(context)?;
+        store.put(&row[key_column], value).with_context(context)?;
+        imported += 1;
+    }
+    Ok(imported)
+}
+
+/// Rows of RFC 4180 CSV: fields separated by commas, and double-quoted if they
+/// hold commas, quotes (doubled) or line breaks.
+struct CsvRows<R> {
+    input: R,
+    line: usize,
+}
+
+impl<R: BufRead> CsvRows<R> {
+    fn new(input: R) -> Self {
+        Self { input, line: 0 }
+    }
+
+    /// The next row and the line it starts on; None at the end of the input.
+    fn next_row(&mut self) -> Result<Option<(usize, Vec<String>)>> {
+        let mut text = String::new();
+        if self.input.read_line(&mut text)? == 0 {
+            return Ok(None);
+        }
+        self.line += 1;
+        let start = self.line;
+        // A quoted field may span lines; read on until the quotes balance.
+        while text.matches('"').count() % 2 == 1 {
+            if self.input.read_line(&mut text)? == 0 {
+                bail!("line {}: unterminated quoted field", start);
+            }
+            self.line += 1;
+        }
+        let text = text
+            .strip_suffix('\n')
+            .map(|text| text.strip_suffix('\r').unwrap_or(text))
+            .unwrap_or(&text);
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = !quoted,
+                ',' if !quoted => fields.push(std::mem::take(&mut field)),
+                c => field.push(c),
+            }
+        }
+        fields.push(field);
+        Ok(Some((start, fields)))
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::store::Blob;
+
+/// Column holding each row's key.
+const KEY_COLUMN: &str = "_key";
+
+/// Column holding values that aren't Dicts, and any Dict fields that couldn't be
+/// given a column of their own, as JSON.
+const VALUE_COLUMN: &str = "_value";
+
+/// The kinds of value seen in a column, which determine its SQL type.
+#[derive(Default)]
+struct ColumnKinds {
+    int: bool,
+    float: bool,
+    text: bool,
+    bytes: bool,
+    json: bool,
+}
+
+impl ColumnKinds {
+    fn add(&mut self, value: &Blob) {
+        match value {
+            Blob::Null => {}
+            Blob::Int(_) => self.int = true,
+            Blob::Float(_) => self.float = true,
+            Blob::Str(_) => self.text = true,
+            Blob::Bytes(_) => self.bytes = true,
+            Blob::List(_) | Blob::Dict(_) => self.json = true,
+        }
+    }
+
+    fn sql_type(&self) -> &'static str {
+        match (self.int, self.float, self.text, self.bytes, self.json) {
+            (true, false, false, false, false) => "INTEGER",
+            (_, true, false, false, false) => "REAL",
+            (false, false, false, true, false) => "BLOB",
+            _ => "TEXT",
+        }
+    }
+}
+
+/// Schema for a table of `(key, value)` rows: Dict fields become columns of their
+/// own where possible, and everything else ends up in `VALUE_COLUMN`. Nested
+/// values (Lists and Dicts) are stored as JSON.
+struct Schema {
+    fields: BTreeMap<String, ColumnKinds>,
+    value: Option<ColumnKinds>,
+}
+
+impl Schema {
+    fn derive(rows: &[(String, Blob)]) -> Self {
+        let mut fields: BTreeMap<String, ColumnKinds> = BTreeMap::new();
+        let mut value: Option<ColumnKinds> = None;
+        for (_, blob) in rows {
+            match blob {
+                Blob::Dict(dict) => {
+                    for (field, field_value) in dict {
+                        if Self::is_reserved(field) {
+                            value.get_or_insert_with(Default::default).json = true;
+                        } else {
+                            fields.entry(field.clone()).or_default().add(field_value);
+                        }
+                    }
+                }
+                blob => value.get_or_insert_with(Default::default).add(blob),
+            }
+        }
+        Self { fields, value }
+    }
+
+    fn is_reserved(field: &str) -> bool {
+        field == KEY_COLUMN || field == VALUE_COLUMN
+    }
+
+    fn columns(&self) -> Vec<&str> {
+        let mut columns = vec![KEY_COLUMN];
+        columns.extend(self.fields.keys().map(String::as_str));
+        if self.value.is_some() {
+            columns.push(VALUE_COLUMN);
+        }
+        columns
+    }
+
+    fn create_table(&self, table: &str) -> String {
+        let mut columns = vec![format!("{} TEXT PRIMARY KEY", identifier(KEY_COLUMN))];
+        for (field, kinds) in &self.fields {
+            columns.push(format!("{} {}", identifier(field), kinds.sql_type()));
+        }
+        if let Some(kinds) = &self.value {
+            columns.push(format!("{} {}", identifier(VALUE_COLUMN), kinds.sql_type()));
+        }
+        format!(
+            "CREATE TABLE {} (\n    {}\n);",
+            identifier(table),
+            columns.join(",\n    ")
+        )
+    }
+
+    /// The row's values, in `columns()` order.
+    fn row_values(&self, key: &str, blob: &Blob) -> Result<Vec<String>> {
+        let mut values = vec![literal(&Blob::Str(key.to_string()))?];
+        let empty = HashMap::new();
+        let dict = match blob {
+            Blob::Dict(dict) => dict,
+            _ => &empty,
+        };
+        for field in self.fields.keys() {
+            values.push(literal(dict.get(field).unwrap_or(&Blob::Null))?);
+        }
+        if self.value.is_some() {
+            let value = match blob {
+                Blob::Dict(dict) => {
+                    let leftover: HashMap<_, _> = dict
+                        .iter()
+                        .filter(|(field, _)| Self::is_reserved(field))
+                        .map(|(field, value)| (field.clone(), value.clone()))
+                        .collect();
+                    if leftover.is_empty() {
+                        Blob::Null
+                    } else {
+                        Blob::Dict(leftover)
+                    }
+                }
+                blob => blob.clone(),
+            };
+            values.push(literal(&value)?);
+        }
+        Ok(values)
+    }
+}
+
+fn identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Plain JSON for nested values, rather than `Blob`'s tagged serde representation,
+/// so they can be queried with SQL JSON functions. Unlike `From<Blob>`, bytes
+/// become hex strings.
+fn to_json(value: &Blob) -> serde_json::Value {
+    match value {
+        Blob::Bytes(bytes) => hex(bytes).into(),
+        Blob::List(values) => values.iter().map(to_json).collect(),
+        Blob::Dict(dict) => dict
+            .iter()
+            .map(|(field, value)| (field.clone(), to_json(value)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        scalar => scalar.clone().into(),
+    }
+}
+
+fn literal(value: &Blob) -> Result<String> {
+    Ok(match value {
+        Blob::Null => "NULL".to_string(),
+        Blob::Int(i) => i.to_string(),
+        Blob::Float(f) if f.is_finite() => format!("{:?}", f),
+        Blob::Float(_) => "NULL".to_string(),
+        Blob::Str(s) => format!("'{}'", s.replace('\'', "''")),
+        Blob::Bytes(bytes) => format!("X'{}'", hex(bytes)),
+        Blob::List(_) | Blob::Dict(_) => format!(
+            "'{}'",
+            serde_json::to_string(&to_json(value))?.replace('\'', "''")
+        ),
+    })
+}
+
+/// Rows written between checkpoints of a resumable export.
+const CHECKPOINT_ROWS: usize = 10_000;
+
+const STORE_CHANGED: &str =
+    "The store has changed since the export was interrupted; export again without resuming";
+
+/// Call `line` with each line of the SQL script for `rows`, which must be sorted,
+/// along with the number of rows written once that line is.
+fn sql_lines(
+    rows: &[(String, Blob)],
+    table: &str,
+    mut line: impl FnMut(usize, &str) -> Result<()>,
+) -> Result<()> {
+    let schema = Schema::derive(rows);
+    let columns: Vec<String> = schema.columns().into_iter().map(identifier).collect();
+    line(0, "BEGIN TRANSACTION;")?;
+    line(0, &schema.create_table(table))?;
+    for (written, (key, blob)) in rows.iter().enumerate() {
+        line(
+            written + 1,
+            &format!(
+                "INSERT INTO {} ({}) VALUES ({});",
+                identifier(table),
+                columns.join(", "),
+                schema.row_values(key, blob)?.join(", ")
+            ),
+        )?;
+    }
+    line(rows.len(), "COMMIT;")
+}
+
+/// Write `rows` as a SQL script that creates `table` and inserts every row,
+/// inside a single transaction. Rows are written in key order.
+pub fn write_sql(rows: &mut [(String, Blob)], table: &str, mut out: impl Write) -> Result<()> {
+    rows.sort_by(|a, b| a.0.cmp(&b.0));
+    sql_lines(rows, table, |_, line| Ok(writeln!(out, "{}", line)?))?;
+    out.flush()?;
+    Ok(())
+}
+
+/// How far an interrupted export to `path` got, saved next to it.
+#[derive(Debug, Deserialize, Serialize)]
+struct ExportCheckpoint {
+    table: String,
+    rows: usize,
+    last_key: Option<String>,
+    /// Length and CRC32 of the script written so far.
+    bytes: u64,
+    crc: u32,
+}
+
+impl ExportCheckpoint {
+    fn path(sql: &Path) -> PathBuf {
+        let mut path = sql.as_os_str().to_owned();
+        path.push(".checkpoint");
+        PathBuf::from(path)
+    }
+
+    fn load(path: &Path) -> Result<Option<Self>> {
+        match File::open(path) {
+            Ok(file) => Ok(Some(serde_json::from_reader(BufReader::new(file))?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Write via a temporary file, so that a crash leaves the previous checkpoint.
+    fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        let file = File::create(&tmp)?;
+        serde_json::to_writer(&file, self)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// The script being written, skipping the prefix already written by an
+/// interrupted export once it has been regenerated identically.
+struct ResumedScript {
+    out: BufWriter<File>,
+    crc: crc32fast::Hasher,
+    bytes: u64,
+    /// Length and CRC32 of the script already on disk.
+    skip: u64,
+    skip_crc: u32,
+}
+
+impl ResumedScript {
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        let line = format!("{}\n", line);
+        self.crc.update(line.as_bytes());
+        let start = self.bytes;
+        self.bytes += line.len() as u64;
+        if start >= self.skip {
+            self.out.write_all(line.as_bytes())?;
+        } else if self.bytes > self.skip
+            || (self.bytes == self.skip && self.crc.clone().finalize() != self.skip_crc)
+        {
+            // The line doesn't end where the partial script does, or the script up
+            // to there differs.
+            bail!(STORE_CHANGED);
+        }
+        Ok(())
+    }
+}
+
+/// As `write_sql`, but to the file at `sql`, checkpointing every
+/// `CHECKPOINT_ROWS` rows so that an interrupted export can be resumed. On
+/// resume, the script on disk must match its checkpoint, and the store must
+/// produce the same script up to that point; the rest is then appended. The
+/// checkpoint is removed once the script is complete.
+pub fn write_sql_resumable(
+    rows: &mut [(String, Blob)],
+    table: &str,
+    sql: &Path,
+    resume: bool,
+) -> Result<()> {
+    rows.sort_by(|a, b| a.0.cmp(&b.0));
+    let checkpoint_path = ExportCheckpoint::path(sql);
+    let checkpoint = if resume {
+        ExportCheckpoint::load(&checkpoint_path)?
+    } else {
+        None
+    };
+    let file = match &checkpoint {
+        Some(checkpoint) => {
+            if checkpoint.table != table {
+                bail!(
+                    "The export checkpoint is for table {:?}, not {:?}",
+                    checkpoint.table,
+                    table
+                );
+            }
+            let mut file = OpenOptions::new().read(true).write(true).open(sql)?;
+            verify_prefix(&mut file, checkpoint.bytes, checkpoint.crc)
+                .with_context(|| format!("Verifying the partial export {:?}", sql))?;
+            file.set_len(checkpoint.bytes)?;
+            file.seek(SeekFrom::End(0))?;
+            log::info!(
+                "Resuming export to {:?} after {} rows (last key {:?}).",
+                sql,
+                checkpoint.rows,
+                checkpoint.last_key
+            );
+            file
+        }
+        None => File::create(sql)?,
+    };
+    let mut script = ResumedScript {
+        out: BufWriter::new(file),
+        crc: crc32fast::Hasher::new(),
+        bytes: 0,
+        skip: checkpoint.as_ref().map(|c| c.bytes).unwrap_or(0),
+        skip_crc: checkpoint.as_ref().map(|c| c.crc).unwrap_or(0),
+    };
+    sql_lines(rows, table, |written, line| {
+        script.write_line(line)?;
+        if script.bytes > script.skip && written > 0 && written % CHECKPOINT_ROWS == 0 {
+            script.out.flush()?;
+            script.out.get_ref().sync_data()?;
+            ExportCheckpoint {
+                table: table.to_string(),
+                rows: written,
+                last_key: rows.get(written - 1).map(|(key, _)| key.clone()),
+                bytes: script.bytes,
+                crc: script.crc.clone().finalize(),
+            }
+            .save(&checkpoint_path)?;
+        }
+        Ok(())
+    })?;
+    if script.bytes < script.skip {
+        bail!(STORE_CHANGED);
+    }
+    script.out.flush()?;
+    script.out.get_ref().sync_all()?;
+    match std::fs::remove_file(&checkpoint_path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Check that the first `len` bytes of `file` have CRC32 `crc`.
+fn verify_prefix(file: &mut File, len: u64, crc: u32) -> Result<()> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut prefix = BufReader::new(&mut *file).take(len);
+    let mut buf = [0; 64 * 1024];
+    let mut read = 0;
+    loop {
+        let n = prefix.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        read += n as u64;
+    }
+    if read != len || hasher.finalize() != crc {
+        bail!("The partial script doesn't match its checkpoint; export again without resuming");
+    }
+    Ok(())
+}
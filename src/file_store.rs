@@ -1,21 +1,30 @@
-use std::collections::hash_map::DefaultHasher;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet, VecDeque};
 use std::fs::File;
 use std::hash::{Hash, Hasher};
 use std::io::{BufWriter, Read, Write};
-use std::path::Path;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use rand::Rng;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use structopt::clap::arg_enum;
 
-use crate::mem_store::MemoryStoreSingleThreaded;
-use crate::store::{Blob, Store, StoreError};
+use crate::chaos::Crash;
+use crate::manifest::Manifest;
+use crate::mem_store::{MemoryStoreSingleThreaded, Tombstone};
+use crate::router::{HashFunction, Router, ShardRouter};
+use crate::spans;
+use crate::store::{Blob, ExplainStep, Explanation, Store, StoreError};
+use crate::value_sizes::{self, ValueSizes};
 
 arg_enum! {
-    #[derive(Clone, Debug)]
+    #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
     pub enum Serializer {
         Json,
         Cbor,
@@ -24,7 +33,17 @@ arg_enum! {
 }
 
 impl Serializer {
-    fn write<T: Serialize, W: Write>(&self, writer: W, value: &T) -> Result<()> {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, StoreError> {
+        let mut bytes = Vec::new();
+        match self {
+            Self::Json => serde_json::to_writer(&mut bytes, value)?,
+            Self::Cbor => ciborium::ser::into_writer(value, &mut bytes)?,
+            // Add new serialization formats here.
+        };
+        Ok(bytes)
+    }
+
+    fn write<T: Serialize, W: Write>(&self, writer: W, value: &T) -> Result<(), StoreError> {
         let mut writer = BufWriter::new(writer);
         match self {
             Self::Json => serde_json::to_writer(&mut writer, value)?,
@@ -35,38 +54,154 @@ impl Serializer {
         Ok(())
     }
 
-    fn read<T: DeserializeOwned, R: Read>(&self, reader: R) -> Result<T> {
+    fn read<T: DeserializeOwned, R: Read>(&self, reader: R) -> Result<T, StoreError> {
         Ok(match self {
             Self::Json => serde_json::from_reader(reader)?,
             Self::Cbor => ciborium::de::from_reader(reader)?,
             // Add new serialization formats here.
         })
     }
+
+    /// Replace the contents of `path` with `value`, returning the number of bytes
+    /// written. Writes to a temporary file first so that an interrupted write never
+    /// leaves a truncated file behind, and removes the delta log the new contents
+    /// supersede. The time spent encoding and writing is added to `phases`, if given.
+    fn overwrite<T: Serialize>(
+        &self,
+        path: &Path,
+        value: &T,
+        phases: Option<&WritePhases>,
+    ) -> Result<u64, StoreError> {
+        self.overwrite_with(path, value, false, phases)
+    }
+
+    /// As `overwrite`, but only returns once the new contents and the rename are on
+    /// disk: the temporary file is fsynced before the rename, and the directory
+    /// after it.
+    fn overwrite_durably<T: Serialize>(
+        &self,
+        path: &Path,
+        value: &T,
+        phases: Option<&WritePhases>,
+    ) -> Result<u64, StoreError> {
+        self.overwrite_with(path, value, true, phases)
+    }
+
+    fn overwrite_with<T: Serialize>(
+        &self,
+        path: &Path,
+        value: &T,
+        durable: bool,
+        phases: Option<&WritePhases>,
+    ) -> Result<u64, StoreError> {
+        let mut span = spans::span(if durable { "durable flush" } else { "flush" }, "persist");
+        let _flush = phases.map(FlushInProgress::new);
+        let started = Instant::now();
+        let bytes = self.encode(value)?;
+        span.set_arg("bytes", bytes.len() as u64);
+        let serialized = Instant::now();
+
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&bytes)?;
+        let checksum_path = ShardChecksum::path(path);
+        let checksum_tmp_path = checksum_path.with_extension("crc32.tmp");
+        let mut checksum_file = File::create(&checksum_tmp_path)?;
+        checksum_file.write_all(ShardChecksum::of(&bytes).to_string().as_bytes())?;
+        let mut fsync = Duration::ZERO;
+        if durable {
+            let started = Instant::now();
+            file.sync_all()?;
+            checksum_file.sync_all()?;
+            fsync += started.elapsed();
+        }
+        // A crash between the renames leaves a checksum that doesn't match a shard
+        // that still decodes, which `verify` reports as stale rather than corrupt.
+        std::fs::rename(tmp_path, path)?;
+        std::fs::rename(checksum_tmp_path, checksum_path)?;
+        DeltaLog::remove(path)?;
+        if durable {
+            if let Some(dir) = path.parent() {
+                let started = Instant::now();
+                File::open(dir)?.sync_all()?;
+                fsync += started.elapsed();
+            }
+        }
+        if let Some(phases) = phases {
+            phases.serialize.record(serialized - started);
+            phases.disk_write.record(serialized.elapsed() - fsync);
+            if durable {
+                phases.fsync.record(fsync);
+            }
+        }
+        Ok(bytes.len() as u64)
+    }
 }
 
-/// Simple hasher to determine the output file for a given key.
-#[derive(Clone, Debug)]
-struct SimpleHasher {
-    max_values: usize,
+/// CRC32 and length of a shard file's contents, kept next to it in a ".crc32"
+/// file that is rewritten with every flush, so that `verify` can spot corruption
+/// that still decodes. Shards written before checksums were kept have none.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShardChecksum {
+    pub crc32: u32,
+    pub len: u64,
 }
 
-impl SimpleHasher {
-    fn new(max_values: usize) -> Self {
-        Self { max_values }
+impl ShardChecksum {
+    pub fn of(bytes: &[u8]) -> Self {
+        Self {
+            crc32: crc32fast::hash(bytes),
+            len: bytes.len() as u64,
+        }
+    }
+
+    /// Where the checksum of the shard file at `shard` is kept.
+    pub fn path(shard: &Path) -> PathBuf {
+        shard.with_extension("crc32")
+    }
+
+    /// The checksum recorded for the shard file at `shard`; None if there is none.
+    pub fn load(shard: &Path) -> Result<Option<Self>> {
+        let text = match std::fs::read_to_string(Self::path(shard)) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let parsed = text.trim().split_once(' ').and_then(|(crc32, len)| {
+            Some(Self {
+                crc32: u32::from_str_radix(crc32, 16).ok()?,
+                len: len.parse().ok()?,
+            })
+        });
+        parsed
+            .map(Some)
+            .ok_or_else(|| anyhow!("Malformed checksum file for {:?}: {:?}", shard, text))
+    }
+
+    /// Record this as the checksum of the shard file at `shard`, durably.
+    pub fn save(&self, shard: &Path) -> Result<()> {
+        let path = Self::path(shard);
+        let tmp_path = path.with_extension("crc32.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(self.to_string().as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, path)?;
+        Ok(())
     }
 }
 
-impl SimpleHasher {
-    fn hash_key(&self, key: &str) -> usize {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
-        // In production code, we'd use u64 everywhere to be explicit; for now
-        // we'll stick with usize for simplicity.
-        (hash as usize) % self.max_values
+impl std::fmt::Display for ShardChecksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:08x} {}", self.crc32, self.len)
     }
 }
 
+/// How long `FileStore::flush` waits for asynchronous writers to drain.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of most recent background errors kept for a store's `Health`.
+const BACKGROUND_ERROR_LOG_CAPACITY: usize = 64;
+
 struct Poller {
     period: Duration,
     last_time: Instant,
@@ -91,221 +226,4070 @@ impl Poller {
     }
 }
 
-pub enum WritePolicy {
-    Synchronous { write_period: Duration },
-    Asynchronous { queue_depth: usize },
+/// A message to the asynchronous writer's background thread.
+enum Queued {
+    /// Persist a change to a key; a value of None marks a delete.
+    Write(String, Option<Blob>),
+    /// Persist a soft delete of a key made at this time (ms since the epoch).
+    SoftDelete(String, u64),
+    Undelete(String),
+    /// Persist the purge of tombstones made before this time (ms since the epoch).
+    Purge(u64),
+    /// Durably persist everything queued before this, then acknowledge.
+    Sync(crossbeam_channel::Sender<Result<(), StoreError>>),
+    /// Stop at once, leaving the rest of the queue unwritten; see `Writer::crash`.
+    Abandon,
 }
 
-enum Writer {
+#[derive(Clone, Debug, PartialEq)]
+pub enum WritePolicy {
     Synchronous {
-        poller: Poller,
-        serializer: Serializer,
-        file: File,
+        write_period: Duration,
     },
     Asynchronous {
-        sender: crossbeam_channel::Sender<(String, Blob)>,
-        _handle: std::thread::JoinHandle<()>,
+        queue_depth: usize,
+    },
+    /// Append every write to the shard's write-ahead log as it happens, if `wal`
+    /// is set, and snapshot dirty shards in the background every
+    /// `snapshot_interval`, which empties the log.
+    Hybrid {
+        wal: bool,
+        snapshot_interval: Duration,
     },
 }
 
-impl Writer {
-    fn new(
-        policy: &WritePolicy,
-        mem_store: &MemoryStoreSingleThreaded,
-        serializer: Serializer,
-        filename: &Path,
-    ) -> Result<Self> {
-        let file = File::create(&filename)?;
+/// Parses "sync:WRITE_PERIOD_US", "async:QUEUE_DEPTH", "hybrid:SNAPSHOT_INTERVAL_MS",
+/// or "snapshot:SNAPSHOT_INTERVAL_MS" for the hybrid policy without its log.
+impl std::str::FromStr for WritePolicy {
+    type Err = anyhow::Error;
 
-        let writer = match policy {
-            WritePolicy::Synchronous { write_period } => {
-                let poller = Poller::new(*write_period);
-                Self::Synchronous {
-                    poller,
-                    serializer,
-                    file,
-                }
-            }
-            WritePolicy::Asynchronous { queue_depth } => {
-                #[allow(clippy::type_complexity)]
-                let (sender, receiver): (
-                    crossbeam_channel::Sender<(String, Blob)>,
-                    crossbeam_channel::Receiver<(String, Blob)>,
-                ) = crossbeam_channel::bounded(*queue_depth);
-
-                // Keep a copy of the memstore state in the background thread.
-                let mut async_writer_mem_store_mirror = mem_store.clone();
-
-                let handle = std::thread::spawn(move || loop {
-                    if let Ok((key, value)) = receiver.recv() {
-                        if let Err(err) = async_writer_mem_store_mirror.put(&key, value) {
-                            // TODO: Hard failure.
-                            log::error!("put error: {:?}", err);
-                        }
-                        if let Err(err) = serializer.write(&file, &async_writer_mem_store_mirror) {
-                            // TODO: This should be a hard failure; we can imagine an "errors"
-                            // return channel that dequeues any pending write errors and handles
-                            // them appropriately.
-                            log::error!("write error: {:?}", err);
-                        }
-                    }
-                });
-                Self::Asynchronous {
-                    _handle: handle,
-                    sender,
-                }
-            }
-        };
-        Ok(writer)
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, value) = s.split_once(':').ok_or_else(|| {
+            anyhow!(
+                "Expected sync:WRITE_PERIOD_US, async:QUEUE_DEPTH, hybrid:SNAPSHOT_INTERVAL_MS or snapshot:SNAPSHOT_INTERVAL_MS, got {:?}",
+                s
+            )
+        })?;
+        Ok(match kind.trim() {
+            "sync" => Self::Synchronous {
+                write_period: Duration::from_micros(value.trim().parse()?),
+            },
+            "async" => match value.trim().parse()? {
+                0 => bail!("The asynchronous queue depth must be at least 1"),
+                queue_depth => Self::Asynchronous { queue_depth },
+            },
+            kind @ ("hybrid" | "snapshot") => match value.trim().parse()? {
+                0 => bail!("The snapshot interval must be at least 1ms"),
+                interval_ms => Self::Hybrid {
+                    wal: kind == "hybrid",
+                    snapshot_interval: Duration::from_millis(interval_ms),
+                },
+            },
+            other => bail!(
+                "Unknown write policy {:?}; expected sync, async, hybrid or snapshot",
+                other
+            ),
+        })
     }
+}
 
-    fn write(
-        &mut self,
-        key: &str,
-        value: &Blob,
-        mem_store: &MemoryStoreSingleThreaded,
-    ) -> Result<()> {
-        match self {
-            Writer::Synchronous {
-                poller,
-                file,
-                serializer,
-            } => {
-                if poller.elapsed() {
-                    serializer.write(file, mem_store)?;
-                }
-            }
-            Writer::Asynchronous { sender, .. } => {
-                sender.send((key.to_owned(), value.clone()))?;
+/// How long a writer blocking with a timeout sleeps between checks for room in
+/// its queue.
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+/// What an asynchronous writer does with a change when its queue is full. Parses
+/// "block", "fail-fast", "drop-oldest" or "block-with-timeout:TIMEOUT_MS".
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum QueueFullPolicy {
+    /// Wait for room, however long it takes.
+    #[default]
+    Block,
+    /// Fail the change with `StoreError::QueueFull`, leaving the shard unchanged.
+    FailFast,
+    /// Drop the oldest queued change to make room. The persisted shard misses it
+    /// until its key is written again; a durable put waiting on a dropped sync
+    /// fails with `StoreError::QueueFull`.
+    DropOldest,
+    /// Wait for room, failing as `FailFast` does once the timeout passes.
+    BlockWithTimeout(Duration),
+}
+
+impl std::str::FromStr for QueueFullPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim().split_once(':') {
+            Some(("block-with-timeout", timeout_ms)) => {
+                Self::BlockWithTimeout(Duration::from_millis(timeout_ms.trim().parse()?))
             }
-        };
-        Ok(())
+            _ => match s.trim() {
+                "block" => Self::Block,
+                "fail-fast" => Self::FailFast,
+                "drop-oldest" => Self::DropOldest,
+                other => bail!(
+                    "Unknown queue full policy {:?}; expected block, fail-fast, drop-oldest or block-with-timeout:TIMEOUT_MS",
+                    other
+                ),
+            },
+        })
     }
 }
 
-/// Internal representation to encapsulate file operations.
-struct BackingFile {
-    mem_store: MemoryStoreSingleThreaded,
-    writer: Writer,
+/// What a store's background threads do when they hit an error: the asynchronous
+/// writers, the persistence flusher, the compactor and the policy switcher.
+/// Parses "abort-process", "fail-store" or "log-and-continue".
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BackgroundErrorPolicy {
+    /// Log the error and abort the whole process at once.
+    AbortProcess,
+    /// Log the error and fail every later operation on the store.
+    FailStore,
+    /// Log the error and carry on, as if it hadn't happened.
+    #[default]
+    LogAndContinue,
 }
 
-impl BackingFile {
-    fn new(
-        size: usize,
-        index: usize,
-        path: &Path,
-        write_policy: &WritePolicy,
-        serializer: Serializer,
-    ) -> Result<Self> {
-        // TODO: Use file locks, otherwise multiple threads creating backing files could
-        // cause odd issues.
-        let basename = format!("store_size={}_idx={}", size, index);
-        let filename = path.join(basename);
-        // If the file already exists, load it from memory.
-        let mem_store = if filename.exists() {
-            log::info!(
-                "File {:?} already exists. Attempting to load previous data.",
-                filename
-            );
-            // Try to read existing data.
-            let existing_file = File::open(&filename)?;
-            match serializer.read(existing_file) {
-                Ok(existing_data) => existing_data,
-                Err(err) => {
-                    // TODO Rename with timestamp
-                    log::error!(
-                        "Could not load key/value store data from {:?}; skipping: {:?}",
-                        filename,
-                        err
-                    );
-                    let now = chrono::Local::now();
-                    let timestamp = now.format("%Y-%m-%d_%H%M%S");
-                    let backup_filename = filename.with_extension(format!("backup{}", timestamp));
-                    std::fs::rename(&filename, backup_filename)?;
-                    MemoryStoreSingleThreaded::new()
+impl std::str::FromStr for BackgroundErrorPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim() {
+            "abort-process" => Self::AbortProcess,
+            "fail-store" => Self::FailStore,
+            "log-and-continue" => Self::LogAndContinue,
+            other => bail!(
+                "Unknown background error policy {:?}; expected abort-process, fail-store or log-and-continue",
+                other
+            ),
+        })
+    }
+}
+
+impl std::fmt::Display for BackgroundErrorPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::AbortProcess => "abort-process",
+            Self::FailStore => "fail-store",
+            Self::LogAndContinue => "log-and-continue",
+        })
+    }
+}
+
+/// An error hit by one of a store's background threads.
+#[derive(Clone, Debug)]
+pub struct BackgroundError {
+    /// Which thread: "async writer", "flusher", "compactor" or "policy switcher".
+    pub thread: &'static str,
+    pub error: String,
+    /// Since the store was opened.
+    pub at: Duration,
+}
+
+/// Background errors of a store and the policy applied to them, shared by all of
+/// its shards, handles and threads.
+struct BackgroundErrors {
+    policy: Mutex<BackgroundErrorPolicy>,
+    started: Instant,
+    count: AtomicU64,
+    recent: Mutex<VecDeque<BackgroundError>>,
+    /// The first error that failed the store under `FailStore`.
+    failure: Mutex<Option<String>>,
+    failed: AtomicBool,
+}
+
+impl BackgroundErrors {
+    fn new() -> Self {
+        Self {
+            policy: Mutex::new(BackgroundErrorPolicy::default()),
+            started: Instant::now(),
+            count: AtomicU64::new(0),
+            recent: Mutex::new(VecDeque::with_capacity(BACKGROUND_ERROR_LOG_CAPACITY)),
+            failure: Mutex::new(None),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    fn policy(&self) -> BackgroundErrorPolicy {
+        self.policy.lock().map(|policy| *policy).unwrap_or_default()
+    }
+
+    /// Log and record `err`, then apply the policy to it.
+    fn report(&self, thread: &'static str, err: impl std::fmt::Debug) {
+        let policy = self.policy();
+        log::error!("{} error: {:?} (policy {})", thread, err, policy);
+        let error = format!("{:?}", err);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut recent) = self.recent.lock() {
+            if recent.len() == BACKGROUND_ERROR_LOG_CAPACITY {
+                recent.pop_front();
+            }
+            recent.push_back(BackgroundError {
+                thread,
+                error: error.clone(),
+                at: self.started.elapsed(),
+            });
+        }
+        match policy {
+            BackgroundErrorPolicy::AbortProcess => {
+                log::logger().flush();
+                std::process::abort();
+            }
+            BackgroundErrorPolicy::FailStore => {
+                if let Ok(mut failure) = self.failure.lock() {
+                    failure.get_or_insert_with(|| format!("{} error: {}", thread, error));
                 }
+                self.failed.store(true, Ordering::Relaxed);
             }
-        } else {
-            MemoryStoreSingleThreaded::new()
-        };
+            BackgroundErrorPolicy::LogAndContinue => {}
+        }
+    }
 
-        let writer = Writer::new(write_policy, &mem_store, serializer, &filename)?;
+    /// Fails once a background error has failed the store.
+    fn check(&self) -> Result<(), StoreError> {
+        if !self.failed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let failure = self
+            .failure
+            .lock()
+            .map_err(|_| StoreError::LockError)?
+            .clone()
+            .unwrap_or_default();
+        Err(StoreError::BackgroundFailure(failure))
+    }
 
-        Ok(Self { mem_store, writer })
+    fn health(&self) -> Result<Health> {
+        Ok(Health {
+            policy: self.policy(),
+            errors: self.count.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            recent: self
+                .recent
+                .lock()
+                .map_err(|_| StoreError::LockError)?
+                .iter()
+                .cloned()
+                .collect(),
+        })
     }
+}
 
-    fn read(&self, key: &str) -> Result<Blob> {
-        self.mem_store.get(key)
+/// A store's background error policy and the errors it was applied to.
+#[derive(Clone, Debug)]
+pub struct Health {
+    pub policy: BackgroundErrorPolicy,
+    /// Every background error, including those no longer in `recent`.
+    pub errors: u64,
+    /// Whether an error has failed the store under `FailStore`.
+    pub failed: bool,
+    /// The most recent errors, oldest first.
+    pub recent: Vec<BackgroundError>,
+}
+
+impl Health {
+    pub fn log(&self) {
+        log::info!("background_error_policy: {}", self.policy);
+        log::info!("background_errors: {}", self.errors);
+        log::info!("store_failed: {}", self.failed);
+        for error in &self.recent {
+            log::info!(
+                "background_error: {:?} {} {}",
+                error.at,
+                error.thread,
+                error.error
+            );
+        }
     }
 
-    fn write(&mut self, key: &str, value: Blob) -> Result<()> {
-        self.writer.write(key, &value, &self.mem_store)?;
-        self.mem_store.put(key, value)?;
+    /// As `log`, but written to `out`.
+    pub fn write(&self, mut out: impl Write) -> Result<()> {
+        writeln!(out, "background_error_policy: {}", self.policy)?;
+        writeln!(out, "background_errors: {}", self.errors)?;
+        writeln!(out, "store_failed: {}", self.failed)?;
+        for error in &self.recent {
+            writeln!(out, "{:?} {} {}", error.at, error.thread, error.error)?;
+        }
         Ok(())
     }
 }
 
-pub struct FileStore {
-    files: Vec<Arc<Mutex<BackingFile>>>,
-    hasher: SimpleHasher,
+/// Reads a store's `Health` from outside it, e.g. for a failure bundle.
+#[derive(Clone)]
+pub struct HealthHandle {
+    errors: Arc<BackgroundErrors>,
 }
 
-impl FileStore {
-    // TODO: Use a builder.
-    pub fn new(
-        output_path: &Path,
-        file_count: usize,
-        write_policy: &WritePolicy,
-        serializer: Serializer,
-    ) -> Result<Self> {
-        // Preinitialize backing stores.
-        let mut files = Vec::with_capacity(file_count);
-        for index in 0..file_count {
-            files.push(Arc::new(Mutex::new(BackingFile::new(
-                file_count,
-                index,
-                output_path,
-                write_policy,
-                serializer.clone(),
-            )?)));
-        }
+impl HealthHandle {
+    pub fn health(&self) -> Result<Health> {
+        self.errors.health()
+    }
+}
+
+/// A change of write policy at a time after `FileStore::spawn_policy_switcher`.
+/// Parses "AT_SEC:POLICY", e.g. "30:async:1024".
+#[derive(Clone, Debug)]
+pub struct PolicySwitch {
+    pub at: Duration,
+    pub policy: WritePolicy,
+}
+
+impl std::str::FromStr for PolicySwitch {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (at, policy) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected AT_SEC:POLICY, got {:?}", s))?;
         Ok(Self {
-            files,
-            hasher: SimpleHasher::new(file_count),
+            at: Duration::from_secs_f64(at.trim().parse()?),
+            policy: policy.parse()?,
         })
     }
 }
 
-impl Store for FileStore {
-    fn get(&self, key: &str) -> Result<Blob> {
-        let index = self.hasher.hash_key(key);
-        let file = self
-            .files
-            .get(index)
-            .ok_or(StoreError::BadFileHash(index))?;
-        {
-            let guard = file.lock().map_err(|_| StoreError::LockError)?;
-            guard.read(key)
+/// Persistence lag of one shard under the synchronous policy.
+#[derive(Clone, Debug, Default)]
+pub struct LagGauge {
+    pub flushes: u64,
+    /// Longest a write has waited to be flushed.
+    pub max_lag: Duration,
+    /// Flushes that happened after the configured deadline.
+    pub deadline_misses: u64,
+    /// How long the oldest unflushed write has been waiting, if any.
+    pub current_lag: Option<Duration>,
+}
+
+/// Time spent in one phase of the write path, summed over a shard's writes.
+#[derive(Debug, Default)]
+struct PhaseTimer {
+    count: AtomicU64,
+    total_ns: AtomicU64,
+    max_ns: AtomicU64,
+}
+
+impl PhaseTimer {
+    fn record(&self, elapsed: Duration) {
+        let ns = elapsed.as_nanos() as u64;
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_ns.fetch_add(ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(ns, Ordering::Relaxed);
+    }
+
+    fn since(&self, started: Instant) {
+        self.record(started.elapsed());
+    }
+}
+
+/// Where a shard's writes spend their time. Puts, durable puts and deletes wait
+/// for the shard's lock and update its map; then a synchronous writer's flushes,
+/// or the asynchronous writer's thread once per batch of queued changes, encode
+/// the whole shard, write it to disk and, for durable puts, fsync it. The
+/// asynchronous policy adds the wait to hand a write to its thread when the queue
+/// is full.
+#[derive(Debug, Default)]
+struct WritePhases {
+    lock_wait: PhaseTimer,
+    map_insert: PhaseTimer,
+    queue: PhaseTimer,
+    serialize: PhaseTimer,
+    disk_write: PhaseTimer,
+    fsync: PhaseTimer,
+    /// Flushes begun and ended, however they ended; a flush is in progress while
+    /// they differ.
+    flushes_begun: AtomicU64,
+    flushes_ended: AtomicU64,
+    /// Writes of the shard by the asynchronous writer, and the changes they held.
+    batches: AtomicU64,
+    batched_changes: AtomicU64,
+    /// Changes dropped from the asynchronous writer's full queue, under
+    /// `QueueFullPolicy::DropOldest`.
+    dropped_changes: AtomicU64,
+    /// Bytes persisted since the store was opened, by whichever writers.
+    bytes_written: AtomicU64,
+}
+
+impl WritePhases {
+    /// Flushes begun so far, and whether one is in progress.
+    fn flush_state(&self) -> (u64, bool) {
+        let ended = self.flushes_ended.load(Ordering::Relaxed);
+        let begun = self.flushes_begun.load(Ordering::Relaxed);
+        (begun, begun > ended)
+    }
+
+    fn timers(&self) -> [(&'static str, &PhaseTimer); 6] {
+        [
+            ("lock_wait", &self.lock_wait),
+            ("map_insert", &self.map_insert),
+            ("queue", &self.queue),
+            ("serialize", &self.serialize),
+            ("disk_write", &self.disk_write),
+            ("fsync", &self.fsync),
+        ]
+    }
+}
+
+/// Counts a flush as begun until dropped, however the flush ends.
+struct FlushInProgress<'a>(&'a WritePhases);
+
+impl<'a> FlushInProgress<'a> {
+    fn new(phases: &'a WritePhases) -> Self {
+        phases.flushes_begun.fetch_add(1, Ordering::Relaxed);
+        Self(phases)
+    }
+}
+
+impl Drop for FlushInProgress<'_> {
+    fn drop(&mut self) {
+        self.0.flushes_ended.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Time spent in one write-path phase, over every shard; see `FileStore::write_phases`.
+#[derive(Clone, Debug, Default)]
+pub struct PhaseStats {
+    pub phase: &'static str,
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+/// One key's state as a shard's delta log records it: its value, if it has one,
+/// and its tombstone, if it was soft-deleted.
+#[derive(Deserialize, Serialize)]
+struct DeltaEntry {
+    key: String,
+    value: Option<Blob>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tombstone: Option<Tombstone>,
+}
+
+/// Length of a delta log's header: the CRC32 and length of the snapshot it applies
+/// to.
+const DELTA_HEADER_LEN: usize = 12;
+
+/// Changes appended to a synchronous shard's ".delta" file between full snapshots
+/// of it; see `FileStore::with_delta_persistence`. Each flush appends one frame
+/// holding the state of every key changed since the last: its length and CRC32,
+/// then the entries, encoded with the shard's serializer.
+///
+/// The log starts with the checksum of the snapshot it was written on top of, so
+/// that a snapshot written since, however it was written, makes it stale instead of
+/// wrong; writing a snapshot also removes it.
+///
+/// With a maximum segment size, the log is split into numbered segments, ".delta"
+/// then ".delta.1", ".delta.2" and so on, each starting with the same checksum. A
+/// frame that would take a segment past the maximum starts the next one instead.
+struct DeltaLog {
+    /// The segment being appended to, and its size so far.
+    segment: u32,
+    segment_bytes: u64,
+    max_segment_bytes: Option<u64>,
+    /// Set when the log is the hybrid policy's write-ahead log, appended to on
+    /// every change, leaving flushes to write snapshots.
+    wal: bool,
+    /// Keys changed since the last flush.
+    dirty: HashSet<String>,
+    /// Write a full snapshot every this many flushes.
+    snapshot_every: u32,
+    /// Flushes appended to the log since the last snapshot; None when the next
+    /// flush must write a snapshot, as the first one does.
+    appended: Option<u32>,
+    /// Bytes of the log, and of the snapshot it applies to; a log grown larger than
+    /// its snapshot is replaced by a new one.
+    log_bytes: u64,
+    snapshot_bytes: u64,
+}
+
+impl DeltaLog {
+    fn new(snapshot_every: u32) -> Self {
+        Self {
+            segment: 0,
+            segment_bytes: 0,
+            max_segment_bytes: None,
+            wal: false,
+            dirty: HashSet::new(),
+            snapshot_every,
+            appended: None,
+            log_bytes: 0,
+            snapshot_bytes: 0,
         }
     }
 
-    fn put(&mut self, key: &str, value: Blob) -> Result<()> {
-        let index = self.hasher.hash_key(key);
-        let file = self
-            .files
-            .get(index)
-            .ok_or(StoreError::BadFileHash(index))?;
-        // Minimizing the length of time we hold the lock for.
-        {
-            let mut guard = file.lock().map_err(|_| StoreError::LockError)?;
-            guard.write(key, value)
+    /// The hybrid policy's write-ahead log.
+    fn wal() -> Self {
+        Self {
+            wal: true,
+            ..Self::new(u32::MAX)
         }
     }
 
-    fn spawn(&mut self) -> Result<Self> {
-        Ok(Self {
-            files: self.files.iter().map(Arc::clone).collect(),
-            hasher: self.hasher.clone(),
-        })
+    /// Where the delta log of the shard file at `shard` starts: its first segment.
+    fn path(shard: &Path) -> PathBuf {
+        Self::segment_path(shard, 0)
+    }
+
+    fn segment_path(shard: &Path, segment: u32) -> PathBuf {
+        match segment {
+            0 => shard.with_extension("delta"),
+            segment => shard.with_extension(format!("delta.{}", segment)),
+        }
+    }
+
+    /// The number of the log segment at `path`, if it's one of the shard's.
+    fn segment_number(shard: &Path, path: &Path) -> Option<u32> {
+        let log = Self::path(shard);
+        let rest = path.to_str()?.strip_prefix(log.to_str()?)?;
+        match rest.strip_prefix('.') {
+            None if rest.is_empty() => Some(0),
+            Some(number) => number.parse().ok().filter(|&number| number > 0),
+            None => None,
+        }
+    }
+
+    /// The delta log segments of the shard file at `shard` that exist, in order.
+    fn segments(shard: &Path) -> std::io::Result<Vec<(u32, PathBuf)>> {
+        let dir = match shard.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut segments = Vec::new();
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(segments),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let path = dir.join(entry?.file_name());
+            if let Some(number) = Self::segment_number(shard, &path) {
+                segments.push((number, path));
+            }
+        }
+        segments.sort_unstable();
+        Ok(segments)
+    }
+
+    /// Remove every segment of the shard's log, the last first, so that an
+    /// interrupted removal leaves a stale log rather than a broken one.
+    fn remove(shard: &Path) -> std::io::Result<()> {
+        for (_, path) in Self::segments(shard)?.iter().rev() {
+            match std::fs::remove_file(path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Record that `change` needs persisting. A purge changes too many keys to
+    /// list, so it waits for a snapshot: the next flush's, or for a write-ahead
+    /// log, the next timed one. Purged tombstones that come back after a crash are
+    /// only purged again.
+    fn record(&mut self, change: Queued) {
+        match change {
+            Queued::Write(key, _) | Queued::SoftDelete(key, _) | Queued::Undelete(key) => {
+                self.dirty.insert(key);
+            }
+            Queued::Purge(_) if self.wal => {}
+            Queued::Purge(_) => self.appended = None,
+            Queued::Sync(_) | Queued::Abandon => {}
+        }
+    }
+
+    /// Persist the keys changed since the last flush, returning the number of bytes
+    /// written: appended to the log, or in a full snapshot when one is due. A
+    /// write-ahead log's flushes always write a snapshot.
+    fn flush(
+        &mut self,
+        serializer: &Serializer,
+        shard: &Path,
+        mem_store: &MemoryStoreSingleThreaded,
+        phases: &WritePhases,
+    ) -> Result<u64, StoreError> {
+        let due = self.wal
+            || self
+                .appended
+                .is_some_and(|appended| appended + 1 >= self.snapshot_every);
+        if due || self.appended.is_none() || self.log_bytes > self.snapshot_bytes {
+            return self.snapshot(serializer, shard, mem_store, phases);
+        }
+        self.append_dirty(serializer, shard, mem_store, phases)
+    }
+
+    /// Write a full snapshot, which the log then starts from, returning its size.
+    fn snapshot(
+        &mut self,
+        serializer: &Serializer,
+        shard: &Path,
+        mem_store: &MemoryStoreSingleThreaded,
+        phases: &WritePhases,
+    ) -> Result<u64, StoreError> {
+        let len = serializer.overwrite(shard, mem_store, Some(phases))?;
+        self.snapshotted(len);
+        Ok(len)
+    }
+
+    /// Append a write-ahead log's changed keys, however large the log has grown:
+    /// snapshots are left to the snapshot flusher's timer. Only a log that must
+    /// start over, after a failed append, is snapshotted here.
+    fn append_ahead(
+        &mut self,
+        serializer: &Serializer,
+        shard: &Path,
+        mem_store: &MemoryStoreSingleThreaded,
+        phases: &WritePhases,
+    ) -> Result<u64, StoreError> {
+        if self.appended.is_none() {
+            return self.snapshot(serializer, shard, mem_store, phases);
+        }
+        self.append_dirty(serializer, shard, mem_store, phases)
+    }
+
+    /// Append the keys changed since the last flush to the log, returning the
+    /// number of bytes written.
+    fn append_dirty(
+        &mut self,
+        serializer: &Serializer,
+        shard: &Path,
+        mem_store: &MemoryStoreSingleThreaded,
+        phases: &WritePhases,
+    ) -> Result<u64, StoreError> {
+        let entries: Vec<DeltaEntry> = self
+            .dirty
+            .drain()
+            .map(|key| {
+                Ok(DeltaEntry {
+                    value: mem_store.get(&key)?,
+                    tombstone: mem_store.tombstone(&key).cloned(),
+                    key,
+                })
+            })
+            .collect::<Result<_, StoreError>>()?;
+        // A failed append may leave a torn frame, which would hide the ones after it.
+        let appended = self.appended.take();
+        let len = self.append(serializer, shard, &entries, phases)?;
+        self.appended = appended.map(|appended| appended + 1);
+        self.log_bytes += len;
+        Ok(len)
+    }
+
+    /// Note that a full snapshot of `len` bytes was written, which the log now
+    /// starts from.
+    fn snapshotted(&mut self, len: u64) {
+        self.dirty.clear();
+        self.segment = 0;
+        self.segment_bytes = 0;
+        self.appended = Some(0);
+        self.log_bytes = 0;
+        self.snapshot_bytes = len;
+    }
+
+    fn append(
+        &mut self,
+        serializer: &Serializer,
+        shard: &Path,
+        entries: &[DeltaEntry],
+        phases: &WritePhases,
+    ) -> Result<u64, StoreError> {
+        let mut span = spans::span("delta flush", "persist");
+        let _flush = FlushInProgress::new(phases);
+        let started = Instant::now();
+        let payload = serializer.encode(&entries)?;
+        let frame_len = 8 + payload.len() as u64;
+        // However the log went, a missing first segment means it starts over.
+        let starts = if !Self::path(shard).exists() {
+            self.segment = 0;
+            true
+        } else if self.max_segment_bytes.is_some_and(|max| {
+            self.segment_bytes > DELTA_HEADER_LEN as u64 && self.segment_bytes + frame_len > max
+        }) {
+            self.segment += 1;
+            true
+        } else {
+            false
+        };
+        if starts {
+            self.segment_bytes = 0;
+        }
+        let mut frame = Vec::with_capacity(DELTA_HEADER_LEN + frame_len as usize);
+        if starts {
+            let base = ShardChecksum::load(shard)
+                .map_err(store_error)?
+                .ok_or_else(|| {
+                    StoreError::Io(std::io::Error::other(format!(
+                        "{:?} has no checksum to start a delta log from",
+                        shard
+                    )))
+                })?;
+            frame.extend_from_slice(&base.crc32.to_le_bytes());
+            frame.extend_from_slice(&base.len.to_le_bytes());
+        }
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        span.set_arg("bytes", frame.len() as u64);
+        let serialized = Instant::now();
+        let path = Self::segment_path(shard, self.segment);
+        // A new segment replaces any stale one of the same number.
+        let mut file = if starts {
+            File::create(&path)?
+        } else {
+            std::fs::OpenOptions::new().append(true).open(&path)?
+        };
+        file.write_all(&frame)?;
+        self.segment_bytes += frame.len() as u64;
+        phases.serialize.record(serialized - started);
+        phases.disk_write.since(serialized);
+        Ok(frame.len() as u64)
+    }
+
+    /// Apply the delta log of the shard file at `shard`, whose contents are
+    /// `snapshot`, to `store`, one segment after another. A log written on top of
+    /// another snapshot is ignored, and so is everything from a torn or undecodable
+    /// frame on, as a crash mid-append leaves. A tear before the last segment, a
+    /// later segment written on top of another snapshot, or a gap in the numbering
+    /// instead hides segments that should have applied, which the result reports.
+    fn replay(
+        shard: &Path,
+        snapshot: &[u8],
+        serializer: &Serializer,
+        store: &mut MemoryStoreSingleThreaded,
+    ) -> Result<LogReplay, StoreError> {
+        let mut replay = LogReplay::default();
+        let base = (ShardChecksum::of(snapshot).crc32, snapshot.len() as u64);
+        let segments = Self::segments(shard)?;
+        'segments: for (expected, (number, path)) in (0..).zip(&segments) {
+            let later = segments.len() - replay.segments;
+            if *number != expected {
+                replay.broken = Some(format!(
+                    "segment {} of its delta log is missing, hiding {} after it",
+                    expected, later
+                ));
+                break;
+            }
+            let log = std::fs::read(path)?;
+            if le_u32(&log).zip(log.get(4..).and_then(le_u64)) != Some(base) {
+                if expected == 0 {
+                    log::warn!(
+                        "Ignoring {:?}, which doesn't apply to the current snapshot of its shard.",
+                        path
+                    );
+                } else {
+                    replay.broken = Some(format!(
+                        "segment {} of its delta log follows another snapshot, hiding it and {} after it",
+                        expected,
+                        later - 1
+                    ));
+                }
+                break;
+            }
+            let mut rest = &log[DELTA_HEADER_LEN..];
+            while !rest.is_empty() {
+                let Some((entries, remainder)) =
+                    next_frame(rest).and_then(|(payload, remainder)| {
+                        let entries: Vec<DeltaEntry> = serializer.read(payload).ok()?;
+                        Some((entries, remainder))
+                    })
+                else {
+                    if later > 1 {
+                        replay.broken = Some(format!(
+                            "segment {} of its delta log is torn, hiding {} after it",
+                            expected,
+                            later - 1
+                        ));
+                    } else {
+                        log::warn!("Ignoring a torn change at the end of {:?}.", path);
+                    }
+                    break 'segments;
+                };
+                rest = remainder;
+                replay.applied += entries.len();
+                for entry in entries {
+                    store.restore(entry.key, entry.value, entry.tombstone);
+                }
+            }
+            replay.segments += 1;
+        }
+        if let Some(broken) = &replay.broken {
+            log::warn!("Shard {:?}: {}.", shard, broken);
+        }
+        Ok(replay)
+    }
+}
+
+/// What `DeltaLog::replay` made of a shard's log.
+#[derive(Debug, Default)]
+struct LogReplay {
+    /// Entries applied.
+    applied: usize,
+    /// Segments applied in full.
+    segments: usize,
+    /// Why segments that should have applied didn't, if any didn't.
+    broken: Option<String>,
+}
+
+/// The payload of the delta log frame at the start of `bytes`, and the bytes after
+/// it; None if the frame is torn or doesn't match its CRC32.
+fn next_frame(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = le_u32(bytes)? as usize;
+    let crc32 = le_u32(bytes.get(4..)?)?;
+    let payload = bytes.get(8..8 + len)?;
+    (crc32fast::hash(payload) == crc32).then_some((payload, &bytes[8 + len..]))
+}
+
+fn le_u32(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+fn le_u64(bytes: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(..8)?.try_into().ok()?))
+}
+
+/// How a shard's writers are set up, beyond their `WritePolicy`; see
+/// `FileStore::with_delta_persistence`, `FileStore::with_queue_full_policy` and
+/// `FileStore::with_writer_threads`.
+#[derive(Clone, Default)]
+struct WriterOptions {
+    snapshot_every: Option<u32>,
+    max_segment_bytes: Option<u64>,
+    queue_full: QueueFullPolicy,
+    pool: Option<Arc<WriterPool>>,
+}
+
+/// The background half of an asynchronous writer: its shard's queue, and a mirror
+/// of the shard that it applies queued changes to and persists.
+struct AsyncShard {
+    receiver: crossbeam_channel::Receiver<Queued>,
+    mirror: MemoryStoreSingleThreaded,
+    serializer: Serializer,
+    filename: PathBuf,
+    /// Most changes taken from the queue for one write of the shard.
+    batch_limit: usize,
+    pending: Arc<AtomicUsize>,
+    phases: Arc<WritePhases>,
+    abandoned: Arc<AtomicBool>,
+    errors: Arc<BackgroundErrors>,
+    /// Dropped with this, telling a pooled writer that its shard is done with.
+    _done: crossbeam_channel::Sender<()>,
+}
+
+impl AsyncShard {
+    /// Apply `first`, and whatever else has queued up behind it, a queue's worth at
+    /// most, then write the shard once for all of it. Returns false once the
+    /// writer is abandoned.
+    fn write_batch(&mut self, first: Queued) -> bool {
+        // Changes applied to the mirror since it was last written.
+        let mut unwritten = 0;
+        let batch = std::iter::once(first).chain(
+            self.receiver
+                .try_iter()
+                .take(self.batch_limit.saturating_sub(1)),
+        );
+        for queued in batch {
+            if self.abandoned.load(Ordering::Relaxed) {
+                return false;
+            }
+            let mirror = &mut self.mirror;
+            let result = match queued {
+                Queued::Write(key, Some(value)) => mirror.put(&key, value),
+                Queued::Write(key, None) => mirror.delete(&key),
+                Queued::SoftDelete(key, deleted_at_ms) => {
+                    mirror.soft_delete(&key, deleted_at_ms);
+                    Ok(())
+                }
+                Queued::Undelete(key) => {
+                    mirror.undelete(&key);
+                    Ok(())
+                }
+                Queued::Purge(before_ms) => {
+                    mirror.purge(before_ms);
+                    Ok(())
+                }
+                Queued::Abandon => return false,
+                Queued::Sync(ack) => {
+                    let result = self
+                        .serializer
+                        .overwrite_durably(&self.filename, &self.mirror, Some(&self.phases))
+                        .map(|len| {
+                            self.phases.bytes_written.fetch_add(len, Ordering::Relaxed);
+                        });
+                    // That wrote the changes before it too.
+                    if result.is_ok() {
+                        self.pending.fetch_sub(unwritten, Ordering::Relaxed);
+                        unwritten = 0;
+                    }
+                    // The durable put may have given up waiting.
+                    let _ = ack.send(result);
+                    continue;
+                }
+            };
+            if let Err(err) = result {
+                self.errors.report("async writer", err);
+            }
+            unwritten += 1;
+        }
+        if unwritten == 0 {
+            return true;
+        }
+        match self
+            .serializer
+            .overwrite(&self.filename, &self.mirror, Some(&self.phases))
+        {
+            Ok(len) => {
+                self.phases.bytes_written.fetch_add(len, Ordering::Relaxed);
+            }
+            Err(err) => self.errors.report("async writer", err),
+        }
+        self.phases.batches.fetch_add(1, Ordering::Relaxed);
+        self.phases
+            .batched_changes
+            .fetch_add(unwritten as u64, Ordering::Relaxed);
+        self.pending.fetch_sub(unwritten, Ordering::Relaxed);
+        true
+    }
+}
+
+/// The thread an asynchronous writer's `AsyncShard` runs on.
+enum WriterThread {
+    /// A thread of its own, which exits with the writer.
+    Dedicated(std::thread::JoinHandle<()>),
+    /// One of a `WriterPool`'s, which disconnects this once it drops the shard.
+    Pooled(crossbeam_channel::Receiver<()>),
+}
+
+impl WriterThread {
+    /// Wait until the shard's queue is abandoned, or drained and disconnected.
+    fn wait(self) -> Result<(), StoreError> {
+        match self {
+            Self::Dedicated(handle) => handle.join().map_err(|_| StoreError::WriterDisconnected),
+            Self::Pooled(done) => {
+                // Nothing is ever sent; this returns once the sender is dropped.
+                let _ = done.recv();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Threads shared by the asynchronous writers of a store's shards, so that a store
+/// with many shards needn't have a thread for each; see
+/// `FileStore::with_writer_threads`. Each thread serves the queues of the shards
+/// assigned to it, writing a batch for whichever has changes waiting. A thread
+/// exits once its pool is dropped and its shards' writers have shut down.
+struct WriterPool {
+    /// Hands each thread the shards it serves.
+    threads: Vec<crossbeam_channel::Sender<AsyncShard>>,
+    /// The thread the next shard goes to, round robin.
+    next: AtomicUsize,
+}
+
+impl WriterPool {
+    fn start(threads: usize) -> Self {
+        let threads = (0..threads)
+            .map(|_| {
+                let (assign, assigned) = crossbeam_channel::unbounded();
+                std::thread::spawn(move || Self::serve(assigned));
+                assign
+            })
+            .collect();
+        Self {
+            threads,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Hand `shard` to the next thread, returning which one.
+    fn assign(&self, shard: AsyncShard) -> Result<usize, StoreError> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.threads.len();
+        self.threads
+            .get(index)
+            .ok_or(StoreError::WriterDisconnected)?
+            .send(shard)
+            .map_err(|_| StoreError::WriterDisconnected)?;
+        Ok(index)
+    }
+
+    fn serve(assigned: crossbeam_channel::Receiver<AsyncShard>) {
+        /// What a thread found waiting for it.
+        enum Ready {
+            Assigned(Result<AsyncShard, crossbeam_channel::RecvError>),
+            Queued(usize, Result<Queued, crossbeam_channel::RecvError>),
+        }
+
+        let mut assigned = Some(assigned);
+        let mut shards: Vec<AsyncShard> = Vec::new();
+        while assigned.is_some() || !shards.is_empty() {
+            let ready = {
+                let mut select = crossbeam_channel::Select::new();
+                for shard in &shards {
+                    select.recv(&shard.receiver);
+                }
+                if let Some(assigned) = &assigned {
+                    select.recv(assigned);
+                }
+                let op = select.select();
+                match (shards.get(op.index()), &assigned) {
+                    (Some(shard), _) => Ready::Queued(op.index(), op.recv(&shard.receiver)),
+                    (None, Some(assigned)) => Ready::Assigned(op.recv(assigned)),
+                    (None, None) => break,
+                }
+            };
+            match ready {
+                Ready::Assigned(Ok(shard)) => shards.push(shard),
+                // The pool was dropped.
+                Ready::Assigned(Err(_)) => assigned = None,
+                Ready::Queued(index, Ok(first)) => {
+                    if !shards[index].write_batch(first) {
+                        shards.swap_remove(index);
+                    }
+                }
+                // Its writer shut down, and its queue is empty.
+                Ready::Queued(index, Err(_)) => {
+                    shards.swap_remove(index);
+                }
+            }
+        }
+    }
+}
+
+enum Writer {
+    Synchronous {
+        /// None under the hybrid policy, whose snapshots are left to a background
+        /// timer instead of piggybacking on writes.
+        poller: Option<Poller>,
+        serializer: Serializer,
+        filename: PathBuf,
+        /// When the oldest write that hasn't been flushed yet happened.
+        dirty_since: Option<Instant>,
+        /// Maximum persistence lag; only used to count misses.
+        deadline: Option<Duration>,
+        lag: LagGauge,
+        phases: Arc<WritePhases>,
+        /// Set to persist changes as deltas between snapshots.
+        delta: Option<DeltaLog>,
+    },
+    Asynchronous {
+        sender: crossbeam_channel::Sender<Queued>,
+        /// The background thread's end of the queue, for dropping the oldest change.
+        receiver: crossbeam_channel::Receiver<Queued>,
+        queue_full: QueueFullPolicy,
+        /// Writes sent to the background thread that it hasn't persisted yet.
+        pending: Arc<AtomicUsize>,
+        phases: Arc<WritePhases>,
+        /// Set by `crash`, telling the thread to drop whatever it dequeues next.
+        abandoned: Arc<AtomicBool>,
+        /// None once the thread has been waited for.
+        thread: Option<WriterThread>,
+        /// Which of the `WriterPool`'s threads serves this shard, if pooled.
+        pool_thread: Option<usize>,
+    },
+    /// A read-only store's, which persists nothing: every change fails with
+    /// `StoreError::ReadOnly` before the shard's memory changes.
+    ReadOnly,
+}
+
+impl Writer {
+    fn new(
+        policy: &WritePolicy,
+        mem_store: &MemoryStoreSingleThreaded,
+        serializer: Serializer,
+        filename: PathBuf,
+        phases: Arc<WritePhases>,
+        errors: Arc<BackgroundErrors>,
+        pool: Option<&WriterPool>,
+    ) -> Result<Self> {
+        let writer = match policy {
+            WritePolicy::Synchronous { write_period } => Self::Synchronous {
+                poller: Some(Poller::new(*write_period)),
+                serializer,
+                filename,
+                dirty_since: None,
+                deadline: None,
+                lag: LagGauge::default(),
+                phases,
+                delta: None,
+            },
+            WritePolicy::Hybrid { wal, .. } => Self::Synchronous {
+                poller: None,
+                // Snapshot now, so that the log starts here rather than on the
+                // first write.
+                delta: if *wal {
+                    let mut delta = DeltaLog::wal();
+                    delta.snapshot(&serializer, &filename, mem_store, &phases)?;
+                    Some(delta)
+                } else {
+                    None
+                },
+                serializer,
+                filename,
+                dirty_since: None,
+                deadline: None,
+                lag: LagGauge::default(),
+                phases,
+            },
+            WritePolicy::Asynchronous { queue_depth } => {
+                let (sender, receiver) = crossbeam_channel::bounded(*queue_depth);
+                let pending = Arc::new(AtomicUsize::new(0));
+                let abandoned = Arc::new(AtomicBool::new(false));
+                let (done, finished) = crossbeam_channel::bounded(0);
+                let shard = AsyncShard {
+                    receiver: receiver.clone(),
+                    // Keep a copy of the memstore state in the background thread.
+                    mirror: mem_store.clone_unindexed(),
+                    serializer,
+                    filename,
+                    batch_limit: *queue_depth,
+                    pending: Arc::clone(&pending),
+                    phases: Arc::clone(&phases),
+                    abandoned: Arc::clone(&abandoned),
+                    errors,
+                    _done: done,
+                };
+                let (thread, pool_thread) = match pool {
+                    Some(pool) => (WriterThread::Pooled(finished), Some(pool.assign(shard)?)),
+                    // Runs until the store, and with it the sender, is dropped.
+                    None => (
+                        WriterThread::Dedicated(std::thread::spawn(move || {
+                            let mut shard = shard;
+                            while let Ok(first) = shard.receiver.recv() {
+                                if !shard.write_batch(first) {
+                                    break;
+                                }
+                            }
+                        })),
+                        None,
+                    ),
+                };
+                Self::Asynchronous {
+                    thread: Some(thread),
+                    pool_thread,
+                    receiver,
+                    queue_full: QueueFullPolicy::default(),
+                    pending,
+                    phases,
+                    abandoned,
+                    sender,
+                }
+            }
+        };
+        Ok(writer)
+    }
+
+    /// Persist a change to `key`, which `mem_store` already reflects; a value of None
+    /// marks a delete.
+    fn write(
+        &mut self,
+        key: &str,
+        value: Option<&Blob>,
+        mem_store: &MemoryStoreSingleThreaded,
+    ) -> Result<(), StoreError> {
+        self.persist(|| Queued::Write(key.to_owned(), value.cloned()), mem_store)
+    }
+
+    /// Make room in the asynchronous writer's queue for one more change, as its
+    /// `QueueFullPolicy` says, before the shard's memory changes, so that a change
+    /// it refuses leaves the shard as it was. The room stays free, since only the
+    /// holder of the shard's lock sends. A no-op for the synchronous policy, and
+    /// for `QueueFullPolicy::Block`, which waits in `persist`; a read-only writer
+    /// refuses every change here.
+    fn make_room(&mut self) -> Result<(), StoreError> {
+        if let Writer::ReadOnly = self {
+            return Err(StoreError::ReadOnly);
+        }
+        let Writer::Asynchronous {
+            sender,
+            receiver,
+            queue_full,
+            pending,
+            phases,
+            ..
+        } = self
+        else {
+            return Ok(());
+        };
+        match *queue_full {
+            QueueFullPolicy::Block => {}
+            QueueFullPolicy::FailFast => {
+                if sender.is_full() {
+                    return Err(StoreError::QueueFull);
+                }
+            }
+            QueueFullPolicy::BlockWithTimeout(timeout) => {
+                let started = Instant::now();
+                if sender.is_full() {
+                    while sender.is_full() {
+                        if started.elapsed() >= timeout {
+                            phases.queue.since(started);
+                            return Err(StoreError::QueueFull);
+                        }
+                        std::thread::sleep(QUEUE_POLL_INTERVAL);
+                    }
+                    phases.queue.since(started);
+                }
+            }
+            QueueFullPolicy::DropOldest => {
+                while sender.is_full() {
+                    match receiver.try_recv() {
+                        Ok(Queued::Sync(ack)) => {
+                            let _ = ack.send(Err(StoreError::QueueFull));
+                        }
+                        Ok(Queued::Abandon) => {}
+                        Ok(_) => {
+                            pending.fetch_sub(1, Ordering::Relaxed);
+                            phases.dropped_changes.fetch_add(1, Ordering::Relaxed);
+                        }
+                        // The background thread emptied it first.
+                        Err(_) => break,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Persist a change that `mem_store` already reflects. The asynchronous policy
+    /// sends `change` to its mirror; the synchronous one only notes its key, for a
+    /// delta log, which the hybrid policy appends to there and then.
+    fn persist(
+        &mut self,
+        change: impl FnOnce() -> Queued,
+        mem_store: &MemoryStoreSingleThreaded,
+    ) -> Result<(), StoreError> {
+        match self {
+            Writer::Synchronous {
+                poller,
+                serializer,
+                filename,
+                dirty_since,
+                phases,
+                delta,
+                ..
+            } => {
+                dirty_since.get_or_insert_with(Instant::now);
+                if let Some(delta) = delta {
+                    delta.record(change());
+                    if delta.wal {
+                        let len = delta.append_ahead(serializer, filename, mem_store, phases)?;
+                        phases.bytes_written.fetch_add(len, Ordering::Relaxed);
+                    }
+                }
+                if poller.as_mut().is_some_and(Poller::elapsed) {
+                    self.flush(mem_store)?;
+                }
+            }
+            Writer::Asynchronous {
+                sender,
+                pending,
+                phases,
+                ..
+            } => {
+                pending.fetch_add(1, Ordering::Relaxed);
+                let started = Instant::now();
+                sender.send(change())?;
+                phases.queue.since(started);
+            }
+            Writer::ReadOnly => return Err(StoreError::ReadOnly),
+        };
+        Ok(())
+    }
+
+    /// Flush any unflushed writes. A no-op for the asynchronous policy, which
+    /// persists writes as it dequeues them.
+    fn flush(&mut self, mem_store: &MemoryStoreSingleThreaded) -> Result<(), StoreError> {
+        if let Writer::Synchronous {
+            serializer,
+            filename,
+            dirty_since,
+            deadline,
+            lag,
+            phases,
+            delta,
+            ..
+        } = self
+        {
+            if let Some(since) = dirty_since.take() {
+                let len = match delta {
+                    Some(delta) => delta.flush(serializer, filename, mem_store, phases)?,
+                    None => serializer.overwrite(filename, mem_store, Some(phases))?,
+                };
+                phases.bytes_written.fetch_add(len, Ordering::Relaxed);
+                let elapsed = since.elapsed();
+                lag.flushes += 1;
+                lag.max_lag = lag.max_lag.max(elapsed);
+                if deadline.map(|d| elapsed > d).unwrap_or(false) {
+                    lag.deadline_misses += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Make every write so far durable. The synchronous policy flushes with an
+    /// fsync before returning None; the asynchronous policy queues a sync behind
+    /// the pending writes and returns the receiver its acknowledgement arrives on.
+    fn sync(
+        &mut self,
+        mem_store: &MemoryStoreSingleThreaded,
+    ) -> Result<Option<crossbeam_channel::Receiver<Result<(), StoreError>>>, StoreError> {
+        match self {
+            Writer::Synchronous {
+                serializer,
+                filename,
+                dirty_since,
+                deadline,
+                lag,
+                phases,
+                delta,
+                ..
+            } => {
+                // Even a clean shard is rewritten, since its last flush wasn't
+                // fsynced. So is one with a delta log, which starts over.
+                let len = serializer.overwrite_durably(filename, mem_store, Some(phases))?;
+                phases.bytes_written.fetch_add(len, Ordering::Relaxed);
+                if let Some(delta) = delta {
+                    delta.snapshotted(len);
+                }
+                if let Some(since) = dirty_since.take() {
+                    let elapsed = since.elapsed();
+                    lag.flushes += 1;
+                    lag.max_lag = lag.max_lag.max(elapsed);
+                    if deadline.map(|d| elapsed > d).unwrap_or(false) {
+                        lag.deadline_misses += 1;
+                    }
+                }
+                Ok(None)
+            }
+            Writer::Asynchronous { sender, .. } => {
+                let (ack, acknowledged) = crossbeam_channel::bounded(1);
+                sender.send(Queued::Sync(ack))?;
+                Ok(Some(acknowledged))
+            }
+            Writer::ReadOnly => Err(StoreError::ReadOnly),
+        }
+    }
+
+    /// Stop the writer once everything handed to it is persisted: flush under the
+    /// synchronous policy, or wait for the background thread to work through its
+    /// queue and exit.
+    fn shut_down(self, mem_store: &MemoryStoreSingleThreaded) -> Result<(), StoreError> {
+        match self {
+            mut writer @ Writer::Synchronous { .. } => writer.flush(mem_store),
+            Writer::Asynchronous { sender, thread, .. } => {
+                // Disconnecting the channel ends the thread's loop once it's empty.
+                drop(sender);
+                thread.map_or(Ok(()), WriterThread::wait)
+            }
+            Writer::ReadOnly => Ok(()),
+        }
+    }
+
+    /// Stop persisting at once, as if the process had been killed: unflushed
+    /// synchronous writes are forgotten, and the asynchronous thread exits without
+    /// working through its queue. Only a write already being persisted completes.
+    fn crash(&mut self) -> Result<(), StoreError> {
+        match self {
+            Writer::Synchronous {
+                dirty_since, delta, ..
+            } => {
+                *dirty_since = None;
+                if let Some(delta) = delta {
+                    delta.dirty.clear();
+                }
+                Ok(())
+            }
+            Writer::Asynchronous {
+                sender,
+                pending,
+                abandoned,
+                thread,
+                ..
+            } => {
+                abandoned.store(true, Ordering::Relaxed);
+                // Wake the thread if its queue is empty; if it's full, the thread
+                // stops at the next write it dequeues anyway.
+                let _ = sender.try_send(Queued::Abandon);
+                pending.store(0, Ordering::Relaxed);
+                thread.take().map_or(Ok(()), WriterThread::wait)
+            }
+            Writer::ReadOnly => Ok(()),
+        }
+    }
+
+    /// Force the persisted state of `key` to match `mem_store`.
+    fn repair(
+        &mut self,
+        key: &str,
+        mem_store: &MemoryStoreSingleThreaded,
+    ) -> Result<(), StoreError> {
+        match self {
+            Writer::Synchronous {
+                dirty_since, delta, ..
+            } => {
+                dirty_since.get_or_insert_with(Instant::now);
+                if let Some(delta) = delta {
+                    delta.dirty.insert(key.to_owned());
+                }
+                self.flush(mem_store)?;
+            }
+            Writer::Asynchronous {
+                sender, pending, ..
+            } => {
+                pending.fetch_add(1, Ordering::Relaxed);
+                sender.send(Queued::Write(key.to_owned(), mem_store.get(key)?))?;
+            }
+            Writer::ReadOnly => return Err(StoreError::ReadOnly),
+        };
+        Ok(())
+    }
+
+    /// Whether persisted state is expected to lag memory.
+    fn has_pending_writes(&self) -> bool {
+        match self {
+            Writer::Synchronous { dirty_since, .. } => dirty_since.is_some(),
+            Writer::Asynchronous { pending, .. } => pending.load(Ordering::Relaxed) > 0,
+            Writer::ReadOnly => false,
+        }
+    }
+
+    /// Bytes persisted to disk since the writer was created.
+    fn bytes_written(&self) -> u64 {
+        match self {
+            Writer::Synchronous { phases, .. } | Writer::Asynchronous { phases, .. } => {
+                phases.bytes_written.load(Ordering::Relaxed)
+            }
+            Writer::ReadOnly => 0,
+        }
+    }
+
+    fn pool_thread(&self) -> Option<usize> {
+        match self {
+            Writer::Synchronous { .. } | Writer::ReadOnly => None,
+            Writer::Asynchronous { pool_thread, .. } => *pool_thread,
+        }
+    }
+
+    fn queue_depth(&self) -> usize {
+        match self {
+            Writer::Synchronous { .. } | Writer::ReadOnly => 0,
+            Writer::Asynchronous { pending, .. } => pending.load(Ordering::Relaxed),
+        }
+    }
+
+    fn dirty_since(&self) -> Option<Instant> {
+        match self {
+            Writer::Synchronous { dirty_since, .. } => *dirty_since,
+            Writer::Asynchronous { .. } | Writer::ReadOnly => None,
+        }
+    }
+
+    fn lag_gauge(&self) -> Option<LagGauge> {
+        match self {
+            Writer::Synchronous {
+                lag, dirty_since, ..
+            } => Some(LagGauge {
+                current_lag: dirty_since.map(|since| since.elapsed()),
+                ..lag.clone()
+            }),
+            Writer::Asynchronous { .. } | Writer::ReadOnly => None,
+        }
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum FlushOrder {
+        // Flush the shards whose oldest unflushed write is closest to the deadline first.
+        Deadline,
+        RoundRobin,
+    }
+}
+
+/// Background thread flushing dirty shards of a synchronous `FileStore`, so that
+/// writes are persisted within a deadline even if their shard sees no further writes.
+/// Stops once the last handle of the store is dropped, joining the thread.
+///
+/// Shards are flushed one at a time. Stopping takes effect between shards, never
+/// during a flush, so shutdown waits for at most one shard's flush; since a flush
+/// writes a temporary file and renames it over the shard, each shard's file holds
+/// either its old contents or its new ones.
+struct Flusher {
+    max_lag: Duration,
+    /// Set when the flusher is the hybrid policy's snapshot timer.
+    snapshots: bool,
+    stop: Option<crossbeam_channel::Sender<()>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Flusher {
+    fn start(
+        files: Vec<Arc<Shard>>,
+        max_lag: Duration,
+        order: FlushOrder,
+        errors: Arc<BackgroundErrors>,
+    ) -> Self {
+        Self::spawn(files, max_lag, false, order, errors)
+    }
+
+    /// The hybrid policy's snapshot timer: snapshot each shard once its oldest
+    /// change since the last snapshot is about `interval` old.
+    fn start_snapshots(
+        files: Vec<Arc<Shard>>,
+        interval: Duration,
+        errors: Arc<BackgroundErrors>,
+    ) -> Self {
+        Self::spawn(files, interval, true, FlushOrder::Deadline, errors)
+    }
+
+    fn spawn(
+        files: Vec<Arc<Shard>>,
+        max_lag: Duration,
+        snapshots: bool,
+        order: FlushOrder,
+        errors: Arc<BackgroundErrors>,
+    ) -> Self {
+        let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+        // Check often enough that a shard's whole deadline isn't spent asleep.
+        let tick = (max_lag / 10).max(Duration::from_micros(100));
+        // Snapshots wait out most of the interval; other flushes don't wait.
+        let min_age = if snapshots {
+            max_lag.saturating_sub(tick)
+        } else {
+            Duration::ZERO
+        };
+        let handle = std::thread::spawn(move || {
+            let mut cursor = 0;
+            while let Err(crossbeam_channel::RecvTimeoutError::Timeout) = stopped.recv_timeout(tick)
+            {
+                if let Err(err) = Self::flush_pass(&files, order, min_age, &mut cursor, &stopped) {
+                    errors.report("flusher", err);
+                }
+            }
+        });
+        Self {
+            max_lag,
+            snapshots,
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// Flush every shard dirty for at least `min_age` in `order`, unless stopped
+    /// part way through.
+    fn flush_pass(
+        files: &[Arc<Shard>],
+        order: FlushOrder,
+        min_age: Duration,
+        cursor: &mut usize,
+        stopped: &crossbeam_channel::Receiver<()>,
+    ) -> Result<()> {
+        let mut dirty = Vec::new();
+        for (index, shard) in files.iter().enumerate() {
+            let guard = shard.lock_uncounted()?;
+            if let Some(since) = guard.writer.dirty_since() {
+                if since.elapsed() >= min_age {
+                    dirty.push((since, index));
+                }
+            }
+        }
+        match order {
+            FlushOrder::Deadline => dirty.sort_unstable(),
+            FlushOrder::RoundRobin => {
+                let start = *cursor;
+                dirty.sort_unstable_by_key(|&(_, index)| {
+                    (index + files.len() - start) % files.len()
+                });
+                *cursor = (start + 1) % files.len();
+            }
+        }
+        for (_, index) in dirty {
+            if let Err(crossbeam_channel::TryRecvError::Disconnected) = stopped.try_recv() {
+                break;
+            }
+            let mut guard = files[index].lock_uncounted()?;
+            guard.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the thread up.
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Counters for sampled read-repair checks, shared by every handle of a `FileStore`.
+#[derive(Debug, Default)]
+pub struct ReadRepairStats {
+    pub checks: AtomicU64,
+    /// Disk differed from memory, but writes were still pending.
+    pub lagging: AtomicU64,
+    /// Disk differed from memory with no writes pending; these are repaired.
+    pub divergent: AtomicU64,
+}
+
+impl ReadRepairStats {
+    pub fn summarize(&self) {
+        log::info!(
+            "read_repair_checks: {}",
+            self.checks.load(Ordering::Relaxed)
+        );
+        log::info!(
+            "read_repair_lagging: {}",
+            self.lagging.load(Ordering::Relaxed)
+        );
+        log::info!(
+            "read_repair_divergent: {}",
+            self.divergent.load(Ordering::Relaxed)
+        );
+    }
+}
+
+struct ReadRepair {
+    sample_rate: f64,
+    stats: Arc<ReadRepairStats>,
+}
+
+/// Counters for soft deletes, shared by every handle of a `FileStore`.
+#[derive(Debug, Default)]
+pub struct SoftDeleteStats {
+    pub soft_deletes: AtomicU64,
+    pub undeletes: AtomicU64,
+    pub compactions: AtomicU64,
+    /// Tombstones dropped by compactions once past the purge horizon.
+    pub purged: AtomicU64,
+}
+
+impl SoftDeleteStats {
+    pub fn summarize(&self) {
+        log::info!(
+            "soft_deletes: {}",
+            self.soft_deletes.load(Ordering::Relaxed)
+        );
+        log::info!("undeletes: {}", self.undeletes.load(Ordering::Relaxed));
+        log::info!(
+            "soft_delete_compactions: {}",
+            self.compactions.load(Ordering::Relaxed)
+        );
+        log::info!("purged_tombstones: {}", self.purged.load(Ordering::Relaxed));
+    }
+}
+
+struct SoftDelete {
+    horizon: Duration,
+    stats: Arc<SoftDeleteStats>,
+}
+
+impl SoftDelete {
+    /// Tombstones made before this time (ms since the epoch) are past the horizon.
+    fn cutoff_ms(&self) -> u64 {
+        now_ms().saturating_sub(self.horizon.as_millis() as u64)
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Outcome of a journaled operation.
+#[derive(Clone, Debug)]
+pub enum JournalOutcome {
+    Ok,
+    /// A get of a key with no value.
+    Miss,
+    Error(String),
+}
+
+/// One operation recorded by a shard's journal.
+#[derive(Clone, Debug)]
+pub struct JournalEntry {
+    /// Time since the journal was enabled.
+    pub at: Duration,
+    pub op: &'static str,
+    /// Keys are hashed rather than copied, to keep journaling cheap.
+    pub key_hash: u64,
+    pub outcome: JournalOutcome,
+}
+
+/// Bounded record of a shard's most recent operations. Lives under the shard's
+/// lock, so recording needs no synchronization of its own.
+struct Journal {
+    capacity: usize,
+    started: Instant,
+    entries: VecDeque<JournalEntry>,
+}
+
+impl Journal {
+    fn new(capacity: usize, started: Instant) -> Self {
+        Self {
+            capacity,
+            started,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn disabled() -> Self {
+        Self::new(0, Instant::now())
+    }
+
+    fn record(&mut self, op: &'static str, key: &str, outcome: impl FnOnce() -> JournalOutcome) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        key.hash(&mut hasher);
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(JournalEntry {
+            at: self.started.elapsed(),
+            op,
+            key_hash: hasher.finish(),
+            outcome: outcome(),
+        });
+    }
+}
+
+impl JournalOutcome {
+    fn of<T>(result: &Result<T, StoreError>) -> Self {
+        match result {
+            Ok(_) => Self::Ok,
+            Err(err) => Self::Error(err.to_string()),
+        }
+    }
+}
+
+/// Read access to every shard's `Journal`, from `FileStore::journal`.
+pub struct JournalHandle {
+    files: Vec<Arc<Shard>>,
+}
+
+impl JournalHandle {
+    /// Each shard's journal, oldest entry first.
+    pub fn entries(&self) -> Result<Vec<Vec<JournalEntry>>> {
+        self.files
+            .iter()
+            .map(|shard| {
+                Ok(shard
+                    .lock_uncounted()?
+                    .journal
+                    .entries
+                    .iter()
+                    .cloned()
+                    .collect())
+            })
+            .collect()
+    }
+
+    /// Write every shard's journal as text. Shards that are locked (e.g. by the
+    /// operation that failed) are skipped rather than waited on.
+    pub fn dump(&self, out: &mut impl Write) -> Result<()> {
+        for (index, shard) in self.files.iter().enumerate() {
+            let guard = match shard.file.try_lock() {
+                Ok(guard) => guard,
+                Err(_) => {
+                    writeln!(out, "shard {}: locked or poisoned; skipped", index)?;
+                    continue;
+                }
+            };
+            writeln!(out, "shard {}:", index)?;
+            for entry in &guard.journal.entries {
+                writeln!(
+                    out,
+                    "  +{:?} {} key#{:016x} {:?}",
+                    entry.at, entry.op, entry.key_hash, entry.outcome
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Internal representation to encapsulate file operations.
+struct BackingFile {
+    mem_store: MemoryStoreSingleThreaded,
+    writer: Writer,
+    filename: PathBuf,
+    serializer: Serializer,
+    journal: Journal,
+    /// Sizes of the values written since the store was opened.
+    written_sizes: ValueSizes,
+    /// Number of writes and deletes so far, which orders them for `GroupCommit`.
+    write_seq: u64,
+    /// Set by `FileStore::crash`; every later write fails.
+    crashed: bool,
+    phases: Arc<WritePhases>,
+    errors: Arc<BackgroundErrors>,
+}
+
+impl BackingFile {
+    fn filename(size: usize, index: usize, path: &Path) -> PathBuf {
+        path.join(format!("store_size={}_idx={}", size, index))
+    }
+
+    /// Create a new, empty backing file, truncating any existing data.
+    fn create(
+        size: usize,
+        index: usize,
+        path: &Path,
+        write_policy: &WritePolicy,
+        serializer: Serializer,
+        errors: Arc<BackgroundErrors>,
+    ) -> Result<Self> {
+        let filename = Self::filename(size, index, path);
+        File::create(&filename)?;
+        remove_if_exists(&ShardChecksum::path(&filename))?;
+        DeltaLog::remove(&filename)?;
+        let mem_store = MemoryStoreSingleThreaded::new();
+        let phases = Arc::<WritePhases>::default();
+        let writer = Writer::new(
+            write_policy,
+            &mem_store,
+            serializer.clone(),
+            filename.clone(),
+            Arc::clone(&phases),
+            Arc::clone(&errors),
+            None,
+        )?;
+        Ok(Self {
+            mem_store,
+            writer,
+            filename,
+            serializer,
+            journal: Journal::disabled(),
+            written_sizes: ValueSizes::new(),
+            write_seq: 0,
+            crashed: false,
+            phases,
+            errors,
+        })
+    }
+
+    /// Open an existing backing file, loading its data into memory.
+    fn open(
+        size: usize,
+        index: usize,
+        path: &Path,
+        write_policy: &WritePolicy,
+        serializer: Serializer,
+        errors: Arc<BackgroundErrors>,
+    ) -> Result<Self> {
+        // TODO: Use file locks, otherwise multiple threads creating backing files could
+        // cause odd issues.
+        let filename = Self::filename(size, index, path);
+        let started = Instant::now();
+        let mem_store = match Self::load(&filename, &serializer) {
+            Ok(existing_data) => existing_data,
+            Err(err) => {
+                log::error!(
+                    "Could not load key/value store data from {:?}; skipping: {:?}",
+                    filename,
+                    err
+                );
+                let now = chrono::Local::now();
+                let timestamp = now.format("%Y-%m-%d_%H%M%S");
+                let backup_filename = filename.with_extension(format!("backup{}", timestamp));
+                std::fs::rename(&filename, backup_filename)?;
+                remove_if_exists(&ShardChecksum::path(&filename))?;
+                DeltaLog::remove(&filename)?;
+                MemoryStoreSingleThreaded::new()
+            }
+        };
+        log::info!(
+            "Loaded {} keys from {:?} in {:.2?}.",
+            mem_store.len(),
+            filename,
+            started.elapsed()
+        );
+
+        let phases = Arc::<WritePhases>::default();
+        let writer = Writer::new(
+            write_policy,
+            &mem_store,
+            serializer.clone(),
+            filename.clone(),
+            Arc::clone(&phases),
+            Arc::clone(&errors),
+            None,
+        )?;
+
+        Ok(Self {
+            mem_store,
+            writer,
+            filename,
+            serializer,
+            journal: Journal::disabled(),
+            written_sizes: ValueSizes::new(),
+            write_seq: 0,
+            crashed: false,
+            phases,
+            errors,
+        })
+    }
+
+    /// Open an existing backing file without a writer, touching no files: one that
+    /// can't be read fails instead of being set aside. The write policy is ignored.
+    fn open_read_only(
+        size: usize,
+        index: usize,
+        path: &Path,
+        _write_policy: &WritePolicy,
+        serializer: Serializer,
+        errors: Arc<BackgroundErrors>,
+    ) -> Result<Self> {
+        let filename = Self::filename(size, index, path);
+        let started = Instant::now();
+        let mem_store = Self::load(&filename, &serializer)
+            .map_err(|err| anyhow!("Could not load {:?}: {}", filename, err))?;
+        log::info!(
+            "Loaded {} keys from {:?} in {:.2?}, read-only.",
+            mem_store.len(),
+            filename,
+            started.elapsed()
+        );
+        Ok(Self {
+            mem_store,
+            writer: Writer::ReadOnly,
+            filename,
+            serializer,
+            journal: Journal::disabled(),
+            written_sizes: ValueSizes::new(),
+            write_seq: 0,
+            crashed: false,
+            phases: Arc::default(),
+            errors,
+        })
+    }
+
+    /// Read a shard's persisted data, applying its delta log if it has one. A shard
+    /// that was never written to may legitimately be empty or missing.
+    fn load(
+        filename: &Path,
+        serializer: &Serializer,
+    ) -> Result<MemoryStoreSingleThreaded, StoreError> {
+        if DeltaLog::path(filename).exists() {
+            // The log's header is checked against the snapshot's checksum.
+            let snapshot = match std::fs::read(filename) {
+                Ok(snapshot) => snapshot,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(err) => return Err(err.into()),
+            };
+            let mut store = if snapshot.is_empty() {
+                MemoryStoreSingleThreaded::new()
+            } else {
+                serializer.read(snapshot.as_slice())?
+            };
+            DeltaLog::replay(filename, &snapshot, serializer, &mut store)?;
+            return Ok(store);
+        }
+        let has_data = filename.metadata().map(|m| m.len() > 0).unwrap_or(false);
+        if !has_data {
+            return Ok(MemoryStoreSingleThreaded::new());
+        }
+        serializer.read(File::open(filename)?)
+    }
+
+    fn read(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        self.mem_store.get(key)
+    }
+
+    fn write(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        if self.crashed {
+            return Err(StoreError::Crashed);
+        }
+        self.writer.make_room()?;
+        self.write_seq += 1;
+        self.written_sizes.record(key, &value);
+        // Update memory first, so a synchronous flush includes this write.
+        let started = Instant::now();
+        self.mem_store.put(key, value.clone())?;
+        self.phases.map_insert.since(started);
+        self.writer.write(key, Some(&value), &self.mem_store)?;
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        if self.crashed {
+            return Err(StoreError::Crashed);
+        }
+        self.writer.make_room()?;
+        self.write_seq += 1;
+        let started = Instant::now();
+        self.mem_store.delete(key)?;
+        self.phases.map_insert.since(started);
+        self.writer.write(key, None, &self.mem_store)?;
+        Ok(())
+    }
+
+    fn soft_delete(&mut self, key: &str) -> Result<(), StoreError> {
+        if self.crashed {
+            return Err(StoreError::Crashed);
+        }
+        self.writer.make_room()?;
+        self.write_seq += 1;
+        let deleted_at_ms = now_ms();
+        let started = Instant::now();
+        self.mem_store.soft_delete(key, deleted_at_ms);
+        self.phases.map_insert.since(started);
+        self.writer.persist(
+            || Queued::SoftDelete(key.to_owned(), deleted_at_ms),
+            &self.mem_store,
+        )
+    }
+
+    /// `key`'s tombstone, unless it was made before `cutoff_ms`.
+    fn tombstone(&self, key: &str, cutoff_ms: u64) -> Option<&Tombstone> {
+        self.mem_store
+            .tombstone(key)
+            .filter(|tombstone| tombstone.deleted_at_ms >= cutoff_ms)
+    }
+
+    fn undelete(&mut self, key: &str, cutoff_ms: u64) -> Result<bool, StoreError> {
+        if self.crashed {
+            return Err(StoreError::Crashed);
+        }
+        if self.tombstone(key, cutoff_ms).is_none() {
+            return Ok(false);
+        }
+        self.writer.make_room()?;
+        self.write_seq += 1;
+        self.mem_store.undelete(key);
+        self.writer
+            .persist(|| Queued::Undelete(key.to_owned()), &self.mem_store)?;
+        Ok(true)
+    }
+
+    /// Drop the tombstones made before `cutoff_ms` and rewrite the shard,
+    /// returning how many were dropped. A shard without any is left alone. Waits
+    /// for room in a full queue, whatever the `QueueFullPolicy`.
+    fn compact(&mut self, cutoff_ms: u64) -> Result<usize, StoreError> {
+        if self.crashed {
+            return Err(StoreError::Crashed);
+        }
+        if let Writer::ReadOnly = self.writer {
+            return Err(StoreError::ReadOnly);
+        }
+        let purged = self.mem_store.purge(cutoff_ms);
+        if purged > 0 {
+            self.writer
+                .persist(|| Queued::Purge(cutoff_ms), &self.mem_store)?;
+            self.writer.flush(&self.mem_store)?;
+        }
+        Ok(purged)
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        self.writer.flush(&self.mem_store)
+    }
+
+    fn sync(
+        &mut self,
+    ) -> Result<Option<crossbeam_channel::Receiver<Result<(), StoreError>>>, StoreError> {
+        if self.crashed {
+            return Err(StoreError::Crashed);
+        }
+        self.writer.make_room()?;
+        self.writer.sync(&self.mem_store)
+    }
+
+    fn crash(&mut self) -> Result<(), StoreError> {
+        self.crashed = true;
+        self.writer.crash()
+    }
+
+    /// Replace the writer with one for `policy`, once the old one has persisted
+    /// everything. The new writer starts from the in-memory state, which the old
+    /// one has caught up to, and keeps counting bytes written; a synchronous one
+    /// counts misses of `deadline`. Either is set up as `options` says.
+    fn switch_writer(
+        &mut self,
+        policy: &WritePolicy,
+        deadline: Option<Duration>,
+        options: &WriterOptions,
+    ) -> Result<()> {
+        if let Writer::ReadOnly = self.writer {
+            return Err(StoreError::ReadOnly.into());
+        }
+        let mut writer = Writer::new(
+            policy,
+            &self.mem_store,
+            self.serializer.clone(),
+            self.filename.clone(),
+            Arc::clone(&self.phases),
+            Arc::clone(&self.errors),
+            options.pool.as_deref(),
+        )?;
+        match &mut writer {
+            Writer::Synchronous {
+                deadline: d, delta, ..
+            } => {
+                *d = deadline;
+                if delta.is_none() {
+                    *delta = options.snapshot_every.map(DeltaLog::new);
+                }
+                if let Some(delta) = delta {
+                    delta.max_segment_bytes = options.max_segment_bytes;
+                }
+            }
+            Writer::Asynchronous { queue_full, .. } => *queue_full = options.queue_full,
+            Writer::ReadOnly => {}
+        }
+        // Nothing reaches the new writer before the caller releases the shard's
+        // lock, so the old one's last writes can't overwrite newer ones.
+        std::mem::replace(&mut self.writer, writer).shut_down(&self.mem_store)?;
+        Ok(())
+    }
+
+    /// Compare the in-memory value of `key` against what's on disk, repairing the
+    /// persisted copy if they diverge for reasons other than a pending write.
+    fn check_and_repair(&mut self, key: &str, stats: &ReadRepairStats) -> Result<(), StoreError> {
+        stats.checks.fetch_add(1, Ordering::Relaxed);
+        let persisted = Self::load(&self.filename, &self.serializer)?;
+        if persisted.get(key)? == self.mem_store.get(key)? {
+            return Ok(());
+        }
+        if self.writer.has_pending_writes() {
+            stats.lagging.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        log::warn!("Repairing divergent persisted value for {:?}", key);
+        stats.divergent.fetch_add(1, Ordering::Relaxed);
+        self.writer.repair(key, &self.mem_store)
+    }
+}
+
+/// Point-in-time load and size statistics for one shard.
+#[derive(Clone, Debug)]
+pub struct ShardStats {
+    pub key_count: usize,
+    pub bytes_on_disk: u64,
+    /// Locks taken by store operations, and how many of those had to wait.
+    pub lock_acquisitions: u64,
+    pub lock_contentions: u64,
+    /// Writes handed to the asynchronous writer that it hasn't persisted yet.
+    pub queue_depth: usize,
+    /// Which thread of the store's writer pool serves the shard's asynchronous
+    /// writer, if it has a pool; see `FileStore::with_writer_threads`.
+    pub writer_thread: Option<usize>,
+    /// Bytes persisted since the store was opened; each flush rewrites the whole
+    /// shard, unless it keeps a delta log.
+    pub bytes_written: u64,
+    /// Soft-deleted keys whose tombstones haven't been purged yet.
+    pub tombstones: usize,
+}
+
+/// What `FileStore::verify` found in one shard file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ShardCondition {
+    /// Decoded, and matched its checksum.
+    Ok,
+    /// Decoded, but has no checksum: never flushed, or written before checksums
+    /// were kept.
+    Unchecked,
+    /// Decoded, but didn't match its checksum: either corrupted in a way that
+    /// still decodes, or left by a crash between writing the shard and its
+    /// checksum.
+    ChecksumMismatch {
+        expected: ShardChecksum,
+        actual: ShardChecksum,
+    },
+    Missing,
+    /// Couldn't be decoded; its keys are lost to the store.
+    Unreadable(String),
+}
+
+/// The result of `FileStore::verify`.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Each shard's condition and key count, in shard order.
+    pub shards: Vec<(ShardCondition, usize)>,
+    /// Keys stored in a shard they don't route to, with no copy in the shard they
+    /// do: gets can't find them.
+    pub misplaced_keys: Vec<String>,
+    /// Keys stored in a shard they don't route to, shadowed by a copy in the shard
+    /// they do.
+    pub duplicate_keys: Vec<String>,
+    /// Shard files outside the store's layout: left by an interrupted flush or
+    /// reshard, or set aside as unreadable when the store was opened.
+    pub orphaned_files: Vec<PathBuf>,
+    /// Keys in orphaned files that the store doesn't hold.
+    pub orphaned_keys: usize,
+    /// Shards whose delta log has segments that should apply but can't, and why:
+    /// a gap in their numbering, or a torn or stale segment before the last.
+    pub broken_logs: Vec<(usize, String)>,
+    /// What `--repair` did, if asked to.
+    pub repairs: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Failures that leave keys unreachable or possibly corrupt. Unchecked shards
+    /// and orphans are only worth a warning.
+    pub fn problem_count(&self) -> usize {
+        let bad_shards = self
+            .shards
+            .iter()
+            .filter(|(condition, _)| {
+                !matches!(condition, ShardCondition::Ok | ShardCondition::Unchecked)
+            })
+            .count();
+        bad_shards + self.misplaced_keys.len() + self.duplicate_keys.len() + self.broken_logs.len()
+    }
+
+    pub fn text_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (index, (condition, keys)) in self.shards.iter().enumerate() {
+            let condition = match condition {
+                ShardCondition::Ok => "ok".to_string(),
+                ShardCondition::Unchecked => "ok, no checksum".to_string(),
+                ShardCondition::ChecksumMismatch { expected, actual } => {
+                    format!("CHECKSUM MISMATCH: expected {}, got {}", expected, actual)
+                }
+                ShardCondition::Missing => "MISSING".to_string(),
+                ShardCondition::Unreadable(err) => format!("UNREADABLE: {}", err),
+            };
+            lines.push(format!("shard {}: {} keys, {}", index, keys, condition));
+        }
+        let keys: usize = self.shards.iter().map(|(_, keys)| keys).sum();
+        lines.push(format!("keys: {}", keys));
+        lines.push(format!("misplaced_keys: {}", self.misplaced_keys.len()));
+        for key in self.misplaced_keys.iter().take(VERIFY_EXAMPLE_KEYS) {
+            lines.push(format!("  misplaced: {:?}", key));
+        }
+        lines.push(format!("duplicate_keys: {}", self.duplicate_keys.len()));
+        for key in self.duplicate_keys.iter().take(VERIFY_EXAMPLE_KEYS) {
+            lines.push(format!("  duplicate: {:?}", key));
+        }
+        lines.push(format!("orphaned_files: {}", self.orphaned_files.len()));
+        for path in &self.orphaned_files {
+            lines.push(format!("  orphaned: {:?}", path));
+        }
+        lines.push(format!("orphaned_keys: {}", self.orphaned_keys));
+        lines.push(format!("broken_logs: {}", self.broken_logs.len()));
+        for (index, broken) in &self.broken_logs {
+            lines.push(format!("  shard {}: {}", index, broken));
+        }
+        for repair in &self.repairs {
+            lines.push(format!("repaired: {}", repair));
+        }
+        lines.push(format!("problems: {}", self.problem_count()));
+        lines
+    }
+}
+
+/// How many misplaced or duplicate keys `VerifyReport::text_lines` lists by name.
+const VERIFY_EXAMPLE_KEYS: usize = 10;
+
+/// Durable puts to one shard that are waiting for a sync. Whichever arrives
+/// while no sync is running starts one that covers every write made so far, and
+/// the rest wait for it rather than each syncing the shard themselves.
+#[derive(Default)]
+struct GroupCommit {
+    state: Mutex<CommitState>,
+    synced: Condvar,
+}
+
+#[derive(Default)]
+struct CommitState {
+    syncing: bool,
+    /// Every write up to this `BackingFile::write_seq` is durable.
+    durable_seq: u64,
+    durable_puts: u64,
+    syncs: u64,
+}
+
+/// How many syncs the durable puts to a store needed.
+#[derive(Clone, Debug, Default)]
+pub struct GroupCommitStats {
+    pub durable_puts: u64,
+    pub syncs: u64,
+}
+
+/// A backing file behind a lock that counts how often it was contended.
+struct Shard {
+    file: Mutex<BackingFile>,
+    /// The backing file's, readable without its lock.
+    phases: Arc<WritePhases>,
+    lock_acquisitions: AtomicU64,
+    lock_contentions: AtomicU64,
+    commits: GroupCommit,
+}
+
+impl Shard {
+    fn new(file: BackingFile) -> Self {
+        Self {
+            phases: Arc::clone(&file.phases),
+            file: Mutex::new(file),
+            lock_acquisitions: AtomicU64::new(0),
+            lock_contentions: AtomicU64::new(0),
+            commits: GroupCommit::default(),
+        }
+    }
+
+    /// Wait until the write numbered `seq` is durable, syncing the shard unless a
+    /// sync that covers it is already running.
+    fn wait_durable(&self, seq: u64) -> Result<(), StoreError> {
+        let commits = &self.commits;
+        let mut state = commits.state.lock().map_err(|_| StoreError::LockError)?;
+        state.durable_puts += 1;
+        while state.durable_seq < seq {
+            if state.syncing {
+                state = commits
+                    .synced
+                    .wait(state)
+                    .map_err(|_| StoreError::LockError)?;
+                continue;
+            }
+            state.syncing = true;
+            drop(state);
+            let result = self.sync();
+            state = commits.state.lock().map_err(|_| StoreError::LockError)?;
+            state.syncing = false;
+            commits.synced.notify_all();
+            // A failed sync fails only this put; the others try again.
+            let covered = result?;
+            state.durable_seq = state.durable_seq.max(covered);
+            state.syncs += 1;
+        }
+        Ok(())
+    }
+
+    /// Make every write so far durable, returning the last one's sequence number.
+    /// Under the asynchronous policy, the lock is released while the background
+    /// thread catches up.
+    fn sync(&self) -> Result<u64, StoreError> {
+        let (covered, acknowledged) = {
+            let mut guard = self.lock_uncounted()?;
+            (guard.write_seq, guard.sync()?)
+        };
+        if let Some(acknowledged) = acknowledged {
+            acknowledged
+                .recv_timeout(FLUSH_TIMEOUT)
+                .map_err(|_| StoreError::WriterDisconnected)??;
+        }
+        Ok(covered)
+    }
+
+    /// Lock for a store operation, counting contention.
+    fn lock(&self) -> Result<MutexGuard<'_, BackingFile>, StoreError> {
+        Ok(self.lock_reporting_contention()?.0)
+    }
+
+    /// As `lock`, also returning whether the lock had to be waited for.
+    /// Fails once a background error has failed the store.
+    fn lock_reporting_contention(&self) -> Result<(MutexGuard<'_, BackingFile>, bool), StoreError> {
+        self.lock_acquisitions.fetch_add(1, Ordering::Relaxed);
+        let mut span = spans::span("lock", "shard");
+        let (guard, contended) = match self.file.try_lock() {
+            Ok(guard) => (guard, false),
+            Err(TryLockError::WouldBlock) => {
+                self.lock_contentions.fetch_add(1, Ordering::Relaxed);
+                let guard = self.file.lock().map_err(|_| StoreError::LockError)?;
+                (guard, true)
+            }
+            Err(TryLockError::Poisoned(_)) => return Err(StoreError::LockError),
+        };
+        span.set_arg("contended", contended as u64);
+        drop(span);
+        guard.errors.check()?;
+        Ok((guard, contended))
+    }
+
+    /// Lock for bookkeeping (flushing, statistics) that shouldn't skew the counters.
+    fn lock_uncounted(&self) -> Result<MutexGuard<'_, BackingFile>, StoreError> {
+        self.file.lock().map_err(|_| StoreError::LockError)
+    }
+
+    fn stats(&self) -> Result<ShardStats> {
+        let guard = self.lock_uncounted()?;
+        Ok(ShardStats {
+            key_count: guard.mem_store.len(),
+            bytes_on_disk: std::iter::once(guard.filename.clone())
+                .chain(
+                    DeltaLog::segments(&guard.filename)?
+                        .into_iter()
+                        .map(|(_, path)| path),
+                )
+                .map(|path| path.metadata().map(|m| m.len()).unwrap_or(0))
+                .sum(),
+            lock_acquisitions: self.lock_acquisitions.load(Ordering::Relaxed),
+            lock_contentions: self.lock_contentions.load(Ordering::Relaxed),
+            queue_depth: guard.writer.queue_depth(),
+            writer_thread: guard.writer.pool_thread(),
+            bytes_written: guard.writer.bytes_written(),
+            tombstones: guard.mem_store.tombstone_count(),
+        })
+    }
+}
+
+/// Switches a store's write policy on a schedule until dropped; see
+/// `FileStore::spawn_policy_switcher`.
+pub struct PolicySwitcher {
+    stop: Option<crossbeam_channel::Sender<()>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for PolicySwitcher {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the thread up.
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn compact_shards(files: &[Arc<Shard>], soft_delete: &SoftDelete) -> Result<usize> {
+    let cutoff_ms = soft_delete.cutoff_ms();
+    let mut purged = 0;
+    for shard in files {
+        purged += shard.lock_uncounted()?.compact(cutoff_ms)?;
+    }
+    let stats = &soft_delete.stats;
+    stats.compactions.fetch_add(1, Ordering::Relaxed);
+    stats.purged.fetch_add(purged as u64, Ordering::Relaxed);
+    Ok(purged)
+}
+
+/// Compacts a soft-deleting store's shards on an interval until dropped; see
+/// `FileStore::spawn_compactor`.
+pub struct Compactor {
+    stop: Option<crossbeam_channel::Sender<()>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the thread up.
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Periodically logs per-shard statistics until dropped.
+pub struct StatsLogger {
+    stop: Option<crossbeam_channel::Sender<()>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for StatsLogger {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the thread up.
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Operations and bytes persisted per shard in each time bucket of a run, as
+/// recorded by `FileStore::spawn_heatmap`. Rows are buckets and columns shards.
+#[derive(Debug, Serialize)]
+pub struct Heatmap {
+    pub bucket_ms: u64,
+    pub ops: Vec<Vec<u64>>,
+    pub bytes: Vec<Vec<u64>>,
+}
+
+impl Heatmap {
+    /// One `bucket_start_ms,shard,ops,bytes` row per bucket and shard, for plotting.
+    pub fn write_csv(&self, mut out: impl Write) -> Result<()> {
+        writeln!(out, "bucket_start_ms,shard,ops,bytes")?;
+        for (bucket, (ops, bytes)) in self.ops.iter().zip(&self.bytes).enumerate() {
+            for (shard, (ops, bytes)) in ops.iter().zip(bytes).enumerate() {
+                writeln!(
+                    out,
+                    "{},{},{},{}",
+                    bucket as u64 * self.bucket_ms,
+                    shard,
+                    ops,
+                    bytes
+                )?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Write as JSON if `path` ends in `.json`, and as CSV otherwise.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let out = BufWriter::new(File::create(path)?);
+        if path.extension().map(|e| e == "json").unwrap_or(false) {
+            serde_json::to_writer(out, self)?;
+        } else {
+            self.write_csv(out)?;
+        }
+        log::info!("Wrote shard heatmap to {:?}.", path);
+        Ok(())
+    }
+}
+
+/// Records a `Heatmap` in the background until finished.
+pub struct HeatmapRecorder {
+    stop: Option<crossbeam_channel::Sender<()>>,
+    handle: Option<std::thread::JoinHandle<Heatmap>>,
+}
+
+impl HeatmapRecorder {
+    /// Stop recording, including the final partial bucket.
+    pub fn finish(mut self) -> Result<Heatmap> {
+        self.stop.take();
+        self.handle
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("Heatmap recorder already finished"))?
+            .join()
+            .map_err(|_| anyhow::anyhow!("Heatmap recorder panicked"))
+    }
+}
+
+/// `BackingFile::create` or `BackingFile::open`.
+type InitFile =
+    fn(usize, usize, &Path, &WritePolicy, Serializer, Arc<BackgroundErrors>) -> Result<BackingFile>;
+
+/// Logs an operation when dropped, if it took longer than `threshold`; see
+/// `FileStore::with_slow_op_log`.
+struct SlowOpWatch<'a> {
+    threshold: Duration,
+    op: &'static str,
+    key: &'a str,
+    index: usize,
+    shard: &'a Shard,
+    started: Instant,
+    flush_state: (u64, bool),
+}
+
+impl Drop for SlowOpWatch<'_> {
+    fn drop(&mut self) {
+        let took = self.started.elapsed();
+        if took <= self.threshold {
+            return;
+        }
+        let (begun_before, in_progress_before) = self.flush_state;
+        let (begun_after, _) = self.shard.phases.flush_state();
+        let queue_depth = match self.shard.lock_uncounted() {
+            Ok(guard) => guard.writer.queue_depth().to_string(),
+            Err(err) => format!("({})", err),
+        };
+        log::warn!(
+            "slow_op: {} key={:?} took={:?} shard={} queue_depth={} flush_in_progress={}",
+            self.op,
+            self.key,
+            took,
+            self.index,
+            queue_depth,
+            in_progress_before || begun_after > begun_before
+        );
+    }
+}
+
+pub struct FileStore {
+    files: Vec<Arc<Shard>>,
+    router: Arc<dyn ShardRouter>,
+    read_repair: Option<Arc<ReadRepair>>,
+    flusher: Option<Arc<Flusher>>,
+    soft_delete: Option<Arc<SoftDelete>>,
+    errors: Arc<BackgroundErrors>,
+    slow_op_threshold: Option<Duration>,
+    /// How long creating or opening the store took.
+    startup_time: Duration,
+    /// For the writers of later policy switches.
+    writer_options: WriterOptions,
+}
+
+impl FileStore {
+    /// Returns true if a store has previously been created at `output_path`.
+    pub fn exists(output_path: &Path) -> bool {
+        Manifest::exists(output_path)
+    }
+
+    /// Create a new store at `output_path`. Fails if a store already exists there.
+    // TODO: Use a builder.
+    pub fn create(
+        output_path: &Path,
+        file_count: usize,
+        write_policy: &WritePolicy,
+        serializer: Serializer,
+        router: Router,
+        hash: HashFunction,
+    ) -> Result<Self> {
+        if Self::exists(output_path) {
+            return Err(StoreError::AlreadyExists(output_path.to_path_buf()).into());
+        }
+        std::fs::create_dir_all(output_path)?;
+        let manifest = Manifest::new(file_count, serializer, router, hash);
+        manifest.save(output_path)?;
+        Self::init(output_path, &manifest, write_policy, BackingFile::create)
+    }
+
+    /// Open an existing store at `output_path`, with the shard count and serializer
+    /// from its manifest, first finishing any interrupted serializer migration.
+    pub fn open(output_path: &Path, write_policy: &WritePolicy) -> Result<Self> {
+        let mut manifest = Manifest::load(output_path)?;
+        if let Some(serializer) = manifest.migrating_to.clone() {
+            log::warn!("Resuming the interrupted migration to {:?}.", serializer);
+            Self::migrate_serializer(output_path, serializer)?;
+            manifest = Manifest::load(output_path)?;
+        }
+        Self::init(output_path, &manifest, write_policy, BackingFile::open)
+    }
+
+    /// Open the store at `output_path` to read it, changing no file: shards are
+    /// loaded, with their delta logs, but get no writers, so every change fails
+    /// with `StoreError::ReadOnly`. Fails on an unreadable shard, and on a store
+    /// whose serializer migration was interrupted, rather than repairing either.
+    pub fn open_read_only(output_path: &Path) -> Result<Self> {
+        let manifest = Manifest::load(output_path)?;
+        if manifest.migrating_to.is_some() {
+            bail!("The store's migration to another serializer was interrupted; open it read-write to finish it");
+        }
+        // Read-only shards have no writer for the policy to apply to.
+        let policy = WritePolicy::Synchronous {
+            write_period: Duration::ZERO,
+        };
+        Self::init(output_path, &manifest, &policy, BackingFile::open_read_only)
+    }
+
+    /// Convert the shards of the store at `output_path` to `serializer`, one at a
+    /// time, logging progress. The manifest records the migration first, and
+    /// switches to the new serializer once every shard is converted; a shard
+    /// already converted before an interruption is read in the new format, so an
+    /// interrupted migration can simply be run again.
+    pub fn migrate_serializer(output_path: &Path, serializer: Serializer) -> Result<()> {
+        let mut manifest = Manifest::load(output_path)?;
+        if let Some(pending) = &manifest.migrating_to {
+            if *pending != serializer {
+                bail!(
+                    "An interrupted migration to {:?} must finish first; open the store to finish it",
+                    pending
+                );
+            }
+        } else if manifest.serializer == serializer {
+            log::info!(
+                "Store is already serialized as {:?}; nothing to do.",
+                serializer
+            );
+            return Ok(());
+        }
+        let from = manifest.serializer.clone();
+        manifest.migrating_to = Some(serializer.clone());
+        manifest.save(output_path)?;
+        log::info!(
+            "Converting {} shards from {:?} to {:?}.",
+            manifest.file_count,
+            from,
+            serializer
+        );
+
+        let started = Instant::now();
+        let (mut key_count, mut bytes_before, mut bytes_after) = (0, 0, 0);
+        for index in 0..manifest.file_count {
+            let filename = BackingFile::filename(manifest.file_count, index, output_path);
+            // Unlike opening, a corrupt shard is a hard failure here: resetting it
+            // would lose the data the migration is meant to keep.
+            let shard = match BackingFile::load(&filename, &from) {
+                Ok(shard) => shard,
+                Err(err) => BackingFile::load(&filename, &serializer).map_err(|_| {
+                    anyhow!("Could not read shard {:?} as {:?}: {}", filename, from, err)
+                })?,
+            };
+            let before = filename.metadata().map(|m| m.len()).unwrap_or(0);
+            let after = serializer.overwrite_durably(&filename, &shard, None)?;
+            log::info!(
+                "Converted shard {}/{}: {} keys, {} -> {} bytes",
+                index + 1,
+                manifest.file_count,
+                shard.len(),
+                before,
+                after
+            );
+            key_count += shard.len();
+            bytes_before += before;
+            bytes_after += after;
+        }
+
+        let new_manifest = Manifest {
+            serializer: serializer.clone(),
+            migrating_to: None,
+            ..manifest
+        };
+        new_manifest.save(output_path)?;
+        log::info!(
+            "Converted {} keys to {:?} in {:?}: {} -> {} bytes.",
+            key_count,
+            serializer,
+            started.elapsed(),
+            bytes_before,
+            bytes_after
+        );
+        Ok(())
+    }
+
+    /// Rehash every key of the store at `output_path` into `file_count` shards,
+    /// optionally switching to a different router or hash function.
+    ///
+    /// Shard filenames include the shard count, so the new layout is written next
+    /// to the old one; replacing the manifest is the atomic commit point, after which
+    /// the old shards are removed. Must not run while the store is open elsewhere.
+    pub fn reshard(
+        output_path: &Path,
+        file_count: usize,
+        router: Option<Router>,
+        hash: Option<HashFunction>,
+    ) -> Result<()> {
+        Self::reshard_as(output_path, file_count, router, hash, None)
+    }
+
+    /// Rewrite the store at `output_path` with `serializer`, and with
+    /// `file_count` shards if given, routed by `router` and `hash` (each defaulting
+    /// to the store's current one). Keeping the shard count converts each shard in
+    /// place, as `migrate_serializer` does; changing it converts while resharding,
+    /// in a single pass. Must not run while the store is open elsewhere.
+    pub fn convert(
+        output_path: &Path,
+        serializer: Serializer,
+        file_count: Option<usize>,
+        router: Option<Router>,
+        hash: Option<HashFunction>,
+    ) -> Result<()> {
+        let manifest = Manifest::load(output_path)?;
+        match file_count {
+            Some(file_count) if file_count != manifest.file_count => {
+                if manifest.migrating_to.is_some() {
+                    bail!("An interrupted migration must finish before resharding; open the store, or convert it without changing file_count, to finish it");
+                }
+                Self::reshard_as(output_path, file_count, router, hash, Some(serializer))
+            }
+            _ => {
+                let same_routing = router.is_none_or(|router| router == manifest.router)
+                    && hash.is_none_or(|hash| hash == manifest.hash);
+                if !same_routing {
+                    // Shard filenames would collide with the old layout.
+                    bail!("Changing the router or hash requires changing the shard count");
+                }
+                Self::migrate_serializer(output_path, serializer)
+            }
+        }
+    }
+
+    /// Check every shard of the store at `output_path`: that it exists and decodes,
+    /// that it matches its checksum, and that each of its keys routes to it. Shard
+    /// files outside the layout are reported, with the keys in them that the store
+    /// doesn't hold.
+    ///
+    /// With `repair`, unreadable shards are set aside with a ".corrupt" extension
+    /// and replaced with empty ones, missing shards are created empty, misplaced
+    /// keys are moved to the shard they route to, duplicates are dropped, and
+    /// checksums are rewritten for shards that decode. No file is deleted, and
+    /// orphaned keys aren't restored, since they may be stale. Must not run while
+    /// the store is open elsewhere.
+    pub fn verify(output_path: &Path, repair: bool) -> Result<VerifyReport> {
+        let manifest = Manifest::load(output_path)?;
+        if repair && manifest.migrating_to.is_some() {
+            bail!("An interrupted migration must finish before repairing; open the store to finish it");
+        }
+        // A shard may be in either format until a pending migration finishes.
+        let serializers: Vec<&Serializer> = std::iter::once(&manifest.serializer)
+            .chain(manifest.migrating_to.as_ref())
+            .collect();
+        let decode = |bytes: &[u8]| -> Result<MemoryStoreSingleThreaded, StoreError> {
+            let mut result = Ok(MemoryStoreSingleThreaded::new());
+            if bytes.is_empty() {
+                return result;
+            }
+            for serializer in &serializers {
+                result = serializer.read(bytes);
+                if result.is_ok() {
+                    break;
+                }
+            }
+            result
+        };
+
+        let mut report = VerifyReport::default();
+        let mut shards = Vec::with_capacity(manifest.file_count);
+        for index in 0..manifest.file_count {
+            let filename = BackingFile::filename(manifest.file_count, index, output_path);
+            let bytes = match std::fs::read(&filename) {
+                Ok(bytes) => bytes,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    report.shards.push((ShardCondition::Missing, 0));
+                    shards.push(None);
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let mut shard = match decode(&bytes) {
+                Ok(shard) => shard,
+                Err(err) => {
+                    report
+                        .shards
+                        .push((ShardCondition::Unreadable(err.to_string()), 0));
+                    shards.push(None);
+                    continue;
+                }
+            };
+            let replay = DeltaLog::replay(&filename, &bytes, &manifest.serializer, &mut shard)?;
+            if let Some(broken) = replay.broken {
+                report.broken_logs.push((index, broken));
+            }
+            let actual = ShardChecksum::of(&bytes);
+            let condition = match ShardChecksum::load(&filename)? {
+                Some(expected) if expected == actual => ShardCondition::Ok,
+                Some(expected) => ShardCondition::ChecksumMismatch { expected, actual },
+                None => ShardCondition::Unchecked,
+            };
+            report.shards.push((condition, shard.len()));
+            shards.push(Some(shard));
+        }
+
+        let router = manifest.router.build(manifest.file_count, manifest.hash);
+        let mut moves = Vec::new();
+        for (index, shard) in shards.iter().enumerate() {
+            for (key, value) in shard.iter().flat_map(|shard| shard.iter()) {
+                let routed = router.route(key);
+                if routed == index {
+                    continue;
+                }
+                let shadowed =
+                    matches!(shards.get(routed), Some(Some(shard)) if shard.get(key)?.is_some());
+                if shadowed {
+                    report.duplicate_keys.push(key.clone());
+                } else {
+                    report.misplaced_keys.push(key.clone());
+                }
+                moves.push((index, routed, key.clone(), value.clone(), shadowed));
+            }
+        }
+        report.misplaced_keys.sort();
+        report.duplicate_keys.sort();
+
+        let layout: Vec<PathBuf> = (0..manifest.file_count)
+            .map(|index| BackingFile::filename(manifest.file_count, index, output_path))
+            .collect();
+        let mut orphans = Vec::new();
+        for entry in std::fs::read_dir(output_path)? {
+            let path = entry?.path();
+            let is_shard_file = path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("store_size="));
+            let in_layout = layout.iter().any(|shard| {
+                *shard == path
+                    || ShardChecksum::path(shard) == path
+                    || DeltaLog::segment_number(shard, &path).is_some()
+            });
+            if is_shard_file && !in_layout {
+                orphans.push(path);
+            }
+        }
+        orphans.sort();
+        for path in &orphans {
+            let is_log = path.to_string_lossy().contains(".delta");
+            if is_log
+                || path
+                    .extension()
+                    .is_some_and(|extension| extension == "crc32")
+            {
+                continue;
+            }
+            let Ok(orphan) = std::fs::read(path)
+                .map_err(StoreError::from)
+                .and_then(|bytes| decode(&bytes))
+            else {
+                continue;
+            };
+            for (key, _) in orphan.iter() {
+                let held = matches!(shards.get(router.route(key)), Some(Some(shard)) if shard.get(key)?.is_some());
+                if !held {
+                    report.orphaned_keys += 1;
+                }
+            }
+        }
+        report.orphaned_files = orphans;
+
+        if repair {
+            let mut dirty = vec![false; manifest.file_count];
+            for (index, _) in &report.broken_logs {
+                dirty[*index] = true;
+                report.repairs.push(format!(
+                    "rewrote shard {} from its snapshot and the log segments that apply",
+                    index
+                ));
+            }
+            for (index, (condition, _)) in report.shards.iter().enumerate() {
+                let filename = &layout[index];
+                match condition {
+                    ShardCondition::Ok => continue,
+                    ShardCondition::Unreadable(_) => {
+                        let timestamp = chrono::Local::now().format("%Y-%m-%d_%H%M%S");
+                        let aside = filename.with_extension(format!("corrupt{}", timestamp));
+                        std::fs::rename(filename, &aside)?;
+                        report.repairs.push(format!(
+                            "set unreadable shard {} aside as {:?}, replacing it with an empty one",
+                            index, aside
+                        ));
+                    }
+                    ShardCondition::Missing => {
+                        report
+                            .repairs
+                            .push(format!("created missing shard {}, empty", index));
+                    }
+                    ShardCondition::ChecksumMismatch { .. } | ShardCondition::Unchecked => {
+                        report
+                            .repairs
+                            .push(format!("rewrote shard {} with a new checksum", index));
+                    }
+                }
+                dirty[index] = true;
+            }
+            let mut shards: Vec<MemoryStoreSingleThreaded> =
+                shards.into_iter().map(Option::unwrap_or_default).collect();
+            let (mut moved, mut dropped) = (0, 0);
+            for (from, to, key, value, shadowed) in moves {
+                shards[from].delete(&key)?;
+                dirty[from] = true;
+                if shadowed {
+                    dropped += 1;
+                } else {
+                    shards[to].put(&key, value)?;
+                    dirty[to] = true;
+                    moved += 1;
+                }
+            }
+            if moved > 0 {
+                report
+                    .repairs
+                    .push(format!("moved {} misplaced keys to their shards", moved));
+            }
+            if dropped > 0 {
+                report
+                    .repairs
+                    .push(format!("dropped {} duplicate keys", dropped));
+            }
+            for (index, shard) in shards.iter().enumerate() {
+                if dirty[index] {
+                    manifest
+                        .serializer
+                        .overwrite_durably(&layout[index], shard, None)?;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// As `reshard`, also switching to `serializer` if given.
+    fn reshard_as(
+        output_path: &Path,
+        file_count: usize,
+        router: Option<Router>,
+        hash: Option<HashFunction>,
+        serializer: Option<Serializer>,
+    ) -> Result<()> {
+        let manifest = Manifest::load(output_path)?;
+        let serializer = serializer.unwrap_or_else(|| manifest.serializer.clone());
+        let router = router.unwrap_or(manifest.router);
+        let hash = hash.unwrap_or(manifest.hash);
+        let same_routing = manifest.router == router && manifest.hash == hash;
+        if manifest.file_count == file_count && same_routing {
+            log::info!("Store already has {} shards; nothing to do.", file_count);
+            return Ok(());
+        }
+        if manifest.file_count == file_count {
+            // Shard filenames would collide with the old layout.
+            bail!("Changing the router or hash requires changing the shard count");
+        }
+
+        let new_router = router.build(file_count, hash);
+        let mut new_shards = vec![MemoryStoreSingleThreaded::new(); file_count];
+        let mut key_count = 0;
+        let mut moved_count = 0;
+        for index in 0..manifest.file_count {
+            let filename = BackingFile::filename(manifest.file_count, index, output_path);
+            // Unlike opening, a corrupt shard is a hard failure here: we're about to
+            // delete the old layout.
+            let shard = BackingFile::load(&filename, &manifest.serializer)?;
+            for (key, value) in shard.iter() {
+                let new_index = new_router.route(key);
+                new_shards[new_index].put(key, value.clone())?;
+                key_count += 1;
+                if new_index != index {
+                    moved_count += 1;
+                }
+            }
+            // Soft-deleted keys move with the rest, so they can still be undeleted.
+            for (key, tombstone) in shard.tombstones() {
+                let new_shard = &mut new_shards[new_router.route(key)];
+                let value = new_shard.get(key)?;
+                new_shard.restore(key.clone(), value, Some(tombstone.clone()));
+            }
+        }
+
+        // The new shards and then the manifest must be on disk before the old
+        // layout goes, or a crash could leave neither.
+        for (index, shard) in new_shards.iter().enumerate() {
+            let filename = BackingFile::filename(file_count, index, output_path);
+            serializer.overwrite_durably(&filename, shard, None)?;
+        }
+        let new_manifest = Manifest {
+            file_count,
+            router,
+            hash,
+            serializer: serializer.clone(),
+            ..manifest.clone()
+        };
+        new_manifest.save(output_path)?;
+
+        for index in 0..manifest.file_count {
+            let filename = BackingFile::filename(manifest.file_count, index, output_path);
+            remove_if_exists(&filename)?;
+            remove_if_exists(&ShardChecksum::path(&filename))?;
+            DeltaLog::remove(&filename)?;
+        }
+        log::info!(
+            "Resharded {} keys from {} to {} shards; {} changed shard.",
+            key_count,
+            manifest.file_count,
+            file_count,
+            moved_count
+        );
+        if serializer != manifest.serializer {
+            log::info!(
+                "Converted them from {:?} to {:?}.",
+                manifest.serializer,
+                serializer
+            );
+        }
+        Ok(())
+    }
+
+    fn init(
+        output_path: &Path,
+        manifest: &Manifest,
+        write_policy: &WritePolicy,
+        init_file: InitFile,
+    ) -> Result<Self> {
+        let started = Instant::now();
+        let file_count = manifest.file_count;
+        let errors = Arc::new(BackgroundErrors::new());
+        // Load the shards in parallel, each thread taking every `threads`th one, as
+        // decoding a large shard keeps a core busy.
+        let threads = std::thread::available_parallelism()
+            .map_or(1, |cores| cores.get())
+            .clamp(1, file_count.max(1));
+        let mut loaded: Vec<Option<Result<BackingFile>>> = (0..file_count).map(|_| None).collect();
+        std::thread::scope(|scope| {
+            let loaders: Vec<_> = (0..threads)
+                .map(|first| {
+                    let errors = &errors;
+                    scope.spawn(move || {
+                        (first..file_count)
+                            .step_by(threads)
+                            .map(|index| {
+                                let file = init_file(
+                                    file_count,
+                                    index,
+                                    output_path,
+                                    write_policy,
+                                    manifest.serializer.clone(),
+                                    Arc::clone(errors),
+                                );
+                                (index, file)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            for loader in loaders {
+                let Ok(files) = loader.join() else {
+                    continue;
+                };
+                for (index, file) in files {
+                    loaded[index] = Some(file);
+                }
+            }
+        });
+        let mut files = Vec::with_capacity(file_count);
+        for (index, file) in loaded.into_iter().enumerate() {
+            let file =
+                file.ok_or_else(|| anyhow!("the thread loading shard {} panicked", index))?;
+            files.push(Arc::new(Shard::new(file?)));
+        }
+        let flusher = match write_policy {
+            WritePolicy::Hybrid {
+                snapshot_interval, ..
+            } => Some(Arc::new(Flusher::start_snapshots(
+                files.iter().map(Arc::clone).collect(),
+                *snapshot_interval,
+                Arc::clone(&errors),
+            ))),
+            _ => None,
+        };
+        Ok(Self {
+            files,
+            router: manifest.router.build(file_count, manifest.hash),
+            read_repair: None,
+            flusher,
+            soft_delete: None,
+            errors,
+            slow_op_threshold: None,
+            startup_time: started.elapsed(),
+            writer_options: WriterOptions::default(),
+        })
+    }
+}
+
+impl FileStore {
+    /// Cross-check a random `sample_rate` fraction of reads against the persisted
+    /// value, repairing divergence. Applies to handles spawned afterwards.
+    pub fn with_read_repair(mut self, sample_rate: f64) -> Self {
+        self.read_repair = Some(Arc::new(ReadRepair {
+            sample_rate,
+            stats: Arc::new(ReadRepairStats::default()),
+        }));
+        self
+    }
+
+    /// Log every get, put and delete that takes longer than `threshold`, with its
+    /// shard, the shard's asynchronous queue depth, and whether the shard was being
+    /// flushed meanwhile. Applies to handles spawned afterwards.
+    pub fn with_slow_op_log(mut self, threshold: Duration) -> Self {
+        self.slow_op_threshold = Some(threshold);
+        self
+    }
+
+    fn watch_slow<'a>(
+        &self,
+        op: &'static str,
+        key: &'a str,
+        index: usize,
+        shard: &'a Shard,
+    ) -> Option<SlowOpWatch<'a>> {
+        let threshold = self.slow_op_threshold?;
+        Some(SlowOpWatch {
+            threshold,
+            op,
+            key,
+            index,
+            shard,
+            started: Instant::now(),
+            flush_state: shard.phases.flush_state(),
+        })
+    }
+
+    /// Apply `policy` to errors the store's background threads hit from now on.
+    pub fn with_background_error_policy(self, policy: BackgroundErrorPolicy) -> Result<Self> {
+        *self
+            .errors
+            .policy
+            .lock()
+            .map_err(|_| StoreError::LockError)? = policy;
+        Ok(self)
+    }
+
+    /// The background error policy and the errors it has been applied to.
+    pub fn health(&self) -> Result<Health> {
+        self.errors.health()
+    }
+
+    /// How long creating or opening the store took, loading every shard.
+    pub fn startup_time(&self) -> Duration {
+        self.startup_time
+    }
+
+    pub fn health_handle(&self) -> HealthHandle {
+        HealthHandle {
+            errors: Arc::clone(&self.errors),
+        }
+    }
+
+    /// Flush dirty shards in the background so that no write waits longer than
+    /// `max_lag` to be persisted. Only supported with the synchronous write policy.
+    pub fn with_flusher(mut self, max_lag: Duration, order: FlushOrder) -> Result<Self> {
+        for shard in &self.files {
+            let mut guard = shard.lock_uncounted()?;
+            match &mut guard.writer {
+                Writer::Synchronous { poller: None, .. } => {
+                    bail!("The hybrid write policy already snapshots on its own timer")
+                }
+                Writer::Synchronous { deadline, .. } => *deadline = Some(max_lag),
+                Writer::Asynchronous { .. } | Writer::ReadOnly => {
+                    bail!("A persistence deadline requires the synchronous write policy")
+                }
+            }
+        }
+        let files = self.files.iter().map(Arc::clone).collect();
+        self.flusher = Some(Arc::new(Flusher::start(
+            files,
+            max_lag,
+            order,
+            Arc::clone(&self.errors),
+        )));
+        Ok(self)
+    }
+
+    /// Persist each flush as a delta of the keys changed since the last one,
+    /// appended to the shard's ".delta" file, instead of rewriting the shard; every
+    /// `snapshot_every`th flush, and any flush once the log outgrows the shard,
+    /// writes a full snapshot instead, as do compactions and durable puts. Opening a
+    /// store applies the log to its snapshot. Requires the synchronous write policy.
+    pub fn with_delta_persistence(mut self, snapshot_every: u32) -> Result<Self> {
+        if snapshot_every == 0 {
+            bail!("A delta log needs a snapshot at least every 1 flushes");
+        }
+        for shard in &self.files {
+            let mut guard = shard.lock_uncounted()?;
+            let log = DeltaLog {
+                max_segment_bytes: self.writer_options.max_segment_bytes,
+                ..DeltaLog::new(snapshot_every)
+            };
+            match &mut guard.writer {
+                Writer::Synchronous {
+                    poller: Some(_),
+                    delta,
+                    ..
+                } => *delta = Some(log),
+                Writer::Synchronous { .. } | Writer::Asynchronous { .. } | Writer::ReadOnly => {
+                    bail!("Delta persistence requires the synchronous write policy")
+                }
+            }
+        }
+        self.writer_options.snapshot_every = Some(snapshot_every);
+        Ok(self)
+    }
+
+    /// Split each shard's delta log, or the hybrid policy's write-ahead log, into
+    /// numbered segments of at most `max_bytes` each, including those of later
+    /// policy switches. Call after `with_delta_persistence`. A snapshot removes
+    /// every segment it covers, which is all of them.
+    pub fn with_max_segment_bytes(mut self, max_bytes: u64) -> Result<Self> {
+        if max_bytes <= (DELTA_HEADER_LEN + 8) as u64 {
+            bail!(
+                "A log segment needs more than {} bytes to hold a change",
+                DELTA_HEADER_LEN + 8
+            );
+        }
+        let mut logs = 0;
+        for shard in &self.files {
+            if let Writer::Synchronous {
+                delta: Some(delta), ..
+            } = &mut shard.lock_uncounted()?.writer
+            {
+                delta.max_segment_bytes = Some(max_bytes);
+                logs += 1;
+            }
+        }
+        if logs == 0 && self.writer_options.snapshot_every.is_none() {
+            bail!(
+                "Log segments need delta persistence or the hybrid write policy's write-ahead log"
+            );
+        }
+        self.writer_options.max_segment_bytes = Some(max_bytes);
+        Ok(self)
+    }
+
+    /// What asynchronous writers do with changes that find their queue full,
+    /// including those of later policy switches. Compactions always wait.
+    pub fn with_queue_full_policy(mut self, policy: QueueFullPolicy) -> Result<Self> {
+        for shard in &self.files {
+            if let Writer::Asynchronous { queue_full, .. } = &mut shard.lock_uncounted()?.writer {
+                *queue_full = policy;
+            }
+        }
+        self.writer_options.queue_full = policy;
+        Ok(self)
+    }
+
+    /// Serve the asynchronous writers of every shard, including those of later
+    /// policy switches, with a pool of `threads` threads instead of a thread each.
+    /// Each shard's queue is served by one of the threads, which are assigned
+    /// round robin.
+    pub fn with_writer_threads(mut self, threads: usize) -> Result<Self> {
+        if threads == 0 {
+            bail!("A writer pool needs at least 1 thread");
+        }
+        self.writer_options.pool = Some(Arc::new(WriterPool::start(threads)));
+        for shard in &self.files {
+            let mut guard = shard.lock_uncounted()?;
+            if let Writer::Asynchronous { sender, .. } = &guard.writer {
+                let policy = WritePolicy::Asynchronous {
+                    queue_depth: sender.capacity().unwrap_or(1),
+                };
+                guard.switch_writer(&policy, None, &self.writer_options)?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// Per-shard persistence lag, for stores using the synchronous write policy.
+    pub fn lag_gauges(&self) -> Result<Vec<LagGauge>> {
+        let mut gauges = Vec::with_capacity(self.files.len());
+        for shard in &self.files {
+            let guard = shard.lock_uncounted()?;
+            gauges.extend(guard.writer.lag_gauge());
+        }
+        Ok(gauges)
+    }
+
+    pub fn log_lag_gauges(&self) -> Result<()> {
+        for (index, gauge) in self.lag_gauges()?.iter().enumerate() {
+            log::info!(
+                "shard {}: flushes={} max_lag={:?} deadline_misses={} current_lag={:?}",
+                index,
+                gauge.flushes,
+                gauge.max_lag,
+                gauge.deadline_misses,
+                gauge.current_lag
+            );
+        }
+        Ok(())
+    }
+
+    /// Time spent in each phase of the write path since the store was opened,
+    /// summed over every shard; see `WritePhases`.
+    pub fn write_phases(&self) -> Result<Vec<PhaseStats>> {
+        let mut stats: Vec<PhaseStats> = WritePhases::default()
+            .timers()
+            .iter()
+            .map(|(phase, _)| PhaseStats {
+                phase,
+                ..PhaseStats::default()
+            })
+            .collect();
+        for shard in &self.files {
+            let phases = Arc::clone(&shard.lock_uncounted()?.phases);
+            for (stats, (_, timer)) in stats.iter_mut().zip(phases.timers()) {
+                stats.count += timer.count.load(Ordering::Relaxed);
+                stats.total += Duration::from_nanos(timer.total_ns.load(Ordering::Relaxed));
+                stats.max = stats
+                    .max
+                    .max(Duration::from_nanos(timer.max_ns.load(Ordering::Relaxed)));
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Log one line per write-path phase: how often it ran, its mean and maximum
+    /// time, and its share of the time spent in all of them. Then, for
+    /// asynchronous writers, how many changes each write of a shard held, and how
+    /// many were dropped from full queues.
+    pub fn log_write_phases(&self) -> Result<()> {
+        let stats = self.write_phases()?;
+        let total: Duration = stats.iter().map(|s| s.total).sum();
+        for s in &stats {
+            if s.count == 0 {
+                continue;
+            }
+            log::info!(
+                "write_phase {}: count={} total={:.1}ms mean={:.1}us max={:.1}us share={:.1}%",
+                s.phase,
+                s.count,
+                s.total.as_secs_f64() * 1000.0,
+                s.total.as_secs_f64() * 1e6 / s.count as f64,
+                s.max.as_secs_f64() * 1e6,
+                s.total.as_secs_f64() / total.as_secs_f64().max(f64::MIN_POSITIVE) * 100.0
+            );
+        }
+        let (mut batches, mut changes, mut dropped) = (0, 0, 0);
+        for shard in &self.files {
+            let phases = Arc::clone(&shard.lock_uncounted()?.phases);
+            batches += phases.batches.load(Ordering::Relaxed);
+            changes += phases.batched_changes.load(Ordering::Relaxed);
+            dropped += phases.dropped_changes.load(Ordering::Relaxed);
+        }
+        if batches > 0 {
+            log::info!(
+                "write_batches: count={} changes={} mean_size={:.1}",
+                batches,
+                changes,
+                changes as f64 / batches as f64
+            );
+        }
+        if dropped > 0 {
+            log::info!("write_queue_dropped_changes: {}", dropped);
+        }
+        Ok(())
+    }
+
+    pub fn read_repair_stats(&self) -> Option<Arc<ReadRepairStats>> {
+        self.read_repair.as_ref().map(|rr| Arc::clone(&rr.stats))
+    }
+
+    /// Make deletes soft: a deleted key's value is kept as a tombstone, readable
+    /// through `get_deleted` and restorable with `undelete`, until `horizon` has
+    /// passed. Tombstones are persisted with the rest of the shard, and dropped by
+    /// `compact` once they're past the horizon. Applies to handles spawned
+    /// afterwards.
+    pub fn with_soft_delete(mut self, horizon: Duration) -> Self {
+        self.soft_delete = Some(Arc::new(SoftDelete {
+            horizon,
+            stats: Arc::new(SoftDeleteStats::default()),
+        }));
+        self
+    }
+
+    pub fn soft_delete_stats(&self) -> Option<Arc<SoftDeleteStats>> {
+        self.soft_delete.as_ref().map(|sd| Arc::clone(&sd.stats))
+    }
+
+    /// Drop every tombstone past the purge horizon, rewriting the shards that had
+    /// any, and return how many were dropped. Does nothing without soft deletes.
+    pub fn compact(&self) -> Result<usize> {
+        let Some(soft_delete) = &self.soft_delete else {
+            return Ok(0);
+        };
+        compact_shards(&self.files, soft_delete)
+    }
+
+    /// Run `compact` every `interval` until the returned compactor is dropped.
+    pub fn spawn_compactor(&self, interval: Duration) -> Compactor {
+        let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+        let files: Vec<Arc<Shard>> = self.files.iter().map(Arc::clone).collect();
+        let soft_delete = self.soft_delete.clone();
+        let errors = Arc::clone(&self.errors);
+        let handle = std::thread::spawn(move || {
+            let Some(soft_delete) = soft_delete else {
+                return;
+            };
+            while let Err(crossbeam_channel::RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(interval)
+            {
+                if let Err(err) = compact_shards(&files, &soft_delete) {
+                    errors.report("compactor", err);
+                }
+            }
+        });
+        Compactor {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// Keep a journal of the last `capacity` operations on each shard, readable
+    /// through `journal`.
+    pub fn with_journal(self, capacity: usize) -> Result<Self> {
+        let started = Instant::now();
+        for shard in &self.files {
+            shard.lock_uncounted()?.journal = Journal::new(capacity, started);
+        }
+        Ok(self)
+    }
+
+    pub fn journal(&self) -> JournalHandle {
+        JournalHandle {
+            files: self.files.iter().map(Arc::clone).collect(),
+        }
+    }
+
+    /// Persist all pending writes, waiting for asynchronous writers to drain.
+    pub fn flush(&self) -> Result<()> {
+        for shard in &self.files {
+            shard.lock_uncounted()?.flush()?;
+        }
+        let deadline = Instant::now() + FLUSH_TIMEOUT;
+        for shard in &self.files {
+            while shard.lock_uncounted()?.writer.queue_depth() > 0 {
+                if Instant::now() > deadline {
+                    bail!("Timed out waiting for asynchronous writes to drain");
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+        Ok(())
+    }
+
+    /// Another handle on the same shards.
+    fn share(&self) -> Self {
+        Self {
+            files: self.files.iter().map(Arc::clone).collect(),
+            router: Arc::clone(&self.router),
+            read_repair: self.read_repair.clone(),
+            flusher: self.flusher.clone(),
+            soft_delete: self.soft_delete.clone(),
+            errors: Arc::clone(&self.errors),
+            slow_op_threshold: self.slow_op_threshold,
+            startup_time: self.startup_time,
+            writer_options: self.writer_options.clone(),
+        }
+    }
+
+    /// Switch every shard to `policy`, one at a time. Each shard's writes wait
+    /// while its writer drains: all the old writer was handed is persisted before
+    /// the new one takes over, so a switch never loses or reorders writes. A
+    /// persistence deadline set by `with_flusher` carries over to synchronous
+    /// writers; asynchronous ones have none. A store can't switch to the hybrid
+    /// policy, whose snapshot timer starts with the store.
+    pub fn set_write_policy(&self, policy: &WritePolicy) -> Result<()> {
+        if let WritePolicy::Hybrid { .. } = policy {
+            bail!(
+                "A store can't switch to the hybrid write policy; open it with that policy instead"
+            );
+        }
+        let deadline = self
+            .flusher
+            .as_ref()
+            .filter(|flusher| !flusher.snapshots)
+            .map(|flusher| flusher.max_lag);
+        for shard in &self.files {
+            shard
+                .lock_uncounted()?
+                .switch_writer(policy, deadline, &self.writer_options)?;
+        }
+        log::info!("Switched write policy to {:?}.", policy);
+        Ok(())
+    }
+
+    /// Apply each of `switches` when it's due, until dropped.
+    pub fn spawn_policy_switcher(&self, mut switches: Vec<PolicySwitch>) -> PolicySwitcher {
+        switches.sort_by_key(|switch| switch.at);
+        let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+        let store = self.share();
+        let started = Instant::now();
+        let handle = std::thread::spawn(move || {
+            for switch in switches {
+                let wait = (started + switch.at).saturating_duration_since(Instant::now());
+                if let Err(crossbeam_channel::RecvTimeoutError::Disconnected) =
+                    stopped.recv_timeout(wait)
+                {
+                    return;
+                }
+                if let Err(err) = store.set_write_policy(&switch.policy) {
+                    store.errors.report("policy switcher", err);
+                }
+            }
+        });
+        PolicySwitcher {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// Durable puts and the syncs that covered them, across all shards.
+    pub fn group_commit_stats(&self) -> Result<GroupCommitStats> {
+        let mut stats = GroupCommitStats::default();
+        for shard in &self.files {
+            let state = shard
+                .commits
+                .state
+                .lock()
+                .map_err(|_| StoreError::LockError)?;
+            stats.durable_puts += state.durable_puts;
+            stats.syncs += state.syncs;
+        }
+        Ok(stats)
+    }
+
+    /// Every key and value in the store. All shards are locked together, so the
+    /// result is consistent across shards even while other threads are writing.
+    pub fn snapshot(&self) -> Result<Vec<(String, Blob)>> {
+        let guards = self
+            .files
+            .iter()
+            .map(|shard| shard.lock_uncounted())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(guards
+            .iter()
+            .flat_map(|guard| guard.mem_store.iter())
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    /// Sizes of the values written through any handle since the store was opened,
+    /// per key namespace.
+    pub fn written_value_sizes(&self) -> Result<ValueSizes> {
+        let mut sizes = ValueSizes::new();
+        for shard in &self.files {
+            sizes.merge(&shard.lock_uncounted()?.written_sizes)?;
+        }
+        Ok(sizes)
+    }
+
+    /// Sizes of the values currently in the store, per key namespace. Shards are
+    /// scanned one at a time, so unlike `snapshot` this doesn't copy the store.
+    pub fn stored_value_sizes(&self) -> Result<ValueSizes> {
+        let mut sizes = ValueSizes::new();
+        for shard in &self.files {
+            for (key, value) in shard.lock_uncounted()?.mem_store.iter() {
+                sizes.record(key, value);
+            }
+        }
+        Ok(sizes)
+    }
+
+    /// Read `key` from its shard's file on disk, bypassing memory.
+    pub fn persisted_value(&self, key: &str) -> Result<Option<Blob>> {
+        let index = self.router.route(key);
+        let shard = self
+            .files
+            .get(index)
+            .ok_or(StoreError::BadFileHash(index))?;
+        let (filename, serializer) = {
+            let guard = shard.lock_uncounted()?;
+            (guard.filename.clone(), guard.serializer.clone())
+        };
+        Ok(BackingFile::load(&filename, &serializer)?.get(key)?)
+    }
+
+    pub fn stats(&self) -> Result<Vec<ShardStats>> {
+        self.files.iter().map(|shard| shard.stats()).collect()
+    }
+
+    /// Writes handed to asynchronous writers that they haven't persisted yet, across
+    /// all shards.
+    pub fn queue_depth(&self) -> Result<usize> {
+        let mut depth = 0;
+        for shard in &self.files {
+            depth += shard.lock_uncounted()?.writer.queue_depth();
+        }
+        Ok(depth)
+    }
+
+    /// Log per-shard statistics every `interval`, plus the shard that saw the most
+    /// operations during that interval, until the returned logger is dropped. A
+    /// store with a writer pool also logs the queue depth of each of its threads,
+    /// summed over the shards it serves.
+    pub fn spawn_stats_logger(&self, interval: Duration) -> StatsLogger {
+        let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+        let files: Vec<Arc<Shard>> = self.files.iter().map(Arc::clone).collect();
+        let handle = std::thread::spawn(move || {
+            let mut last_ops = vec![0; files.len()];
+            while let Err(crossbeam_channel::RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(interval)
+            {
+                let stats: Result<Vec<ShardStats>> = files.iter().map(|s| s.stats()).collect();
+                let stats = match stats {
+                    Ok(stats) => stats,
+                    Err(err) => {
+                        log::error!("shard stats error: {:?}", err);
+                        continue;
+                    }
+                };
+                let ops: Vec<u64> = stats
+                    .iter()
+                    .zip(&last_ops)
+                    .map(|(s, last)| s.lock_acquisitions - last)
+                    .collect();
+                last_ops = stats.iter().map(|s| s.lock_acquisitions).collect();
+                let hottest = (0..ops.len()).max_by_key(|&i| ops[i]).unwrap_or(0);
+                log::info!(
+                    "shard_stats: hottest={} ops={:?} contended={:?} keys={:?} bytes={:?} queue={:?}",
+                    hottest,
+                    ops,
+                    stats.iter().map(|s| s.lock_contentions).collect::<Vec<_>>(),
+                    stats.iter().map(|s| s.key_count).collect::<Vec<_>>(),
+                    stats.iter().map(|s| s.bytes_on_disk).collect::<Vec<_>>(),
+                    stats.iter().map(|s| s.queue_depth).collect::<Vec<_>>(),
+                );
+                let threads = stats.iter().filter_map(|s| s.writer_thread).max();
+                if let Some(threads) = threads.map(|max| max + 1) {
+                    let mut depths = vec![0; threads];
+                    for s in &stats {
+                        if let Some(thread) = s.writer_thread {
+                            depths[thread] += s.queue_depth;
+                        }
+                    }
+                    log::info!("writer_thread_stats: queue={:?}", depths);
+                }
+            }
+        });
+        StatsLogger {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// Record each shard's operations and bytes persisted per `bucket` until the
+    /// returned recorder is finished.
+    pub fn spawn_heatmap(&self, bucket: Duration) -> HeatmapRecorder {
+        let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+        let files: Vec<Arc<Shard>> = self.files.iter().map(Arc::clone).collect();
+        let handle = std::thread::spawn(move || {
+            let mut heatmap = Heatmap {
+                bucket_ms: bucket.as_millis() as u64,
+                ops: Vec::new(),
+                bytes: Vec::new(),
+            };
+            let totals = |files: &[Arc<Shard>]| -> (Vec<u64>, Vec<u64>) {
+                files
+                    .iter()
+                    .map(|shard| {
+                        let bytes = shard
+                            .lock_uncounted()
+                            .map(|guard| guard.writer.bytes_written())
+                            .unwrap_or(0);
+                        (shard.lock_acquisitions.load(Ordering::Relaxed), bytes)
+                    })
+                    .unzip()
+            };
+            let (mut last_ops, mut last_bytes) = totals(&files);
+            loop {
+                let finished = !matches!(
+                    stopped.recv_timeout(bucket),
+                    Err(crossbeam_channel::RecvTimeoutError::Timeout)
+                );
+                let (ops, bytes) = totals(&files);
+                heatmap
+                    .ops
+                    .push(ops.iter().zip(&last_ops).map(|(o, l)| o - l).collect());
+                heatmap
+                    .bytes
+                    .push(bytes.iter().zip(&last_bytes).map(|(b, l)| b - l).collect());
+                (last_ops, last_bytes) = (ops, bytes);
+                if finished {
+                    return heatmap;
+                }
+            }
+        });
+        HeatmapRecorder {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// Log how evenly keys are spread across shards.
+    pub fn log_shard_balance(&self) -> Result<()> {
+        let counts: Vec<usize> = self.stats()?.iter().map(|s| s.key_count).collect();
+        let total: usize = counts.iter().sum();
+        let mean = total as f64 / counts.len().max(1) as f64;
+        let variance = counts
+            .iter()
+            .map(|&c| (c as f64 - mean).powi(2))
+            .sum::<f64>()
+            / counts.len().max(1) as f64;
+        log::info!("shard_keys_total: {}", total);
+        log::info!("shard_keys_min: {}", counts.iter().min().unwrap_or(&0));
+        log::info!("shard_keys_max: {}", counts.iter().max().unwrap_or(&0));
+        log::info!("shard_keys_stddev: {:.2}", variance.sqrt());
+        Ok(())
+    }
+}
+
+/// Crashes every shard: see `Writer::crash`. Later writes through any handle
+/// fail, so only a store reopened from disk can be written again.
+impl Crash for FileStore {
+    fn crash(&self) -> Result<(), StoreError> {
+        for shard in &self.files {
+            shard.lock_uncounted()?.crash()?;
+        }
+        Ok(())
+    }
+}
+
+/// Surface an error as-is if it's already a `StoreError`, or as an I/O error if
+/// not.
+fn store_error(err: anyhow::Error) -> StoreError {
+    err.downcast::<StoreError>()
+        .unwrap_or_else(|err| StoreError::Io(std::io::Error::other(format!("{:#}", err))))
+}
+
+/// Merge runs sorted by key into one, with a heap of each run's next key. Shards
+/// never share a key, so there's nothing to deduplicate.
+fn merge_sorted(runs: Vec<Vec<(String, Blob)>>) -> Vec<(String, Blob)> {
+    let mut merged = Vec::with_capacity(runs.iter().map(Vec::len).sum());
+    let mut runs: Vec<_> = runs.into_iter().map(Vec::into_iter).collect();
+    let mut values: Vec<Option<Blob>> = vec![None; runs.len()];
+    let mut next = BinaryHeap::with_capacity(runs.len());
+    for (index, run) in runs.iter_mut().enumerate() {
+        if let Some((key, value)) = run.next() {
+            values[index] = Some(value);
+            next.push(Reverse((key, index)));
+        }
+    }
+    while let Some(Reverse((key, index))) = next.pop() {
+        if let Some(value) = values[index].take() {
+            merged.push((key, value));
+        }
+        if let Some((key, value)) = runs[index].next() {
+            values[index] = Some(value);
+            next.push(Reverse((key, index)));
+        }
+    }
+    merged
+}
+
+impl Store for FileStore {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        let index = self.router.route(key);
+        let _span = spans::span("get", "store").arg("shard", index as u64);
+        let file = self
+            .files
+            .get(index)
+            .ok_or(StoreError::BadFileHash(index))?;
+        let _slow = self.watch_slow("get", key, index, file);
+        {
+            let mut guard = file.lock()?;
+            if let Some(read_repair) = &self.read_repair {
+                if rand::thread_rng().gen::<f64>() < read_repair.sample_rate {
+                    guard.check_and_repair(key, &read_repair.stats)?;
+                }
+            }
+            let result = guard.read(key);
+            guard.journal.record("get", key, || match &result {
+                Ok(None) => JournalOutcome::Miss,
+                result => JournalOutcome::of(result),
+            });
+            result
+        }
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let index = self.router.route(key);
+        let _span = spans::span("put", "store").arg("shard", index as u64);
+        let file = self
+            .files
+            .get(index)
+            .ok_or(StoreError::BadFileHash(index))?;
+        let _slow = self.watch_slow("put", key, index, file);
+        // Minimizing the length of time we hold the lock for.
+        {
+            let started = Instant::now();
+            let mut guard = file.lock()?;
+            guard.phases.lock_wait.since(started);
+            let result = guard.write(key, value);
+            guard
+                .journal
+                .record("put", key, || JournalOutcome::of(&result));
+            result
+        }
+    }
+
+    /// Concurrent durable puts to a shard share its syncs; see `GroupCommit`. Puts
+    /// to other keys of the shard made before the sync start are made durable too.
+    fn put_durable(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let index = self.router.route(key);
+        let _span = spans::span("put_durable", "store").arg("shard", index as u64);
+        let file = self
+            .files
+            .get(index)
+            .ok_or(StoreError::BadFileHash(index))?;
+        let _slow = self.watch_slow("put_durable", key, index, file);
+        let seq = {
+            let started = Instant::now();
+            let mut guard = file.lock()?;
+            guard.phases.lock_wait.since(started);
+            let result = guard.write(key, value);
+            guard
+                .journal
+                .record("put_durable", key, || JournalOutcome::of(&result));
+            result?;
+            guard.write_seq
+        };
+        file.wait_durable(seq)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        let index = self.router.route(key);
+        let _span = spans::span("delete", "store").arg("shard", index as u64);
+        let file = self
+            .files
+            .get(index)
+            .ok_or(StoreError::BadFileHash(index))?;
+        let _slow = self.watch_slow("delete", key, index, file);
+        {
+            let started = Instant::now();
+            let mut guard = file.lock()?;
+            guard.phases.lock_wait.since(started);
+            let result = match &self.soft_delete {
+                Some(soft_delete) => guard.soft_delete(key).inspect(|_| {
+                    soft_delete
+                        .stats
+                        .soft_deletes
+                        .fetch_add(1, Ordering::Relaxed);
+                }),
+                None => guard.delete(key),
+            };
+            guard
+                .journal
+                .record("delete", key, || JournalOutcome::of(&result));
+            result
+        }
+    }
+
+    fn get_deleted(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        let soft_delete = self
+            .soft_delete
+            .as_ref()
+            .ok_or(StoreError::Unsupported("soft deletes"))?;
+        let index = self.router.route(key);
+        let file = self
+            .files
+            .get(index)
+            .ok_or(StoreError::BadFileHash(index))?;
+        let guard = file.lock()?;
+        Ok(guard
+            .tombstone(key, soft_delete.cutoff_ms())
+            .map(|tombstone| tombstone.value.clone()))
+    }
+
+    fn undelete(&mut self, key: &str) -> Result<bool, StoreError> {
+        let soft_delete = self
+            .soft_delete
+            .as_ref()
+            .ok_or(StoreError::Unsupported("soft deletes"))?;
+        let index = self.router.route(key);
+        let file = self
+            .files
+            .get(index)
+            .ok_or(StoreError::BadFileHash(index))?;
+        let restored = file.lock()?.undelete(key, soft_delete.cutoff_ms())?;
+        if restored {
+            soft_delete.stats.undeletes.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(restored)
+    }
+
+    /// Shards are scanned one at a time, so unlike `snapshot` the result isn't
+    /// consistent across shards while other threads are writing.
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Blob)>, StoreError> {
+        let mut found = Vec::new();
+        for shard in &self.files {
+            let guard = shard.lock_uncounted()?;
+            found.extend(
+                guard
+                    .mem_store
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(prefix))
+                    .map(|(key, value)| (key.clone(), value.clone())),
+            );
+        }
+        Ok(found)
+    }
+
+    /// Each shard's sorted index gives its keys in order, and the shards' runs are
+    /// merged. As with `scan`, shards are read one at a time.
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        let mut runs = Vec::with_capacity(self.files.len());
+        for shard in &self.files {
+            runs.push(shard.lock_uncounted()?.mem_store.range(range.clone())?);
+        }
+        Ok(merge_sorted(runs))
+    }
+
+    /// Totals across shards, then one line per shard.
+    fn describe_stats(&self) -> Result<Vec<String>, StoreError> {
+        let stats = self.stats().map_err(store_error)?;
+        let mut lines = vec![
+            format!("shards: {}", stats.len()),
+            format!("keys: {}", stats.iter().map(|s| s.key_count).sum::<usize>()),
+            format!(
+                "bytes_on_disk: {}",
+                stats.iter().map(|s| s.bytes_on_disk).sum::<u64>()
+            ),
+            format!(
+                "queue_depth: {}",
+                stats.iter().map(|s| s.queue_depth).sum::<usize>()
+            ),
+        ];
+        for (index, s) in stats.iter().enumerate() {
+            lines.push(format!(
+                "shard {}: keys={} bytes_on_disk={} bytes_written={} queue_depth={} tombstones={} locks={} contended={}",
+                index,
+                s.key_count,
+                s.bytes_on_disk,
+                s.bytes_written,
+                s.queue_depth,
+                s.tombstones,
+                s.lock_acquisitions,
+                s.lock_contentions
+            ));
+        }
+        Ok(lines)
+    }
+
+    fn persist(&self) -> Result<(), StoreError> {
+        self.flush().map_err(store_error)
+    }
+
+    /// Breaks the get down into routing, waiting for the shard's lock, and reading
+    /// the shard's in-memory map. Read repair isn't sampled, so that the get
+    /// doesn't touch the disk.
+    fn explain_get(&self, key: &str) -> Result<Explanation, StoreError> {
+        let started = Instant::now();
+        let index = self.router.route(key);
+        let route = ExplainStep {
+            name: "route",
+            detail: format!(
+                "{}; on the local node, since stores aren't replicated",
+                self.router.describe_route(key)
+            ),
+            elapsed: started.elapsed(),
+        };
+        let shard = self
+            .files
+            .get(index)
+            .ok_or(StoreError::BadFileHash(index))?;
+
+        let lock_started = Instant::now();
+        let (mut guard, contended) = shard.lock_reporting_contention()?;
+        let lock = ExplainStep {
+            name: "lock",
+            detail: format!(
+                "shard {} lock, {}",
+                index,
+                if contended {
+                    "contended"
+                } else {
+                    "uncontended"
+                }
+            ),
+            elapsed: lock_started.elapsed(),
+        };
+
+        let read_started = Instant::now();
+        let result = guard.read(key);
+        let read_elapsed = read_started.elapsed();
+        guard.journal.record("get", key, || match &result {
+            Ok(None) => JournalOutcome::Miss,
+            result => JournalOutcome::of(result),
+        });
+        let value = result?;
+        let pending = guard.writer.queue_depth();
+        let filename = guard.filename.clone();
+        let size = match &value {
+            Some(value) => {
+                let mut serialized = Vec::new();
+                guard.serializer.write(&mut serialized, value)?;
+                format!(
+                    "{} payload bytes, {} as {:?}",
+                    value_sizes::blob_size(value),
+                    serialized.len(),
+                    guard.serializer
+                )
+            }
+            None => "no value".to_string(),
+        };
+        drop(guard);
+        let read = ExplainStep {
+            name: "read",
+            detail: format!(
+                "in-memory map of {:?} (there is no separate cache tier, and nothing is \
+                 read from disk); {}; {} writes waiting to be persisted",
+                filename.file_name().unwrap_or_default(),
+                size,
+                pending
+            ),
+            elapsed: read_elapsed,
+        };
+        Ok(Explanation {
+            value,
+            steps: vec![route, lock, read],
+            total: started.elapsed(),
+        })
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(self.share())
     }
 }
@@ -0,0 +1,166 @@
+use std::io::Write;
+use std::ops::Range;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::store::{Blob, Store, StoreError};
+
+/// Runs before a value is written; may modify the value, or reject the write by
+/// returning an error.
+pub type PreWriteHook = Arc<dyn Fn(&str, &mut Blob) -> Result<()> + Send + Sync>;
+
+/// Runs after a value has been successfully written.
+pub type PostWriteHook = Arc<dyn Fn(&str, &Blob) -> Result<()> + Send + Sync>;
+
+/// Registered hooks, run in registration order.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    pre_write: Vec<PreWriteHook>,
+    post_write: Vec<PostWriteHook>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pre_write.is_empty() && self.post_write.is_empty()
+    }
+
+    pub fn on_pre_write<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&str, &mut Blob) -> Result<()> + Send + Sync + 'static,
+    {
+        self.pre_write.push(Arc::new(hook));
+        self
+    }
+
+    pub fn on_post_write<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&str, &Blob) -> Result<()> + Send + Sync + 'static,
+    {
+        self.post_write.push(Arc::new(hook));
+        self
+    }
+
+    /// Register an external command as a pre-write hook. The command is run via
+    /// `sh -c` with the key in `KV_KEY` and the JSON-encoded value on stdin. A
+    /// non-zero exit rejects the write; non-empty stdout replaces the value.
+    pub fn pre_write_command(&mut self, command: &str) -> &mut Self {
+        let command = command.to_owned();
+        self.on_pre_write(move |key, value| {
+            let stdout = run_command(&command, key, value)?;
+            if !stdout.trim().is_empty() {
+                *value = serde_json::from_str(&stdout)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Register an external command as a post-write hook, invoked the same way
+    /// as `pre_write_command`. Its output is ignored.
+    pub fn post_write_command(&mut self, command: &str) -> &mut Self {
+        let command = command.to_owned();
+        self.on_post_write(move |key, value| run_command(&command, key, value).map(|_| ()))
+    }
+}
+
+fn run_command(command: &str, key: &str, value: &Blob) -> Result<String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("KV_KEY", key)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        serde_json::to_writer(&mut stdin, value)?;
+        stdin.flush()?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(StoreError::HookRejected(format!(
+            "{:?} exited with {}",
+            command, output.status
+        ))
+        .into());
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Surface a hook's error as-is if it's already a `StoreError`, or as a rejection
+/// if not.
+fn hook_error(err: anyhow::Error) -> StoreError {
+    err.downcast::<StoreError>()
+        .unwrap_or_else(|err| StoreError::HookRejected(format!("{:#}", err)))
+}
+
+/// Wraps any store, running the registered hooks around each write.
+pub struct HookedStore<S: Store> {
+    inner: S,
+    hooks: Arc<Hooks>,
+}
+
+impl<S: Store> HookedStore<S> {
+    pub fn new(inner: S, hooks: Hooks) -> Self {
+        Self {
+            inner,
+            hooks: Arc::new(hooks),
+        }
+    }
+}
+
+impl<S: Store> HookedStore<S> {
+    /// Run the hooks around a put made by `put`.
+    fn put_with(
+        &mut self,
+        key: &str,
+        mut value: Blob,
+        put: fn(&mut S, &str, Blob) -> Result<(), StoreError>,
+    ) -> Result<(), StoreError> {
+        for hook in &self.hooks.pre_write {
+            hook(key, &mut value).map_err(hook_error)?;
+        }
+        if self.hooks.post_write.is_empty() {
+            return put(&mut self.inner, key, value);
+        }
+        put(&mut self.inner, key, value.clone())?;
+        for hook in &self.hooks.post_write {
+            hook(key, &value).map_err(hook_error)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Store> Store for HookedStore<S> {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        self.inner.get(key)
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        self.put_with(key, value, S::put)
+    }
+
+    /// Post-write hooks run once the put is durable.
+    fn put_durable(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        self.put_with(key, value, S::put_durable)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        self.inner.delete(key)
+    }
+
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        self.inner.range(range)
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(Self {
+            inner: self.inner.spawn()?,
+            hooks: Arc::clone(&self.hooks),
+        })
+    }
+}
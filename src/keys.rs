@@ -0,0 +1,178 @@
+use std::borrow::Cow;
+use std::ops::Range;
+use std::sync::Arc;
+
+use structopt::clap::arg_enum;
+
+use crate::store::{Blob, Explanation, KeyViolation, Store, StoreError};
+
+arg_enum! {
+    /// Characters allowed in keys: anything (Any), visible ASCII without spaces
+    /// (Printable), or ASCII letters, digits and `_-.:/` (Identifier).
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum KeyCharset {
+        Any,
+        Printable,
+        Identifier,
+    }
+}
+
+impl Default for KeyCharset {
+    /// Keys were unrestricted before they could be validated.
+    fn default() -> Self {
+        Self::Any
+    }
+}
+
+impl KeyCharset {
+    fn allows(&self, c: char) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Printable => c.is_ascii_graphic(),
+            Self::Identifier => c.is_ascii_alphanumeric() || "_-.:/".contains(c),
+        }
+    }
+}
+
+/// How keys are normalized, then validated, before they reach a store.
+/// Normalization comes first, so e.g. with `trim` set, `"Key1 "` and `"Key1"`
+/// are the same key rather than one of them being rejected.
+#[derive(Clone, Debug, Default)]
+pub struct KeyPolicy {
+    /// Remove leading and trailing whitespace.
+    pub trim: bool,
+    /// Lowercase keys, so that keys differing only in case are the same key.
+    pub fold_case: bool,
+    /// Longest key allowed, in bytes after normalization.
+    pub max_len: Option<usize>,
+    pub charset: KeyCharset,
+}
+
+impl KeyPolicy {
+    /// True if every key is used as-is.
+    pub fn is_empty(&self) -> bool {
+        !self.trim && !self.fold_case && self.max_len.is_none() && self.charset == KeyCharset::Any
+    }
+
+    /// The canonical form of `key`, or the rule it breaks.
+    pub fn canonicalize<'a>(&self, key: &'a str) -> Result<Cow<'a, str>, StoreError> {
+        let mut canonical = Cow::Borrowed(key);
+        if self.trim {
+            canonical = match canonical {
+                Cow::Borrowed(key) => Cow::Borrowed(key.trim()),
+                Cow::Owned(key) => Cow::Owned(key.trim().to_string()),
+            };
+        }
+        if self.fold_case && canonical.chars().any(char::is_uppercase) {
+            canonical = Cow::Owned(canonical.to_lowercase());
+        }
+        let violation = if canonical.is_empty() {
+            Some(KeyViolation::Empty)
+        } else if let Some(max_len) = self.max_len.filter(|max| canonical.len() > *max) {
+            Some(KeyViolation::TooLong {
+                len: canonical.len(),
+                max_len,
+            })
+        } else {
+            canonical
+                .chars()
+                .find(|c| !self.charset.allows(*c))
+                .map(KeyViolation::DisallowedChar)
+        };
+        match violation {
+            Some(violation) => Err(StoreError::InvalidKey {
+                key: key.to_string(),
+                violation,
+            }),
+            None => Ok(canonical),
+        }
+    }
+}
+
+/// Wraps any store, canonicalizing every key according to a `KeyPolicy` and
+/// rejecting keys that break it with `StoreError::InvalidKey`.
+pub struct CanonicalKeyStore<S: Store> {
+    inner: S,
+    policy: Arc<KeyPolicy>,
+}
+
+impl<S: Store> CanonicalKeyStore<S> {
+    pub fn new(inner: S, policy: KeyPolicy) -> Self {
+        Self {
+            inner,
+            policy: Arc::new(policy),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: Store> Store for CanonicalKeyStore<S> {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        self.inner.get(&self.policy.canonicalize(key)?)
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let key = self.policy.canonicalize(key)?;
+        self.inner.put(&key, value)
+    }
+
+    fn put_durable(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let key = self.policy.canonicalize(key)?;
+        self.inner.put_durable(&key, value)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        let key = self.policy.canonicalize(key)?;
+        self.inner.delete(&key)
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(Self {
+            inner: self.inner.spawn()?,
+            policy: Arc::clone(&self.policy),
+        })
+    }
+
+    fn explain_get(&self, key: &str) -> Result<Explanation, StoreError> {
+        self.inner.explain_get(&self.policy.canonicalize(key)?)
+    }
+
+    fn get_deleted(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        self.inner.get_deleted(&self.policy.canonicalize(key)?)
+    }
+
+    fn undelete(&mut self, key: &str) -> Result<bool, StoreError> {
+        let key = self.policy.canonicalize(key)?;
+        self.inner.undelete(&key)
+    }
+
+    /// Only case folding applies to a prefix: the other rules are about whole keys.
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Blob)>, StoreError> {
+        if self.policy.fold_case {
+            self.inner.scan(&prefix.to_lowercase())
+        } else {
+            self.inner.scan(prefix)
+        }
+    }
+
+    /// As with `scan`, only case folding applies to the bounds.
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        if self.policy.fold_case {
+            let (start, end) = (range.start.to_lowercase(), range.end.to_lowercase());
+            self.inner.range(start.as_str()..end.as_str())
+        } else {
+            self.inner.range(range)
+        }
+    }
+
+    fn describe_stats(&self) -> Result<Vec<String>, StoreError> {
+        self.inner.describe_stats()
+    }
+
+    fn persist(&self) -> Result<(), StoreError> {
+        self.inner.persist()
+    }
+}
@@ -0,0 +1,114 @@
+use crate::chaos::{ChaosSchedule, ChaosStore};
+use crate::hooks::{HookedStore, Hooks};
+use crate::load_test::{self, all_latencies, LoadParams, Stats};
+use crate::script::ScriptableStore;
+use crate::store::{Store, StoreError};
+use crate::ttl::{ExpiryMode, TtlStore};
+use anyhow::Result;
+
+/// Write procedure used to measure the scripting layer: it does nothing but the
+/// put itself, so its cost is the engine's.
+const PASSTHROUGH_PROCEDURE: &str = "put(key, value)";
+
+/// Throughput and latency of one run of the layer-cost preset.
+#[derive(Debug)]
+pub struct LayerCost {
+    pub layer: &'static str,
+    pub ops_per_sec: f64,
+    pub p50_latency_us: u64,
+    pub p99_latency_us: u64,
+}
+
+impl LayerCost {
+    fn new(layer: &'static str, all_stats: &[Stats]) -> Result<Self> {
+        let total_ops: i64 = all_stats.iter().map(|s| s.ops.0).sum();
+        let runtime = all_stats
+            .iter()
+            .map(|s| s.runtime)
+            .max()
+            .ok_or(StoreError::NoThreadsCompleted)?;
+        let latencies = all_latencies(all_stats)?;
+        Ok(Self {
+            layer,
+            ops_per_sec: total_ops as f64 / runtime.as_secs_f64(),
+            p50_latency_us: latencies.value_at_percentile(50.0),
+            p99_latency_us: latencies.value_at_percentile(99.0),
+        })
+    }
+}
+
+/// Run the same workload against `store` once bare, then once with each layer
+/// wrapped directly around it in its cheapest configuration: fault injection
+/// with an empty schedule, TTLs that never expire, a write procedure that only
+/// puts, and no-op hooks. The difference from the bare run is that layer's cost.
+///
+/// Every run lasts `load_params.tot_time` (or does `total_ops`) and shares the
+/// store, so later runs see the keys written by earlier ones; the bare store is
+/// measured again at the end to show how much that alone moved the numbers.
+/// Returns the first bare run's stats for the usual summary, and one `LayerCost`
+/// per run.
+pub fn measure<S: Store + 'static>(
+    store: &mut S,
+    load_params: &LoadParams,
+) -> Result<(Vec<Stats>, Vec<LayerCost>)> {
+    log::info!("Measuring the bare store");
+    let bare_stats = load_test::load_test(store.spawn()?, load_params.clone())?;
+    let mut costs = vec![LayerCost::new("bare", &bare_stats)?];
+
+    let chaos = ChaosStore::new(
+        store.spawn()?,
+        ChaosSchedule { events: Vec::new() },
+        load_params.tot_time,
+    );
+    costs.push(run("chaos", chaos, load_params)?);
+
+    let ttl = TtlStore::new(store.spawn()?, None, ExpiryMode::Lazy)?;
+    costs.push(run("ttl", ttl, load_params)?);
+
+    let script =
+        ScriptableStore::new(store.spawn()?)?.with_write_procedure(PASSTHROUGH_PROCEDURE)?;
+    costs.push(run("script", script, load_params)?);
+
+    let mut hooks = Hooks::new();
+    hooks
+        .on_pre_write(|_, _| Ok(()))
+        .on_post_write(|_, _| Ok(()));
+    costs.push(run(
+        "hooks",
+        HookedStore::new(store.spawn()?, hooks),
+        load_params,
+    )?);
+
+    costs.push(run("bare (again)", store.spawn()?, load_params)?);
+    Ok((bare_stats, costs))
+}
+
+fn run<S: Store>(layer: &'static str, store: S, load_params: &LoadParams) -> Result<LayerCost> {
+    log::info!("Measuring the {} layer", layer);
+    LayerCost::new(layer, &load_test::load_test(store, load_params.clone())?)
+}
+
+/// Log each layer's throughput and latency, and its cost relative to the first
+/// (bare) run: throughput as a percentage, latency in microseconds.
+pub fn report(costs: &[LayerCost]) {
+    let Some(bare) = costs.first() else {
+        return;
+    };
+    for cost in costs {
+        let throughput_change = if bare.ops_per_sec > 0.0 {
+            (cost.ops_per_sec - bare.ops_per_sec) / bare.ops_per_sec * 100.0
+        } else {
+            0.0
+        };
+        log::info!(
+            "layer_cost {}: {:.2} ops/sec ({:+.1}%), p50={}us ({:+}us), p99={}us ({:+}us)",
+            cost.layer,
+            cost.ops_per_sec,
+            throughput_change,
+            cost.p50_latency_us,
+            cost.p50_latency_us as i64 - bare.p50_latency_us as i64,
+            cost.p99_latency_us,
+            cost.p99_latency_us as i64 - bare.p99_latency_us as i64,
+        );
+    }
+}
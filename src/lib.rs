@@ -0,0 +1,41 @@
+//! Toy key-value stores and a harness for load testing them.
+//!
+//! Every store implements `store::Store`; `load_test::load_test` drives any of them
+//! from a configurable number of threads. The wrappers in `ttl`, `script`,
+//! `hooks`, `keys` and `read_through` layer extra behaviour over any other store,
+//! and `standby` replicates one to a warm standby.
+
+pub mod advise;
+pub mod artifacts;
+pub mod build_info;
+pub mod chaos;
+pub mod compare;
+pub mod config;
+pub mod consistency;
+pub mod dump;
+pub mod export;
+pub mod file_store;
+pub mod hooks;
+pub mod keys;
+pub mod layer_cost;
+pub mod limits;
+pub mod load_test;
+pub mod lru;
+pub mod manifest;
+pub mod mem_store;
+pub mod read_through;
+pub mod redis_import;
+pub mod remote;
+pub mod repl;
+pub mod router;
+pub mod script;
+pub mod self_test;
+pub mod server;
+pub mod spans;
+pub mod standby;
+pub mod store;
+pub mod tiered;
+pub mod trace;
+pub mod ttl;
+pub mod value_sizes;
+pub mod ycsb;
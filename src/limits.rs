@@ -0,0 +1,468 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use structopt::clap::arg_enum;
+
+/// Where cgroup v2 is mounted.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Length of a cgroup CPU period, in microseconds; `cpu.max` allows a quota of
+/// `cpus` times this per period.
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// How often the internal budget measures the process's CPU time and memory.
+const GOVERNOR_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Clock ticks per second in /proc/self/stat, which Linux fixes at 100 for every
+/// architecture it runs the tool on.
+const USER_HZ: u64 = 100;
+
+arg_enum! {
+    /// How resource limits are enforced: by a cgroup v2 child group if one can be
+    /// created, and by the internal budget otherwise (Auto); only by a cgroup
+    /// (Cgroup); or only by the internal budget (Internal).
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum LimitMode {
+        Auto,
+        Cgroup,
+        Internal,
+    }
+}
+
+/// Caps on the whole process's resources, so the load generator (and the stores
+/// it runs in process) can't starve whatever else shares the machine.
+#[derive(Clone, Debug)]
+pub struct ResourceLimits {
+    /// CPUs' worth of time the process may use, e.g. 1.5.
+    pub cpus: Option<f64>,
+    pub memory_bytes: Option<u64>,
+    pub mode: LimitMode,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self.cpus.is_none() && self.memory_bytes.is_none()
+    }
+}
+
+/// The internal budget, which load-test threads consult between steps through
+/// `LoadParams::budget`. A governor thread pauses them long enough to pay back
+/// any CPU time the process used beyond `cpus` (idle time earns no credit), and
+/// stops the run once resident memory exceeds the limit, since pausing doesn't
+/// free memory.
+#[derive(Debug)]
+pub struct Budget {
+    origin: Instant,
+    /// Nanoseconds from `origin` until which the load-test threads should pause.
+    paused_until: AtomicU64,
+    over_memory: AtomicBool,
+    cpu_throttles: AtomicU64,
+    cpu_throttled_ns: AtomicU64,
+    memory_limit_hits: AtomicU64,
+    memory_peak: AtomicU64,
+}
+
+impl Budget {
+    fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+            paused_until: AtomicU64::new(0),
+            over_memory: AtomicBool::new(false),
+            cpu_throttles: AtomicU64::new(0),
+            cpu_throttled_ns: AtomicU64::new(0),
+            memory_limit_hits: AtomicU64::new(0),
+            memory_peak: AtomicU64::new(0),
+        }
+    }
+
+    /// Sleep through any CPU pause. Returns false once the memory budget has been
+    /// exceeded, after which the run should stop.
+    pub fn wait(&self) -> bool {
+        loop {
+            if self.exhausted() {
+                return false;
+            }
+            let until = Duration::from_nanos(self.paused_until.load(Ordering::Relaxed));
+            let now = self.origin.elapsed();
+            if until <= now {
+                return true;
+            }
+            std::thread::sleep((until - now).min(GOVERNOR_INTERVAL));
+        }
+    }
+
+    /// Whether the memory budget has been exceeded.
+    pub fn exhausted(&self) -> bool {
+        self.over_memory.load(Ordering::Relaxed)
+    }
+
+    /// Take a measurement, and pause or stop the load-test threads as needed.
+    /// `debt` is the CPU time used beyond the limit, carried between calls.
+    fn govern(&self, limits: &ResourceLimits, debt: &mut Duration, last: &mut Measurement) {
+        let now = Measurement::take();
+        if let (Some(cpus), Some(cpu), Some(last_cpu)) = (limits.cpus, now.cpu, last.cpu) {
+            let used = cpu.saturating_sub(last_cpu);
+            let allowed = (now.at - last.at).mul_f64(cpus);
+            *debt = (*debt + used).saturating_sub(allowed);
+            if !debt.is_zero() {
+                // Paying the debt back takes this long with the threads idle.
+                let elapsed = self.origin.elapsed();
+                let until = elapsed + debt.div_f64(cpus);
+                let previous = Duration::from_nanos(self.paused_until.load(Ordering::Relaxed));
+                let added = until.saturating_sub(previous.max(elapsed));
+                if previous < elapsed {
+                    self.cpu_throttles.fetch_add(1, Ordering::Relaxed);
+                }
+                self.cpu_throttled_ns
+                    .fetch_add(added.as_nanos() as u64, Ordering::Relaxed);
+                self.paused_until
+                    .store(until.as_nanos() as u64, Ordering::Relaxed);
+            }
+        }
+        if let Some(rss) = now.rss {
+            self.memory_peak.fetch_max(rss, Ordering::Relaxed);
+            if let Some(limit) = limits.memory_bytes {
+                if rss > limit {
+                    self.memory_limit_hits.fetch_add(1, Ordering::Relaxed);
+                    if !self.over_memory.swap(true, Ordering::Relaxed) {
+                        log::warn!(
+                            "Resident memory ({} bytes) exceeded the {}-byte limit; stopping the run",
+                            rss,
+                            limit
+                        );
+                    }
+                }
+            }
+        }
+        *last = now;
+    }
+}
+
+/// The process's CPU time and resident memory at some instant, where /proc has
+/// them.
+struct Measurement {
+    at: Instant,
+    cpu: Option<Duration>,
+    rss: Option<u64>,
+}
+
+impl Measurement {
+    fn take() -> Self {
+        Self {
+            at: Instant::now(),
+            cpu: process_cpu_time().ok(),
+            rss: resident_memory().ok(),
+        }
+    }
+}
+
+/// User plus system CPU time of every thread in the process so far.
+fn process_cpu_time() -> Result<Duration> {
+    let stat = std::fs::read_to_string("/proc/self/stat")?;
+    // The command name in parentheses may contain spaces; fields after it are
+    // numbered from 3 (state), so utime (14) and stime (15) are 11 and 12 here.
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .ok_or_else(|| anyhow!("Unexpected /proc/self/stat: {:?}", stat))?
+        .1
+        .split_whitespace()
+        .collect();
+    let ticks = |index: usize| -> Result<u64> {
+        Ok(fields
+            .get(index)
+            .ok_or_else(|| anyhow!("Short /proc/self/stat: {:?}", stat))?
+            .parse()?)
+    };
+    let ticks = ticks(11)? + ticks(12)?;
+    Ok(Duration::from_millis(ticks * 1000 / USER_HZ))
+}
+
+/// Resident set size, in bytes.
+fn resident_memory() -> Result<u64> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|rest| {
+            rest.trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .ok()
+        })
+        .ok_or_else(|| anyhow!("No VmRSS in /proc/self/status"))?;
+    Ok(kib * 1024)
+}
+
+/// How often and for how long the limits held the run back.
+#[derive(Debug, Default)]
+pub struct Throttling {
+    /// CPU periods (cgroup) or governor intervals (internal) in which the process
+    /// was held back.
+    pub cpu_throttles: u64,
+    pub cpu_throttled: Duration,
+    /// Times memory reached the limit: the cgroup's `max` events, which reclaimed
+    /// memory or throttled allocations, or the internal budget's samples over it.
+    pub memory_limit_hits: u64,
+    /// Processes the kernel killed for exceeding the cgroup's memory limit.
+    pub oom_kills: u64,
+    /// Highest memory use seen, where the kernel or governor records it.
+    pub memory_peak_bytes: Option<u64>,
+}
+
+impl Throttling {
+    pub fn log(&self) {
+        log::info!(
+            "resource_limits: cpu throttled {} times for {:.1}ms, memory limit hit {} times, {} oom kills, peak memory {}",
+            self.cpu_throttles,
+            self.cpu_throttled.as_secs_f64() * 1000.0,
+            self.memory_limit_hits,
+            self.oom_kills,
+            self.memory_peak_bytes
+                .map(|bytes| format!("{} bytes", bytes))
+                .unwrap_or_else(|| "unknown".to_string())
+        );
+        if self.cpu_throttles > 0 || self.memory_limit_hits > 0 {
+            log::warn!("Resource limits throttled this run; its results understate the store");
+        }
+    }
+}
+
+/// A child cgroup the process moved itself into, and is moved back out of on
+/// drop.
+#[derive(Debug)]
+struct Cgroup {
+    path: PathBuf,
+    parent: PathBuf,
+    /// Controllers this process enabled in the parent, to disable again.
+    enabled: Vec<&'static str>,
+}
+
+impl Cgroup {
+    /// Move the process into a new child of its cgroup, enabling the controllers
+    /// the limits need there, and set the limits.
+    fn create(limits: &ResourceLimits) -> Result<Self> {
+        if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+            bail!("cgroup v2 isn't mounted at {}", CGROUP_ROOT);
+        }
+        let membership = std::fs::read_to_string("/proc/self/cgroup")?;
+        let relative = membership
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .ok_or_else(|| anyhow!("The process isn't in a cgroup v2 group"))?;
+        let parent = Path::new(CGROUP_ROOT).join(relative.trim_start_matches('/'));
+        let available = std::fs::read_to_string(parent.join("cgroup.controllers"))?;
+        let mut needed = Vec::new();
+        if limits.cpus.is_some() {
+            needed.push("cpu");
+        }
+        if limits.memory_bytes.is_some() {
+            needed.push("memory");
+        }
+        for controller in &needed {
+            if !available.split_whitespace().any(|c| c == *controller) {
+                bail!(
+                    "The {} controller isn't delegated to {:?}",
+                    controller,
+                    parent
+                );
+            }
+        }
+
+        let path = parent.join(format!("key_value_store-{}", std::process::id()));
+        std::fs::create_dir(&path).with_context(|| format!("Creating cgroup {:?}", path))?;
+        let mut cgroup = Self {
+            path,
+            parent,
+            enabled: Vec::new(),
+        };
+        // On failure, dropping `cgroup` undoes what was done so far.
+        write(
+            &cgroup.path.join("cgroup.procs"),
+            &std::process::id().to_string(),
+        )?;
+        let subtree = std::fs::read_to_string(cgroup.parent.join("cgroup.subtree_control"))?;
+        for controller in needed {
+            if !subtree.split_whitespace().any(|c| c == controller) {
+                // Allowed now that the parent has no processes of its own.
+                write(
+                    &cgroup.parent.join("cgroup.subtree_control"),
+                    &format!("+{}", controller),
+                )?;
+                cgroup.enabled.push(controller);
+            }
+        }
+        if let Some(cpus) = limits.cpus {
+            let quota = ((cpus * CPU_PERIOD_US as f64) as u64).max(1000);
+            write(
+                &cgroup.path.join("cpu.max"),
+                &format!("{} {}", quota, CPU_PERIOD_US),
+            )?;
+        }
+        if let Some(bytes) = limits.memory_bytes {
+            write(&cgroup.path.join("memory.max"), &bytes.to_string())?;
+        }
+        Ok(cgroup)
+    }
+
+    fn throttling(&self) -> Result<Throttling> {
+        let cpu = read_keyed(&self.path.join("cpu.stat"));
+        let memory = read_keyed(&self.path.join("memory.events"));
+        let value = |stats: &[(String, u64)], key: &str| {
+            stats
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| *v)
+                .unwrap_or(0)
+        };
+        Ok(Throttling {
+            cpu_throttles: value(&cpu, "nr_throttled"),
+            cpu_throttled: Duration::from_micros(value(&cpu, "throttled_usec")),
+            memory_limit_hits: value(&memory, "max"),
+            oom_kills: value(&memory, "oom_kill"),
+            memory_peak_bytes: std::fs::read_to_string(self.path.join("memory.peak"))
+                .ok()
+                .and_then(|peak| peak.trim().parse().ok()),
+        })
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // The parent can only hold processes again once it no longer distributes
+        // the controllers this process enabled.
+        for controller in &self.enabled {
+            let _ = write(
+                &self.parent.join("cgroup.subtree_control"),
+                &format!("-{}", controller),
+            );
+        }
+        let _ = write(
+            &self.parent.join("cgroup.procs"),
+            &std::process::id().to_string(),
+        );
+        if let Err(err) = std::fs::remove_dir(&self.path) {
+            log::warn!("Could not remove cgroup {:?}: {}", self.path, err);
+        }
+    }
+}
+
+fn write(path: &Path, contents: &str) -> Result<()> {
+    std::fs::write(path, contents).with_context(|| format!("Writing {:?} to {:?}", contents, path))
+}
+
+/// `key value` lines, as in cpu.stat and memory.events; empty if unreadable.
+fn read_keyed(path: &Path) -> Vec<(String, u64)> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(' ')?;
+            Some((key.to_string(), value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+enum Enforcement {
+    Cgroup(Cgroup),
+    Internal {
+        budget: Arc<Budget>,
+        stop: Option<crossbeam_channel::Sender<()>>,
+        handle: Option<std::thread::JoinHandle<()>>,
+    },
+}
+
+/// Enforces `ResourceLimits` until dropped.
+#[derive(Debug)]
+pub struct Limiter {
+    enforcement: Enforcement,
+}
+
+impl Limiter {
+    pub fn apply(limits: &ResourceLimits) -> Result<Self> {
+        let enforcement = match limits.mode {
+            LimitMode::Cgroup => Enforcement::Cgroup(Cgroup::create(limits)?),
+            LimitMode::Internal => Self::internal(limits)?,
+            LimitMode::Auto => match Cgroup::create(limits) {
+                Ok(cgroup) => Enforcement::Cgroup(cgroup),
+                Err(err) => {
+                    log::info!(
+                        "Enforcing resource limits internally, since no cgroup could be used: {:#}",
+                        err
+                    );
+                    Self::internal(limits)?
+                }
+            },
+        };
+        if let Enforcement::Cgroup(cgroup) = &enforcement {
+            log::info!("Enforcing resource limits with cgroup {:?}", cgroup.path);
+        }
+        Ok(Self { enforcement })
+    }
+
+    fn internal(limits: &ResourceLimits) -> Result<Enforcement> {
+        // Fail now rather than run unlimited.
+        process_cpu_time().context("The internal budget needs /proc")?;
+        let budget = Arc::new(Budget::new());
+        let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+        let handle = {
+            let budget = Arc::clone(&budget);
+            let limits = limits.clone();
+            std::thread::spawn(move || {
+                let mut debt = Duration::ZERO;
+                let mut last = Measurement::take();
+                while let Err(crossbeam_channel::RecvTimeoutError::Timeout) =
+                    stopped.recv_timeout(GOVERNOR_INTERVAL)
+                {
+                    budget.govern(&limits, &mut debt, &mut last);
+                }
+            })
+        };
+        Ok(Enforcement::Internal {
+            budget,
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+
+    /// The internal budget, for `LoadParams::budget`; None under a cgroup, which
+    /// the kernel enforces.
+    pub fn budget(&self) -> Option<Arc<Budget>> {
+        match &self.enforcement {
+            Enforcement::Cgroup(_) => None,
+            Enforcement::Internal { budget, .. } => Some(Arc::clone(budget)),
+        }
+    }
+
+    pub fn throttling(&self) -> Result<Throttling> {
+        match &self.enforcement {
+            Enforcement::Cgroup(cgroup) => cgroup.throttling(),
+            Enforcement::Internal { budget, .. } => Ok(Throttling {
+                cpu_throttles: budget.cpu_throttles.load(Ordering::Relaxed),
+                cpu_throttled: Duration::from_nanos(
+                    budget.cpu_throttled_ns.load(Ordering::Relaxed),
+                ),
+                memory_limit_hits: budget.memory_limit_hits.load(Ordering::Relaxed),
+                oom_kills: 0,
+                memory_peak_bytes: Some(budget.memory_peak.load(Ordering::Relaxed))
+                    .filter(|&peak| peak > 0),
+            }),
+        }
+    }
+}
+
+impl Drop for Limiter {
+    fn drop(&mut self) {
+        if let Enforcement::Internal { stop, handle, .. } = &mut self.enforcement {
+            // Disconnecting the channel wakes the governor up.
+            stop.take();
+            if let Some(handle) = handle.take() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
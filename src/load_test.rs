@@ -1,12 +1,26 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
 use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
 use crossbeam::thread;
+use hdrhistogram::Histogram;
 use rand::prelude::*;
+use rand_distr::{LogNormal, Zipf};
+use serde::Serialize;
 use structopt::clap::arg_enum;
 
+use crate::build_info::BuildInfo;
+use crate::consistency::{History, OpKind, Operation};
+use crate::limits::Budget;
+use crate::self_test;
 use crate::store::{Blob, Store, StoreError};
+use crate::tiered::TieredStats;
+use crate::trace::{Trace, TraceEvent, TraceOp, TraceRecorder, TraceResult};
+use crate::value_sizes;
 
 arg_enum! {
     #[derive(Clone, Copy, Debug)]
@@ -30,14 +44,385 @@ const BURSTY_SHORT_WAIT_RANGE_US: Range<u64> = 10..20;
 /// Short wait range when under consistent load.
 const CONSISTENT_SHORT_WAIT_RANGE_US: Range<u64> = 1..20;
 
-/// Split for reads vs writes (higher -> more reads).
-const READ_WRITE_SPLIT: f64 = 0.10;
+arg_enum! {
+    /// When an open-loop load starts each step: after exponentially distributed
+    /// gaps (Poisson), as from many independent clients, or evenly spaced (Fixed).
+    #[derive(Clone, Copy, Debug)]
+    pub enum Arrivals {
+        Poisson,
+        Fixed,
+    }
+}
+
+/// An open-loop load: workload steps (one operation each by default) are started
+/// on a schedule at this rate across all threads, whether or not the store kept
+/// up with the previous ones.
+#[derive(Clone, Copy, Debug)]
+pub struct TargetRate {
+    pub ops_per_sec: f64,
+    pub arrivals: Arrivals,
+}
+
+/// Fraction of the target rate below which an open-loop run warns that the load
+/// generator couldn't keep up.
+const TARGET_RATE_SHORTFALL: f64 = 0.95;
+
+arg_enum! {
+    #[derive(Clone, Copy, Debug)]
+    pub enum ValueShape {
+        Str,
+        Bytes,
+        Dict,
+    }
+}
+
+/// String fields per level of generated `Dict` values.
+const DICT_FIELDS_PER_LEVEL: usize = 4;
+
+/// Distribution of written value sizes in bytes: fixed ("64"), uniform over an
+/// inclusive range ("64-4096" or "uniform:64-4096"), or log-normal around a median
+/// ("lognormal:MEDIAN:SIGMA"), which gives the long tail of real payloads.
+#[derive(Clone, Copy, Debug)]
+pub enum ValueSize {
+    Fixed(usize),
+    Uniform {
+        min: usize,
+        max: usize,
+    },
+    /// About two thirds of sizes fall within a factor of e^sigma of the median.
+    LogNormal {
+        median: f64,
+        sigma: f64,
+    },
+}
+
+impl ValueSize {
+    fn sample(&self, rng: &mut impl Rng) -> usize {
+        match *self {
+            Self::Fixed(size) => size,
+            Self::Uniform { min, max } => rng.gen_range(min..=max),
+            Self::LogNormal { median, sigma } => LogNormal::new(median.ln(), sigma)
+                .map(|distribution| distribution.sample(rng).round() as usize)
+                .unwrap_or(median as usize),
+        }
+    }
+}
+
+impl std::str::FromStr for ValueSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, spec) = s.split_once(':').unwrap_or(("", s));
+        match kind.trim() {
+            "lognormal" => {
+                let (median, sigma) = spec
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Expected lognormal:MEDIAN:SIGMA, got {:?}", s))?;
+                let (median, sigma): (f64, f64) = (median.trim().parse()?, sigma.trim().parse()?);
+                if !(median >= 1.0 && sigma >= 0.0 && sigma.is_finite()) {
+                    bail!("Log-normal value size needs median >= 1 and sigma >= 0");
+                }
+                Ok(Self::LogNormal { median, sigma })
+            }
+            "" | "uniform" => match spec.split_once('-') {
+                Some((min, max)) => {
+                    let (min, max) = (min.trim().parse()?, max.trim().parse()?);
+                    if min > max {
+                        bail!("Value size range {:?} is empty", s);
+                    }
+                    Ok(Self::Uniform { min, max })
+                }
+                None if kind.is_empty() => Ok(Self::Fixed(spec.trim().parse()?)),
+                None => bail!("Expected uniform:MIN-MAX, got {:?}", s),
+            },
+            other => bail!("Unknown value size distribution {:?}", other),
+        }
+    }
+}
+
+/// Formats as `FromStr` parses.
+impl std::fmt::Display for ValueSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fixed(size) => write!(f, "{}", size),
+            Self::Uniform { min, max } => write!(f, "{}-{}", min, max),
+            Self::LogNormal { median, sigma } => write!(f, "lognormal:{}:{}", median, sigma),
+        }
+    }
+}
+
+/// Zipf exponent used if `--key-distribution zipfian` doesn't give one; the YCSB
+/// default.
+pub const DEFAULT_ZIPF_EXPONENT: f64 = 0.99;
+
+/// How the default workload picks keys: "uniform", "zipfian[:EXPONENT]",
+/// "hotspot[:HOT_KEY_FRACTION:HOT_OP_FRACTION]" or "sequential".
+#[derive(Clone, Copy, Debug)]
+pub enum KeyDistribution {
+    Uniform,
+    /// Key ranks follow a Zipf distribution; a higher exponent is more skewed.
+    Zipfian {
+        exponent: f64,
+    },
+    /// `hot_op_fraction` of operations go to the first `hot_key_fraction` of keys.
+    Hotspot {
+        hot_key_fraction: f64,
+        hot_op_fraction: f64,
+    },
+    /// Each thread walks the key space in order, starting from its own offset.
+    Sequential,
+}
+
+impl std::str::FromStr for KeyDistribution {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(':').map(str::trim);
+        let kind = parts.next().unwrap_or_default();
+        let params = parts
+            .map(|p| p.parse::<f64>().map_err(anyhow::Error::from))
+            .collect::<Result<Vec<_>>>()?;
+        let fraction = |value: f64| {
+            if (0.0..=1.0).contains(&value) {
+                Ok(value)
+            } else {
+                Err(anyhow!(
+                    "Hotspot fractions must be between 0 and 1, got {}",
+                    value
+                ))
+            }
+        };
+        match (kind, params.as_slice()) {
+            ("uniform", []) => Ok(Self::Uniform),
+            ("sequential", []) => Ok(Self::Sequential),
+            ("zipfian", []) => Ok(Self::Zipfian {
+                exponent: DEFAULT_ZIPF_EXPONENT,
+            }),
+            ("zipfian", [exponent]) if *exponent > 0.0 => Ok(Self::Zipfian {
+                exponent: *exponent,
+            }),
+            ("hotspot", []) => Ok(Self::Hotspot {
+                hot_key_fraction: 0.2,
+                hot_op_fraction: 0.8,
+            }),
+            ("hotspot", [keys, ops]) => Ok(Self::Hotspot {
+                hot_key_fraction: fraction(*keys)?,
+                hot_op_fraction: fraction(*ops)?,
+            }),
+            _ => bail!(
+                "Expected uniform, zipfian[:EXPONENT], hotspot[:KEYS:OPS] or sequential, got {:?}",
+                s
+            ),
+        }
+    }
+}
+
+/// Formats as `FromStr` parses.
+impl std::fmt::Display for KeyDistribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Uniform => write!(f, "uniform"),
+            Self::Zipfian { exponent } => write!(f, "zipfian:{}", exponent),
+            Self::Hotspot {
+                hot_key_fraction,
+                hot_op_fraction,
+            } => write!(f, "hotspot:{}:{}", hot_key_fraction, hot_op_fraction),
+            Self::Sequential => write!(f, "sequential"),
+        }
+    }
+}
+
+/// Per-thread state for drawing keys from a `KeyDistribution`.
+struct KeySampler {
+    key_count: u64,
+    kind: SamplerKind,
+}
+
+enum SamplerKind {
+    Uniform,
+    Zipfian(Zipf<f64>),
+    Hotspot { hot_keys: u64, hot_op_fraction: f64 },
+    Sequential { next: u64 },
+}
+
+impl KeySampler {
+    fn new(load_params: &LoadParams, thread: usize) -> Self {
+        let key_count = load_params.key_count.max(1);
+        let kind = SamplerKind::new(
+            load_params.key_distribution,
+            key_count,
+            thread,
+            load_params.threads,
+        );
+        Self { key_count, kind }
+    }
+
+    /// Index of the next key, in `0..key_count`.
+    fn next(&mut self, rng: &mut impl Rng) -> u64 {
+        let key_count = self.key_count;
+        match &mut self.kind {
+            SamplerKind::Uniform => rng.gen_range(0..key_count),
+            // Ranks start at 1, for the most popular key.
+            SamplerKind::Zipfian(zipf) => (zipf.sample(rng) as u64 - 1).min(key_count - 1),
+            SamplerKind::Hotspot {
+                hot_keys,
+                hot_op_fraction,
+            } => {
+                if *hot_keys == key_count || rng.gen::<f64>() < *hot_op_fraction {
+                    rng.gen_range(0..*hot_keys)
+                } else {
+                    rng.gen_range(*hot_keys..key_count)
+                }
+            }
+            SamplerKind::Sequential { next } => {
+                let key = *next;
+                *next = (*next + 1) % key_count;
+                key
+            }
+        }
+    }
+}
+
+impl SamplerKind {
+    fn new(distribution: KeyDistribution, key_count: u64, thread: usize, threads: usize) -> Self {
+        match distribution {
+            KeyDistribution::Uniform => Self::Uniform,
+            KeyDistribution::Zipfian { exponent } => Zipf::new(key_count, exponent)
+                .map(Self::Zipfian)
+                .unwrap_or(Self::Uniform),
+            KeyDistribution::Hotspot {
+                hot_key_fraction,
+                hot_op_fraction,
+            } => Self::Hotspot {
+                hot_keys: ((key_count as f64 * hot_key_fraction) as u64).clamp(1, key_count),
+                hot_op_fraction,
+            },
+            KeyDistribution::Sequential => Self::Sequential {
+                next: key_count / threads.max(1) as u64 * thread as u64,
+            },
+        }
+    }
+}
+
+/// Threads whose throughput differs from the median thread's by more than this
+/// fraction are reported as stragglers.
+const STRAGGLER_THRESHOLD: f64 = 0.25;
+
+/// Upper bound of recorded latencies, in microseconds; slower operations are
+/// recorded as this.
+const MAX_RECORDED_LATENCY_US: u64 = 60_000_000;
+
+/// Significant figures kept by latency histograms.
+const LATENCY_PRECISION: u8 = 3;
+
+/// Significant figures of the per-interval histograms behind `Progress::time_series`,
+/// lower than `LATENCY_PRECISION` so that long runs' series stay small.
+const SAMPLE_PRECISION: u8 = 2;
+
+/// Percentiles reported by `summarize`.
+const REPORTED_PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+
+/// Operations slower than this are recorded in `Progress::slow_ops`.
+const SLOW_OP_THRESHOLD: Duration = Duration::from_millis(10);
+
+/// Number of most recent slow operations kept.
+const SLOW_OP_LOG_CAPACITY: usize = 256;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct LoadParams {
     pub threads: usize,
     pub load_pattern: LoadPattern,
     pub tot_time: Duration,
+    /// Stop once this many operations have been measured across all threads,
+    /// each doing an equal share, rather than after `tot_time`.
+    pub total_ops: Option<u64>,
+    /// Seed for the threads' random number generators; thread `i` of a run uses
+    /// `seed + i`, so runs with the same seed and parameters generate the same
+    /// keys, values and pauses. Seeded from entropy if unset.
+    pub seed: Option<u64>,
+    /// How long each thread runs the workload before measuring starts. Nothing done
+    /// during the warmup is counted, except slow operations.
+    pub warmup: Duration,
+    /// Percentage (0-100) of operations that are gets; the rest are puts. Only
+    /// applies to the default workload.
+    pub read_percent: f64,
+    /// Percentage (0-100) of the default workload's puts that are durable, i.e.
+    /// made with `Store::put_durable`. Their latencies are reported separately.
+    pub durable_percent: f64,
+    /// Percentage (0-100) of the default workload's gets that are range scans
+    /// instead, made with `Store::range`; see `RandomReadWrite`. Their latencies
+    /// are reported separately.
+    pub scan_percent: f64,
+    /// How the default workload picks keys.
+    pub key_distribution: KeyDistribution,
+    /// Number of distinct keys, named `key_prefix` followed by an index.
+    pub key_count: u64,
+    pub key_prefix: String,
+    /// Put the first this many keys, with values of the configured shape and
+    /// size, before the workload's load phase and the warmup, so that reads
+    /// don't only measure misses. Each thread puts the keys it would own under
+    /// `validate`. At most `key_count`, and `replay` doesn't apply.
+    pub preload_keys: u64,
+    pub value_shape: ValueShape,
+    pub value_size: ValueSize,
+    /// Nesting depth of `ValueShape::Dict` values; 1 is a flat dict.
+    pub value_depth: usize,
+    /// Check the store with `self_test::run` before generating load.
+    pub self_test: bool,
+    /// Check every get of a key the thread has written against the value it last
+    /// wrote (or deleted). Each thread owns the keys whose index is its position
+    /// modulo `threads`, and only draws those from `WorkloadContext::random_key_index`,
+    /// so no other thread changes them. Mismatches are counted as
+    /// `Stats::violations`, from the warmup on. `key_count` must be at least
+    /// `threads`, and `replay` doesn't apply.
+    pub validate: bool,
+    /// What each thread does; `RandomReadWrite` if unset.
+    pub workload: Option<Arc<dyn WorkloadOp>>,
+    /// Where to record progress, so it can be inspected if the run fails part-way.
+    /// A private recorder is used if unset.
+    pub progress: Option<Arc<Progress>>,
+    /// Length of the intervals over which throughput and latency are sampled, for
+    /// `Stats::samples` and `Progress::time_series`. None disables sampling.
+    pub sample_interval: Option<Duration>,
+    /// Start workload steps on a schedule at this rate instead of one after
+    /// another, measuring each step's first operation from when it was due, so
+    /// that time spent queued behind a stall counts as latency. Replaces
+    /// `load_pattern`'s pauses.
+    pub target_rate: Option<TargetRate>,
+    /// Record every operation, including the warmup's, to this trace.
+    pub record_trace: Option<Arc<TraceRecorder>>,
+    /// Record every operation, including the warmup's and the workload's load
+    /// phase, with its result and when it was invoked and returned, for
+    /// `consistency::check`. Each thread adds its operations when it finishes.
+    pub history: Option<Arc<History>>,
+    /// Pause between steps while this internal resource budget says so, and stop
+    /// early once it's exhausted.
+    pub budget: Option<Arc<Budget>>,
+    /// End the run at the first failed operation, failing it with that error,
+    /// instead of counting errors and carrying on.
+    pub abort_on_error: bool,
+    /// Replay this trace instead of generating operations: thread `i` runs the
+    /// trace's thread `i` events, each when it's due relative to the start of the
+    /// run and measured from then, until they run out. `threads` must match the
+    /// trace, and `tot_time`, `total_ops`, `warmup` and `target_rate` don't apply.
+    /// Puts write the recorded value if the trace has it, or else a string of the
+    /// recorded size.
+    pub replay: Option<Arc<Trace>>,
+    /// Under `replay`, check each operation's result against the one recorded
+    /// with `TraceRecorder::with_results`, counting mismatches as
+    /// `Stats::divergences`.
+    pub compare_results: bool,
+    /// Run these in turn against the same store instead of this run's own
+    /// workload, logging a summary of each; see `load_test`.
+    pub scenarios: Vec<Scenario>,
+}
+
+/// A named workload of a run that shifts between several.
+#[derive(Clone, Debug)]
+pub struct Scenario {
+    pub name: String,
+    /// Its `scenarios` are ignored.
+    pub params: LoadParams,
 }
 
 /// Total number of operations.
@@ -48,100 +433,2086 @@ pub struct Ops(pub i64);
 #[derive(Debug)]
 pub struct OpsPerSec(pub f64);
 
+/// Latency histograms for each kind of operation, in microseconds.
+#[derive(Clone, Debug)]
+pub struct Latencies {
+    pub get: Histogram<u64>,
+    /// Ordinary puts only.
+    pub put: Histogram<u64>,
+    pub durable_put: Histogram<u64>,
+    pub delete: Histogram<u64>,
+    pub scan: Histogram<u64>,
+}
+
+/// An empty histogram of latencies in microseconds, with the bounds and precision
+/// the load test uses.
+pub fn latency_histogram() -> Result<Histogram<u64>> {
+    Ok(Histogram::new_with_bounds(
+        1,
+        MAX_RECORDED_LATENCY_US,
+        LATENCY_PRECISION,
+    )?)
+}
+
+impl Latencies {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            get: latency_histogram()?,
+            put: latency_histogram()?,
+            durable_put: latency_histogram()?,
+            delete: latency_histogram()?,
+            scan: latency_histogram()?,
+        })
+    }
+
+    fn record(&mut self, op: &str, latency: Duration) {
+        let histogram = match op {
+            "get" => &mut self.get,
+            "put" => &mut self.put,
+            "durable_put" => &mut self.durable_put,
+            "scan" => &mut self.scan,
+            _ => &mut self.delete,
+        };
+        histogram.saturating_record(latency.as_micros() as u64);
+    }
+
+    fn iter(&self) -> [(&'static str, &Histogram<u64>); 5] {
+        [
+            ("get", &self.get),
+            ("put", &self.put),
+            ("durable_put", &self.durable_put),
+            ("delete", &self.delete),
+            ("scan", &self.scan),
+        ]
+    }
+
+    fn add(&mut self, other: &Self) -> Result<()> {
+        self.get.add(&other.get)?;
+        self.put.add(&other.put)?;
+        self.durable_put.add(&other.durable_put)?;
+        self.delete.add(&other.delete)?;
+        self.scan.add(&other.scan)?;
+        Ok(())
+    }
+}
+
+/// A count for each kind of operation.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct OpCounts {
+    pub get: i64,
+    pub put: i64,
+    pub durable_put: i64,
+    pub delete: i64,
+    pub scan: i64,
+}
+
+impl OpCounts {
+    fn count(&mut self, op: &str) {
+        match op {
+            "get" => self.get += 1,
+            "put" => self.put += 1,
+            "durable_put" => self.durable_put += 1,
+            "scan" => self.scan += 1,
+            _ => self.delete += 1,
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.get += other.get;
+        self.put += other.put;
+        self.durable_put += other.durable_put;
+        self.delete += other.delete;
+        self.scan += other.scan;
+    }
+
+    /// Operations of each kind recorded in `latencies`.
+    fn of(latencies: &Latencies) -> Self {
+        Self {
+            get: latencies.get.len() as i64,
+            put: latencies.put.len() as i64,
+            durable_put: latencies.durable_put.len() as i64,
+            delete: latencies.delete.len() as i64,
+            scan: latencies.scan.len() as i64,
+        }
+    }
+
+    fn iter(&self) -> [(&'static str, i64); 5] {
+        [
+            ("get", self.get),
+            ("put", self.put),
+            ("durable_put", self.durable_put),
+            ("delete", self.delete),
+            ("scan", self.scan),
+        ]
+    }
+}
+
 /// Performance metrics for a single thread.
 #[derive(Debug)]
 pub struct Stats {
     pub ops: Ops,
+    /// Gets, including misses, and scans.
+    pub reads: i64,
+    /// Puts and deletes.
+    pub writes: i64,
+    /// Operations that failed. The load test counts these and carries on, so that
+    /// intermittent faults show up in the results rather than ending the run.
+    pub errors: i64,
+    /// `errors` by kind of operation. All zero in `Progress::snapshot`s.
+    pub errors_by_op: OpCounts,
+    /// Gets that found a value.
+    pub hits: i64,
+    /// Gets of keys that had no value. Not errors.
+    pub misses: i64,
+    /// Gets checked against the thread's own last write under
+    /// `LoadParams::validate`, and those that didn't match it. Unlike the other
+    /// counts, these include the warmup.
+    pub validated_reads: i64,
+    pub violations: i64,
+    /// Replayed operations checked against their recorded results under
+    /// `LoadParams::compare_results`, and those whose result differed.
+    pub compared_ops: i64,
+    pub divergences: i64,
     pub runtime: Duration,
+    /// Empty in `Progress::snapshot`s; only recorded for finished threads.
+    pub latencies: Latencies,
+    /// Throughput and latency per `LoadParams::sample_interval`, oldest first.
+    pub samples: Vec<Sample>,
 }
 
 impl Stats {
     pub fn ops_per_sec(&self) -> OpsPerSec {
         OpsPerSec((self.ops.0 as f64) / self.runtime.as_secs_f64())
     }
+
+    /// Count `later`, the same thread's stats from a later run, as part of this
+    /// one.
+    fn extend(&mut self, later: Stats) -> Result<()> {
+        self.ops.0 += later.ops.0;
+        self.reads += later.reads;
+        self.writes += later.writes;
+        self.errors += later.errors;
+        self.errors_by_op.add(&later.errors_by_op);
+        self.hits += later.hits;
+        self.misses += later.misses;
+        self.validated_reads += later.validated_reads;
+        self.violations += later.violations;
+        self.compared_ops += later.compared_ops;
+        self.divergences += later.divergences;
+        self.runtime += later.runtime;
+        self.latencies.add(&later.latencies)?;
+        self.samples.extend(later.samples);
+        Ok(())
+    }
+}
+
+/// Throughput and latency over one sampling interval, for one thread or for all
+/// of them.
+#[derive(Clone, Debug, Serialize)]
+pub struct Sample {
+    /// Start of the interval, relative to when the `Progress` was created.
+    #[serde(rename = "start_sec", serialize_with = "serialize_secs")]
+    pub start: Duration,
+    /// Length of the interval. The last interval of a run is usually cut short.
+    #[serde(rename = "elapsed_sec", serialize_with = "serialize_secs")]
+    pub elapsed: Duration,
+    pub ops: i64,
+    pub p50_latency_us: u64,
+    pub p90_latency_us: u64,
+    pub p99_latency_us: u64,
+    pub max_latency_us: u64,
+}
+
+impl Sample {
+    fn new(start: Duration, elapsed: Duration, ops: i64, latencies: &Histogram<u64>) -> Self {
+        Self {
+            start,
+            elapsed,
+            ops,
+            p50_latency_us: latencies.value_at_percentile(50.0),
+            p90_latency_us: latencies.value_at_percentile(90.0),
+            p99_latency_us: latencies.value_at_percentile(99.0),
+            max_latency_us: latencies.max(),
+        }
+    }
+
+    pub fn ops_per_sec(&self) -> OpsPerSec {
+        OpsPerSec(self.ops as f64 / self.elapsed.as_secs_f64())
+    }
+}
+
+fn serialize_secs<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// Every thread's operations in one sampling interval.
+#[derive(Debug)]
+struct IntervalTotals {
+    start: Duration,
+    elapsed: Duration,
+    ops: i64,
+    latencies: Histogram<u64>,
+}
+
+/// Splits a thread's operations into sampling intervals, aligned across threads
+/// by measuring from when the `Progress` was created.
+struct Sampler {
+    interval: Duration,
+    origin: Instant,
+    index: u32,
+    ops: i64,
+    latencies: Histogram<u64>,
+    samples: Vec<Sample>,
+}
+
+impl Sampler {
+    fn new(interval: Duration, origin: Instant) -> Result<Self> {
+        Ok(Self {
+            interval,
+            origin,
+            index: Self::index_at(interval, origin, Instant::now()),
+            ops: 0,
+            latencies: Histogram::new_with_bounds(1, MAX_RECORDED_LATENCY_US, SAMPLE_PRECISION)?,
+            samples: Vec::new(),
+        })
+    }
+
+    fn index_at(interval: Duration, origin: Instant, at: Instant) -> u32 {
+        (at.duration_since(origin).as_secs_f64() / interval.as_secs_f64()) as u32
+    }
+
+    fn record(&mut self, started: Instant, latency: Duration, progress: &Progress) {
+        let index = Self::index_at(self.interval, self.origin, started);
+        // Intervals in which the thread completed nothing are kept, with no ops.
+        while self.index < index {
+            self.finish_interval(self.interval, progress);
+        }
+        self.ops += 1;
+        self.latencies.saturating_record(latency.as_micros() as u64);
+    }
+
+    fn finish_interval(&mut self, elapsed: Duration, progress: &Progress) {
+        let start = self.interval * self.index;
+        self.samples
+            .push(Sample::new(start, elapsed, self.ops, &self.latencies));
+        progress.add_interval(self.index, start, elapsed, self.ops, &self.latencies);
+        self.ops = 0;
+        self.latencies.reset();
+        self.index += 1;
+    }
+
+    /// Close the current interval at `end`, returning every sample.
+    fn finish(mut self, end: Instant, progress: &Progress) -> Vec<Sample> {
+        let index = Self::index_at(self.interval, self.origin, end);
+        while self.index < index {
+            self.finish_interval(self.interval, progress);
+        }
+        let elapsed = end.duration_since(self.origin) - self.interval * self.index;
+        self.finish_interval(elapsed, progress);
+        self.samples
+    }
+}
+
+/// A single operation that took longer than `SLOW_OP_THRESHOLD`.
+#[derive(Clone, Debug)]
+pub struct SlowOp {
+    pub thread: usize,
+    pub op: &'static str,
+    pub key: String,
+    pub latency: Duration,
+    /// Time since the load test started.
+    pub at: Duration,
+}
+
+#[derive(Debug)]
+struct ThreadProgress {
+    ops: AtomicI64,
+    reads: AtomicI64,
+    writes: AtomicI64,
+    errors: AtomicI64,
+    hits: AtomicI64,
+    misses: AtomicI64,
+    validated_reads: AtomicI64,
+    violations: AtomicI64,
+    compared_ops: AtomicI64,
+    divergences: AtomicI64,
+    /// When measurement started, after any warmup.
+    started: Mutex<Instant>,
+}
+
+/// Live view of a load test, readable while it runs or after it has failed.
+#[derive(Debug)]
+pub struct Progress {
+    started: Instant,
+    threads: Mutex<Vec<Arc<ThreadProgress>>>,
+    slow_ops: Mutex<VecDeque<SlowOp>>,
+    /// Indexed by interval; None for intervals in which no thread was running.
+    intervals: Mutex<Vec<Option<IntervalTotals>>>,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            threads: Mutex::new(Vec::new()),
+            slow_ops: Mutex::new(VecDeque::with_capacity(SLOW_OP_LOG_CAPACITY)),
+            intervals: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What `Sample::start` counts from.
+    pub fn started(&self) -> Instant {
+        self.started
+    }
+
+    fn register_thread(&self) -> Result<(usize, Arc<ThreadProgress>)> {
+        let thread = Arc::new(ThreadProgress {
+            ops: AtomicI64::new(0),
+            reads: AtomicI64::new(0),
+            writes: AtomicI64::new(0),
+            errors: AtomicI64::new(0),
+            hits: AtomicI64::new(0),
+            misses: AtomicI64::new(0),
+            validated_reads: AtomicI64::new(0),
+            violations: AtomicI64::new(0),
+            compared_ops: AtomicI64::new(0),
+            divergences: AtomicI64::new(0),
+            started: Mutex::new(Instant::now()),
+        });
+        let mut threads = self.threads.lock().map_err(|_| StoreError::LockError)?;
+        threads.push(Arc::clone(&thread));
+        Ok((threads.len() - 1, thread))
+    }
+
+    fn record_slow_op(&self, slow_op: SlowOp) {
+        if let Ok(mut slow_ops) = self.slow_ops.lock() {
+            if slow_ops.len() == SLOW_OP_LOG_CAPACITY {
+                slow_ops.pop_front();
+            }
+            slow_ops.push_back(slow_op);
+        }
+    }
+
+    fn add_interval(
+        &self,
+        index: u32,
+        start: Duration,
+        elapsed: Duration,
+        ops: i64,
+        latencies: &Histogram<u64>,
+    ) {
+        let Ok(mut intervals) = self.intervals.lock() else {
+            return;
+        };
+        let index = index as usize;
+        if intervals.len() <= index {
+            intervals.resize_with(index + 1, || None);
+        }
+        let totals = intervals[index].get_or_insert_with(|| IntervalTotals {
+            start,
+            elapsed,
+            ops: 0,
+            latencies: Histogram::new_from(latencies),
+        });
+        totals.elapsed = totals.elapsed.max(elapsed);
+        totals.ops += ops;
+        // Every sampler's histogram has the same bounds, so this can't fail.
+        let _ = totals.latencies.add(latencies);
+    }
+
+    /// Throughput and latency of all threads together, per sampling interval so
+    /// far; empty unless `LoadParams::sample_interval` was set.
+    pub fn time_series(&self) -> Result<Vec<Sample>> {
+        let intervals = self.intervals.lock().map_err(|_| StoreError::LockError)?;
+        Ok(intervals
+            .iter()
+            .flatten()
+            .map(|totals| Sample::new(totals.start, totals.elapsed, totals.ops, &totals.latencies))
+            .collect())
+    }
+
+    /// Per-thread stats so far, for threads that have started.
+    pub fn snapshot(&self) -> Result<Vec<Stats>> {
+        let threads = self.threads.lock().map_err(|_| StoreError::LockError)?;
+        threads
+            .iter()
+            .map(|thread| {
+                Ok(Stats {
+                    ops: Ops(thread.ops.load(Ordering::Relaxed)),
+                    reads: thread.reads.load(Ordering::Relaxed),
+                    writes: thread.writes.load(Ordering::Relaxed),
+                    errors: thread.errors.load(Ordering::Relaxed),
+                    errors_by_op: OpCounts::default(),
+                    hits: thread.hits.load(Ordering::Relaxed),
+                    misses: thread.misses.load(Ordering::Relaxed),
+                    validated_reads: thread.validated_reads.load(Ordering::Relaxed),
+                    violations: thread.violations.load(Ordering::Relaxed),
+                    compared_ops: thread.compared_ops.load(Ordering::Relaxed),
+                    divergences: thread.divergences.load(Ordering::Relaxed),
+                    runtime: thread
+                        .started
+                        .lock()
+                        .map_err(|_| StoreError::LockError)?
+                        .elapsed(),
+                    latencies: Latencies::new()?,
+                    samples: Vec::new(),
+                })
+            })
+            .collect()
+    }
+
+    /// The most recent slow operations, oldest first.
+    pub fn slow_ops(&self) -> Result<Vec<SlowOp>> {
+        let slow_ops = self.slow_ops.lock().map_err(|_| StoreError::LockError)?;
+        Ok(slow_ops.iter().cloned().collect())
+    }
+
+    /// Operations and errors so far, across all threads.
+    fn totals(&self) -> Result<(i64, i64)> {
+        let threads = self.threads.lock().map_err(|_| StoreError::LockError)?;
+        Ok(threads.iter().fold((0, 0), |(ops, errors), thread| {
+            (
+                ops + thread.ops.load(Ordering::Relaxed),
+                errors + thread.errors.load(Ordering::Relaxed),
+            )
+        }))
+    }
+
+    /// Log the time since spawning, the throughput over the last `interval`, and
+    /// the errors so far, across all threads, every `interval` until the returned
+    /// logger is dropped. `queue_depth` reads the store's pending asynchronous
+    /// writes, if it has any.
+    pub fn spawn_live_stats(
+        self: &Arc<Self>,
+        interval: Duration,
+        queue_depth: Option<Gauge>,
+    ) -> LiveStats {
+        let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+        let progress = Arc::clone(self);
+        let handle = std::thread::spawn(move || {
+            let started = Instant::now();
+            let mut last = (started, 0);
+            while let Err(crossbeam_channel::RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(interval)
+            {
+                let (ops, errors) = match progress.totals() {
+                    Ok(totals) => totals,
+                    Err(err) => {
+                        log::error!("live stats error: {:?}", err);
+                        continue;
+                    }
+                };
+                let now = Instant::now();
+                let ops_per_sec = (ops - last.1) as f64 / (now - last.0).as_secs_f64();
+                last = (now, ops);
+                let queue = match queue_depth.as_ref().map(|gauge| gauge()).transpose() {
+                    Ok(Some(depth)) => format!(" queue_depth={}", depth),
+                    Ok(None) => String::new(),
+                    Err(err) => format!(" queue_depth=({})", err),
+                };
+                log::info!(
+                    "live: elapsed={}s ops={} ops_per_sec={:.2} errors={}{}",
+                    (now - started).as_secs(),
+                    ops,
+                    ops_per_sec,
+                    errors,
+                    queue
+                );
+            }
+        });
+        LiveStats {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+/// Reads a store-side number shown with the live stats.
+pub type Gauge = Box<dyn Fn() -> Result<usize> + Send>;
+
+/// Logs live stats until dropped; see `Progress::spawn_live_stats`.
+pub struct LiveStats {
+    stop: Option<crossbeam_channel::Sender<()>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for LiveStats {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the thread up.
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn random_string(rng: &mut impl Rng, len: usize) -> String {
+    rng.sample_iter(rand::distributions::Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// A dict of `DICT_FIELDS_PER_LEVEL` string fields of `field_len` bytes, plus a
+/// `child` dict of the same form if `depth` is more than 1.
+fn random_dict(rng: &mut impl Rng, field_len: usize, depth: usize) -> Blob {
+    let mut dict: HashMap<String, Blob> = (0..DICT_FIELDS_PER_LEVEL)
+        .map(|i| {
+            (
+                format!("field{}", i),
+                Blob::Str(random_string(rng, field_len)),
+            )
+        })
+        .collect();
+    if depth > 1 {
+        dict.insert("child".to_string(), random_dict(rng, field_len, depth - 1));
+    }
+    Blob::Dict(dict)
+}
+
+/// A value of `load_params`' shape and size.
+pub fn random_value(rng: &mut impl Rng, load_params: &LoadParams) -> Blob {
+    let len = load_params.value_size.sample(rng);
+    match load_params.value_shape {
+        ValueShape::Str => Blob::Str(random_string(rng, len)),
+        ValueShape::Bytes => {
+            let mut bytes = vec![0; len];
+            rng.fill_bytes(&mut bytes);
+            Blob::Bytes(bytes)
+        }
+        ValueShape::Dict => {
+            // Split the size evenly between every string field.
+            let depth = load_params.value_depth.max(1);
+            let field_len = len.div_ceil(DICT_FIELDS_PER_LEVEL * depth);
+            random_dict(rng, field_len, depth)
+        }
+    }
 }
 
-fn single_tester<S: Store>(mut store: S, load_params: LoadParams) -> Result<Stats> {
-    let mut ops = 0;
-    let mut rng = rand::thread_rng();
+/// The object-safe subset of `Store` that workloads operate through.
+trait StoreOps {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError>;
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError>;
+    fn put_durable(&mut self, key: &str, value: Blob) -> Result<(), StoreError>;
+    fn delete(&mut self, key: &str) -> Result<(), StoreError>;
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError>;
+}
+
+impl<S: Store> StoreOps for S {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        Store::get(self, key)
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        Store::put(self, key, value)
+    }
 
-    let start = Instant::now();
-    while Instant::now() - start < load_params.tot_time {
-        let key = format!("Key{}", rng.gen::<u16>());
+    fn put_durable(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        Store::put_durable(self, key, value)
+    }
 
-        let read_or_write = rng.gen::<f64>() > READ_WRITE_SPLIT;
-        if read_or_write {
-            store.put(&key, Blob::Str("foo".to_string()))?;
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        Store::delete(self, key)
+    }
+
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        Store::range(self, range)
+    }
+}
+
+/// One step of a load-test workload, e.g. a single get or put, or a sequence of
+/// related operations. Each thread calls `run` in a loop until the run's time is
+/// up, pausing between calls according to the load pattern.
+///
+/// Implement this to reuse the harness's threading, timing and reporting for a
+/// custom workload, and set it as `LoadParams::workload`.
+pub trait WorkloadOp: std::fmt::Debug + Send + Sync {
+    /// Operate on the store through `ctx`, which records every operation in the
+    /// thread's stats. An error ends this step early; it has already been counted.
+    fn run(&self, ctx: &mut WorkloadContext) -> Result<(), StoreError>;
+
+    /// Populate the store before the run. Every thread calls this once, before
+    /// the warmup, and waits for the others to finish before going on; nothing it
+    /// does is measured. Does nothing by default.
+    fn load(&self, _ctx: &mut WorkloadContext) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// The default workload: a get or a put of a key drawn from
+/// `LoadParams::key_distribution`, mixed according to `LoadParams::read_percent`,
+/// writing values generated by `random_value`, durably for
+/// `LoadParams::durable_percent` of the puts.
+///
+/// `LoadParams::scan_percent` of the gets are range scans instead, of the drawn
+/// key and every key that starts with it: a scan from `Key12` reads `Key12`,
+/// `Key120` to `Key129`, `Key1200` to `Key1299` and so on, through the key space.
+#[derive(Debug, Default)]
+pub struct RandomReadWrite;
+
+impl WorkloadOp for RandomReadWrite {
+    fn run(&self, ctx: &mut WorkloadContext) -> Result<(), StoreError> {
+        let key = ctx.random_key();
+        if ctx.rng().gen::<f64>() * 100.0 < ctx.params().read_percent {
+            if ctx.rng().gen::<f64>() * 100.0 < ctx.params().scan_percent {
+                // Key names end in digits, and ':' sorts right after '9'.
+                let end = format!("{}:", key);
+                ctx.scan(key.as_str()..end.as_str())?;
+            } else {
+                ctx.get(&key)?;
+            }
         } else {
-            let _ = store.get(&key);
-        }
-        match load_params.load_pattern {
-            LoadPattern::Bursty => {
-                // wait a bit, then continue.
-                let choose_long_wait = rng.gen::<f64>() > BURSTY_PERCENT_LONG_WAITS;
-                if choose_long_wait {
-                    std::thread::sleep(Duration::from_micros(
-                        rng.gen_range(BURSTY_LONG_WAIT_RANGE_US),
-                    ));
-                } else {
-                    std::thread::sleep(Duration::from_micros(
-                        rng.gen_range(BURSTY_SHORT_WAIT_RANGE_US),
-                    ));
-                }
+            let value = ctx.random_value();
+            if ctx.rng().gen::<f64>() * 100.0 < ctx.params().durable_percent {
+                ctx.put_durable(&key, value)?;
+            } else {
+                ctx.put(&key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A load-test thread's handle on the store under test, given to `WorkloadOp`s.
+pub struct WorkloadContext<'a> {
+    store: &'a mut dyn StoreOps,
+    thread: usize,
+    /// Position of the thread in this run; see `single_tester`.
+    index: usize,
+    load_params: &'a LoadParams,
+    progress: &'a Progress,
+    thread_progress: &'a ThreadProgress,
+    rng: StdRng,
+    keys: KeySampler,
+    latencies: Latencies,
+    sampler: Option<Sampler>,
+    /// When the current step was due to start under an open-loop schedule, for
+    /// the next operation's latency.
+    due: Option<Instant>,
+    /// What this thread last wrote to each of its keys, under
+    /// `LoadParams::validate`; None for keys it deleted.
+    written: Option<HashMap<String, Option<Blob>>>,
+    /// This thread's operations, under `LoadParams::history`.
+    history: Option<Vec<Operation>>,
+    validated_reads: i64,
+    violations: i64,
+    compared_ops: i64,
+    divergences: i64,
+    /// `RunSync::aborting`.
+    aborting: &'a AtomicBool,
+    /// This thread's first error, if it's why the run is aborting.
+    abort_error: Option<String>,
+    ops: i64,
+    reads: i64,
+    writes: i64,
+    errors: i64,
+    errors_by_op: OpCounts,
+    hits: i64,
+    misses: i64,
+}
+
+impl WorkloadContext<'_> {
+    /// Index of this load-test thread.
+    pub fn thread(&self) -> usize {
+        self.thread
+    }
+
+    pub fn params(&self) -> &LoadParams {
+        self.load_params
+    }
+
+    /// This thread's random number generator, seeded from `LoadParams::seed` if set.
+    pub fn rng(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+
+    /// A key drawn from the configured key distribution.
+    pub fn random_key(&mut self) -> String {
+        let index = self.random_key_index();
+        self.key(index)
+    }
+
+    /// Index of a key drawn from the configured key distribution, in
+    /// `0..key_count`.
+    pub fn random_key_index(&mut self) -> u64 {
+        let index = self.keys.next(&mut self.rng);
+        if !self.load_params.validate {
+            return index;
+        }
+        // The nearest key this thread owns, keeping within the key space.
+        let threads = self.load_params.threads as u64;
+        let owned = index - index % threads + self.index as u64;
+        if owned < self.load_params.key_count {
+            owned
+        } else {
+            owned.saturating_sub(threads)
+        }
+    }
+
+    /// The key with index `index`; indexes past `key_count` name keys the default
+    /// workload never uses.
+    pub fn key(&self, index: u64) -> String {
+        format!("{}{}", self.load_params.key_prefix, index)
+    }
+
+    /// A value of the configured shape and size.
+    pub fn random_value(&mut self) -> Blob {
+        random_value(&mut self.rng, self.load_params)
+    }
+
+    /// Get `key`, counting a read, and a miss if it has no value.
+    pub fn get(&mut self, key: &str) -> Result<Option<Blob>, StoreError> {
+        let started = self.op_start();
+        let invoked = Instant::now();
+        let result = self.store.get(key);
+        self.trace(TraceOp::Get, key, started, None, || {
+            TraceResult::of_get(&result)
+        });
+        if self.history.is_some() {
+            let seen = result.as_ref().ok().cloned().flatten();
+            self.remember_op(key, OpKind::Get(seen), invoked, result.is_err());
+        }
+        if let Ok(value) = &result {
+            self.validate(key, value);
+        }
+        match result {
+            Ok(Some(_)) => {
+                self.hits += 1;
+                self.thread_progress
+                    .hits
+                    .store(self.hits, Ordering::Relaxed);
+            }
+            Ok(None) => {
+                self.misses += 1;
+                self.thread_progress
+                    .misses
+                    .store(self.misses, Ordering::Relaxed);
+            }
+            Err(_) => {}
+        }
+        self.reads += 1;
+        self.thread_progress
+            .reads
+            .store(self.reads, Ordering::Relaxed);
+        self.record("get", key, started, result)
+    }
+
+    /// Read every key in `range`, counting a read. Scans aren't traced or
+    /// validated, and are left out of `LoadParams::history`.
+    pub fn scan(&mut self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        let started = self.op_start();
+        let result = self.store.range(range.clone());
+        self.reads += 1;
+        self.thread_progress
+            .reads
+            .store(self.reads, Ordering::Relaxed);
+        self.record("scan", range.start, started, result)
+    }
+
+    /// Put `key`, counting a write.
+    pub fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let started = self.op_start();
+        let traced = self.traced_value(&value);
+        let expected = self.written.is_some().then(|| value.clone());
+        let kind = self.history.is_some().then(|| OpKind::Put(value.clone()));
+        let invoked = Instant::now();
+        let result = self.store.put(key, value);
+        self.trace(TraceOp::Put, key, started, traced, || {
+            TraceResult::of_write(&result)
+        });
+        if let Some(kind) = kind {
+            self.remember_op(key, kind, invoked, result.is_err());
+        }
+        self.remember(key, expected, result.is_ok());
+        self.count_write();
+        self.record("put", key, started, result)
+    }
+
+    /// Durably put `key`, counting a write. Traced as an ordinary put.
+    pub fn put_durable(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let started = self.op_start();
+        let traced = self.traced_value(&value);
+        let expected = self.written.is_some().then(|| value.clone());
+        let kind = self.history.is_some().then(|| OpKind::Put(value.clone()));
+        let invoked = Instant::now();
+        let result = self.store.put_durable(key, value);
+        self.trace(TraceOp::Put, key, started, traced, || {
+            TraceResult::of_write(&result)
+        });
+        if let Some(kind) = kind {
+            self.remember_op(key, kind, invoked, result.is_err());
+        }
+        self.remember(key, expected, result.is_ok());
+        self.count_write();
+        self.record("durable_put", key, started, result)
+    }
+
+    /// Delete `key`, counting a write.
+    pub fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        let started = self.op_start();
+        let invoked = Instant::now();
+        let result = self.store.delete(key);
+        self.trace(TraceOp::Delete, key, started, None, || {
+            TraceResult::of_write(&result)
+        });
+        if self.history.is_some() {
+            self.remember_op(key, OpKind::Delete, invoked, result.is_err());
+        }
+        self.remember(key, None, result.is_ok());
+        self.count_write();
+        self.record("delete", key, started, result)
+    }
+
+    /// Under `LoadParams::validate`, note that `key` now holds `value` (None for
+    /// a delete), or if the write failed, that its value is unknown.
+    fn remember(&mut self, key: &str, value: Option<Blob>, succeeded: bool) {
+        if let Some(written) = &mut self.written {
+            if succeeded {
+                written.insert(key.to_string(), value);
+            } else {
+                written.remove(key);
+            }
+        }
+    }
+
+    /// Whether the run is ending early: `LoadParams::budget` has run out, or a
+    /// thread failed an operation under `LoadParams::abort_on_error`.
+    fn stopping(&self) -> bool {
+        self.aborting.load(Ordering::Relaxed)
+            || self
+                .load_params
+                .budget
+                .as_ref()
+                .is_some_and(|budget| budget.exhausted())
+    }
+
+    /// Under `LoadParams::history`, record an operation on `key` that was invoked
+    /// at `invoked` and has just returned.
+    fn remember_op(&mut self, key: &str, kind: OpKind, invoked: Instant, failed: bool) {
+        let returned = Instant::now();
+        if let (Some(ops), Some(history)) = (&mut self.history, &self.load_params.history) {
+            ops.push(Operation {
+                thread: self.index,
+                key: key.to_string(),
+                kind,
+                invoked: history.offset_ns(invoked),
+                returned: history.offset_ns(returned),
+                failed,
+            });
+        }
+    }
+
+    /// Under `LoadParams::validate`, check a get of `key` against the thread's
+    /// last write to it, if any.
+    fn validate(&mut self, key: &str, value: &Option<Blob>) {
+        let Some(expected) = self.written.as_ref().and_then(|written| written.get(key)) else {
+            return;
+        };
+        if expected != value {
+            if self.violations == 0 {
+                log::error!(
+                    "First read-after-write violation on load-test thread {}: {:?} returned {}, \
+                     but the thread last wrote {}",
+                    self.thread,
+                    key,
+                    describe(value.as_ref()),
+                    describe(expected.as_ref())
+                );
+            }
+            self.violations += 1;
+            self.thread_progress
+                .violations
+                .store(self.violations, Ordering::Relaxed);
+        }
+        self.validated_reads += 1;
+        self.thread_progress
+            .validated_reads
+            .store(self.validated_reads, Ordering::Relaxed);
+    }
+
+    /// What the trace records of a put's value: its size and, if the trace has
+    /// results, the value itself. None if there's no trace.
+    fn traced_value(&self, value: &Blob) -> Option<(u64, Option<Blob>)> {
+        let recorder = self.load_params.record_trace.as_ref()?;
+        let exact = recorder.records_results().then(|| value.clone());
+        Some((value_sizes::blob_size(value), exact))
+    }
+
+    /// Record an operation that has just returned; `result` is only called if the
+    /// trace has results.
+    fn trace(
+        &self,
+        op: TraceOp,
+        key: &str,
+        started: Instant,
+        value: Option<(u64, Option<Blob>)>,
+        result: impl FnOnce() -> TraceResult,
+    ) {
+        if let Some(recorder) = &self.load_params.record_trace {
+            let (value_size, value) = value.unzip();
+            recorder.record(&TraceEvent {
+                at_us: recorder.offset_us(started),
+                thread: self.index,
+                op,
+                key: key.to_string(),
+                value_size,
+                value: value.flatten(),
+                result: recorder.records_results().then(result),
+            });
+        }
+    }
+
+    /// Under `LoadParams::compare_results`, check a replayed operation's result
+    /// against the one recorded.
+    fn compare_result(&mut self, event: &TraceEvent, replayed: TraceResult) {
+        let Some(recorded) = event.result else {
+            return;
+        };
+        if recorded != replayed {
+            if self.divergences == 0 {
+                log::error!(
+                    "First divergence on load-test thread {}: {:?} of {:?} returned {}, \
+                     but {} when it was recorded",
+                    self.thread,
+                    event.op,
+                    event.key,
+                    replayed,
+                    recorded
+                );
+            }
+            self.divergences += 1;
+            self.thread_progress
+                .divergences
+                .store(self.divergences, Ordering::Relaxed);
+        }
+        self.compared_ops += 1;
+        self.thread_progress
+            .compared_ops
+            .store(self.compared_ops, Ordering::Relaxed);
+    }
+
+    /// When the operation about to be run started: when its step was due, if it's
+    /// the step's first operation under an open-loop schedule, or else now.
+    fn op_start(&mut self) -> Instant {
+        let now = Instant::now();
+        self.due.take().map(|due| due.min(now)).unwrap_or(now)
+    }
+
+    /// Discard everything counted so far (i.e. during the warmup), and start
+    /// sampling intervals if configured.
+    fn start_measuring(&mut self) -> Result<()> {
+        self.ops = 0;
+        self.reads = 0;
+        self.writes = 0;
+        self.errors = 0;
+        self.errors_by_op = OpCounts::default();
+        self.hits = 0;
+        self.misses = 0;
+        self.latencies = Latencies::new()?;
+        self.sampler = self
+            .load_params
+            .sample_interval
+            .map(|interval| Sampler::new(interval, self.progress.started))
+            .transpose()?;
+        let progress = self.thread_progress;
+        for counter in [
+            &progress.ops,
+            &progress.reads,
+            &progress.writes,
+            &progress.errors,
+            &progress.hits,
+            &progress.misses,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        *progress.started.lock().map_err(|_| StoreError::LockError)? = Instant::now();
+        Ok(())
+    }
+
+    fn count_write(&mut self) {
+        self.writes += 1;
+        self.thread_progress
+            .writes
+            .store(self.writes, Ordering::Relaxed);
+    }
+
+    fn record<T>(
+        &mut self,
+        op: &'static str,
+        key: &str,
+        started: Instant,
+        result: Result<T, StoreError>,
+    ) -> Result<T, StoreError> {
+        let latency = started.elapsed();
+        self.latencies.record(op, latency);
+        if let Some(sampler) = &mut self.sampler {
+            sampler.record(started, latency, self.progress);
+        }
+        self.ops += 1;
+        self.thread_progress.ops.store(self.ops, Ordering::Relaxed);
+        if let Err(err) = &result {
+            if self.errors == 0 {
+                log::warn!("First error on load-test thread {}: {}", self.thread, err);
+            }
+            if self.load_params.abort_on_error && !self.aborting.swap(true, Ordering::Relaxed) {
+                self.abort_error = Some(format!("{} of {:?} failed: {}", op, key, err));
             }
-            LoadPattern::Consistent => {
+            self.errors += 1;
+            self.errors_by_op.count(op);
+            self.thread_progress
+                .errors
+                .store(self.errors, Ordering::Relaxed);
+        }
+        if latency > SLOW_OP_THRESHOLD {
+            self.progress.record_slow_op(SlowOp {
+                thread: self.thread,
+                op,
+                key: key.to_string(),
+                latency,
+                at: started - self.progress.started,
+            });
+        }
+        result
+    }
+}
+
+/// A value for a log line; values can be too large to print.
+fn describe(value: Option<&Blob>) -> String {
+    match value {
+        Some(value) => format!("a {}-byte value", value_sizes::blob_size(value)),
+        None => "no value".to_string(),
+    }
+}
+
+fn pause(rng: &mut impl Rng, load_pattern: LoadPattern) {
+    match load_pattern {
+        LoadPattern::Bursty => {
+            // wait a bit, then continue.
+            let choose_long_wait = rng.gen::<f64>() > BURSTY_PERCENT_LONG_WAITS;
+            if choose_long_wait {
+                std::thread::sleep(Duration::from_micros(
+                    rng.gen_range(BURSTY_LONG_WAIT_RANGE_US),
+                ));
+            } else {
                 std::thread::sleep(Duration::from_micros(
-                    rng.gen_range(CONSISTENT_SHORT_WAIT_RANGE_US),
+                    rng.gen_range(BURSTY_SHORT_WAIT_RANGE_US),
                 ));
             }
-            LoadPattern::Unthrottled => {}
         }
-        ops += 1;
+        LoadPattern::Consistent => {
+            std::thread::sleep(Duration::from_micros(
+                rng.gen_range(CONSISTENT_SHORT_WAIT_RANGE_US),
+            ));
+        }
+        LoadPattern::Unthrottled => {}
+    }
+}
+
+/// One thread's share of an open-loop load: when its next step is due.
+struct Schedule {
+    next: Instant,
+    /// Mean time between steps.
+    interval: Duration,
+    arrivals: Arrivals,
+}
+
+impl Schedule {
+    fn new(target_rate: TargetRate, threads: usize, rng: &mut impl Rng) -> Result<Self> {
+        if !(target_rate.ops_per_sec > 0.0 && target_rate.ops_per_sec.is_finite()) {
+            bail!(
+                "The target rate must be positive, not {}",
+                target_rate.ops_per_sec
+            );
+        }
+        let interval = Duration::try_from_secs_f64(threads as f64 / target_rate.ops_per_sec)
+            .map_err(|err| anyhow!("A target rate of {}: {}", target_rate.ops_per_sec, err))?;
+        Ok(Self {
+            // Offset each thread's first step so that fixed arrivals from several
+            // threads don't line up.
+            next: Instant::now() + interval.mul_f64(rng.gen()),
+            interval,
+            arrivals: target_rate.arrivals,
+        })
+    }
+
+    /// Wait until the next step is due, and return when that was. If the thread
+    /// has fallen behind, it returns at once, so the schedule is never stretched
+    /// to fit the store.
+    fn wait(&mut self, rng: &mut impl Rng) -> Instant {
+        let due = self.next;
+        let now = Instant::now();
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+        self.next += match self.arrivals {
+            Arrivals::Poisson => self.interval.mul_f64(-(1.0 - rng.gen::<f64>()).ln()),
+            Arrivals::Fixed => self.interval,
+        };
+        due
+    }
+}
+
+/// Run one step of `workload`, after a pause or when it's due.
+fn step(ctx: &mut WorkloadContext, workload: &dyn WorkloadOp, schedule: &mut Option<Schedule>) {
+    if let Some(budget) = &ctx.load_params.budget {
+        budget.wait();
+    }
+    if let Some(schedule) = schedule {
+        ctx.due = Some(schedule.wait(&mut ctx.rng));
+    }
+    // Errors have already been counted by the context.
+    let _ = workload.run(ctx);
+    ctx.due = None;
+    if schedule.is_none() {
+        pause(&mut ctx.rng, ctx.load_params.load_pattern);
     }
+}
+
+/// Thread `index`'s share of `total_ops`; the remainder goes to the first threads.
+fn ops_share(total_ops: u64, threads: usize, index: usize) -> u64 {
+    let threads = threads as u64;
+    total_ops / threads + u64::from((index as u64) < total_ops % threads)
+}
+
+/// Run `events` at their recorded offsets from `origin`.
+fn replay(ctx: &mut WorkloadContext, events: &[TraceEvent], origin: Instant) {
+    for event in events {
+        if let Some(budget) = &ctx.load_params.budget {
+            budget.wait();
+        }
+        if ctx.stopping() {
+            break;
+        }
+        let due = origin + Duration::from_micros(event.at_us);
+        let now = Instant::now();
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+        ctx.due = Some(due);
+        // Errors have already been counted by the context.
+        let result = match event.op {
+            TraceOp::Get => TraceResult::of_get(&ctx.get(&event.key)),
+            TraceOp::Put => {
+                let value = match &event.value {
+                    Some(value) => value.clone(),
+                    None => {
+                        let size = event.value_size.unwrap_or(0) as usize;
+                        Blob::Str(random_string(&mut ctx.rng, size))
+                    }
+                };
+                TraceResult::of_write(&ctx.put(&event.key, value))
+            }
+            TraceOp::Delete => TraceResult::of_write(&ctx.delete(&event.key)),
+        };
+        if ctx.load_params.compare_results {
+            ctx.compare_result(event, result);
+        }
+        ctx.due = None;
+    }
+}
+
+/// What a run's threads coordinate through.
+struct RunSync {
+    /// Passed once every thread has run the workload's load phase.
+    loaded: Barrier,
+    /// Set by the first thread to fail an operation under
+    /// `LoadParams::abort_on_error`, stopping the others.
+    aborting: AtomicBool,
+}
+
+/// Put this thread's share of `LoadParams::preload_keys`: every `threads`th key,
+/// starting from its position. Like the workload's load phase, it carries on past
+/// failures, which show as misses later.
+fn preload(ctx: &mut WorkloadContext) {
+    let load_params = ctx.load_params;
+    let threads = load_params.threads as u64;
+    let mut index = ctx.index as u64;
+    while index < load_params.preload_keys && !ctx.stopping() {
+        let key = ctx.key(index);
+        let value = ctx.random_value();
+        let _ = ctx.put(&key, value);
+        index += threads;
+    }
+}
+
+/// `index` is the thread's position in this run, which unlike its index in
+/// `progress` is the same from run to run. `origin` is when the run started.
+fn single_tester<S: Store>(
+    mut store: S,
+    index: usize,
+    origin: Instant,
+    load_params: &LoadParams,
+    workload: &dyn WorkloadOp,
+    progress: &Progress,
+    sync: &RunSync,
+) -> Result<Stats> {
+    let setup = progress
+        .register_thread()
+        .and_then(|registered| Ok((registered, Latencies::new()?)));
+    let ((thread, thread_progress), latencies) = match setup {
+        Ok(setup) => setup,
+        Err(err) => {
+            // Stop the others, but still pass the barrier they are heading for.
+            sync.aborting.store(true, Ordering::Relaxed);
+            if load_params.replay.is_none() {
+                sync.loaded.wait();
+            }
+            return Err(err);
+        }
+    };
+    let mut ctx = WorkloadContext {
+        store: &mut store,
+        thread,
+        index,
+        load_params,
+        progress,
+        thread_progress: &thread_progress,
+        rng: match load_params.seed {
+            Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(index as u64)),
+            None => StdRng::from_entropy(),
+        },
+        keys: KeySampler::new(load_params, index),
+        latencies,
+        sampler: None,
+        due: None,
+        written: load_params.validate.then(HashMap::new),
+        history: load_params.history.as_ref().map(|_| Vec::new()),
+        validated_reads: 0,
+        violations: 0,
+        compared_ops: 0,
+        divergences: 0,
+        aborting: &sync.aborting,
+        abort_error: None,
+        ops: 0,
+        reads: 0,
+        writes: 0,
+        errors: 0,
+        errors_by_op: OpCounts::default(),
+        hits: 0,
+        misses: 0,
+    };
+
+    let start = if let Some(trace) = &load_params.replay {
+        ctx.start_measuring()?;
+        replay(&mut ctx, trace.thread_events(index), origin);
+        origin
+    } else {
+        preload(&mut ctx);
+        // Errors have already been counted by the context, and are discarded with
+        // everything else it did.
+        let _ = workload.load(&mut ctx);
+        if sync.loaded.wait().is_leader() && load_params.preload_keys > 0 {
+            log::info!(
+                "Preloaded {} keys; loading took {:.2?}.",
+                load_params.preload_keys,
+                origin.elapsed()
+            );
+        }
+        let mut schedule = load_params
+            .target_rate
+            .map(|target_rate| Schedule::new(target_rate, load_params.threads, &mut ctx.rng))
+            .transpose()?;
+        let warmup_start = Instant::now();
+        while warmup_start.elapsed() < load_params.warmup && !ctx.stopping() {
+            step(&mut ctx, workload, &mut schedule);
+        }
+        ctx.start_measuring()?;
+
+        let share = load_params
+            .total_ops
+            .map(|total_ops| ops_share(total_ops, load_params.threads, index));
+        let start = Instant::now();
+        while !ctx.stopping()
+            && match share {
+                Some(share) => (ctx.ops as u64) < share,
+                None => Instant::now() - start < load_params.tot_time,
+            }
+        {
+            step(&mut ctx, workload, &mut schedule);
+        }
+        start
+    };
     let end = Instant::now();
+    if let (Some(ops), Some(history)) = (ctx.history.take(), &load_params.history) {
+        history.extend(ops)?;
+    }
+    if let Some(err) = ctx.abort_error.take() {
+        bail!("Aborting the run on load-test thread {}: {}", index, err);
+    }
+    let samples = ctx
+        .sampler
+        .take()
+        .map(|sampler| sampler.finish(end, progress))
+        .unwrap_or_default();
     Ok(Stats {
-        ops: Ops(ops),
+        ops: Ops(ctx.ops),
+        reads: ctx.reads,
+        writes: ctx.writes,
+        errors: ctx.errors,
+        errors_by_op: ctx.errors_by_op,
+        hits: ctx.hits,
+        misses: ctx.misses,
+        validated_reads: ctx.validated_reads,
+        violations: ctx.violations,
+        compared_ops: ctx.compared_ops,
+        divergences: ctx.divergences,
         runtime: end - start,
+        latencies: ctx.latencies,
+        samples,
     })
 }
 
+/// Run `load_params`' workload from `load_params.threads` threads, each with its
+/// own spawned handle of `store`, and return each thread's stats.
+///
+/// With `load_params.scenarios`, each scenario runs in turn instead, against
+/// handles of the same store, and its summary is logged when it finishes. Each
+/// thread's stats then cover every scenario it ran in, as if they were one run.
 pub fn load_test<S: Store>(mut store: S, load_params: LoadParams) -> Result<Vec<Stats>> {
-    let results = thread::scope(|s| {
+    if load_params.scenarios.is_empty() {
+        return run_workload(store, load_params);
+    }
+    let count = load_params.scenarios.len();
+    let mut all_stats: Vec<Stats> = Vec::new();
+    for (index, scenario) in load_params.scenarios.into_iter().enumerate() {
+        log::info!(
+            "Starting scenario {} ({} of {}).",
+            scenario.name,
+            index + 1,
+            count
+        );
+        let scenario_stats = run_workload(
+            store.spawn()?,
+            LoadParams {
+                scenarios: Vec::new(),
+                ..scenario.params
+            },
+        )
+        .with_context(|| format!("Scenario {}", scenario.name))?;
+        log::info!("Scenario {} summary:", scenario.name);
+        summarize(&scenario_stats)?;
+        for (thread, stats) in scenario_stats.into_iter().enumerate() {
+            match all_stats.get_mut(thread) {
+                Some(total) => total.extend(stats)?,
+                None => all_stats.push(stats),
+            }
+        }
+    }
+    log::info!(
+        "Finished all {} scenarios; the summary covers them all.",
+        count
+    );
+    Ok(all_stats)
+}
+
+fn run_workload<S: Store>(mut store: S, load_params: LoadParams) -> Result<Vec<Stats>> {
+    if let Some(trace) = &load_params.replay {
+        if trace.threads() != load_params.threads {
+            bail!(
+                "The trace was recorded with {} threads, but {} were requested",
+                trace.threads(),
+                load_params.threads
+            );
+        }
+    }
+    if load_params.validate {
+        if load_params.replay.is_some() {
+            bail!("Replayed traces can't be validated, since their threads share keys");
+        }
+        if load_params.key_count < load_params.threads as u64 {
+            bail!("Validation needs at least one key per thread");
+        }
+    }
+    if load_params.preload_keys > load_params.key_count {
+        bail!(
+            "Can't preload {} keys out of a key space of {}",
+            load_params.preload_keys,
+            load_params.key_count
+        );
+    }
+    if load_params.preload_keys > 0 && load_params.replay.is_some() {
+        bail!("Replayed traces start from the recorded store; they can't be preloaded");
+    }
+    if load_params.self_test {
+        self_test::run(&mut store)?;
+    }
+    let progress = load_params.progress.clone().unwrap_or_default();
+    let workload = load_params
+        .workload
+        .clone()
+        .unwrap_or_else(|| Arc::new(RandomReadWrite));
+    let load_params = &load_params;
+    let progress = progress.as_ref();
+    let workload = workload.as_ref();
+    let origin = Instant::now();
+    let sync = &RunSync {
+        loaded: Barrier::new(load_params.threads),
+        aborting: AtomicBool::new(false),
+    };
+    // Spawned up front: a thread left out by a failed spawn would leave the others
+    // waiting at `RunSync::loaded` forever.
+    let thread_stores = (0..load_params.threads)
+        .map(|_| store.spawn())
+        .collect::<Result<Vec<_>, _>>()?;
+    thread::scope(|s| {
         let mut handles = Vec::with_capacity(load_params.threads);
-        for _ in 0..load_params.threads {
-            let thread_store = store.spawn().expect("Could not spawn store.");
-            handles.push(s.spawn(|_| single_tester(thread_store, load_params)));
+        for (index, thread_store) in thread_stores.into_iter().enumerate() {
+            handles.push(s.spawn(move |_| {
+                single_tester(
+                    thread_store,
+                    index,
+                    origin,
+                    load_params,
+                    workload,
+                    progress,
+                    sync,
+                )
+            }));
         }
         let mut all_stats = Vec::with_capacity(load_params.threads);
         for h in handles {
-            let thread_result = h.join().expect("thread join");
-            all_stats.push(thread_result.expect("test results"));
+            let thread_result = h.join().map_err(|_| anyhow!("Load-test thread panicked"))?;
+            all_stats.push(thread_result?);
         }
-        all_stats
+        if let Some(target_rate) = load_params.target_rate {
+            warn_if_behind(&all_stats, target_rate);
+        }
+        Ok(all_stats)
     })
-    .unwrap();
-    Ok(results)
+    .map_err(|_| anyhow!("Load-test thread panicked"))?
 }
 
-pub fn summarize(all_stats: &[Stats]) -> Result<()> {
-    let total_ops: i64 = all_stats.iter().map(|s| s.ops.0).sum();
-    let total_runtime = all_stats
-        .iter()
-        .map(|s| s.runtime)
-        .max()
-        .ok_or(StoreError::NoThreadsCompleted)?;
+/// Warn if an open-loop run completed noticeably fewer operations than were
+/// scheduled. Each thread catches up after a stall, so this means the threads
+/// were saturated: the store can't sustain the target, or there are too few
+/// threads to cover its latency.
+fn warn_if_behind(all_stats: &[Stats], target_rate: TargetRate) {
+    let achieved: f64 = all_stats.iter().map(|s| s.ops_per_sec().0).sum();
+    if achieved < target_rate.ops_per_sec * TARGET_RATE_SHORTFALL {
+        log::warn!(
+            "Achieved {:.2} ops/sec of the {:.2} targeted; the store is overloaded, \
+             or more threads are needed to cover its latency",
+            achieved,
+            target_rate.ops_per_sec
+        );
+    }
+}
+
+/// How evenly throughput was spread across threads.
+#[derive(Debug, Serialize)]
+pub struct Fairness {
+    pub min_ops_per_sec: f64,
+    pub max_ops_per_sec: f64,
+    pub median_ops_per_sec: f64,
+    pub stddev_ops_per_sec: f64,
+    /// Jain's fairness index: 1.0 when every thread did the same work, down to
+    /// 1/threads when one thread did all of it.
+    pub index: f64,
+    /// Indices of threads more than `STRAGGLER_THRESHOLD` from the median.
+    pub stragglers: Vec<usize>,
+}
+
+impl Fairness {
+    pub fn new(all_stats: &[Stats]) -> Result<Self> {
+        let rates: Vec<f64> = all_stats.iter().map(|s| s.ops_per_sec().0).collect();
+        if rates.is_empty() {
+            return Err(StoreError::NoThreadsCompleted.into());
+        }
+        let n = rates.len() as f64;
+        let mut sorted = rates.clone();
+        sorted.sort_by(f64::total_cmp);
+        let median = if sorted.len().is_multiple_of(2) {
+            (sorted[sorted.len() / 2 - 1] + sorted[sorted.len() / 2]) / 2.0
+        } else {
+            sorted[sorted.len() / 2]
+        };
+        let mean = rates.iter().sum::<f64>() / n;
+        let variance = rates.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / n;
+        let sum_squares: f64 = rates.iter().map(|r| r * r).sum();
+        let index = if sum_squares > 0.0 {
+            rates.iter().sum::<f64>().powi(2) / (n * sum_squares)
+        } else {
+            1.0
+        };
+        let stragglers = rates
+            .iter()
+            .enumerate()
+            .filter(|(_, rate)| {
+                median > 0.0 && ((*rate - median) / median).abs() > STRAGGLER_THRESHOLD
+            })
+            .map(|(thread, _)| thread)
+            .collect();
+        Ok(Self {
+            min_ops_per_sec: sorted[0],
+            max_ops_per_sec: sorted[sorted.len() - 1],
+            median_ops_per_sec: median,
+            stddev_ops_per_sec: variance.sqrt(),
+            index,
+            stragglers,
+        })
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum OutputFormat {
+        Text,
+        Json,
+        Csv,
+    }
+}
+
+/// Percentiles of one operation's latency histogram, in microseconds.
+#[derive(Debug, Serialize)]
+pub struct LatencySummary {
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    #[serde(rename = "p99.9")]
+    pub p99_9: u64,
+    pub max: u64,
+}
+
+impl LatencySummary {
+    /// None if no operations of this kind were recorded.
+    pub fn new(histogram: &Histogram<u64>) -> Option<Self> {
+        if histogram.is_empty() {
+            return None;
+        }
+        let [p50, p90, p99, p99_9] = REPORTED_PERCENTILES.map(|p| histogram.value_at_percentile(p));
+        Some(Self {
+            p50,
+            p90,
+            p99,
+            p99_9,
+            max: histogram.max(),
+        })
+    }
+}
+
+/// One kind of operation's share of a `StatsSummary`.
+#[derive(Debug, Serialize)]
+pub struct OpSummary {
+    pub op: &'static str,
+    pub ops: i64,
+    /// This operation's share of the thread's (or run's) ops/sec.
+    pub ops_per_sec: f64,
+    pub errors: i64,
+    pub error_rate: f64,
+    /// Only counted for gets.
+    pub hits: Option<i64>,
+    pub misses: Option<i64>,
+    pub latency_us: LatencySummary,
+}
+
+/// Counts, throughput and latency percentiles for one thread, or for all of them.
+#[derive(Debug, Serialize)]
+pub struct StatsSummary {
+    pub ops: i64,
+    pub reads: i64,
+    pub writes: i64,
+    pub errors: i64,
+    /// Fraction of operations that failed.
+    pub error_rate: f64,
+    pub errors_by_op: OpCounts,
+    /// Operations of each kind, the denominators of their error rates.
+    pub ops_by_op: OpCounts,
+    pub hits: i64,
+    pub misses: i64,
+    /// Fraction of successful gets that found a value.
+    pub hit_rate: f64,
+    pub validated_reads: i64,
+    pub violations: i64,
+    pub compared_ops: i64,
+    pub divergences: i64,
+    pub runtime_sec: f64,
+    pub ops_per_sec: f64,
+    /// A row for each kind of operation that ran.
+    pub by_op: Vec<OpSummary>,
+    pub get_latency_us: Option<LatencySummary>,
+    pub put_latency_us: Option<LatencySummary>,
+    pub durable_put_latency_us: Option<LatencySummary>,
+    pub delete_latency_us: Option<LatencySummary>,
+    pub scan_latency_us: Option<LatencySummary>,
+}
+
+impl StatsSummary {
+    fn new(stats: &Stats) -> Self {
+        let ops_by_op = OpCounts::of(&stats.latencies);
+        let ops_per_sec = stats.ops_per_sec().0;
+        let by_op = ops_by_op
+            .iter()
+            .into_iter()
+            .zip(stats.errors_by_op.iter())
+            .zip(stats.latencies.iter())
+            .filter_map(|(((op, ops), (_, errors)), (_, latencies))| {
+                Some(OpSummary {
+                    op,
+                    ops,
+                    ops_per_sec: ops_per_sec * rate(ops, stats.ops.0),
+                    errors,
+                    error_rate: rate(errors, ops),
+                    hits: (op == "get").then_some(stats.hits),
+                    misses: (op == "get").then_some(stats.misses),
+                    latency_us: LatencySummary::new(latencies)?,
+                })
+            })
+            .collect();
+        Self {
+            ops: stats.ops.0,
+            reads: stats.reads,
+            writes: stats.writes,
+            errors: stats.errors,
+            error_rate: rate(stats.errors, stats.ops.0),
+            errors_by_op: stats.errors_by_op,
+            ops_by_op,
+            hits: stats.hits,
+            misses: stats.misses,
+            hit_rate: rate(stats.hits, stats.hits + stats.misses),
+            validated_reads: stats.validated_reads,
+            violations: stats.violations,
+            compared_ops: stats.compared_ops,
+            divergences: stats.divergences,
+            runtime_sec: stats.runtime.as_secs_f64(),
+            ops_per_sec,
+            by_op,
+            get_latency_us: LatencySummary::new(&stats.latencies.get),
+            put_latency_us: LatencySummary::new(&stats.latencies.put),
+            durable_put_latency_us: LatencySummary::new(&stats.latencies.durable_put),
+            delete_latency_us: LatencySummary::new(&stats.latencies.delete),
+            scan_latency_us: LatencySummary::new(&stats.latencies.scan),
+        }
+    }
+}
+
+/// `count` as a fraction of `of`, or 0 if there were none.
+fn rate(count: i64, of: i64) -> f64 {
+    if of > 0 {
+        count as f64 / of as f64
+    } else {
+        0.0
+    }
+}
+
+/// Everything `summarize` reports about a run, in a form that can be written
+/// out as JSON or CSV for scripts and dashboards.
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    /// The binary that ran the load test.
+    pub build: BuildInfo,
+    /// All threads together; the runtime is the longest thread's.
+    pub total: StatsSummary,
+    /// Mean of the threads' ops/sec.
+    pub average_ops_per_sec: f64,
+    pub fairness: Fairness,
+    pub threads: Vec<StatsSummary>,
+    /// Throughput and latency of all threads per interval, if sampled; see
+    /// `Progress::time_series`.
+    pub time_series: Vec<Sample>,
+    /// How the tiered cache served the run's gets, if there was one.
+    pub cache: Option<CacheSummary>,
+}
+
+/// A `TieredStore`'s counts over the run, shared by all of its handles.
+#[derive(Debug, Serialize)]
+pub struct CacheSummary {
+    pub hits: u64,
+    pub misses: u64,
+    /// Fraction of gets the cache served.
+    pub hit_rate: f64,
+    /// Of the misses, those the backing store didn't have either.
+    pub backing_misses: u64,
+    pub write_backs: u64,
+}
+
+impl CacheSummary {
+    pub fn new(stats: &TieredStats) -> Self {
+        let hits = stats.cache_hits.load(Ordering::Relaxed);
+        let misses = stats.cache_misses.load(Ordering::Relaxed);
+        Self {
+            hits,
+            misses,
+            hit_rate: if hits + misses > 0 {
+                hits as f64 / (hits + misses) as f64
+            } else {
+                0.0
+            },
+            backing_misses: stats.backing_misses.load(Ordering::Relaxed),
+            write_backs: stats.write_backs.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Summary {
+    pub fn new(all_stats: &[Stats]) -> Result<Self> {
+        let mut total = Stats {
+            ops: Ops(all_stats.iter().map(|s| s.ops.0).sum()),
+            reads: all_stats.iter().map(|s| s.reads).sum(),
+            writes: all_stats.iter().map(|s| s.writes).sum(),
+            errors: all_stats.iter().map(|s| s.errors).sum(),
+            errors_by_op: OpCounts::default(),
+            hits: all_stats.iter().map(|s| s.hits).sum(),
+            misses: all_stats.iter().map(|s| s.misses).sum(),
+            validated_reads: all_stats.iter().map(|s| s.validated_reads).sum(),
+            violations: all_stats.iter().map(|s| s.violations).sum(),
+            compared_ops: all_stats.iter().map(|s| s.compared_ops).sum(),
+            divergences: all_stats.iter().map(|s| s.divergences).sum(),
+            runtime: all_stats
+                .iter()
+                .map(|s| s.runtime)
+                .max()
+                .ok_or(StoreError::NoThreadsCompleted)?,
+            latencies: Latencies::new()?,
+            samples: Vec::new(),
+        };
+        for s in all_stats {
+            total.latencies.add(&s.latencies)?;
+            total.errors_by_op.add(&s.errors_by_op);
+        }
+        let sum_ops_per_sec: f64 = all_stats.iter().map(|s| s.ops_per_sec().0).sum();
+        Ok(Self {
+            build: BuildInfo::current(),
+            total: StatsSummary::new(&total),
+            average_ops_per_sec: sum_ops_per_sec / all_stats.len() as f64,
+            fairness: Fairness::new(all_stats)?,
+            threads: all_stats.iter().map(StatsSummary::new).collect(),
+            time_series: Vec::new(),
+            cache: None,
+        })
+    }
 
-    let total_ops_per_sec = total_ops as f64 / total_runtime.as_secs_f64();
-    let sum_ops_per_sec: f64 = all_stats.iter().map(|s| s.ops_per_sec().0).sum();
-    let average_ops_per_sec = sum_ops_per_sec / all_stats.len() as f64;
+    pub fn with_cache(self, cache: Option<CacheSummary>) -> Self {
+        Self { cache, ..self }
+    }
+
+    pub fn with_time_series(self, time_series: Vec<Sample>) -> Self {
+        Self {
+            time_series,
+            ..self
+        }
+    }
+
+    /// The human-readable summary, one line per statistic.
+    pub fn text_lines(&self) -> Vec<String> {
+        let total = &self.total;
+        let mut lines = vec![
+            format!("build: {}", self.build),
+            format!("total_ops: {}", total.ops),
+            format!("total_reads: {}", total.reads),
+            format!("total_writes: {}", total.writes),
+            format!("total_errors: {}", total.errors),
+            format!("total_error_rate: {:.4}%", total.error_rate * 100.0),
+            format!("total_hits: {}", total.hits),
+            format!("total_misses: {}", total.misses),
+            format!("total_hit_rate: {:.4}%", total.hit_rate * 100.0),
+            format!(
+                "total_runtime: {:?}",
+                Duration::from_secs_f64(total.runtime_sec)
+            ),
+            format!("total_ops_per_sec: {:.2}", total.ops_per_sec),
+            format!("average_ops_per_sec: {:.2}", self.average_ops_per_sec),
+        ];
+        if total.validated_reads > 0 {
+            lines.push(format!("total_validated_reads: {}", total.validated_reads));
+            lines.push(format!("total_violations: {}", total.violations));
+        }
+        if total.compared_ops > 0 {
+            lines.push(format!("total_compared_ops: {}", total.compared_ops));
+            lines.push(format!("total_divergences: {}", total.divergences));
+        }
+        lines.push(format!(
+            "{:<12} {:>10} {:>12} {:>8} {:>9} {:>10} {:>10} {:>8} {:>8} {:>8} {:>8} {:>8}",
+            "op",
+            "ops",
+            "ops/sec",
+            "errors",
+            "error%",
+            "hits",
+            "misses",
+            "p50_us",
+            "p90_us",
+            "p99_us",
+            "p99.9_us",
+            "max_us"
+        ));
+        let or_dash = |count: Option<i64>| count.map_or("-".to_string(), |c| c.to_string());
+        for op in &total.by_op {
+            let latency = &op.latency_us;
+            lines.push(format!(
+                "{:<12} {:>10} {:>12.2} {:>8} {:>8.4}% {:>10} {:>10} {:>8} {:>8} {:>8} {:>8} {:>8}",
+                op.op,
+                op.ops,
+                op.ops_per_sec,
+                op.errors,
+                op.error_rate * 100.0,
+                or_dash(op.hits),
+                or_dash(op.misses),
+                latency.p50,
+                latency.p90,
+                latency.p99,
+                latency.p99_9,
+                latency.max
+            ));
+        }
+        let fairness = &self.fairness;
+        lines.extend([
+            format!("thread_ops_per_sec_min: {:.2}", fairness.min_ops_per_sec),
+            format!(
+                "thread_ops_per_sec_median: {:.2}",
+                fairness.median_ops_per_sec
+            ),
+            format!("thread_ops_per_sec_max: {:.2}", fairness.max_ops_per_sec),
+            format!(
+                "thread_ops_per_sec_stddev: {:.2}",
+                fairness.stddev_ops_per_sec
+            ),
+            format!("thread_fairness_index: {:.4}", fairness.index),
+        ]);
+        if let Some(cache) = &self.cache {
+            lines.extend([
+                format!("cache_hits: {}", cache.hits),
+                format!("cache_misses: {}", cache.misses),
+                format!("cache_hit_rate: {:.4}%", cache.hit_rate * 100.0),
+                format!("cache_backing_misses: {}", cache.backing_misses),
+                format!("cache_write_backs: {}", cache.write_backs),
+            ]);
+        }
+        lines
+    }
+
+    /// One row per thread, then a `total` row. Per-operation columns are empty for
+    /// operation types that never ran; the time series isn't included. The cache
+    /// columns, which count the whole store, are only filled in on the `total` row
+    /// of a run with a tiered cache. The last column repeats the build on every row.
+    pub fn write_csv(&self, mut out: impl Write) -> Result<()> {
+        let mut header = vec![
+            "thread",
+            "ops",
+            "reads",
+            "writes",
+            "errors",
+            "error_rate",
+            "get_errors",
+            "put_errors",
+            "durable_put_errors",
+            "delete_errors",
+            "scan_errors",
+            "hits",
+            "misses",
+            "hit_rate",
+            "validated_reads",
+            "violations",
+            "compared_ops",
+            "divergences",
+            "runtime_sec",
+            "ops_per_sec",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+        for op in ["get", "put", "durable_put", "delete", "scan"] {
+            header.push(format!("{}_ops", op));
+            header.push(format!("{}_ops_per_sec", op));
+            for column in ["p50", "p90", "p99", "p99.9", "max"] {
+                header.push(format!("{}_latency_us_{}", op, column));
+            }
+        }
+        for column in ["hits", "misses", "hit_rate"] {
+            header.push(format!("cache_{}", column));
+        }
+        header.push("build".to_string());
+        writeln!(out, "{}", header.join(","))?;
+        let rows = self
+            .threads
+            .iter()
+            .enumerate()
+            .map(|(thread, stats)| (thread.to_string(), stats))
+            .chain([("total".to_string(), &self.total)]);
+        for (thread, stats) in rows {
+            let is_total = thread == "total";
+            let mut row = vec![
+                thread,
+                stats.ops.to_string(),
+                stats.reads.to_string(),
+                stats.writes.to_string(),
+                stats.errors.to_string(),
+                stats.error_rate.to_string(),
+                stats.errors_by_op.get.to_string(),
+                stats.errors_by_op.put.to_string(),
+                stats.errors_by_op.durable_put.to_string(),
+                stats.errors_by_op.delete.to_string(),
+                stats.errors_by_op.scan.to_string(),
+                stats.hits.to_string(),
+                stats.misses.to_string(),
+                stats.hit_rate.to_string(),
+                stats.validated_reads.to_string(),
+                stats.violations.to_string(),
+                stats.compared_ops.to_string(),
+                stats.divergences.to_string(),
+                stats.runtime_sec.to_string(),
+                stats.ops_per_sec.to_string(),
+            ];
+            for op in ["get", "put", "durable_put", "delete", "scan"] {
+                match stats.by_op.iter().find(|summary| summary.op == op) {
+                    Some(summary) => {
+                        let l = &summary.latency_us;
+                        row.extend([summary.ops.to_string(), summary.ops_per_sec.to_string()]);
+                        row.extend([l.p50, l.p90, l.p99, l.p99_9, l.max].map(|v| v.to_string()))
+                    }
+                    None => row.extend(std::iter::repeat_n(String::new(), 7)),
+                }
+            }
+            match &self.cache {
+                Some(cache) if is_total => row.extend([
+                    cache.hits.to_string(),
+                    cache.misses.to_string(),
+                    cache.hit_rate.to_string(),
+                ]),
+                _ => row.extend(std::iter::repeat_n(String::new(), 3)),
+            }
+            row.push(self.build.to_string());
+            writeln!(out, "{}", row.join(","))?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    pub fn write(&self, format: OutputFormat, mut out: impl Write) -> Result<()> {
+        match format {
+            OutputFormat::Text => {
+                for line in self.text_lines() {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+            OutputFormat::Json => {
+                serde_json::to_writer_pretty(&mut out, self)?;
+                writeln!(out)?;
+            }
+            OutputFormat::Csv => self.write_csv(&mut out)?,
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
 
+/// Log a summary of the run, with a warning for each straggling thread.
+pub fn summarize(all_stats: &[Stats]) -> Result<()> {
     for s in all_stats {
         log::trace!("{:#?}", s);
     }
-
-    log::info!("total_ops: {}", total_ops);
-    log::info!("total_runtime: {:?}", total_runtime);
-    log::info!("total_ops_per_sec: {:.2}", total_ops_per_sec);
-    log::info!("average_ops_per_sec: {:.2}", average_ops_per_sec);
+    let summary = Summary::new(all_stats)?;
+    for line in summary.text_lines() {
+        log::info!("{}", line);
+    }
+    let median = summary.fairness.median_ops_per_sec;
+    for &thread in &summary.fairness.stragglers {
+        let rate = summary.threads[thread].ops_per_sec;
+        log::warn!(
+            "Straggler: thread {} ran {:.2} ops/sec, {:+.0}% from the median",
+            thread,
+            rate,
+            (rate - median) / median * 100.0
+        );
+    }
     Ok(())
 }
+
+/// Log `series`, the run's throughput and latency per interval from
+/// `Progress::time_series`, followed by each thread's at debug level.
+pub fn summarize_time_series(all_stats: &[Stats], series: &[Sample]) {
+    let log_sample = |level: log::Level, label: &str, sample: &Sample| {
+        log::log!(
+            level,
+            "{} at {:.1}s: {:.2} ops/sec, latency_us p50={} p90={} p99={} max={}",
+            label,
+            sample.start.as_secs_f64(),
+            sample.ops_per_sec().0,
+            sample.p50_latency_us,
+            sample.p90_latency_us,
+            sample.p99_latency_us,
+            sample.max_latency_us
+        );
+    };
+    for sample in series {
+        log_sample(log::Level::Info, "interval", sample);
+    }
+    for (thread, stats) in all_stats.iter().enumerate() {
+        for sample in &stats.samples {
+            log_sample(
+                log::Level::Debug,
+                &format!("thread {} interval", thread),
+                sample,
+            );
+        }
+    }
+}
+
+/// Latencies of every operation of every thread, in one histogram.
+pub fn all_latencies(all_stats: &[Stats]) -> Result<Histogram<u64>> {
+    let mut histogram = latency_histogram()?;
+    for s in all_stats {
+        histogram.add(&s.latencies.get)?;
+        histogram.add(&s.latencies.put)?;
+        histogram.add(&s.latencies.durable_put)?;
+        histogram.add(&s.latencies.delete)?;
+        histogram.add(&s.latencies.scan)?;
+    }
+    Ok(histogram)
+}
+
+/// Performance a run must reach to pass, for gating on results without parsing
+/// logs.
+#[derive(Clone, Debug, Default)]
+pub struct Thresholds {
+    pub min_ops_per_sec: Option<f64>,
+    /// Over every operation of every thread.
+    pub max_p99_latency: Option<Duration>,
+}
+
+impl Thresholds {
+    pub fn is_empty(&self) -> bool {
+        self.min_ops_per_sec.is_none() && self.max_p99_latency.is_none()
+    }
+
+    /// A description of each threshold the run missed; empty if it passed.
+    pub fn check(&self, ops_per_sec: f64, p99_latency_us: u64) -> Vec<String> {
+        let mut failures = Vec::new();
+        if let Some(min) = self.min_ops_per_sec {
+            if ops_per_sec < min {
+                failures.push(format!(
+                    "ops/sec was {:.2}, below the minimum of {:.2}",
+                    ops_per_sec, min
+                ));
+            }
+        }
+        if let Some(max) = self.max_p99_latency {
+            if u128::from(p99_latency_us) > max.as_micros() {
+                failures.push(format!(
+                    "p99 latency was {:.3}ms, above the maximum of {}ms",
+                    p99_latency_us as f64 / 1000.0,
+                    max.as_secs_f64() * 1000.0
+                ));
+            }
+        }
+        failures
+    }
+
+    /// As `check`, for the stats of a run.
+    pub fn check_run(&self, all_stats: &[Stats]) -> Result<Vec<String>> {
+        if self.is_empty() {
+            return Ok(Vec::new());
+        }
+        let ops_per_sec = Summary::new(all_stats)?.total.ops_per_sec;
+        let p99 = all_latencies(all_stats)?.value_at_percentile(99.0);
+        Ok(self.check(ops_per_sec, p99))
+    }
+}
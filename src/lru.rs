@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::store::{Blob, Store, StoreError};
+use crate::value_sizes::blob_size;
+
+/// Bounds on what an `LruMemoryStore` holds; either or both may be set.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Capacity {
+    pub max_entries: Option<usize>,
+    /// Of keys and values together, sized as `value_sizes::blob_size` does.
+    pub max_bytes: Option<u64>,
+}
+
+impl Capacity {
+    fn exceeded(&self, entries: usize, bytes: u64) -> bool {
+        self.max_entries.is_some_and(|max| entries > max)
+            || self.max_bytes.is_some_and(|max| bytes > max)
+    }
+}
+
+/// What an `LruMemoryStore`'s gets found, and what it evicted.
+#[derive(Debug, Default)]
+pub struct LruStats {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub evictions: AtomicU64,
+    /// Bytes of the evicted keys and values.
+    pub evicted_bytes: AtomicU64,
+}
+
+impl LruStats {
+    pub fn summarize(&self) {
+        log::info!("lru_hits: {}", self.hits.load(Ordering::Relaxed));
+        log::info!("lru_misses: {}", self.misses.load(Ordering::Relaxed));
+        log::info!("lru_evictions: {}", self.evictions.load(Ordering::Relaxed));
+        log::info!(
+            "lru_evicted_bytes: {}",
+            self.evicted_bytes.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// One key's value, and when it was last used.
+struct Entry {
+    value: Blob,
+    bytes: u64,
+    used: u64,
+}
+
+struct Lru {
+    entries: HashMap<String, Entry>,
+    /// Keys by when they were last used, least recently first.
+    recency: BTreeMap<u64, String>,
+    /// Incremented on every use, so that each use is later than the last.
+    clock: u64,
+    bytes: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &str) -> Option<&Entry> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        let key = self.recency.remove(&entry.used)?;
+        entry.used = self.clock;
+        self.recency.insert(self.clock, key);
+        Some(entry)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.used);
+        self.bytes -= entry.bytes;
+        Some(entry)
+    }
+
+    /// Remove least recently used keys until `capacity` holds.
+    fn evict(&mut self, capacity: &Capacity) -> Vec<(String, Entry)> {
+        let mut evicted = Vec::new();
+        while capacity.exceeded(self.entries.len(), self.bytes) {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.bytes -= entry.bytes;
+                evicted.push((key, entry));
+            }
+        }
+        evicted
+    }
+}
+
+/// A memory store bounded by a `Capacity`, which evicts its least recently used
+/// keys to stay within it. Gets and puts both count as uses. A put that doesn't
+/// fit even in an empty store is evicted straight away.
+///
+/// Every operation takes the one lock, as gets reorder the keys too.
+pub struct LruMemoryStore {
+    lru: Arc<Mutex<Lru>>,
+    capacity: Capacity,
+    stats: Arc<LruStats>,
+}
+
+impl LruMemoryStore {
+    pub fn new(capacity: Capacity) -> Self {
+        Self {
+            lru: Arc::new(Mutex::new(Lru {
+                entries: HashMap::with_capacity(128),
+                recency: BTreeMap::new(),
+                clock: 0,
+                bytes: 0,
+            })),
+            capacity,
+            stats: Arc::new(LruStats::default()),
+        }
+    }
+
+    /// Shared by every handle spawned from this store.
+    pub fn stats(&self) -> Arc<LruStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Whether `key` is cached, without counting as a use.
+    pub fn contains(&self, key: &str) -> Result<bool, StoreError> {
+        let lru = self.lru.lock().map_err(|_| StoreError::LockError)?;
+        Ok(lru.entries.contains_key(key))
+    }
+
+    /// Up to `limit` keys, most recently used first.
+    pub fn hottest(&self, limit: usize) -> Result<Vec<String>, StoreError> {
+        let lru = self.lru.lock().map_err(|_| StoreError::LockError)?;
+        Ok(lru.recency.values().rev().take(limit).cloned().collect())
+    }
+
+    /// As `put`, returning the keys and values evicted to make room, least
+    /// recently used first. They may include this one.
+    pub fn put_returning_evicted(
+        &mut self,
+        key: &str,
+        value: Blob,
+    ) -> Result<Vec<(String, Blob)>, StoreError> {
+        let mut lru = self.lru.lock().map_err(|_| StoreError::LockError)?;
+        lru.remove(key);
+        lru.clock += 1;
+        let entry = Entry {
+            bytes: key.len() as u64 + blob_size(&value),
+            value,
+            used: lru.clock,
+        };
+        lru.bytes += entry.bytes;
+        lru.recency.insert(entry.used, key.to_string());
+        lru.entries.insert(key.to_string(), entry);
+        let evicted = lru.evict(&self.capacity);
+        self.stats
+            .evictions
+            .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+        self.stats.evicted_bytes.fetch_add(
+            evicted.iter().map(|(_, entry)| entry.bytes).sum(),
+            Ordering::Relaxed,
+        );
+        Ok(evicted
+            .into_iter()
+            .map(|(key, entry)| (key, entry.value))
+            .collect())
+    }
+}
+
+impl Store for LruMemoryStore {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        let mut lru = self.lru.lock().map_err(|_| StoreError::LockError)?;
+        let value = lru.touch(key).map(|entry| entry.value.clone());
+        let counter = match value {
+            Some(_) => &self.stats.hits,
+            None => &self.stats.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        Ok(value)
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        self.put_returning_evicted(key, value).map(|_| ())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        let mut lru = self.lru.lock().map_err(|_| StoreError::LockError)?;
+        lru.remove(key);
+        Ok(())
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(Self {
+            lru: Arc::clone(&self.lru),
+            capacity: self.capacity,
+            stats: Arc::clone(&self.stats),
+        })
+    }
+
+    /// Scanned keys don't count as used.
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Blob)>, StoreError> {
+        let lru = self.lru.lock().map_err(|_| StoreError::LockError)?;
+        Ok(lru
+            .entries
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, entry)| (key.clone(), entry.value.clone()))
+            .collect())
+    }
+
+    /// Filters and sorts every key, and like scans, doesn't count them as used.
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        let lru = self.lru.lock().map_err(|_| StoreError::LockError)?;
+        let mut rows: Vec<(String, Blob)> = lru
+            .entries
+            .iter()
+            .filter(|(key, _)| range.contains(&key.as_str()))
+            .map(|(key, entry)| (key.clone(), entry.value.clone()))
+            .collect();
+        rows.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(rows)
+    }
+
+    fn describe_stats(&self) -> Result<Vec<String>, StoreError> {
+        let lru = self.lru.lock().map_err(|_| StoreError::LockError)?;
+        Ok(vec![
+            format!("keys: {}", lru.entries.len()),
+            format!("bytes: {}", lru.bytes),
+            format!(
+                "evictions: {}",
+                self.stats.evictions.load(Ordering::Relaxed)
+            ),
+        ])
+    }
+}
@@ -1,27 +1,57 @@
-mod file_store;
-mod load_test;
-mod mem_store;
-mod store;
-
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
-use crate::mem_store::MemoryStore;
+use key_value_store::artifacts::{FailureContext, LogTail, LOG_TAIL_CAPACITY};
+use key_value_store::build_info::BuildInfo;
+use key_value_store::chaos::{self, ChaosSchedule, ChaosStore};
+use key_value_store::hooks::{HookedStore, Hooks};
+use key_value_store::keys::{self, CanonicalKeyStore, KeyPolicy};
+use key_value_store::lru::{self, LruMemoryStore};
+use key_value_store::manifest::Manifest;
+use key_value_store::mem_store::{
+    ConcurrentMemoryStore, MemoryImpl, MemoryLock, MemoryStore, RwLockMemoryStore,
+    ShardedMemoryStore,
+};
+use key_value_store::read_through::{HttpStore, ReadThroughStore};
+use key_value_store::remote::{RedisUrl, RemoteStore};
+use key_value_store::script::ScriptableStore;
+use key_value_store::server::{self, Server};
+use key_value_store::standby::FailoverStore;
+use key_value_store::store::Store;
+use key_value_store::tiered::{self, TieredStore};
+use key_value_store::ttl::{ExpiryMode, TtlStore};
+use key_value_store::{
+    advise, artifacts, compare, config, consistency, dump, export, file_store, layer_cost, limits,
+    load_test, redis_import, repl, router, self_test, spans, trace, ycsb,
+};
 
 /// Run different key-value store implementations under load.
 #[derive(StructOpt, Debug)]
 #[structopt(name = "key_value_store")]
+// Options given twice take the last value, so that the command line can override
+// a config file's; repeatable ones collect both.
+#[structopt(global_settings = &[AppSettings::AllArgsOverrideSelf])]
 struct LoadTestOptions {
+    /// TOML file of options, named as their flags are, with the backend and its
+    /// options in a [backend] table. Options on the command line override the
+    /// file's, and a command on the command line replaces its backend.
+    #[structopt(long)]
+    config: Option<PathBuf>,
+
     /// Number of threads.
     #[structopt(short, long, default_value = "100")]
     threads: usize,
 
-    /// Type of backend.
+    /// Type of backend to load test, or a maintenance command.
     #[structopt(subcommand)]
-    backend: Backend,
+    command: Command,
 
     /// Emulated load pattern.
     #[structopt(long, default_value = "consistent")]
@@ -30,19 +60,591 @@ struct LoadTestOptions {
     /// How long to generate loads for.
     #[structopt(long, default_value = "60")]
     load_time_sec: u64,
+
+    /// Stop after this many operations, split evenly across the threads, instead of
+    /// after load_time_sec, for fixed-work rather than fixed-time comparisons.
+    #[structopt(long)]
+    total_ops: Option<u64>,
+
+    /// Seed for the load-test threads' random number generators, so that runs
+    /// generate the same keys, values and pauses. A random seed is used, and
+    /// logged, if unset.
+    #[structopt(long)]
+    seed: Option<u64>,
+
+    /// Generate an open load at this rate, across all threads: operations are sent on
+    /// a schedule, and their latency is measured from when they were due, so
+    /// stalls aren't hidden by the threads waiting on them. Overrides pattern.
+    #[structopt(long)]
+    target_ops_per_sec: Option<f64>,
+
+    /// How target_ops_per_sec spaces operations out.
+    #[structopt(long, default_value = "poisson")]
+    arrivals: load_test::Arrivals,
+
+    /// Record every operation (start time, thread, op, key and value size) to this
+    /// file as JSON lines, for replay_trace.
+    #[structopt(long)]
+    record_trace: Option<PathBuf>,
+
+    /// Record a span for every file store get, put and delete, shard lock
+    /// acquisition and shard flush, and write them to this file as a Chrome trace
+    /// (for chrome://tracing or Perfetto) at the end of the run.
+    #[structopt(long)]
+    trace_output: Option<PathBuf>,
+
+    /// Replay a trace recorded with record_trace instead of generating operations,
+    /// with the same relative timing and one thread per recorded thread.
+    #[structopt(long)]
+    replay_trace: Option<PathBuf>,
+
+    /// Also record each put's exact value and each operation's result (ok, error,
+    /// miss, or the hash of the value found) to record_trace, for diff_results.
+    /// Needs validate or a single thread, so that each key's results only depend
+    /// on one thread's operations.
+    #[structopt(long, requires = "record-trace")]
+    record_results: bool,
+
+    /// Check each replayed operation's result against the one recorded with
+    /// record_results, and fail the run if any differ.
+    #[structopt(long, requires = "replay-trace")]
+    diff_results: bool,
+
+    /// How long each thread runs the workload before measuring, so cold-start
+    /// effects (file loading, allocator warmup) are left out of the results.
+    #[structopt(long, default_value = "0")]
+    warmup_sec: u64,
+
+    /// Percentage (0-100) of operations that are gets; the rest are puts.
+    #[structopt(long, default_value = "10")]
+    read_percent: f64,
+
+    /// Percentage (0-100) of puts that wait until they're fsynced to disk before
+    /// returning, reported as durable_put latencies. Durable puts to a shard share
+    /// syncs. Needs the file store.
+    #[structopt(long, default_value = "0")]
+    durable_percent: f64,
+
+    /// Percentage (0-100) of gets that are range scans instead, each of a key and
+    /// every key whose name starts with it, reported as scan latencies.
+    #[structopt(long, default_value = "0")]
+    scan_percent: f64,
+
+    /// How keys are picked: "uniform", "zipfian[:EXPONENT]" (default exponent 0.99),
+    /// "hotspot[:HOT_KEY_FRACTION:HOT_OP_FRACTION]" (default 0.2:0.8, so 80% of
+    /// operations hit 20% of keys) or "sequential".
+    #[structopt(long, default_value = "uniform")]
+    key_distribution: load_test::KeyDistribution,
+
+    /// Run a YCSB core workload instead of the default mix: "ycsb-a" (50% reads,
+    /// 50% updates), "ycsb-b" (95/5), "ycsb-c" (reads only), "ycsb-d" (95% reads
+    /// of the latest keys, 5% inserts) or "ycsb-f" (50% reads, 50%
+    /// read-modify-writes). Keys are zipfian, and key_count records are loaded
+    /// first. Overrides read_percent, durable_percent, scan_percent and key_distribution.
+    #[structopt(long)]
+    workload: Option<ycsb::YcsbWorkload>,
+
+    /// Number of distinct keys the load test uses. Large key spaces test memory
+    /// growth and snapshot size; small ones maximize contention.
+    #[structopt(long, default_value = "65536")]
+    key_count: u64,
+
+    /// Prefix of generated key names, which are followed by the key's index.
+    #[structopt(long, default_value = "Key")]
+    key_prefix: String,
+
+    /// Put the first N keys, with values of the configured shape and size, before
+    /// the warmup and the measured workload, so that reads hit. At most key_count.
+    #[structopt(long, default_value = "0")]
+    preload_keys: u64,
+
+    /// Strip leading and trailing whitespace from every key, in the load test, the
+    /// REPL and imports.
+    #[structopt(long)]
+    key_trim: bool,
+
+    /// Lowercase every key, so that keys differing only in case are the same key.
+    #[structopt(long)]
+    key_fold_case: bool,
+
+    /// Reject keys longer than this many bytes (after trimming and case folding).
+    #[structopt(long)]
+    key_max_len: Option<usize>,
+
+    /// Characters allowed in keys: any, printable (visible ASCII, no spaces) or
+    /// identifier (ASCII letters, digits and _-.:/).
+    #[structopt(long, default_value = "any")]
+    key_charset: keys::KeyCharset,
+
+    /// Shape of the values written by the load test.
+    #[structopt(long, alias = "value-type", default_value = "str")]
+    value_shape: load_test::ValueShape,
+
+    /// Size of written values in bytes: fixed ("64"), uniform over a range
+    /// ("64-4096"), or log-normal ("lognormal:MEDIAN:SIGMA"). Dict values split it
+    /// between their fields.
+    #[structopt(long, alias = "value-size", default_value = "3")]
+    value_size_bytes: load_test::ValueSize,
+
+    /// Nesting depth of dict values; 1 is a flat dict.
+    #[structopt(long, default_value = "1")]
+    value_depth: usize,
+
+    /// What a file store's background threads (asynchronous writers, flusher,
+    /// compactor, policy switcher) do on an error: "abort-process", "fail-store"
+    /// (fail every later operation) or "log-and-continue".
+    #[structopt(long, default_value = "log-and-continue")]
+    background_error_policy: file_store::BackgroundErrorPolicy,
+
+    /// Log every file store get, put and delete slower than this, with its key,
+    /// shard, the shard's write queue depth and whether it was being flushed.
+    #[structopt(long)]
+    slow_op_ms: Option<u64>,
+
+    /// How often to log the elapsed time, current throughput, errors so far and,
+    /// for file stores, the asynchronous write queue depth; 0 disables.
+    #[structopt(long, default_value = "1")]
+    live_stats_interval_sec: u64,
+
+    /// Length of the intervals over which throughput and latency are sampled and
+    /// logged as a time series at the end of the run; 0 disables.
+    #[structopt(long, default_value = "1000")]
+    sample_interval_ms: u64,
+
+    /// Write, read and delete sentinel keys through the full stack before the load
+    /// starts (including a flush and reload for file backends), failing fast if any
+    /// step misbehaves.
+    #[structopt(long)]
+    self_test: bool,
+
+    /// Give each thread its own share of the keys and check that every get of a
+    /// key it wrote returns what it last wrote. Violations are reported in the
+    /// summary and fail the run.
+    #[structopt(long)]
+    validate: bool,
+
+    /// Record every operation's result and timing, and check afterwards that the
+    /// history is linearizable: that each key behaves like a single register whose
+    /// operations take effect at some instant while they run. Checking grows
+    /// quickly with the operations per key, so use a short run with few keys and
+    /// several threads. Violations fail the run.
+    #[structopt(long)]
+    check_linearizability: bool,
+
+    /// Stop the run at the first failed operation and fail with its error, rather
+    /// than counting errors by operation and reporting their rates.
+    #[structopt(long)]
+    abort_on_error: bool,
+
+    /// Fail the run, naming the threshold it missed, if its throughput across all
+    /// threads is below this. With compare, every backend must reach it.
+    #[structopt(long)]
+    assert_min_ops_per_sec: Option<f64>,
+
+    /// Fail the run, naming the threshold it missed, if the p99 latency of all its
+    /// operations is above this many milliseconds. With compare, every backend
+    /// must stay under it.
+    #[structopt(long)]
+    assert_max_p99_ms: Option<f64>,
+
+    /// Cap the process (load generator and in-process store alike) at this many
+    /// CPUs' worth of time, e.g. 1.5, so it doesn't starve its neighbours.
+    #[structopt(long)]
+    cpu_limit: Option<f64>,
+
+    /// Cap the process's memory at this many megabytes. A cgroup reclaims memory
+    /// and then kills the process past it; the internal budget stops the run.
+    #[structopt(long)]
+    memory_limit_mb: Option<u64>,
+
+    /// How cpu_limit and memory_limit_mb are enforced: auto (a cgroup v2 child
+    /// group where one can be created, otherwise the internal budget), cgroup, or
+    /// internal (pausing load-test threads while over the CPU budget).
+    #[structopt(long, default_value = "auto")]
+    limit_mode: limits::LimitMode,
+
+    /// Instead of a single run, run the workload bare and then once under each of
+    /// the chaos, TTL, script and hook layers in a no-op configuration, reporting
+    /// each layer's throughput and latency cost. Takes load_time_sec (or total_ops)
+    /// per run.
+    #[structopt(long)]
+    layer_cost: bool,
+
+    /// Shell command run before every put, with the key in $KV_KEY and the JSON value
+    /// on stdin. A non-zero exit rejects the put; any output replaces the value.
+    #[structopt(long)]
+    pre_write_command: Option<String>,
+
+    /// Shell command run after every successful put, invoked like pre_write_command.
+    #[structopt(long)]
+    post_write_command: Option<String>,
+
+    /// Path to a rhai script run atomically in place of every put, with `key` and
+    /// `value` in scope and `get`/`put` functions available.
+    #[structopt(long)]
+    write_procedure: Option<PathBuf>,
+
+    /// Path to a rhai script evaluated once against the store before the load starts,
+    /// e.g. to seed keys used by write_procedure.
+    #[structopt(long)]
+    setup_script: Option<PathBuf>,
+
+    /// Expire every key this many milliseconds after it was last written.
+    #[structopt(long)]
+    ttl_ms: Option<u64>,
+
+    /// Whether expired keys are only removed when read (lazy), or also sampled and
+    /// removed by a background thread (active).
+    #[structopt(long, default_value = "lazy")]
+    expiry_mode: ExpiryMode,
+
+    /// Path to a JSON chaos schedule of faults (I/O errors, latency) to inject into
+    /// the backend at given times; see `chaos::ChaosSchedule`.
+    #[structopt(long)]
+    chaos_schedule: Option<PathBuf>,
+
+    /// Make the file store's deletes soft: keep each deleted value as a tombstone
+    /// that can be read back (the REPL's get-deleted) or restored (undelete) for
+    /// this many seconds, after which compaction purges it. 0 disables.
+    #[structopt(long, default_value = "0")]
+    soft_delete_horizon_sec: u64,
+
+    /// Replicate the backend to a warm standby (a second, empty store of the same
+    /// kind, seeded with the file store's existing keys), fail the primary this
+    /// many seconds into the load test, promote the standby and carry on against
+    /// it. Reports how long promotion took and how long clients couldn't write.
+    #[structopt(long)]
+    failover_after_sec: Option<u64>,
+
+    /// How long a promotion may spend applying the changes the standby hadn't yet;
+    /// any left after that are lost.
+    #[structopt(long, default_value = "1000")]
+    promotion_timeout_ms: u64,
+
+    /// Treat the backend as a cache in front of an HTTP service: a get that misses
+    /// is fetched from this http:// URL, with {key} replaced by the key, and stored
+    /// in the backend. Upstream latency is reported separately.
+    #[structopt(long, conflicts_with = "read-through-store")]
+    read_through_url: Option<String>,
+
+    /// As read_through_url, but fetching misses from the existing file store in this
+    /// directory.
+    #[structopt(long)]
+    read_through_store: Option<PathBuf>,
+
+    /// Redis RDB file, AOF file or multi-part AOF directory whose string and hash keys
+    /// are loaded into the backend before the load starts. Files ending in .ndjson,
+    /// .jsonl or .csv are loaded as the import subcommand would, with its default
+    /// value encodings.
+    #[structopt(long)]
+    import: Option<PathBuf>,
+
+    /// Only import keys from this Redis database. Defaults to merging all of them.
+    #[structopt(long)]
+    import_db: Option<u64>,
+
+    /// Checkpoint the import into the file store, and continue an interrupted one
+    /// from its last checkpoint rather than starting over.
+    #[structopt(long)]
+    import_resume: bool,
+
+    /// Format of the final stats: per-thread and total counts, throughput and
+    /// latency percentiles. Text is also logged; json and csv are for scripts.
+    #[structopt(long, default_value = "text")]
+    output_format: load_test::OutputFormat,
+
+    /// File to write the final stats to in output_format. Defaults to stdout for
+    /// json and csv.
+    #[structopt(long)]
+    output_file: Option<PathBuf>,
+
+    /// Directory in which to write a zip of config, partial stats, slow operations,
+    /// shard metadata and recent log lines if the run fails. Defaults to the system
+    /// temp directory.
+    #[structopt(long)]
+    artifacts_dir: Option<PathBuf>,
 }
 
+// Parsed once at startup, like `Backend`, which makes it large.
+#[allow(clippy::large_enum_variant)]
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(flatten)]
+    Backend(Backend),
+    /// Report the key count, shard balance and value sizes per key namespace of an
+    /// existing file store.
+    Analyze {
+        /// Store directory to analyze.
+        #[structopt(long)]
+        output: PathBuf,
+
+        /// Path to also write the value sizes to, as JSON.
+        #[structopt(long)]
+        json: Option<PathBuf>,
+    },
+    /// Write a consistent snapshot of an existing file store as a SQL script.
+    Export {
+        /// Store directory to export.
+        #[structopt(long)]
+        output: PathBuf,
+
+        /// Path of the SQL script to write.
+        #[structopt(long)]
+        sql: PathBuf,
+
+        /// Name of the table to create.
+        #[structopt(long, default_value = "kv")]
+        table: String,
+
+        /// Continue an interrupted export from its last checkpoint rather than
+        /// starting over.
+        #[structopt(long)]
+        resume: bool,
+    },
+    /// Write every key and value of an existing file store as NDJSON, in key order,
+    /// to output_file or stdout: a consistent snapshot, for backups and migrations.
+    Dump {
+        /// Store directory to dump.
+        #[structopt(long)]
+        output: PathBuf,
+
+        /// How to write values: "tagged", which keeps every value's type, or "json"
+        /// for plain JSON, which loses some (bytes become lists of integers).
+        #[structopt(long, default_value = "tagged")]
+        values: dump::ValueEncoding,
+    },
+    /// Load NDJSON (as written by dump) or CSV into a file store, created if it
+    /// doesn't exist, or into a running serve.
+    Import {
+        /// File to load, or - for stdin.
+        #[structopt(long)]
+        input: PathBuf,
+
+        /// "ndjson" or "csv". Defaults to csv for files ending in .csv.
+        #[structopt(long)]
+        format: Option<dump::DumpFormat>,
+
+        /// How values are read: "tagged", as dump writes them; "json" for plain JSON;
+        /// or "string" for strings taken as they are. CSV cells hold JSON for the
+        /// first two. Defaults to tagged for NDJSON and string for CSV.
+        #[structopt(long)]
+        values: Option<dump::ValueEncoding>,
+
+        /// File store to load into.
+        #[structopt(long, required_unless = "remote-addr")]
+        output: Option<PathBuf>,
+
+        /// Number of files to shard a new file store across.
+        #[structopt(long)]
+        file_count: Option<usize>,
+
+        /// Address of a running serve to load into instead.
+        #[structopt(long, conflicts_with = "output")]
+        remote_addr: Option<String>,
+
+        /// The protocol the server at remote_addr speaks: line, resp or http.
+        #[structopt(long, default_value = "line")]
+        protocol: server::Protocol,
+    },
+    /// Run the same seeded workload against several backends back to back, each in
+    /// a fresh store, and compare their throughput and latency with the first's.
+    Compare {
+        /// Backends to compare, baseline first: "memory",
+        /// "file:SERIALIZER:sync:WRITE_PERIOD_US[:FILE_COUNT]",
+        /// "file:SERIALIZER:async:QUEUE_DEPTH[:FILE_COUNT]" or
+        /// "file:SERIALIZER:hybrid:SNAPSHOT_INTERVAL_MS[:FILE_COUNT]". File stores
+        /// have 8 files unless given a count.
+        #[structopt(required = true, min_values = 2)]
+        backends: Vec<compare::BackendSpec>,
+    },
+    /// Recommend a file store configuration for the workload: calibrate every
+    /// candidate serializer, file count and write policy with a short run against a
+    /// store populated with key_count keys, and pick the fastest within the target.
+    Advise {
+        /// p99 latency to stay within, in milliseconds.
+        #[structopt(long)]
+        target_p99_ms: f64,
+
+        /// Length of each candidate's calibration run, in seconds.
+        #[structopt(long, default_value = "1")]
+        calibration_sec: u64,
+
+        /// Path to write the recommendation to, as a shell script running the
+        /// workload against it.
+        #[structopt(long)]
+        output: Option<PathBuf>,
+    },
+    /// Run get/put/delete/scan commands against a store, interactively or from a
+    /// script.
+    #[structopt(visible_alias = "shell")]
+    Repl {
+        /// File of commands to run non-interactively. Exits with 1 if an assertion
+        /// fails, or 2 if a command is malformed or errors.
+        #[structopt(long)]
+        script: Option<PathBuf>,
+
+        /// Existing file store to operate on. Defaults to an empty in-memory store.
+        #[structopt(long)]
+        output: Option<PathBuf>,
+
+        /// Operate on another process's `serve` at this address instead.
+        #[structopt(long, conflicts_with = "output")]
+        remote_addr: Option<String>,
+
+        /// The protocol the server at remote_addr speaks: line, resp or http.
+        #[structopt(long, default_value = "line")]
+        protocol: server::Protocol,
+
+        /// File to keep interactive commands in across sessions. Defaults to
+        /// ~/.key_value_store_history.
+        #[structopt(long)]
+        history: Option<PathBuf>,
+
+        /// Keep interactive commands for this session only.
+        #[structopt(long, conflicts_with = "history")]
+        no_history: bool,
+    },
+    /// Rehash every key of an existing file store into a new number of shards.
+    Reshard {
+        /// Store directory to reshard.
+        #[structopt(long)]
+        output: PathBuf,
+
+        /// New number of files to shard across.
+        #[structopt(long)]
+        file_count: usize,
+
+        /// New key-to-shard routing scheme. Defaults to the store's current one.
+        #[structopt(long)]
+        router: Option<router::Router>,
+
+        /// New key hash function. Defaults to the store's current one.
+        #[structopt(long)]
+        hash: Option<router::HashFunction>,
+    },
+    /// Rewrite an existing file store with another serializer, keeping its shard
+    /// layout or resharding it in the same pass.
+    Convert {
+        /// Store directory to convert.
+        #[structopt(long)]
+        output: PathBuf,
+
+        /// Serializer to rewrite the shards with.
+        #[structopt(long)]
+        serializer: file_store::Serializer,
+
+        /// New number of files to shard across. Defaults to the store's current one.
+        #[structopt(long)]
+        file_count: Option<usize>,
+
+        /// New key-to-shard routing scheme, if file_count changes. Defaults to the
+        /// store's current one.
+        #[structopt(long)]
+        router: Option<router::Router>,
+
+        /// New key hash function, if file_count changes. Defaults to the store's
+        /// current one.
+        #[structopt(long)]
+        hash: Option<router::HashFunction>,
+    },
+    /// Check every shard of an existing file store: that it decodes and matches its
+    /// checksum, and that its keys route to it. Reports shard files outside the
+    /// layout, and the keys in them, too. Fails if it finds problems it didn't
+    /// repair.
+    Verify {
+        /// Store directory to check.
+        #[structopt(long)]
+        output: PathBuf,
+
+        /// Fix what can be fixed: set unreadable shards aside for empty ones, move
+        /// misplaced keys to their shards, drop duplicates and rewrite checksums.
+        #[structopt(long)]
+        repair: bool,
+    },
+    /// Serve a store over TCP with a line protocol: "GET key", "PUT key value" and
+    /// "DEL key", one request per line. Runs until killed, or for duration_sec.
+    Serve {
+        /// Address to listen on.
+        #[structopt(long, default_value = "127.0.0.1:7878")]
+        listen: String,
+
+        /// "line"; "resp" to speak the Redis protocol (GET, SET, DEL, EXISTS and
+        /// INCR) to Redis clients and redis-benchmark; or "http" for a JSON API on
+        /// /keys/{key} and /batch.
+        #[structopt(long, default_value = "line")]
+        protocol: server::Protocol,
+
+        /// File store to serve, created with file_count files if it doesn't exist.
+        /// Defaults to an empty in-memory store.
+        #[structopt(long)]
+        output: Option<PathBuf>,
+
+        /// Number of files to shard a new file store across.
+        #[structopt(long)]
+        file_count: Option<usize>,
+
+        /// How the file store persists writes: "sync:WRITE_PERIOD_US",
+        /// "async:QUEUE_DEPTH", "hybrid:SNAPSHOT_INTERVAL_MS" or
+        /// "snapshot:SNAPSHOT_INTERVAL_MS".
+        #[structopt(long, default_value = "sync:1000")]
+        write_policy: file_store::WritePolicy,
+
+        /// Most connections served at once; further ones are told so and closed.
+        #[structopt(long, default_value = "1024")]
+        max_connections: usize,
+
+        /// Stop after this many seconds, flushing the file store, rather than
+        /// serving until killed.
+        #[structopt(long)]
+        duration_sec: Option<u64>,
+    },
+    /// Print the crate version, git commit, build profile and enabled features of
+    /// this binary, in output_format. Reports and store manifests record the same.
+    Version,
+    /// Print the options a run would use, including defaults, as a config file for
+    /// --config: the global options, and the config file's backend, if it has one.
+    PrintConfig,
+}
+
+// Parsed once at startup, so the file options' size doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(StructOpt, Debug)]
 enum Backend {
-    Memory,
+    Memory {
+        /// Split the store across this many independently locked shards, so that
+        /// threads only contend when their keys share one. 1 is a single map
+        /// behind one lock.
+        #[structopt(long, default_value = "1")]
+        shards: usize,
+
+        /// Guard the map with a mutex, or a rwlock so that gets run side by side.
+        /// Only an unsharded store can use a rwlock.
+        #[structopt(long, default_value = "mutex", possible_values = &MemoryLock::variants(), case_insensitive = true)]
+        lock: MemoryLock,
+
+        /// The map to keep keys in: std's HashMap, under --lock and --shards, or a
+        /// concurrent map built as DashMap is, which picks its own shard count.
+        #[structopt(long = "impl", default_value = "hashmap", possible_values = &MemoryImpl::variants(), case_insensitive = true)]
+        implementation: MemoryImpl,
+
+        /// Hold at most this many keys, evicting the least recently used.
+        #[structopt(long)]
+        max_entries: Option<usize>,
+
+        /// Hold at most this many bytes of keys and values, evicting the least
+        /// recently used.
+        #[structopt(long)]
+        max_bytes: Option<u64>,
+    },
     File {
         /// Output path for file-based backends. Defaults to tmp.
         #[structopt(long)]
         output: Option<PathBuf>,
 
-        /// Number of files to shard across.
+        /// Number of files to shard across. Required for new stores; if given for an
+        /// existing store, it must match the store's manifest.
         #[structopt(long)]
-        file_count: usize,
+        file_count: Option<usize>,
 
         /// How often to persist changes to disk, in microseconds. Implies synchronous writing;
         /// mutually exclusive with queue_depth.
@@ -54,63 +656,1917 @@ enum Backend {
         #[structopt(long)]
         queue_depth: Option<usize>,
 
-        /// Target file format.
-        #[structopt(long, default_value = "json")]
-        serializer: file_store::Serializer,
+        /// Append every write to its shard's write-ahead log as it happens, and
+        /// snapshot dirty shards in the background this often, which empties the
+        /// logs. Implies hybrid writing; mutually exclusive with write_period_us and
+        /// queue_depth.
+        #[structopt(long)]
+        snapshot_interval_ms: Option<u64>,
+
+        /// Leave out the hybrid policy's write-ahead log, so that writes are
+        /// persisted only by the snapshots; requires snapshot_interval_ms.
+        #[structopt(long)]
+        no_wal: bool,
+
+        /// What a write does when its shard's asynchronous queue is full: "block",
+        /// "fail-fast", "drop-oldest" or "block-with-timeout:TIMEOUT_MS". Failed
+        /// writes count as load-test errors.
+        #[structopt(long, default_value = "block")]
+        queue_full_policy: file_store::QueueFullPolicy,
+
+        /// Serve every shard's asynchronous writer with a pool of this many threads,
+        /// instead of a thread per shard.
+        #[structopt(long)]
+        writer_threads: Option<usize>,
+
+        /// Switch the write policy during the run: "AT_SEC:sync:WRITE_PERIOD_US" or
+        /// "AT_SEC:async:QUEUE_DEPTH", with AT_SEC counted from the start of the load
+        /// test. Each shard drains its writer before switching. May be repeated.
+        #[structopt(long, number_of_values = 1)]
+        policy_switch: Vec<file_store::PolicySwitch>,
+
+        /// Target file format. Defaults to json for new stores; if given for an existing
+        /// store, it must match the store's manifest.
+        #[structopt(long)]
+        serializer: Option<file_store::Serializer>,
+
+        /// If an existing store was written with a different serializer than
+        /// --serializer, convert it shard by shard before opening it, instead of
+        /// failing.
+        #[structopt(long)]
+        migrate_serializer: bool,
+
+        /// Open an existing store without writers, changing none of its files; every
+        /// put and delete fails. For running read workloads against a shared
+        /// dataset.
+        #[structopt(long)]
+        read_only: bool,
+
+        /// How keys are assigned to files. Defaults to modulo for new stores; if given
+        /// for an existing store, it must match the store's manifest.
+        #[structopt(long)]
+        router: Option<router::Router>,
+
+        /// Hash function used to route keys. Defaults to siphash for new stores; if
+        /// given for an existing store, it must match the store's manifest.
+        #[structopt(long)]
+        hash: Option<router::HashFunction>,
+
+        /// Fraction of reads (0.0-1.0) cross-checked against the persisted value, with
+        /// the persisted copy repaired on divergence.
+        #[structopt(long)]
+        read_repair_rate: Option<f64>,
+
+        /// Maximum time a write may wait to be persisted. Starts a background flusher;
+        /// requires write_period_us.
+        #[structopt(long)]
+        max_persistence_lag_ms: Option<u64>,
+
+        /// Persist each flush as a delta of the changed keys, appended to the shard's
+        /// log, and write a full snapshot of the shard only every this many flushes;
+        /// requires write_period_us.
+        #[structopt(long)]
+        delta_snapshot_every: Option<u32>,
+
+        /// Split each shard's delta log or write-ahead log into numbered segment
+        /// files of at most this many bytes; requires delta_snapshot_every or
+        /// snapshot_interval_ms.
+        #[structopt(long)]
+        max_segment_bytes: Option<u64>,
+
+        /// Order in which the background flusher visits dirty shards.
+        #[structopt(long, default_value = "deadline")]
+        flush_order: file_store::FlushOrder,
+
+        /// How often to log per-shard statistics during the run; 0 disables.
+        #[structopt(long, default_value = "10")]
+        shard_stats_interval_sec: u64,
+
+        /// Path to write a per-shard ops and bytes-persisted heatmap to, as JSON if it
+        /// ends in .json and CSV otherwise.
+        #[structopt(long)]
+        shard_heatmap: Option<PathBuf>,
+
+        /// Width of the heatmap's time buckets, in milliseconds.
+        #[structopt(long, default_value = "1000")]
+        heatmap_bucket_ms: u64,
+
+        /// Keep each shard's last N operations (op, key hash, time, outcome) in
+        /// memory, and include them in the failure bundle.
+        #[structopt(long)]
+        op_journal_size: Option<usize>,
+
+        /// Every this many seconds, crash the store (abandoning whatever it hasn't
+        /// persisted yet), reopen it from disk, and carry on with the load. Logs how
+        /// many acknowledged writes survived each restart; with --validate, lost
+        /// writes also show up as violations. Shard statistics aren't logged during
+        /// the run. 0 disables.
+        #[structopt(long, default_value = "0")]
+        chaos_restart_interval_sec: u64,
+
+        /// Put an LRU memory cache of at most this many keys in front of the store.
+        #[structopt(long)]
+        cache_entries: Option<usize>,
+
+        /// Put an LRU memory cache of at most this many bytes of keys and values in
+        /// front of the store.
+        #[structopt(long)]
+        cache_bytes: Option<u64>,
+
+        /// How the cache passes puts on to the store: "write-through", as they're
+        /// made, or "write-back", once they're evicted or the run ends.
+        #[structopt(long, default_value = "write-through")]
+        cache_write_mode: tiered::WriteMode,
+
+        /// After opening the store, copy the keys the cache held most recently
+        /// (saved beside the manifest) back into it, at up to this many keys a
+        /// second, while the load runs. Needs a cache.
+        #[structopt(long)]
+        cache_rehydrate_rate: Option<f64>,
+
+        /// How often a cached store saves its hottest keys for rehydration.
+        #[structopt(long, default_value = "500")]
+        cache_hot_keys_interval_ms: u64,
+    },
+    /// Another process's `serve`, over the network. Each load thread keeps its own
+    /// connection.
+    Remote {
+        /// Address the server listens on.
+        #[structopt(long)]
+        addr: String,
+
+        /// The protocol the server speaks: line, resp or http. Only http carries
+        /// values other than strings and bytes.
+        #[structopt(long, default_value = "line")]
+        protocol: server::Protocol,
+    },
+    /// An external Redis server, over RESP.
+    Redis {
+        /// redis://[[USERNAME]:PASSWORD@]HOST[:PORT][/DB]; the port defaults to 6379.
+        #[structopt(long, default_value = "redis://127.0.0.1:6379")]
+        url: RedisUrl,
     },
 }
 
-fn run(opts: LoadTestOptions) -> Result<()> {
-    let load_params = load_test::LoadParams {
-        threads: opts.threads,
+/// Only wrap the store if a key policy was configured, so the common case pays
+/// nothing. It's the outermost layer, so every other layer sees canonical keys.
+fn load_test_with_keys<S: Store>(
+    store: S,
+    keys: KeyPolicy,
+    load_params: load_test::LoadParams,
+) -> Result<Vec<load_test::Stats>> {
+    if keys.is_empty() {
+        load_test::load_test(store, load_params)
+    } else {
+        load_test::load_test(CanonicalKeyStore::new(store, keys), load_params)
+    }
+}
+
+/// Only wrap the store if hooks were registered, so the common case pays nothing.
+fn load_test_with_hooks<S: Store>(
+    store: S,
+    hooks: Hooks,
+    keys: KeyPolicy,
+    load_params: load_test::LoadParams,
+) -> Result<Vec<load_test::Stats>> {
+    if hooks.is_empty() {
+        load_test_with_keys(store, keys, load_params)
+    } else {
+        load_test_with_keys(HookedStore::new(store, hooks), keys, load_params)
+    }
+}
+
+/// Rhai sources for scripted workloads; see `ScriptableStore`.
+struct Scripts {
+    setup: Option<String>,
+    write_procedure: Option<String>,
+}
+
+fn load_test_with_scripts<S: Store + 'static>(
+    store: S,
+    scripts: &Scripts,
+    hooks: Hooks,
+    keys: KeyPolicy,
+    load_params: load_test::LoadParams,
+) -> Result<Vec<load_test::Stats>> {
+    if scripts.setup.is_none() && scripts.write_procedure.is_none() {
+        return load_test_with_hooks(store, hooks, keys, load_params);
+    }
+    let mut store = ScriptableStore::new(store)?;
+    if let Some(source) = &scripts.setup {
+        let result = store.eval(source)?;
+        log::info!("Setup script returned: {:?}", result);
+    }
+    if let Some(source) = &scripts.write_procedure {
+        store = store.with_write_procedure(source)?;
+    }
+    load_test_with_hooks(store, hooks, keys, load_params)
+}
+
+/// Source of truth that a `ReadThroughStore` fetches the backend's misses from.
+enum Upstream {
+    Http(HttpStore),
+    FileStore(PathBuf),
+}
+
+/// Optional wrappers applied around the backend under test, innermost first.
+struct Layers {
+    chaos: Option<ChaosSchedule>,
+    read_through: Option<Upstream>,
+    ttl: Option<Duration>,
+    expiry_mode: ExpiryMode,
+    scripts: Scripts,
+    hooks: Hooks,
+    keys: KeyPolicy,
+}
+
+impl Layers {
+    fn is_empty(&self) -> bool {
+        self.chaos.is_none()
+            && self.read_through.is_none()
+            && self.ttl.is_none()
+            && self.scripts.setup.is_none()
+            && self.scripts.write_procedure.is_none()
+            && self.hooks.is_empty()
+            && self.keys.is_empty()
+    }
+}
+
+/// Runs the layer-cost preset instead of `load_test_with_layers` if requested.
+fn run_load_test<S: Store + 'static>(
+    mut store: S,
+    layers: Layers,
+    load_params: load_test::LoadParams,
+    measure_layer_cost: bool,
+) -> Result<Vec<load_test::Stats>> {
+    if !measure_layer_cost {
+        return load_test_with_layers(store, layers, load_params);
+    }
+    let (all_stats, costs) = layer_cost::measure(&mut store, &load_params)?;
+    layer_cost::report(&costs);
+    Ok(all_stats)
+}
+
+/// A standby to fail over to, and when.
+struct FailoverPlan<T> {
+    standby: T,
+    after: Duration,
+    timeout: Duration,
+}
+
+/// Only replicate the store if a failover was planned, so the common case pays
+/// nothing. It's the innermost layer, so the other layers see one store that
+/// switches from primary to standby part-way through the run.
+fn run_with_failover<S: Store + 'static, T: Store + 'static>(
+    store: S,
+    failover: Option<FailoverPlan<T>>,
+    layers: Layers,
+    load_params: load_test::LoadParams,
+    measure_layer_cost: bool,
+) -> Result<Vec<load_test::Stats>> {
+    let Some(plan) = failover else {
+        return run_load_test(store, layers, load_params, measure_layer_cost);
+    };
+    let mut store = FailoverStore::new(store, plan.standby)?;
+    let failover = store.spawn_failover(plan.after, plan.timeout)?;
+    let probe = store.spawn()?;
+    let all_stats = run_load_test(store, layers, load_params, measure_layer_cost)?;
+    drop(failover);
+    match probe.report()? {
+        Some(report) => report.log(),
+        None => log::warn!("The run ended before the failover at {:?}", plan.after),
+    }
+    Ok(all_stats)
+}
+
+/// Load test a memory store, after importing into it, with a standby made by
+/// `new_standby` if a failover is planned.
+fn run_memory<S: Store + 'static>(
+    mut backend: S,
+    new_standby: impl FnOnce() -> S,
+    opts: &LoadTestOptions,
+    key_policy: KeyPolicy,
+    failover: Option<(Duration, Duration)>,
+    layers: Layers,
+    load_params: load_test::LoadParams,
+) -> Result<Vec<load_test::Stats>> {
+    let mut importer = CanonicalKeyStore::new(backend.spawn()?, key_policy);
+    import_dump(&mut importer, &opts.import, opts.import_db, None)?;
+    let failover = failover.map(|(after, timeout)| FailoverPlan {
+        standby: new_standby(),
+        after,
+        timeout,
+    });
+    run_with_failover(backend, failover, layers, load_params, opts.layer_cost)
+}
+
+/// Load test another process's store, after importing into it.
+fn run_remote(
+    mut backend: RemoteStore,
+    opts: &LoadTestOptions,
+    key_policy: KeyPolicy,
+    failover: Option<(Duration, Duration)>,
+    layers: Layers,
+    load_params: load_test::LoadParams,
+) -> Result<Vec<load_test::Stats>> {
+    if opts.import_resume {
+        bail!("import_resume needs a file store to checkpoint into");
+    }
+    if opts.durable_percent > 0.0 {
+        bail!("durable_percent needs a file store to persist to");
+    }
+    if opts.scan_percent > 0.0 {
+        bail!("scan_percent needs a local store; remote stores have no range scans");
+    }
+    if opts.soft_delete_horizon_sec > 0 {
+        bail!("soft_delete_horizon_sec needs a file store to keep tombstones in");
+    }
+    if opts.background_error_policy != file_store::BackgroundErrorPolicy::default() {
+        bail!("background_error_policy applies to a file store's background threads");
+    }
+    if opts.slow_op_ms.is_some() {
+        bail!("slow_op_ms logs a file store's shards and flushes; run it on the server instead");
+    }
+    if failover.is_some() {
+        bail!("failover_after_sec needs a local store to fail over from");
+    }
+    let mut importer = CanonicalKeyStore::new(backend.spawn()?, key_policy);
+    import_dump(&mut importer, &opts.import, opts.import_db, None)?;
+    run_with_failover(
+        backend,
+        None::<FailoverPlan<RemoteStore>>,
+        layers,
+        load_params,
+        opts.layer_cost,
+    )
+}
+
+fn load_test_with_layers<S: Store + 'static>(
+    store: S,
+    mut layers: Layers,
+    load_params: load_test::LoadParams,
+) -> Result<Vec<load_test::Stats>> {
+    let Some(schedule) = layers.chaos.take() else {
+        return load_test_with_read_through(store, layers, load_params);
+    };
+    let store = ChaosStore::new(store, schedule, load_params.tot_time);
+    let timeline = store.timeline();
+    let all_stats = load_test_with_read_through(store, layers, load_params)?;
+    timeline.report();
+    Ok(all_stats)
+}
+
+/// Chaos faults apply to the backend beneath, not to the upstream.
+fn load_test_with_read_through<S: Store + 'static>(
+    store: S,
+    mut layers: Layers,
+    load_params: load_test::LoadParams,
+) -> Result<Vec<load_test::Stats>> {
+    match layers.read_through.take() {
+        None => load_test_with_ttl(store, layers, load_params),
+        Some(Upstream::Http(upstream)) => read_through(store, upstream, layers, load_params),
+        Some(Upstream::FileStore(path)) => {
+            let upstream = file_store::FileStore::open(
+                &path,
+                &file_store::WritePolicy::Synchronous {
+                    write_period: Duration::ZERO,
+                },
+            )?;
+            read_through(store, upstream, layers, load_params)
+        }
+    }
+}
+
+fn read_through<S: Store + 'static, U: Store + 'static>(
+    store: S,
+    upstream: U,
+    layers: Layers,
+    load_params: load_test::LoadParams,
+) -> Result<Vec<load_test::Stats>> {
+    let store = ReadThroughStore::new(store, upstream)?;
+    let stats = store.stats();
+    let all_stats = load_test_with_ttl(store, layers, load_params)?;
+    stats.summarize();
+    Ok(all_stats)
+}
+
+fn load_test_with_ttl<S: Store + 'static>(
+    store: S,
+    layers: Layers,
+    load_params: load_test::LoadParams,
+) -> Result<Vec<load_test::Stats>> {
+    let Layers {
+        ttl,
+        expiry_mode,
+        scripts,
+        hooks,
+        keys,
+        ..
+    } = layers;
+    if ttl.is_none() {
+        return load_test_with_scripts(store, &scripts, hooks, keys, load_params);
+    }
+    let store = TtlStore::new(store, ttl, expiry_mode)?;
+    let metrics = store.metrics();
+    let all_stats = load_test_with_scripts(store, &scripts, hooks, keys, load_params)?;
+    metrics.summarize();
+    Ok(all_stats)
+}
+
+/// How many times per purge horizon a soft-deleting file store is compacted, so
+/// that tombstones outlive the horizon by at most a tenth of it.
+const COMPACTIONS_PER_HORIZON: u32 = 10;
+
+const MIN_COMPACTION_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Name of the checkpoint a resumable import keeps in the file store's directory.
+const IMPORT_CHECKPOINT: &str = "import.checkpoint";
+
+/// Name of the cache's saved hot keys in the file store's directory.
+const HOT_KEYS: &str = "hot_keys.json";
+
+/// Most hot keys a cache saves, however many it holds.
+const MAX_HOT_KEYS: usize = 100_000;
+
+/// How to put a cache in front of a file store, each time it's opened.
+struct CachePlan {
+    capacity: lru::Capacity,
+    write_mode: tiered::WriteMode,
+    /// Shared by every opening, so that counts carry across restarts.
+    stats: Arc<tiered::TieredStats>,
+    hot_keys: tiered::HotKeyLog,
+    rehydrate_rate: Option<f64>,
+}
+
+impl CachePlan {
+    fn cache(&self) -> LruMemoryStore {
+        LruMemoryStore::new(self.capacity)
+    }
+
+    fn build(
+        &self,
+        cache: LruMemoryStore,
+        backend: file_store::FileStore,
+    ) -> Result<TieredStore<LruMemoryStore, file_store::FileStore>> {
+        let mut tiered =
+            TieredStore::new(cache, backend, self.write_mode).with_stats(Arc::clone(&self.stats));
+        // Rehydrate before saving starts, so the save can't replace the keys first.
+        if let Some(rate) = self.rehydrate_rate {
+            tiered = tiered.with_rehydration(&self.hot_keys.path, rate)?;
+        }
+        tiered.with_hot_key_log(self.hot_keys.clone())
+    }
+}
+
+/// Run the load against a store that's crashed and reopened with `reopen` every
+/// `interval`, then log what survived and how quickly latency settled after each
+/// restart. Returns the store as it was after the last restart.
+fn run_restarting<S: Store + chaos::Crash + Send + 'static>(
+    store: S,
+    reopen: impl Fn() -> Result<S> + Send + Sync + 'static,
+    interval: Duration,
+    layers: Layers,
+    load_params: load_test::LoadParams,
+    measure_layer_cost: bool,
+    progress: &load_test::Progress,
+) -> Result<(Vec<load_test::Stats>, S)> {
+    let mut restarting = chaos::RestartingStore::new(store, reopen)?;
+    let restarter = restarting.spawn_restarter(interval);
+    let all_stats = run_load_test(restarting.spawn()?, layers, load_params, measure_layer_cost)?;
+    drop(restarter);
+    let restarts = restarting.stats()?;
+    log::info!(
+        "chaos_restart: {} restarts; {} of {} keys' acknowledged writes survived ({} of {} durable)",
+        restarts.restarts,
+        restarts.survived,
+        restarts.acked,
+        restarts.durable_survived,
+        restarts.durable_acked
+    );
+    let series = progress.time_series()?;
+    if series.is_empty() {
+        log::info!("chaos_restart: sampling is off, so how long latency took to settle after each restart isn't known");
+    } else {
+        chaos::log_recovery(&series, progress.started(), &restarts.restarted_at);
+    }
+    Ok((all_stats, restarting.current()?))
+}
+
+/// An empty file store laid out like the one in `primary_dir`, in `dir`, holding
+/// a copy of `primary`'s keys.
+fn file_standby(
+    primary: &file_store::FileStore,
+    primary_dir: &Path,
+    dir: &Path,
+    write_policy: &file_store::WritePolicy,
+) -> Result<file_store::FileStore> {
+    let manifest = Manifest::load(primary_dir)?;
+    let mut standby = file_store::FileStore::create(
+        dir,
+        manifest.file_count,
+        write_policy,
+        manifest.serializer,
+        manifest.router,
+        manifest.hash,
+    )?;
+    let keys = primary.snapshot()?;
+    for (key, value) in &keys {
+        standby.put(key, value.clone())?;
+    }
+    standby.flush()?;
+    log::info!("Seeded a standby in {:?} with {} keys.", dir, keys.len());
+    Ok(standby)
+}
+
+/// Load a Redis dump into the bare backend, before any layers are applied.
+fn import_dump<S: Store>(
+    store: &mut S,
+    path: &Option<PathBuf>,
+    db: Option<u64>,
+    checkpointing: Option<redis_import::Checkpointing<S>>,
+) -> Result<()> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+    if dump::DumpFormat::is_dump_path(path) {
+        if db.is_some() {
+            bail!("import_db only applies to Redis files");
+        }
+        if checkpointing.is_some() {
+            bail!("import_resume only applies to Redis files");
+        }
+        import_keys(store, path, None, None)?;
+    } else {
+        redis_import::import_resumable(path, store, db, checkpointing)?.summarize();
+    }
+    Ok(())
+}
+
+/// Load an NDJSON or CSV file, or stdin if `input` is "-", into `store`.
+fn import_keys<S: Store>(
+    store: &mut S,
+    input: &Path,
+    format: Option<dump::DumpFormat>,
+    values: Option<dump::ValueEncoding>,
+) -> Result<()> {
+    let format = format.unwrap_or_else(|| dump::DumpFormat::of_path(input));
+    let values = values.unwrap_or_else(|| dump::ValueEncoding::default_for(format));
+    let imported = if input == Path::new("-") {
+        dump::import(std::io::stdin().lock(), format, values, store)?
+    } else {
+        dump::import(BufReader::new(File::open(input)?), format, values, store)
+            .with_context(|| format!("importing {:?}", input))?
+    };
+    log::info!("Imported {} keys from {:?}.", imported, input);
+    Ok(())
+}
+
+fn analyze(path: &Path, json: Option<&Path>) -> Result<()> {
+    let store = file_store::FileStore::open(
+        path,
+        &file_store::WritePolicy::Synchronous {
+            write_period: Duration::ZERO,
+        },
+    )?;
+    log::info!("Analyzing {:?}: {:?}", path, Manifest::load(path)?);
+    store.log_shard_balance()?;
+    let sizes = store.stored_value_sizes()?;
+    sizes.log("value_size_bytes");
+    if let Some(json) = json {
+        serde_json::to_writer_pretty(BufWriter::new(File::create(json)?), &sizes.summaries())?;
+    }
+    Ok(())
+}
+
+/// Exit status of a REPL script in which an assertion failed.
+const REPL_ASSERTION_FAILED: i32 = 1;
+
+/// Exit status of a REPL script that stopped on a malformed command or an error.
+const REPL_ERROR: i32 = 2;
+
+/// The store a REPL session operates on.
+enum ReplTarget {
+    Memory,
+    File(PathBuf),
+    Remote(String, server::Protocol),
+}
+
+/// With `soft_delete_horizon`, the file store is compacted when the session ends.
+fn run_repl(
+    script: Option<&Path>,
+    target: ReplTarget,
+    soft_delete_horizon: Option<Duration>,
+    keys: KeyPolicy,
+    history: Option<PathBuf>,
+) -> Result<()> {
+    if soft_delete_horizon.is_some() && !matches!(target, ReplTarget::File(_)) {
+        bail!("soft_delete_horizon_sec needs a file store; pass --output");
+    }
+    let status = match target {
+        ReplTarget::File(output) => {
+            let mut store = file_store::FileStore::open(
+                &output,
+                &file_store::WritePolicy::Synchronous {
+                    write_period: Duration::ZERO,
+                },
+            )?;
+            if let Some(horizon) = soft_delete_horizon {
+                store = store.with_soft_delete(horizon);
+            }
+            let mut store = CanonicalKeyStore::new(store, keys);
+            let status = repl_session(&mut store, script, history)?;
+            store.inner().compact()?;
+            store.inner().flush()?;
+            status
+        }
+        ReplTarget::Memory => repl_session(
+            &mut CanonicalKeyStore::new(MemoryStore::new(), keys),
+            script,
+            history,
+        )?,
+        ReplTarget::Remote(addr, protocol) => repl_session(
+            &mut CanonicalKeyStore::new(RemoteStore::new(&addr, protocol), keys),
+            script,
+            history,
+        )?,
+    };
+    if status != 0 {
+        std::process::exit(status);
+    }
+    Ok(())
+}
+
+/// Returns the exit status.
+fn repl_session<S: Store>(
+    store: &mut S,
+    script: Option<&Path>,
+    history: Option<PathBuf>,
+) -> Result<i32> {
+    let Some(script) = script else {
+        let mut history = repl::History::load(history)?;
+        repl::run_interactive(
+            store,
+            std::io::stdin().lock(),
+            std::io::stdout(),
+            &mut history,
+        )?;
+        return Ok(0);
+    };
+    let outcome = File::open(script)
+        .map_err(anyhow::Error::from)
+        .and_then(|file| repl::run_script(store, BufReader::new(file), std::io::stdout()));
+    match outcome {
+        Ok(outcome) => {
+            log::info!(
+                "Ran {} commands; {} assertions failed.",
+                outcome.commands,
+                outcome.failed_assertions
+            );
+            Ok(if outcome.failed_assertions > 0 {
+                REPL_ASSERTION_FAILED
+            } else {
+                0
+            })
+        }
+        Err(err) => {
+            eprintln!("error: {:#}", err);
+            Ok(REPL_ERROR)
+        }
+    }
+}
+
+/// Serve `store` on `server` for `duration`, or until the process is killed.
+fn serve<S: Store + 'static>(
+    server: Server,
+    store: &mut S,
+    duration: Option<Duration>,
+) -> Result<()> {
+    let stats = match duration {
+        Some(duration) => server.run(store, crossbeam_channel::after(duration))?,
+        None => server.run(store, crossbeam_channel::never::<()>())?,
+    };
+    stats.log();
+    Ok(())
+}
+
+/// Write the spans recorded so far to `path`, if given.
+fn write_spans(path: Option<&Path>) -> Result<()> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+    let (written, dropped) = spans::write_chrome_trace(BufWriter::new(File::create(path)?))?;
+    log::info!("Wrote {} spans to {:?}.", written, path);
+    if dropped > 0 {
+        log::warn!(
+            "Dropped {} spans past the first {}.",
+            dropped,
+            spans::MAX_SPANS
+        );
+    }
+    Ok(())
+}
+
+/// Subcommands that run the workload against several stores in turn.
+enum MultiRun {
+    Compare(Vec<compare::BackendSpec>),
+    Advise {
+        target: advise::Target,
+        calibration: Duration,
+        output: Option<PathBuf>,
+    },
+}
+
+impl MultiRun {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Compare(_) => "compare",
+            Self::Advise { .. } => "advise",
+        }
+    }
+}
+
+/// Reject workload options that make no sense, whether they are the run's own or
+/// a scenario's.
+fn check_workload_options(opts: &LoadTestOptions) -> Result<()> {
+    if !(0.0..=100.0).contains(&opts.read_percent) {
+        bail!("read_percent must be between 0 and 100");
+    }
+    if !(0.0..=100.0).contains(&opts.durable_percent) {
+        bail!("durable_percent must be between 0 and 100");
+    }
+    if !(0.0..=100.0).contains(&opts.scan_percent) {
+        bail!("scan_percent must be between 0 and 100");
+    }
+    if opts.key_count == 0 {
+        bail!("key_count must be at least 1");
+    }
+    if opts.total_ops == Some(0) {
+        bail!("total_ops must be at least 1");
+    }
+    if matches!(opts.target_ops_per_sec, Some(rate) if !(rate > 0.0 && rate.is_finite())) {
+        bail!("target_ops_per_sec must be positive");
+    }
+    Ok(())
+}
+
+/// `scenarios` are the options of each named scenario to run in turn, if any.
+fn run(
+    mut opts: LoadTestOptions,
+    scenarios: Vec<(String, LoadTestOptions)>,
+    progress: Arc<load_test::Progress>,
+    failure: &FailureContext,
+) -> Result<()> {
+    let key_policy = KeyPolicy {
+        trim: opts.key_trim,
+        fold_case: opts.key_fold_case,
+        max_len: opts.key_max_len,
+        charset: opts.key_charset,
+    };
+    // Taken out, so that the rest of the options can still be borrowed whole.
+    let command = std::mem::replace(&mut opts.command, Command::PrintConfig);
+    let (backend, multi_run) = match command {
+        Command::Backend(backend) => (Some(backend), None),
+        Command::Compare { backends } => (None, Some(MultiRun::Compare(backends))),
+        Command::Advise {
+            target_p99_ms,
+            calibration_sec,
+            output,
+        } => {
+            if !(target_p99_ms > 0.0 && target_p99_ms.is_finite()) {
+                bail!("target_p99_ms must be positive");
+            }
+            if calibration_sec == 0 {
+                bail!("calibration_sec must be at least 1");
+            }
+            if opts.total_ops.is_some() || opts.replay_trace.is_some() {
+                bail!("advise calibrates each candidate for calibration_sec; it cannot be combined with total_ops or replay_trace");
+            }
+            if opts.assert_min_ops_per_sec.is_some() || opts.assert_max_p99_ms.is_some() {
+                bail!("advise recommends a configuration within target_p99_ms; it cannot be combined with assert options");
+            }
+            let advise = MultiRun::Advise {
+                target: advise::Target {
+                    p99_latency: Duration::from_secs_f64(target_p99_ms / 1000.0),
+                },
+                calibration: Duration::from_secs(calibration_sec),
+                output,
+            };
+            (None, Some(advise))
+        }
+        Command::Reshard {
+            output,
+            file_count,
+            router,
+            hash,
+        } => {
+            return file_store::FileStore::reshard(&output, file_count, router, hash);
+        }
+        Command::Verify { output, repair } => {
+            let report = file_store::FileStore::verify(&output, repair)?;
+            for line in report.text_lines() {
+                log::info!("{}", line);
+            }
+            let problems = report.problem_count();
+            if problems > 0 && !repair {
+                bail!(
+                    "Found {} problems in {:?}; run with --repair to fix them",
+                    problems,
+                    output
+                );
+            }
+            return Ok(());
+        }
+        Command::Convert {
+            output,
+            serializer,
+            file_count,
+            router,
+            hash,
+        } => {
+            if file_count == Some(0) {
+                bail!("file_count must be at least 1");
+            }
+            return file_store::FileStore::convert(&output, serializer, file_count, router, hash);
+        }
+        Command::Repl {
+            script,
+            output,
+            remote_addr,
+            protocol,
+            history,
+            no_history,
+        } => {
+            let soft_delete_horizon = (opts.soft_delete_horizon_sec > 0)
+                .then(|| Duration::from_secs(opts.soft_delete_horizon_sec));
+            let history = if no_history {
+                None
+            } else {
+                history.or_else(|| {
+                    std::env::var_os("HOME")
+                        .map(|home| Path::new(&home).join(".key_value_store_history"))
+                })
+            };
+            let target = match (output, remote_addr) {
+                (Some(output), _) => ReplTarget::File(output),
+                (None, Some(addr)) => ReplTarget::Remote(addr, protocol),
+                (None, None) => ReplTarget::Memory,
+            };
+            return run_repl(
+                script.as_deref(),
+                target,
+                soft_delete_horizon,
+                key_policy,
+                history,
+            );
+        }
+        Command::Analyze { output, json } => {
+            return analyze(&output, json.as_deref());
+        }
+        Command::Serve {
+            listen,
+            protocol,
+            output,
+            file_count,
+            write_policy,
+            max_connections,
+            duration_sec,
+        } => {
+            if max_connections == 0 {
+                bail!("max_connections must be at least 1");
+            }
+            let server = Server::bind(&listen, max_connections, protocol)?;
+            log::info!("Listening on {} for {}", server.local_addr()?, protocol);
+            let duration = duration_sec.map(Duration::from_secs);
+            let Some(output) = output else {
+                if file_count.is_some() {
+                    bail!("file_count needs a file store; pass --output");
+                }
+                let mut store = CanonicalKeyStore::new(MemoryStore::new(), key_policy);
+                return serve(server, &mut store, duration);
+            };
+            let store = if file_store::FileStore::exists(&output) {
+                file_store::FileStore::open(&output, &write_policy)?
+            } else if let Some(file_count) = file_count {
+                file_store::FileStore::create(
+                    &output,
+                    file_count,
+                    &write_policy,
+                    file_store::Serializer::Json,
+                    Default::default(),
+                    Default::default(),
+                )?
+            } else {
+                bail!("Must set file_count when creating a new store");
+            };
+            let mut store = CanonicalKeyStore::new(store, key_policy);
+            serve(server, &mut store, duration)?;
+            return store.inner().flush();
+        }
+        // Printed by main, which has the arguments.
+        Command::PrintConfig => return Ok(()),
+        Command::Version => {
+            let build = BuildInfo::current();
+            return match &opts.output_file {
+                Some(path) => build.write(opts.output_format, BufWriter::new(File::create(path)?)),
+                None => build.write(opts.output_format, std::io::stdout().lock()),
+            };
+        }
+        Command::Export {
+            output,
+            sql,
+            table,
+            resume,
+        } => {
+            let store = file_store::FileStore::open(
+                &output,
+                &file_store::WritePolicy::Synchronous {
+                    write_period: Duration::ZERO,
+                },
+            )?;
+            let mut rows = store.snapshot()?;
+            export::write_sql_resumable(&mut rows, &table, &sql, resume)?;
+            log::info!("Exported {} keys to {:?}.", rows.len(), sql);
+            return Ok(());
+        }
+        Command::Dump { output, values } => {
+            let store = file_store::FileStore::open(
+                &output,
+                &file_store::WritePolicy::Synchronous {
+                    write_period: Duration::ZERO,
+                },
+            )?;
+            let mut rows = store.snapshot()?;
+            match &opts.output_file {
+                Some(path) => {
+                    dump::write_ndjson(&mut rows, values, BufWriter::new(File::create(path)?))?
+                }
+                None => {
+                    dump::write_ndjson(&mut rows, values, BufWriter::new(std::io::stdout().lock()))?
+                }
+            }
+            log::info!("Dumped {} keys.", rows.len());
+            return Ok(());
+        }
+        Command::Import {
+            input,
+            format,
+            values,
+            output,
+            file_count,
+            remote_addr,
+            protocol,
+        } => {
+            let Some(output) = output else {
+                let addr = remote_addr.unwrap_or_default();
+                let mut store =
+                    CanonicalKeyStore::new(RemoteStore::new(&addr, protocol), key_policy);
+                return import_keys(&mut store, &input, format, values);
+            };
+            // Each flush rewrites a whole shard, so a bulk load flushes rarely, and
+            // once more at the end.
+            let write_policy = file_store::WritePolicy::Synchronous {
+                write_period: Duration::from_secs(1),
+            };
+            let store = if file_store::FileStore::exists(&output) {
+                file_store::FileStore::open(&output, &write_policy)?
+            } else if let Some(file_count) = file_count {
+                file_store::FileStore::create(
+                    &output,
+                    file_count,
+                    &write_policy,
+                    file_store::Serializer::Json,
+                    Default::default(),
+                    Default::default(),
+                )?
+            } else {
+                bail!("Must set file_count when creating a new store");
+            };
+            let mut store = CanonicalKeyStore::new(store, key_policy);
+            // Keys loaded before a malformed line are kept.
+            let imported = import_keys(&mut store, &input, format, values);
+            store.inner().flush()?;
+            return imported;
+        }
+    };
+    check_workload_options(&opts)?;
+    for (name, scenario) in &scenarios {
+        check_workload_options(scenario).with_context(|| format!("In scenario {:?}", name))?;
+    }
+    if matches!(opts.cpu_limit, Some(cpus) if !(cpus > 0.0 && cpus.is_finite())) {
+        bail!("cpu_limit must be positive");
+    }
+    if matches!(opts.assert_min_ops_per_sec, Some(rate) if !(rate > 0.0 && rate.is_finite())) {
+        bail!("assert_min_ops_per_sec must be positive");
+    }
+    if matches!(opts.assert_max_p99_ms, Some(ms) if !(ms > 0.0 && ms.is_finite())) {
+        bail!("assert_max_p99_ms must be positive");
+    }
+    let thresholds = load_test::Thresholds {
+        min_ops_per_sec: opts.assert_min_ops_per_sec,
+        max_p99_latency: opts
+            .assert_max_p99_ms
+            .map(|ms| Duration::from_secs_f64(ms / 1000.0)),
+    };
+    let resource_limits = limits::ResourceLimits {
+        cpus: opts.cpu_limit,
+        memory_bytes: opts.memory_limit_mb.map(|mb| mb * 1024 * 1024),
+        mode: opts.limit_mode,
+    };
+    // Applied before any store is opened, so loading counts against the limits.
+    let limiter = (!resource_limits.is_empty())
+        .then(|| limits::Limiter::apply(&resource_limits))
+        .transpose()?;
+    let replay = opts
+        .replay_trace
+        .as_deref()
+        .map(trace::Trace::load)
+        .transpose()?
+        .map(Arc::new);
+    let mut threads = opts.threads;
+    if let Some(trace) = &replay {
+        if opts.target_ops_per_sec.is_some() || opts.total_ops.is_some() || opts.warmup_sec > 0 {
+            bail!("replay_trace follows the trace's timing; it cannot be combined with target_ops_per_sec, total_ops or warmup_sec");
+        }
+        if opts.workload.is_some() {
+            bail!(
+                "replay_trace replays the trace's operations; it cannot be combined with workload"
+            );
+        }
+        log::info!(
+            "Replaying {} operations from {} threads.",
+            trace.events(),
+            trace.threads()
+        );
+        if opts.diff_results && !trace.has_results() {
+            bail!("The trace has no results to compare; record it with record_results");
+        }
+        threads = trace.threads();
+    }
+    if !scenarios.is_empty() && (replay.is_some() || multi_run.is_some() || opts.layer_cost) {
+        bail!("Scenarios run one after another against one store; they cannot be combined with replay_trace, compare, advise or layer_cost");
+    }
+    if opts.record_results && !opts.validate && opts.threads != 1 {
+        bail!("record_results needs validate or a single thread, so that each key's results are repeatable");
+    }
+    let record_trace = opts
+        .record_trace
+        .as_deref()
+        .map(trace::TraceRecorder::create)
+        .transpose()?
+        .map(|recorder| {
+            Arc::new(if opts.record_results {
+                recorder.with_results()
+            } else {
+                recorder
+            })
+        });
+    let history = opts
+        .check_linearizability
+        .then(|| Arc::new(consistency::History::new()));
+    if opts.trace_output.is_some() {
+        spans::install()?;
+    }
+    let seed = opts.seed.unwrap_or_else(rand::random);
+    log::info!(
+        "Using seed {}; pass --seed {} to repeat this run.",
+        seed,
+        seed
+    );
+    // Every scenario's parameters are built as the run's own are, from its options.
+    let workload_params = |opts: &LoadTestOptions, threads| load_test::LoadParams {
+        threads,
         load_pattern: opts.pattern,
         tot_time: Duration::from_secs(opts.load_time_sec),
+        total_ops: opts.total_ops,
+        seed: Some(seed),
+        warmup: Duration::from_secs(opts.warmup_sec),
+        read_percent: opts.read_percent,
+        durable_percent: opts.durable_percent,
+        scan_percent: opts.scan_percent,
+        key_distribution: opts
+            .workload
+            .map(ycsb::YcsbWorkload::key_distribution)
+            .unwrap_or(opts.key_distribution),
+        key_count: opts.key_count,
+        key_prefix: opts.key_prefix.clone(),
+        preload_keys: opts.preload_keys,
+        value_shape: opts.value_shape,
+        value_size: opts.value_size_bytes,
+        value_depth: opts.value_depth,
+        self_test: opts.self_test,
+        validate: opts.validate,
+        workload: opts
+            .workload
+            .map(|workload| Arc::new(ycsb::Ycsb::new(workload)) as Arc<dyn load_test::WorkloadOp>),
+        progress: Some(Arc::clone(&progress)),
+        sample_interval: (opts.sample_interval_ms > 0)
+            .then(|| Duration::from_millis(opts.sample_interval_ms)),
+        target_rate: opts
+            .target_ops_per_sec
+            .map(|ops_per_sec| load_test::TargetRate {
+                ops_per_sec,
+                arrivals: opts.arrivals,
+            }),
+        record_trace: record_trace.clone(),
+        history: history.clone(),
+        budget: limiter.as_ref().and_then(limits::Limiter::budget),
+        abort_on_error: opts.abort_on_error,
+        replay: None,
+        compare_results: opts.diff_results,
+        scenarios: Vec::new(),
     };
-    let all_stats = match opts.backend {
-        Backend::Memory => load_test::load_test(MemoryStore::new(), load_params),
+    let scenarios = scenarios
+        .iter()
+        .map(|(name, scenario)| load_test::Scenario {
+            name: name.clone(),
+            params: workload_params(scenario, scenario.threads),
+        })
+        .collect();
+    let load_params = load_test::LoadParams {
+        replay,
+        scenarios,
+        ..workload_params(&opts, threads)
+    };
+    let mut hooks = Hooks::new();
+    if let Some(command) = &opts.pre_write_command {
+        hooks.pre_write_command(command);
+    }
+    if let Some(command) = &opts.post_write_command {
+        hooks.post_write_command(command);
+    }
+    let read_script =
+        |path: &Option<PathBuf>| path.as_ref().map(std::fs::read_to_string).transpose();
+    let scripts = Scripts {
+        setup: read_script(&opts.setup_script)?,
+        write_procedure: read_script(&opts.write_procedure)?,
+    };
+    let layers = Layers {
+        chaos: opts
+            .chaos_schedule
+            .as_deref()
+            .map(ChaosSchedule::load)
+            .transpose()?,
+        read_through: match (&opts.read_through_url, &opts.read_through_store) {
+            (Some(template), _) => Some(Upstream::Http(HttpStore::new(template)?)),
+            (None, Some(path)) => Some(Upstream::FileStore(path.clone())),
+            (None, None) => None,
+        },
+        ttl: opts.ttl_ms.map(Duration::from_millis),
+        expiry_mode: opts.expiry_mode,
+        scripts,
+        hooks,
+        keys: key_policy.clone(),
+    };
+    if opts.layer_cost && !layers.is_empty() {
+        bail!("layer_cost measures its own layers; it cannot be combined with chaos, read-through, TTL, script, hook or key options");
+    }
+    let failover = opts.failover_after_sec.map(|after_sec| {
+        (
+            Duration::from_secs(after_sec),
+            Duration::from_millis(opts.promotion_timeout_ms),
+        )
+    });
+    if failover.is_some() && opts.layer_cost {
+        bail!("layer_cost measures the bare store; it cannot be combined with failover_after_sec");
+    }
+    let live_stats = |queue_depth: Option<load_test::Gauge>| {
+        (opts.live_stats_interval_sec > 0).then(|| {
+            progress.spawn_live_stats(
+                Duration::from_secs(opts.live_stats_interval_sec),
+                queue_depth,
+            )
+        })
+    };
+    let Some(backend) = backend else {
+        let Some(multi_run) = multi_run else {
+            bail!("No backend to run against");
+        };
+        let name = multi_run.name();
+        if failover.is_some() {
+            bail!(
+                "{} runs each backend bare; it cannot be combined with failover_after_sec",
+                name
+            );
+        }
+        if !layers.is_empty() || opts.layer_cost {
+            bail!("{} runs each backend bare; it cannot be combined with chaos, read-through, TTL, script, hook, key or layer_cost options", name);
+        }
+        if opts.import.is_some() || opts.soft_delete_horizon_sec > 0 {
+            bail!("{} creates a fresh store for each backend; it cannot be combined with import or soft_delete_horizon_sec", name);
+        }
+        if opts.background_error_policy != file_store::BackgroundErrorPolicy::default()
+            || opts.slow_op_ms.is_some()
+        {
+            bail!(
+                "{} creates its own stores; it cannot be combined with background_error_policy or slow_op_ms",
+                name
+            );
+        }
+        if history.is_some() || record_trace.is_some() {
+            bail!("{} runs the workload more than once; it cannot be combined with check_linearizability or record_trace", name);
+        }
+        let _live_stats = live_stats(None);
+        let compared = match multi_run {
+            MultiRun::Compare(compared) => compared,
+            MultiRun::Advise {
+                target,
+                calibration,
+                output,
+            } => {
+                let load_params = load_test::LoadParams {
+                    tot_time: calibration,
+                    ..load_params
+                };
+                let advice = advise::advise(&load_params, target)?;
+                for line in advice.text_lines() {
+                    log::info!("{}", line);
+                }
+                if let Some(path) = &output {
+                    advice.write_script(&load_params, BufWriter::new(File::create(path)?))?;
+                    log::info!("Wrote the recommended configuration to {:?}.", path);
+                }
+                return write_spans(opts.trace_output.as_deref());
+            }
+        };
+        let comparison = compare::run(&compared, &load_params)?;
+        for line in comparison.text_lines() {
+            log::info!("{}", line);
+        }
+        if opts.output_format != load_test::OutputFormat::Text || opts.output_file.is_some() {
+            match &opts.output_file {
+                Some(path) => {
+                    comparison.write(opts.output_format, BufWriter::new(File::create(path)?))?
+                }
+                None => comparison.write(opts.output_format, std::io::stdout().lock())?,
+            }
+        }
+        write_spans(opts.trace_output.as_deref())?;
+        let failures: Vec<String> = comparison
+            .backends
+            .iter()
+            .flat_map(|result| {
+                thresholds
+                    .check(result.ops_per_sec, result.p99_latency_us)
+                    .into_iter()
+                    .map(move |failure| format!("{}: {}", result.backend, failure))
+            })
+            .collect();
+        return check_thresholds(&failures);
+    };
+    // Set if a background error failed the file store under the fail-store policy.
+    let mut store_failure = None;
+    // Set if the run had a tiered cache, for the summary.
+    let mut cache_summary = None;
+    let all_stats = match backend {
+        Backend::Memory {
+            shards,
+            lock,
+            implementation,
+            max_entries,
+            max_bytes,
+        } => {
+            if shards == 0 {
+                bail!("shards must be at least 1");
+            }
+            if max_entries == Some(0) || max_bytes == Some(0) {
+                bail!("max_entries and max_bytes must be at least 1");
+            }
+            let capacity = lru::Capacity {
+                max_entries,
+                max_bytes,
+            };
+            if capacity != lru::Capacity::default()
+                && (shards > 1
+                    || lock != MemoryLock::Mutex
+                    || implementation != MemoryImpl::Hashmap)
+            {
+                bail!("max_entries and max_bytes bound a single LRU map; drop --shards, --lock and --impl");
+            }
+            if implementation == MemoryImpl::Dashmap && (shards > 1 || lock != MemoryLock::Mutex) {
+                bail!("--impl dashmap picks its own shards and locks; drop --shards and --lock");
+            }
+            if shards > 1 && lock == MemoryLock::Rwlock {
+                bail!("--lock rwlock applies to an unsharded store; drop --shards");
+            }
+            if opts.import_resume {
+                bail!("import_resume needs a file store to checkpoint into");
+            }
+            if opts.durable_percent > 0.0 {
+                bail!("durable_percent needs a file store to persist to");
+            }
+            if opts.soft_delete_horizon_sec > 0 {
+                bail!("soft_delete_horizon_sec needs a file store to keep tombstones in");
+            }
+            if opts.background_error_policy != file_store::BackgroundErrorPolicy::default() {
+                bail!("background_error_policy applies to a file store's background threads");
+            }
+            if opts.slow_op_ms.is_some() {
+                bail!("slow_op_ms logs a file store's shards and flushes; the memory store has neither");
+            }
+            if failover.is_some() && opts.import.is_some() {
+                bail!("failover_after_sec can't copy imported keys to a memory store's standby; import into a file store instead");
+            }
+            let _live_stats = live_stats(None);
+            if capacity != lru::Capacity::default() {
+                let backend = LruMemoryStore::new(capacity);
+                let stats = backend.stats();
+                let all_stats = run_memory(
+                    backend,
+                    || LruMemoryStore::new(capacity),
+                    &opts,
+                    key_policy,
+                    failover,
+                    layers,
+                    load_params,
+                )?;
+                stats.summarize();
+                Ok(all_stats)
+            } else if implementation == MemoryImpl::Dashmap {
+                run_memory(
+                    ConcurrentMemoryStore::new(),
+                    ConcurrentMemoryStore::new,
+                    &opts,
+                    key_policy,
+                    failover,
+                    layers,
+                    load_params,
+                )
+            } else if lock == MemoryLock::Rwlock {
+                run_memory(
+                    RwLockMemoryStore::new(),
+                    RwLockMemoryStore::new,
+                    &opts,
+                    key_policy,
+                    failover,
+                    layers,
+                    load_params,
+                )
+            } else if shards == 1 {
+                run_memory(
+                    MemoryStore::new(),
+                    MemoryStore::new,
+                    &opts,
+                    key_policy,
+                    failover,
+                    layers,
+                    load_params,
+                )
+            } else {
+                run_memory(
+                    ShardedMemoryStore::new(shards),
+                    || ShardedMemoryStore::new(shards),
+                    &opts,
+                    key_policy,
+                    failover,
+                    layers,
+                    load_params,
+                )
+            }
+        }
+        Backend::Remote { addr, protocol } => {
+            let _live_stats = live_stats(None);
+            run_remote(
+                RemoteStore::new(&addr, protocol),
+                &opts,
+                key_policy,
+                failover,
+                layers,
+                load_params,
+            )
+        }
+        Backend::Redis { url } => {
+            let _live_stats = live_stats(None);
+            run_remote(
+                RemoteStore::redis(&url),
+                &opts,
+                key_policy,
+                failover,
+                layers,
+                load_params,
+            )
+        }
         Backend::File {
             output,
             file_count,
             write_period_us,
             queue_depth,
+            snapshot_interval_ms,
+            no_wal,
+            queue_full_policy,
+            writer_threads,
             serializer,
+            migrate_serializer,
+            read_only,
+            router,
+            hash,
+            read_repair_rate,
+            max_persistence_lag_ms,
+            delta_snapshot_every,
+            max_segment_bytes,
+            flush_order,
+            policy_switch,
+            shard_stats_interval_sec,
+            shard_heatmap,
+            heatmap_bucket_ms,
+            op_journal_size,
+            chaos_restart_interval_sec,
+            cache_entries,
+            cache_bytes,
+            cache_write_mode,
+            cache_rehydrate_rate,
+            cache_hot_keys_interval_ms,
         } => {
-            let (output_path, _tmp_path) = if let Some(output_path) = output {
+            let soft_delete_horizon_sec = opts.soft_delete_horizon_sec;
+            let background_error_policy = opts.background_error_policy;
+            let slow_op_threshold = opts.slow_op_ms.map(Duration::from_millis);
+            let (output_path, tmp_path) = if let Some(output_path) = output {
                 (output_path, None)
             } else {
                 let tmp_path = tempfile::tempdir()?;
                 (tmp_path.path().to_path_buf(), Some(tmp_path))
             };
+            failure.track_store_dir(output_path.clone(), tmp_path)?;
 
-            if write_period_us.is_some() && queue_depth.is_some() {
-                bail!("Cannot set both write_period_us and queue_depth");
+            if [
+                write_period_us.is_some(),
+                queue_depth.is_some(),
+                snapshot_interval_ms.is_some(),
+            ]
+            .iter()
+            .filter(|set| **set)
+            .count()
+                > 1
+            {
+                bail!("Cannot set more than one of write_period_us, queue_depth and snapshot_interval_ms");
+            }
+            if no_wal && snapshot_interval_ms.is_none() {
+                bail!("no_wal requires snapshot_interval_ms");
+            }
+            if read_only
+                && (write_period_us.is_some()
+                    || queue_depth.is_some()
+                    || snapshot_interval_ms.is_some()
+                    || migrate_serializer
+                    || chaos_restart_interval_sec > 0
+                    || !policy_switch.is_empty()
+                    || soft_delete_horizon_sec > 0
+                    || failover.is_some())
+            {
+                bail!("read_only opens the store without writers; it cannot be combined with a write period, queue depth or snapshot interval, migrate_serializer, chaos_restart_interval_sec, policy_switch, soft_delete_horizon_sec or failover_after_sec");
+            }
+            if chaos_restart_interval_sec > 0
+                && (!policy_switch.is_empty()
+                    || shard_heatmap.is_some()
+                    || op_journal_size.is_some()
+                    || soft_delete_horizon_sec > 0
+                    || failover.is_some())
+            {
+                bail!("chaos_restart_interval_sec replaces the store mid-run; it cannot be combined with policy_switch, shard_heatmap, op_journal_size, soft_delete_horizon_sec or failover_after_sec");
+            }
+            if cache_entries == Some(0) || cache_bytes == Some(0) {
+                bail!("cache_entries and cache_bytes must be at least 1");
+            }
+            let cache =
+                (cache_entries.is_some() || cache_bytes.is_some()).then_some(lru::Capacity {
+                    max_entries: cache_entries,
+                    max_bytes: cache_bytes,
+                });
+            if cache.is_some() && (soft_delete_horizon_sec > 0 || failover.is_some()) {
+                bail!("cache_entries and cache_bytes cache the store in memory; they cannot be combined with soft_delete_horizon_sec or failover_after_sec");
+            }
+            if cache_rehydrate_rate.is_some() && cache.is_none() {
+                bail!("cache_rehydrate_rate needs cache_entries or cache_bytes");
             }
+            let cache_plan = cache.map(|capacity| CachePlan {
+                capacity,
+                write_mode: cache_write_mode,
+                stats: Arc::new(tiered::TieredStats::default()),
+                hot_keys: tiered::HotKeyLog {
+                    path: output_path.join(HOT_KEYS),
+                    interval: Duration::from_millis(cache_hot_keys_interval_ms.max(1)),
+                    limit: capacity
+                        .max_entries
+                        .unwrap_or(MAX_HOT_KEYS)
+                        .min(MAX_HOT_KEYS),
+                },
+                rehydrate_rate: cache_rehydrate_rate,
+            });
 
             let write_policy = if let Some(write_period_us) = write_period_us {
                 file_store::WritePolicy::Synchronous {
                     write_period: Duration::from_micros(write_period_us),
                 }
             } else if let Some(queue_depth) = queue_depth {
+                if queue_depth == 0 {
+                    bail!("The asynchronous queue depth must be at least 1");
+                }
                 file_store::WritePolicy::Asynchronous { queue_depth }
+            } else if let Some(interval_ms) = snapshot_interval_ms {
+                if interval_ms == 0 {
+                    bail!("snapshot_interval_ms must be at least 1");
+                }
+                file_store::WritePolicy::Hybrid {
+                    wal: !no_wal,
+                    snapshot_interval: Duration::from_millis(interval_ms),
+                }
+            } else if read_only {
+                // Never used: read-only shards have no writers.
+                file_store::WritePolicy::Synchronous {
+                    write_period: Duration::ZERO,
+                }
             } else {
-                bail!("Must set either a queue depth or write period");
+                bail!("Must set a queue depth, write period or snapshot interval");
             };
 
-            let backend =
-                file_store::FileStore::new(&output_path, file_count, &write_policy, serializer)?;
-            load_test::load_test(backend, load_params)
+            let mut backend = if read_only {
+                if !file_store::FileStore::exists(&output_path) {
+                    bail!("read_only requires an existing store");
+                }
+                let manifest = Manifest::load(&output_path)?;
+                manifest.validate(&output_path, file_count, serializer.as_ref(), router, hash)?;
+                log::info!(
+                    "Opening existing store at {:?} read-only: {:?}",
+                    output_path,
+                    manifest
+                );
+                file_store::FileStore::open_read_only(&output_path)?
+            } else if file_store::FileStore::exists(&output_path) {
+                if let (true, Some(serializer)) = (migrate_serializer, &serializer) {
+                    file_store::FileStore::migrate_serializer(&output_path, serializer.clone())?;
+                }
+                let manifest = Manifest::load(&output_path)?;
+                manifest.validate(&output_path, file_count, serializer.as_ref(), router, hash)?;
+                log::info!(
+                    "Opening existing store at {:?}: {:?}",
+                    output_path,
+                    manifest
+                );
+                file_store::FileStore::open(&output_path, &write_policy)?
+            } else if let Some(file_count) = file_count {
+                let serializer = serializer.unwrap_or(file_store::Serializer::Json);
+                file_store::FileStore::create(
+                    &output_path,
+                    file_count,
+                    &write_policy,
+                    serializer,
+                    router.unwrap_or_default(),
+                    hash.unwrap_or_default(),
+                )?
+            } else {
+                bail!("Must set file_count when creating a new store");
+            };
+            backend = backend
+                .with_background_error_policy(opts.background_error_policy)?
+                .with_queue_full_policy(queue_full_policy)?;
+            if let Some(threads) = writer_threads {
+                backend = backend.with_writer_threads(threads)?;
+            }
+            failure.track_health(backend.health_handle())?;
+            if let Some(threshold) = slow_op_threshold {
+                backend = backend.with_slow_op_log(threshold);
+            }
+            if let Some(sample_rate) = read_repair_rate {
+                backend = backend.with_read_repair(sample_rate);
+            }
+            if let Some(max_lag_ms) = max_persistence_lag_ms {
+                backend = backend.with_flusher(Duration::from_millis(max_lag_ms), flush_order)?;
+            }
+            if let Some(snapshot_every) = delta_snapshot_every {
+                backend = backend.with_delta_persistence(snapshot_every)?;
+            }
+            if let Some(max_bytes) = max_segment_bytes {
+                backend = backend.with_max_segment_bytes(max_bytes)?;
+            }
+            if let Some(capacity) = op_journal_size {
+                backend = backend.with_journal(capacity)?;
+                failure.track_journal(backend.journal())?;
+            }
+            let soft_delete_horizon = Duration::from_secs(soft_delete_horizon_sec);
+            if soft_delete_horizon_sec > 0 {
+                backend = backend.with_soft_delete(soft_delete_horizon);
+            }
+            let checkpointing = opts.import_resume.then(|| redis_import::Checkpointing {
+                path: output_path.join(IMPORT_CHECKPOINT),
+                resume: true,
+                flush: &|store: &mut CanonicalKeyStore<file_store::FileStore>| {
+                    store.inner().flush()
+                },
+            });
+            let mut importer = CanonicalKeyStore::new(backend.spawn()?, key_policy);
+            import_dump(&mut importer, &opts.import, opts.import_db, checkpointing)?;
+            // Kept until the end of the run, along with the standby in it.
+            let standby_dir = failover.is_some().then(tempfile::tempdir).transpose()?;
+            let mut failover = match (failover, &standby_dir) {
+                (Some((after, timeout)), Some(dir)) => Some(FailoverPlan {
+                    standby: file_standby(&backend, &output_path, dir.path(), &write_policy)?,
+                    after,
+                    timeout,
+                }),
+                _ => None,
+            };
+            let standby_probe = failover
+                .as_mut()
+                .map(|plan| plan.standby.spawn())
+                .transpose()?;
+            let mut probe = backend.spawn()?;
+            if load_params.self_test {
+                self_test::check_persistence(&mut probe)?;
+            }
+            let policy_switcher =
+                (!policy_switch.is_empty()).then(|| probe.spawn_policy_switcher(policy_switch));
+            let stats_logger = (shard_stats_interval_sec > 0 && chaos_restart_interval_sec == 0)
+                .then(|| probe.spawn_stats_logger(Duration::from_secs(shard_stats_interval_sec)));
+            let heatmap = shard_heatmap
+                .is_some()
+                .then(|| probe.spawn_heatmap(Duration::from_millis(heatmap_bucket_ms.max(1))));
+            // A restart replaces the store's writers, so only the first would be read.
+            let queue_depth = (chaos_restart_interval_sec == 0)
+                .then(|| probe.spawn())
+                .transpose()?
+                .map(|gauge| Box::new(move || gauge.queue_depth()) as load_test::Gauge);
+            let live_stats = live_stats(queue_depth);
+            let compactor = (soft_delete_horizon_sec > 0).then(|| {
+                probe.spawn_compactor(
+                    (soft_delete_horizon / COMPACTIONS_PER_HORIZON).max(MIN_COMPACTION_INTERVAL),
+                )
+            });
+            let all_stats = if chaos_restart_interval_sec > 0 {
+                let reopen = move || {
+                    let mut store = file_store::FileStore::open(&output_path, &write_policy)?
+                        .with_background_error_policy(background_error_policy)?
+                        .with_queue_full_policy(queue_full_policy)?;
+                    if let Some(threads) = writer_threads {
+                        store = store.with_writer_threads(threads)?;
+                    }
+                    if let Some(sample_rate) = read_repair_rate {
+                        store = store.with_read_repair(sample_rate);
+                    }
+                    if let Some(max_lag_ms) = max_persistence_lag_ms {
+                        store =
+                            store.with_flusher(Duration::from_millis(max_lag_ms), flush_order)?;
+                    }
+                    if let Some(snapshot_every) = delta_snapshot_every {
+                        store = store.with_delta_persistence(snapshot_every)?;
+                    }
+                    if let Some(max_bytes) = max_segment_bytes {
+                        store = store.with_max_segment_bytes(max_bytes)?;
+                    }
+                    if let Some(threshold) = slow_op_threshold {
+                        store = store.with_slow_op_log(threshold);
+                    }
+                    Ok(store)
+                };
+                let interval = Duration::from_secs(chaos_restart_interval_sec);
+                let (all_stats, current) = if let Some(plan) = cache_plan {
+                    let tiered = plan.build(plan.cache(), backend)?;
+                    let reopen = move || plan.build(plan.cache(), reopen()?);
+                    let (all_stats, current) = run_restarting(
+                        tiered,
+                        reopen,
+                        interval,
+                        layers,
+                        load_params,
+                        opts.layer_cost,
+                        &progress,
+                    )?;
+                    let tiered_stats = current.stats();
+                    tiered_stats.summarize();
+                    cache_summary = Some(load_test::CacheSummary::new(&tiered_stats));
+                    (all_stats, current.spawn_backing()?)
+                } else {
+                    run_restarting(
+                        backend,
+                        reopen,
+                        interval,
+                        layers,
+                        load_params,
+                        opts.layer_cost,
+                        &progress,
+                    )?
+                };
+                // The rest of the summary describes the store since the last restart.
+                probe = current;
+                all_stats
+            } else if let Some(plan) = cache_plan {
+                let lru = plan.cache();
+                let lru_stats = lru.stats();
+                let mut tiered = plan.build(lru, backend)?;
+                let tiered_stats = tiered.stats();
+                let tier_probe = tiered.spawn()?;
+                let all_stats = run_load_test(tiered, layers, load_params, opts.layer_cost)?;
+                let written = tier_probe.write_back()?;
+                if written > 0 {
+                    log::info!("Wrote back {} dirty keys at the end of the run.", written);
+                }
+                tiered_stats.summarize();
+                lru_stats.summarize();
+                cache_summary = Some(load_test::CacheSummary::new(&tiered_stats));
+                all_stats
+            } else {
+                run_with_failover(backend, failover, layers, load_params, opts.layer_cost)?
+            };
+            drop(live_stats);
+            drop(stats_logger);
+            drop(policy_switcher);
+            drop(compactor);
+            if let (Some(recorder), Some(path)) = (heatmap, &shard_heatmap) {
+                recorder.finish()?.save(path)?;
+            }
+            let health = probe.health()?;
+            health.log();
+            if health.failed {
+                store_failure = Some(health.errors);
+            }
+            log::info!(
+                "store_startup_ms: {:.2}",
+                probe.startup_time().as_secs_f64() * 1000.0
+            );
+            probe.log_shard_balance()?;
+            probe.log_lag_gauges()?;
+            probe.log_write_phases()?;
+            if opts.durable_percent > 0.0 {
+                let commits = probe.group_commit_stats()?;
+                log::info!(
+                    "group_commit: {} durable puts in {} syncs",
+                    commits.durable_puts,
+                    commits.syncs
+                );
+            }
+            probe.written_value_sizes()?.log("written_value_size_bytes");
+            if let Some(stats) = probe.read_repair_stats() {
+                stats.summarize();
+            }
+            if let Some(stats) = probe.soft_delete_stats() {
+                stats.summarize();
+            }
+            if let Some(standby) = standby_probe {
+                // Drain its writers before its directory is removed.
+                standby.flush()?;
+            }
+            drop(standby_dir);
+            Ok(all_stats)
         }
     }?;
 
     load_test::summarize(&all_stats)?;
+    if let Some(limiter) = &limiter {
+        limiter.throttling()?.log();
+    }
+    let time_series = progress.time_series()?;
+    load_test::summarize_time_series(&all_stats, &time_series);
+    if let (Some(recorder), Some(path)) = (&record_trace, &opts.record_trace) {
+        log::info!("Recorded {} operations to {:?}.", recorder.finish()?, path);
+    }
+    write_spans(opts.trace_output.as_deref())?;
+    if opts.output_format != load_test::OutputFormat::Text || opts.output_file.is_some() {
+        let summary = load_test::Summary::new(&all_stats)?
+            .with_time_series(time_series)
+            .with_cache(cache_summary);
+        match &opts.output_file {
+            Some(path) => summary.write(opts.output_format, BufWriter::new(File::create(path)?))?,
+            None => summary.write(opts.output_format, std::io::stdout().lock())?,
+        }
+    }
+    if let Some(errors) = store_failure {
+        bail!(
+            "The store failed after a background error ({} background errors in all)",
+            errors
+        );
+    }
+    let divergences: i64 = all_stats.iter().map(|s| s.divergences).sum();
+    if divergences > 0 {
+        bail!(
+            "{} replayed operations returned a different result than when they were recorded",
+            divergences
+        );
+    }
+    let violations: i64 = all_stats.iter().map(|s| s.violations).sum();
+    if violations > 0 {
+        bail!(
+            "{} gets didn't return the value their thread last wrote",
+            violations
+        );
+    }
+    if let Some(history) = &history {
+        let report = consistency::check(history)?;
+        report.log();
+        if !report.violations.is_empty() {
+            bail!(
+                "The operations on {} keys aren't linearizable",
+                report.violations.len()
+            );
+        }
+    }
+    check_thresholds(&thresholds.check_run(&all_stats)?)
+}
+
+/// Log each performance assertion that failed, and fail if any did.
+fn check_thresholds(failures: &[String]) -> Result<()> {
+    for failure in failures {
+        log::error!("Assertion failed: {}", failure);
+    }
+    if !failures.is_empty() {
+        bail!("Performance assertions failed: {}", failures.join("; "));
+    }
     Ok(())
 }
 
+/// The options a config file's `[[scenario]]` tables may set: those of the
+/// workload, rather than of the store or the run as a whole.
+const SCENARIO_OPTIONS: &[&str] = &[
+    "threads",
+    "pattern",
+    "load-time-sec",
+    "total-ops",
+    "warmup-sec",
+    "read-percent",
+    "durable-percent",
+    "scan-percent",
+    "key-distribution",
+    "workload",
+    "key-count",
+    "preload-keys",
+    "value-shape",
+    "value-size-bytes",
+    "value-depth",
+    "target-ops-per-sec",
+    "arrivals",
+];
+
 fn main() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let log_tail = Arc::new(LogTail::new(LOG_TAIL_CAPACITY));
+    artifacts::init_logger(
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")),
+        Arc::clone(&log_tail),
+    )?;
 
-    let opt = LoadTestOptions::from_args();
-    log::info!("Using config: {:#?}", opt);
-    run(opt)?;
+    let invocation =
+        config::Invocation::new(&LoadTestOptions::clap(), std::env::args_os().collect())?;
+    let opt = LoadTestOptions::from_iter(invocation.args());
+    let scenario_args = invocation.scenario_args(SCENARIO_OPTIONS)?;
+    if matches!(opt.command, Command::PrintConfig) {
+        let (args, backend) = match invocation.file_backend_args() {
+            Some(args) => (args, true),
+            None => (invocation.args(), false),
+        };
+        let matches = LoadTestOptions::clap().get_matches_from(args);
+        print!(
+            "# Options of key_value_store {}, for --config.\n{}",
+            BuildInfo::current(),
+            config::render(&matches, backend)
+        );
+        for (name, args) in scenario_args {
+            let matches = LoadTestOptions::clap().get_matches_from(args);
+            print!(
+                "{}",
+                config::render_scenario(&name, &matches, SCENARIO_OPTIONS)
+            );
+        }
+        return Ok(());
+    }
+    let scenarios: Vec<(String, LoadTestOptions)> = scenario_args
+        .into_iter()
+        .map(|(name, args)| (name, LoadTestOptions::from_iter(args)))
+        .collect();
+    if !matches!(opt.command, Command::Version) {
+        log::info!("key_value_store {}", BuildInfo::current());
+        if let Some(path) = &opt.config {
+            log::info!(
+                "Read options from {:?}; the command line's override them.",
+                path
+            );
+        }
+        log::info!("Using config: {:#?}", opt);
+    }
+    let progress = Arc::new(load_test::Progress::new());
+    let failure = Arc::new(FailureContext::new(
+        format!("{:#?}", opt),
+        opt.artifacts_dir.clone().unwrap_or_else(std::env::temp_dir),
+        Arc::clone(&progress),
+        log_tail,
+    ));
+
+    let default_hook = std::panic::take_hook();
+    let panic_failure = Arc::clone(&failure);
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let bundle = panic_failure.write_bundle(&format!("panic: {}", info));
+        // Panics elsewhere surface as an error from `run`, reported below.
+        if std::thread::current().name() == Some("main") {
+            report_bundle(bundle);
+        }
+    }));
+
+    if let Err(err) = run(opt, scenarios, progress, &failure) {
+        report_bundle(failure.write_bundle(&format!("error: {:?}", err)));
+        return Err(err);
+    }
     Ok(())
 }
+
+fn report_bundle(bundle: Result<PathBuf>) {
+    match bundle {
+        Ok(path) => eprintln!("Failure artifacts written to {}", path.display()),
+        Err(err) => eprintln!("Could not write failure artifacts: {:#}", err),
+    }
+}
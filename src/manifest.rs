@@ -0,0 +1,157 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::build_info::BuildInfo;
+use crate::file_store::Serializer;
+use crate::router::{HashFunction, Router};
+use crate::store::StoreError;
+
+/// Name of the manifest file within a store's output directory.
+pub const MANIFEST_FILENAME: &str = "MANIFEST";
+
+/// Version of the on-disk layout; bump whenever the shard file format changes.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Describes the on-disk layout of a file-backed store, so that it can be
+/// reopened without the caller having to pass matching parameters.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub file_count: usize,
+    pub serializer: Serializer,
+    #[serde(default)]
+    pub router: Router,
+    #[serde(default)]
+    pub hash: HashFunction,
+    /// RFC 3339 timestamp of when the store was first created.
+    pub created_at: String,
+    /// Serializer the shards are being converted to by
+    /// `FileStore::migrate_serializer`. Until the conversion finishes, each shard
+    /// may be in either format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub migrating_to: Option<Serializer>,
+    /// Binary that last wrote the manifest, by creating, migrating or resharding
+    /// the store; missing from stores written before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<BuildInfo>,
+}
+
+impl Manifest {
+    pub fn new(
+        file_count: usize,
+        serializer: Serializer,
+        router: Router,
+        hash: HashFunction,
+    ) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            file_count,
+            serializer,
+            router,
+            hash,
+            created_at: chrono::Local::now().to_rfc3339(),
+            migrating_to: None,
+            build: None,
+        }
+    }
+
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join(MANIFEST_FILENAME)
+    }
+
+    pub fn exists(dir: &Path) -> bool {
+        Self::path(dir).exists()
+    }
+
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = Self::path(dir);
+        if !path.exists() {
+            return Err(StoreError::MissingManifest(dir.to_path_buf()).into());
+        }
+        // The manifest is always JSON, regardless of the shard serializer, so
+        // it's easy to inspect by hand.
+        let manifest: Self = serde_json::from_reader(File::open(path)?)?;
+        if manifest.format_version > FORMAT_VERSION {
+            return Err(StoreError::UnsupportedFormatVersion(manifest.format_version).into());
+        }
+        Ok(manifest)
+    }
+
+    /// Check that any explicitly requested parameters agree with what's on disk.
+    pub fn validate(
+        &self,
+        dir: &Path,
+        file_count: Option<usize>,
+        serializer: Option<&Serializer>,
+        router: Option<Router>,
+        hash: Option<HashFunction>,
+    ) -> Result<()> {
+        if let Some(file_count) = file_count {
+            if file_count != self.file_count {
+                return Err(Self::mismatch(
+                    dir,
+                    "file_count",
+                    self.file_count,
+                    file_count,
+                ));
+            }
+        }
+        if let Some(serializer) = serializer {
+            if *serializer != self.serializer {
+                return Err(Self::mismatch(
+                    dir,
+                    "serializer",
+                    &self.serializer,
+                    serializer,
+                ));
+            }
+        }
+        if let Some(router) = router {
+            if router != self.router {
+                return Err(Self::mismatch(dir, "router", self.router, router));
+            }
+        }
+        if let Some(hash) = hash {
+            if hash != self.hash {
+                return Err(Self::mismatch(dir, "hash", self.hash, hash));
+            }
+        }
+        Ok(())
+    }
+
+    fn mismatch<T: std::fmt::Display>(
+        dir: &Path,
+        field: &'static str,
+        stored: T,
+        requested: T,
+    ) -> anyhow::Error {
+        StoreError::ManifestMismatch {
+            path: dir.to_path_buf(),
+            field,
+            stored: stored.to_string(),
+            requested: requested.to_string(),
+        }
+        .into()
+    }
+
+    /// Atomically and durably replace the manifest in `dir`, recording this binary
+    /// as its writer. Both the new manifest and its rename are on disk when this
+    /// returns.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let path = Self::path(dir);
+        let tmp_path = path.with_extension("tmp");
+        let manifest = Self {
+            build: Some(BuildInfo::current()),
+            ..self.clone()
+        };
+        let file = File::create(&tmp_path)?;
+        serde_json::to_writer_pretty(&file, &manifest)?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, path)?;
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+}
@@ -1,8 +1,11 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::ops::{Bound, Range};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 
-use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
+use structopt::clap::arg_enum;
 
 use crate::store::{Blob, Store, StoreError};
 
@@ -20,58 +23,552 @@ impl MemoryStore {
     }
 }
 
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Store for MemoryStore {
-    fn get(&self, key: &str) -> Result<Blob> {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
         let values = self.values.lock().map_err(|_| StoreError::LockError)?;
-        if let Some(value) = values.get(key) {
-            Ok(value.clone())
-        } else {
-            bail!("Key not found: {}", key)
-        }
+        Ok(values.get(key).cloned())
     }
 
-    fn put(&mut self, key: &str, value: Blob) -> Result<()> {
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
         let mut values = self.values.lock().map_err(|_| StoreError::LockError)?;
         values.insert(key.to_string(), value);
         Ok(())
     }
 
-    fn spawn(&mut self) -> Result<Self> {
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        let mut values = self.values.lock().map_err(|_| StoreError::LockError)?;
+        values.remove(key);
+        Ok(())
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
         Ok(MemoryStore {
             values: Arc::clone(&self.values),
         })
     }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Blob)>, StoreError> {
+        let values = self.values.lock().map_err(|_| StoreError::LockError)?;
+        Ok(values
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    /// Filters and sorts every key, as there's no index.
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        let values = self.values.lock().map_err(|_| StoreError::LockError)?;
+        Ok(sorted(in_range(values.iter(), range).collect()))
+    }
+
+    fn describe_stats(&self) -> Result<Vec<String>, StoreError> {
+        let values = self.values.lock().map_err(|_| StoreError::LockError)?;
+        Ok(vec![format!("keys: {}", values.len())])
+    }
+}
+
+/// The entries of `entries` with keys in `range`, for stores with no sorted index.
+fn in_range<'a>(
+    entries: impl Iterator<Item = (&'a String, &'a Blob)> + 'a,
+    range: Range<&'a str>,
+) -> impl Iterator<Item = (String, Blob)> + 'a {
+    entries
+        .filter(move |(key, _)| range.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+}
+
+fn sorted(mut rows: Vec<(String, Blob)>) -> Vec<(String, Blob)> {
+    rows.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+    rows
+}
+
+arg_enum! {
+    /// What guards a memory store's map.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum MemoryLock {
+        // One `Mutex`, which every operation takes in turn: `MemoryStore`.
+        Mutex,
+        // One `RwLock`, which gets share: `RwLockMemoryStore`.
+        Rwlock,
+    }
+}
+
+arg_enum! {
+    /// Which map a memory store keeps its keys in.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum MemoryImpl {
+        // A std `HashMap`, or one per shard, under `MemoryLock`.
+        Hashmap,
+        // A concurrent map in DashMap's design: `ConcurrentMemoryStore`.
+        Dashmap,
+    }
+}
+
+/// `MemoryStore` behind a `RwLock` rather than a `Mutex`, so that gets don't
+/// block each other; puts and deletes still wait for every other operation.
+pub struct RwLockMemoryStore {
+    values: Arc<RwLock<HashMap<String, Blob>>>,
+}
+
+impl RwLockMemoryStore {
+    pub fn new() -> Self {
+        Self {
+            values: Arc::new(RwLock::new(HashMap::with_capacity(128))),
+        }
+    }
+}
+
+impl Default for RwLockMemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Store for RwLockMemoryStore {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        let values = self.values.read().map_err(|_| StoreError::LockError)?;
+        Ok(values.get(key).cloned())
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let mut values = self.values.write().map_err(|_| StoreError::LockError)?;
+        values.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        let mut values = self.values.write().map_err(|_| StoreError::LockError)?;
+        values.remove(key);
+        Ok(())
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(Self {
+            values: Arc::clone(&self.values),
+        })
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Blob)>, StoreError> {
+        let values = self.values.read().map_err(|_| StoreError::LockError)?;
+        Ok(values
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    /// Filters and sorts every key, as there's no index.
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        let values = self.values.read().map_err(|_| StoreError::LockError)?;
+        Ok(sorted(in_range(values.iter(), range).collect()))
+    }
+
+    fn describe_stats(&self) -> Result<Vec<String>, StoreError> {
+        let values = self.values.read().map_err(|_| StoreError::LockError)?;
+        Ok(vec![format!("keys: {}", values.len())])
+    }
+}
+
+/// `MemoryStore` split across independently locked shards, so that threads only
+/// contend for a lock when their keys share a shard. Keys are routed by SipHash.
+pub struct ShardedMemoryStore {
+    shards: Arc<[Mutex<HashMap<String, Blob>>]>,
+}
+
+impl ShardedMemoryStore {
+    /// At least one shard.
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1))
+                .map(|_| Mutex::new(HashMap::with_capacity(128)))
+                .collect(),
+        }
+    }
+
+    fn shard(&self, key: &str) -> Result<MutexGuard<'_, HashMap<String, Blob>>, StoreError> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let index = (hasher.finish() % self.shards.len() as u64) as usize;
+        self.shards[index].lock().map_err(|_| StoreError::LockError)
+    }
+}
+
+impl Store for ShardedMemoryStore {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        Ok(self.shard(key)?.get(key).cloned())
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        self.shard(key)?.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        self.shard(key)?.remove(key);
+        Ok(())
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(Self {
+            shards: Arc::clone(&self.shards),
+        })
+    }
+
+    /// Locks one shard at a time, so it isn't a snapshot of the whole store.
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Blob)>, StoreError> {
+        let mut rows = Vec::new();
+        for shard in self.shards.iter() {
+            let values = shard.lock().map_err(|_| StoreError::LockError)?;
+            rows.extend(
+                values
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(prefix))
+                    .map(|(key, value)| (key.clone(), value.clone())),
+            );
+        }
+        Ok(rows)
+    }
+
+    /// Filters every shard, then sorts what they found. Like `scan`, it isn't a
+    /// snapshot of the whole store.
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        let mut rows = Vec::new();
+        for shard in self.shards.iter() {
+            let values = shard.lock().map_err(|_| StoreError::LockError)?;
+            rows.extend(in_range(values.iter(), range.clone()));
+        }
+        Ok(sorted(rows))
+    }
+
+    fn describe_stats(&self) -> Result<Vec<String>, StoreError> {
+        let mut counts = Vec::with_capacity(self.shards.len());
+        for shard in self.shards.iter() {
+            counts.push(shard.lock().map_err(|_| StoreError::LockError)?.len());
+        }
+        let mut lines = vec![
+            format!("shards: {}", counts.len()),
+            format!("keys: {}", counts.iter().sum::<usize>()),
+        ];
+        for (index, count) in counts.iter().enumerate() {
+            lines.push(format!("shard {}: keys={}", index, count));
+        }
+        Ok(lines)
+    }
+}
+
+/// A concurrent hash map built as DashMap is: many shards, each a `HashMap`
+/// behind its own `RwLock`, so that gets of any keys share and writes only wait
+/// for the operations in their shard. The shard count is a power of two, four per
+/// core, and a key's shard is picked from the top bits of its hash.
+///
+/// DashMap isn't lock-free either; a lock-free map would need epoch reclamation
+/// and `unsafe`, which this crate has none of.
+pub struct ConcurrentMemoryStore {
+    shards: Arc<[RwLock<HashMap<String, Blob>>]>,
+    /// How far to shift a hash right to leave its shard index.
+    shift: u32,
+}
+
+impl ConcurrentMemoryStore {
+    pub fn new() -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        Self::with_shards(cores * 4)
+    }
+
+    /// `shards` is rounded up to a power of two.
+    pub fn with_shards(shards: usize) -> Self {
+        let shards = shards.max(1).next_power_of_two();
+        Self {
+            shards: (0..shards)
+                .map(|_| RwLock::new(HashMap::with_capacity(128)))
+                .collect(),
+            shift: u64::BITS - shards.trailing_zeros(),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &RwLock<HashMap<String, Blob>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        // A shift by 64 would overflow, so one shard is special.
+        let index = hasher.finish().checked_shr(self.shift).unwrap_or(0) as usize;
+        &self.shards[index]
+    }
+}
+
+impl Default for ConcurrentMemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Store for ConcurrentMemoryStore {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        let values = self.shard(key).read().map_err(|_| StoreError::LockError)?;
+        Ok(values.get(key).cloned())
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let mut values = self.shard(key).write().map_err(|_| StoreError::LockError)?;
+        values.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        let mut values = self.shard(key).write().map_err(|_| StoreError::LockError)?;
+        values.remove(key);
+        Ok(())
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(Self {
+            shards: Arc::clone(&self.shards),
+            shift: self.shift,
+        })
+    }
+
+    /// Reads one shard at a time, so it isn't a snapshot of the whole store.
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Blob)>, StoreError> {
+        let mut rows = Vec::new();
+        for shard in self.shards.iter() {
+            let values = shard.read().map_err(|_| StoreError::LockError)?;
+            rows.extend(
+                values
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(prefix))
+                    .map(|(key, value)| (key.clone(), value.clone())),
+            );
+        }
+        Ok(rows)
+    }
+
+    /// Filters every shard, then sorts what they found. Like `scan`, it isn't a
+    /// snapshot of the whole store.
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        let mut rows = Vec::new();
+        for shard in self.shards.iter() {
+            let values = shard.read().map_err(|_| StoreError::LockError)?;
+            rows.extend(in_range(values.iter(), range.clone()));
+        }
+        Ok(sorted(rows))
+    }
+
+    fn describe_stats(&self) -> Result<Vec<String>, StoreError> {
+        let mut keys = 0;
+        for shard in self.shards.iter() {
+            keys += shard.read().map_err(|_| StoreError::LockError)?.len();
+        }
+        Ok(vec![
+            format!("shards: {}", self.shards.len()),
+            format!("keys: {}", keys),
+        ])
+    }
+}
+
+/// A soft-deleted value, kept until its store's purge horizon passes.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Tombstone {
+    pub value: Blob,
+    /// Milliseconds since the Unix epoch, so that tombstones keep ageing across
+    /// restarts.
+    pub deleted_at_ms: u64,
 }
 
 /// Same as MemoryStore, but not thread safe.
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(from = "Contents")]
 pub struct MemoryStoreSingleThreaded {
     values: HashMap<String, Blob>,
+    /// Soft-deleted keys, which have no value. Left out when empty, so that a store
+    /// that never soft-deletes serializes as before.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    deleted: HashMap<String, Tombstone>,
+    /// The keys of `values` in order, for range scans; None in copies that never
+    /// serve them. Not serialized; it's rebuilt when the store is deserialized.
+    #[serde(skip)]
+    index: Option<BTreeSet<String>>,
+}
+
+/// What a `MemoryStoreSingleThreaded` serializes.
+#[derive(Deserialize)]
+struct Contents {
+    values: HashMap<String, Blob>,
+    #[serde(default)]
+    deleted: HashMap<String, Tombstone>,
+}
+
+impl From<Contents> for MemoryStoreSingleThreaded {
+    fn from(contents: Contents) -> Self {
+        Self {
+            index: Some(contents.values.keys().cloned().collect()),
+            values: contents.values,
+            deleted: contents.deleted,
+        }
+    }
 }
 
 impl MemoryStoreSingleThreaded {
     pub fn new() -> Self {
         Self {
             values: HashMap::with_capacity(128),
+            deleted: HashMap::new(),
+            index: Some(BTreeSet::new()),
+        }
+    }
+
+    /// A copy without the sorted index, for one that never serves range scans,
+    /// such as an asynchronous writer's mirror.
+    pub fn clone_unindexed(&self) -> Self {
+        Self {
+            values: self.values.clone(),
+            deleted: self.deleted.clone(),
+            index: None,
+        }
+    }
+
+    fn index_insert(&mut self, key: &str) {
+        if let Some(index) = &mut self.index {
+            index.insert(key.to_string());
+        }
+    }
+
+    fn index_remove(&mut self, key: &str) {
+        if let Some(index) = &mut self.index {
+            index.remove(key);
         }
     }
+
+    /// Remove `key`, keeping its value as a tombstone. A key without a value keeps
+    /// any tombstone it already has.
+    pub fn soft_delete(&mut self, key: &str, deleted_at_ms: u64) {
+        if let Some(value) = self.values.remove(key) {
+            self.index_remove(key);
+            self.deleted.insert(
+                key.to_string(),
+                Tombstone {
+                    value,
+                    deleted_at_ms,
+                },
+            );
+        }
+    }
+
+    pub fn tombstone(&self, key: &str) -> Option<&Tombstone> {
+        self.deleted.get(key)
+    }
+
+    /// Restore `key`'s soft-deleted value, returning whether it had one.
+    pub fn undelete(&mut self, key: &str) -> bool {
+        match self.deleted.remove(key) {
+            Some(tombstone) => {
+                self.values.insert(key.to_string(), tombstone.value);
+                self.index_insert(key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop tombstones made before `before_ms`, returning how many there were.
+    pub fn purge(&mut self, before_ms: u64) -> usize {
+        let count = self.deleted.len();
+        self.deleted
+            .retain(|_, tombstone| tombstone.deleted_at_ms >= before_ms);
+        count - self.deleted.len()
+    }
+
+    /// Set both `key`'s value and its tombstone, as a file store's delta log
+    /// records them.
+    pub fn restore(&mut self, key: String, value: Option<Blob>, tombstone: Option<Tombstone>) {
+        match value {
+            Some(value) => {
+                self.values.insert(key.clone(), value);
+                self.index_insert(&key);
+            }
+            None => {
+                self.values.remove(&key);
+                self.index_remove(&key);
+            }
+        }
+        match tombstone {
+            Some(tombstone) => self.deleted.insert(key, tombstone),
+            None => self.deleted.remove(&key),
+        };
+    }
+
+    pub fn tombstone_count(&self) -> usize {
+        self.deleted.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Blob)> {
+        self.values.iter()
+    }
+
+    pub fn tombstones(&self) -> impl Iterator<Item = (&String, &Tombstone)> {
+        self.deleted.iter()
+    }
+}
+
+impl Default for MemoryStoreSingleThreaded {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Store for MemoryStoreSingleThreaded {
-    fn get(&self, key: &str) -> Result<Blob> {
-        if let Some(value) = self.values.get(key) {
-            Ok(value.clone())
-        } else {
-            bail!("Key not found: {}", key)
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        Ok(self.values.get(key).cloned())
+    }
+
+    /// Writing a soft-deleted key discards its tombstone.
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        if !self.deleted.is_empty() {
+            self.deleted.remove(key);
+        }
+        if self.values.insert(key.to_string(), value).is_none() {
+            self.index_insert(key);
         }
+        Ok(())
     }
 
-    fn put(&mut self, key: &str, value: Blob) -> Result<()> {
-        self.values.insert(key.to_string(), value);
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        if self.values.remove(key).is_some() {
+            self.index_remove(key);
+        }
         Ok(())
     }
 
-    fn spawn(&mut self) -> Result<Self> {
-        bail!("Spawning is not supported on {:?}", self)
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Err(StoreError::Unsupported(
+            "spawning a MemoryStoreSingleThreaded",
+        ))
+    }
+
+    /// Walks the sorted index rather than every key, if there is one.
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        if range.start >= range.end {
+            return Ok(Vec::new());
+        }
+        let Some(index) = &self.index else {
+            return Ok(sorted(in_range(self.values.iter(), range).collect()));
+        };
+        Ok(index
+            .range::<str, _>((Bound::Included(range.start), Bound::Excluded(range.end)))
+            .filter_map(|key| Some((key.clone(), self.values.get(key)?.clone())))
+            .collect())
     }
 }
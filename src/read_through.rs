@@ -0,0 +1,281 @@
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use hdrhistogram::Histogram;
+
+use crate::load_test::{latency_histogram, LatencySummary};
+use crate::store::{Blob, Store, StoreError};
+
+/// How long an `HttpStore` waits to connect, and for each read or write.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a `ReadThroughStore`'s reads did, and how long its upstream took.
+#[derive(Debug)]
+pub struct ReadThroughStats {
+    /// Gets served by the local store.
+    pub local_hits: AtomicU64,
+    /// Local misses fetched from upstream and stored locally.
+    pub populated: AtomicU64,
+    /// Local misses upstream didn't have either.
+    pub upstream_misses: AtomicU64,
+    pub upstream_errors: AtomicU64,
+    /// Latency of every upstream fetch, in microseconds.
+    upstream_latency: Mutex<Histogram<u64>>,
+}
+
+impl ReadThroughStats {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            local_hits: AtomicU64::new(0),
+            populated: AtomicU64::new(0),
+            upstream_misses: AtomicU64::new(0),
+            upstream_errors: AtomicU64::new(0),
+            upstream_latency: Mutex::new(latency_histogram()?),
+        })
+    }
+
+    fn record_fetch(&self, elapsed: Duration) {
+        if let Ok(mut latency) = self.upstream_latency.lock() {
+            latency.saturating_record(elapsed.as_micros() as u64);
+        }
+    }
+
+    pub fn summarize(&self) {
+        log::info!(
+            "read_through_local_hits: {}",
+            self.local_hits.load(Ordering::Relaxed)
+        );
+        log::info!(
+            "read_through_populated: {}",
+            self.populated.load(Ordering::Relaxed)
+        );
+        log::info!(
+            "read_through_upstream_misses: {}",
+            self.upstream_misses.load(Ordering::Relaxed)
+        );
+        let errors = self.upstream_errors.load(Ordering::Relaxed);
+        if errors > 0 {
+            log::warn!("read_through_upstream_errors: {}", errors);
+        }
+        let Ok(latency) = self.upstream_latency.lock() else {
+            return;
+        };
+        if let Some(summary) = LatencySummary::new(&latency) {
+            log::info!(
+                "read_through_upstream_latency_us: p50={} p90={} p99={} p99.9={} max={}",
+                summary.p50,
+                summary.p90,
+                summary.p99,
+                summary.p99_9,
+                summary.max
+            );
+        }
+    }
+}
+
+/// Wraps a local store as a cache in front of an upstream source of truth: a get
+/// that misses locally is fetched from `upstream` and, if found, stored locally
+/// before it's returned. Writes go to the local store only, and an upstream miss
+/// isn't remembered, so the next get of that key asks upstream again.
+///
+/// A fetched value is stored with an ordinary put, which can overwrite a write
+/// made to the same key by another thread while the fetch was in flight.
+pub struct ReadThroughStore<S: Store, U: Store> {
+    /// In a `RefCell` so that gets can populate it.
+    inner: RefCell<S>,
+    upstream: U,
+    stats: Arc<ReadThroughStats>,
+}
+
+impl<S: Store, U: Store> ReadThroughStore<S, U> {
+    pub fn new(inner: S, upstream: U) -> Result<Self> {
+        Ok(Self {
+            inner: RefCell::new(inner),
+            upstream,
+            stats: Arc::new(ReadThroughStats::new()?),
+        })
+    }
+
+    /// Shared by every handle spawned from this store.
+    pub fn stats(&self) -> Arc<ReadThroughStats> {
+        Arc::clone(&self.stats)
+    }
+}
+
+impl<S: Store, U: Store> Store for ReadThroughStore<S, U> {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        if let Some(value) = self.inner.borrow().get(key)? {
+            self.stats.local_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(value));
+        }
+        let started = Instant::now();
+        let fetched = self.upstream.get(key);
+        self.stats.record_fetch(started.elapsed());
+        match fetched {
+            Ok(Some(value)) => {
+                self.inner.borrow_mut().put(key, value.clone())?;
+                self.stats.populated.fetch_add(1, Ordering::Relaxed);
+                Ok(Some(value))
+            }
+            Ok(None) => {
+                self.stats.upstream_misses.fetch_add(1, Ordering::Relaxed);
+                Ok(None)
+            }
+            Err(err) => {
+                self.stats.upstream_errors.fetch_add(1, Ordering::Relaxed);
+                Err(err)
+            }
+        }
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        self.inner.get_mut().put(key, value)
+    }
+
+    fn put_durable(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        self.inner.get_mut().put_durable(key, value)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        self.inner.get_mut().delete(key)
+    }
+
+    /// Only the local store's keys: the upstream is only asked for single keys.
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        self.inner.borrow().range(range)
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(Self {
+            inner: RefCell::new(self.inner.get_mut().spawn()?),
+            upstream: self.upstream.spawn()?,
+            stats: Arc::clone(&self.stats),
+        })
+    }
+}
+
+/// A read-only store that gets each key with an HTTP GET of a URL built from a
+/// template, for use as a `ReadThroughStore`'s upstream. A 200 response's body is
+/// the value: plain JSON if it parses as JSON, otherwise a string (or bytes). A
+/// 404 is a miss, and any other status an error. Each get makes a new connection.
+#[derive(Clone, Debug)]
+pub struct HttpStore {
+    /// `host:port`, for connecting and the Host header.
+    authority: String,
+    /// Path and query, with `{key}` where the percent-encoded key goes.
+    path_template: String,
+}
+
+impl HttpStore {
+    /// `template` is an `http://` URL containing `{key}`, e.g.
+    /// `http://localhost:8080/items/{key}`. HTTPS isn't supported.
+    pub fn new(template: &str) -> Result<Self> {
+        let Some(rest) = template.strip_prefix("http://") else {
+            bail!("Expected an http:// URL template, got {:?}", template);
+        };
+        if !template.contains("{key}") {
+            bail!("URL template {:?} has no {{key}} to substitute", template);
+        }
+        let (host, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => bail!("URL template {:?} has no path", template),
+        };
+        if host.contains("{key}") {
+            bail!("URL template {:?} has {{key}} in its host", template);
+        }
+        let authority = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(Self {
+            authority,
+            path_template: path.to_string(),
+        })
+    }
+
+    /// Send a GET for `path`, returning the status code and body.
+    fn fetch(&self, path: &str) -> Result<(u16, Vec<u8>)> {
+        let addr = self
+            .authority
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("{} didn't resolve", self.authority))?;
+        let mut stream = TcpStream::connect_timeout(&addr, HTTP_TIMEOUT)?;
+        stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+        // HTTP/1.0, so that the body comes unchunked and ends with the connection.
+        write!(
+            stream,
+            "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n\r\n",
+            path, self.authority
+        )?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let header_end = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| anyhow!("response from {} has no end of headers", self.authority))?;
+        let head = String::from_utf8_lossy(&response[..header_end]);
+        let status = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| anyhow!("bad status line from {}: {:?}", self.authority, head))?;
+        Ok((status, response.split_off(header_end + 4)))
+    }
+}
+
+impl Store for HttpStore {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        let path = self.path_template.replace("{key}", &percent_encode(key));
+        let (status, body) = self
+            .fetch(&path)
+            .map_err(|err| StoreError::Upstream(format!("GET {}: {:#}", path, err)))?;
+        match status {
+            200 => Ok(Some(
+                match serde_json::from_slice::<serde_json::Value>(&body) {
+                    Ok(json) => json.into(),
+                    Err(_) => Blob::from_bytes(body),
+                },
+            )),
+            404 => Ok(None),
+            _ => Err(StoreError::Upstream(format!(
+                "GET {} returned {}",
+                path, status
+            ))),
+        }
+    }
+
+    fn put(&mut self, _key: &str, _value: Blob) -> Result<(), StoreError> {
+        Err(StoreError::Unsupported("writes to an HTTP upstream"))
+    }
+
+    fn delete(&mut self, _key: &str) -> Result<(), StoreError> {
+        Err(StoreError::Unsupported("writes to an HTTP upstream"))
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(self.clone())
+    }
+}
+
+/// Percent-encode everything but RFC 3986's unreserved characters.
+fn percent_encode(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
@@ -0,0 +1,868 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::store::{Blob, Store};
+
+// RDB opcodes.
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+// RDB value types.
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+/// Keys written (or AOF commands run) between checkpoints of a resumable import.
+const CHECKPOINT_INTERVAL: u64 = 10_000;
+
+/// The most elements allocated ahead of reading them. Lengths come from the file,
+/// so a corrupt one mustn't decide how much memory is asked for.
+const MAX_PREALLOCATION: usize = 1024;
+
+/// Counts of what an import did. For AOF files, strings and hashes count the
+/// commands that wrote them rather than distinct keys.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ImportStats {
+    pub strings: u64,
+    pub hashes: u64,
+    /// Keys of other types (lists, sets, sorted sets), which have no `Blob` mapping.
+    pub skipped: u64,
+    /// Keys whose expiry time had already passed, which Redis would drop on load.
+    pub expired: u64,
+    /// AOF commands other than those that write strings and hashes.
+    pub ignored_commands: u64,
+}
+
+impl ImportStats {
+    pub fn summarize(&self) {
+        log::info!("import_strings: {}", self.strings);
+        log::info!("import_hashes: {}", self.hashes);
+        log::info!("import_skipped: {}", self.skipped);
+        log::info!("import_expired: {}", self.expired);
+        log::info!("import_ignored_commands: {}", self.ignored_commands);
+    }
+}
+
+/// How a resumable import records its progress.
+pub struct Checkpointing<'a, S> {
+    /// File the checkpoint is written to. It is removed once the import completes.
+    pub path: PathBuf,
+    /// Continue from the checkpoint at `path` if there is one, rather than starting
+    /// over.
+    pub resume: bool,
+    /// Make the store's writes so far durable. Called before every checkpoint, so
+    /// that a checkpoint never gets ahead of the data.
+    pub flush: &'a dyn Fn(&mut S) -> Result<()>,
+}
+
+/// Where an interrupted import got to, saved at an entry boundary of one input
+/// file.
+#[derive(Debug, Deserialize, Serialize)]
+struct Checkpoint {
+    /// Index of the file in the import's list of input files.
+    file_index: usize,
+    file: PathBuf,
+    /// The file's length and modification time, to detect it changing.
+    file_len: u64,
+    file_modified: SystemTime,
+    offset: u64,
+    /// Set while the offset is within the file's RDB data, rather than its AOF
+    /// commands.
+    rdb_version: Option<u32>,
+    current_db: u64,
+    stats: ImportStats,
+    /// The last key written and its stored value, to check on resume that the
+    /// store still holds what was imported.
+    last_key: Option<String>,
+    last_value: Option<Blob>,
+}
+
+impl Checkpoint {
+    fn load(path: &Path) -> Result<Option<Self>> {
+        match File::open(path) {
+            Ok(file) => Ok(Some(serde_json::from_reader(BufReader::new(file))?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Write via a temporary file, so that a crash leaves the previous checkpoint.
+    fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        let file = File::create(&tmp)?;
+        serde_json::to_writer(&file, self)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Load the string and hash keys of a Redis dump into `store`: an RDB file, an AOF
+/// file (with or without an RDB preamble), or a Redis 7 multi-part AOF directory.
+/// If `db` is given, only keys from that database are loaded; otherwise keys from
+/// every database are merged. Expiry times are not carried over.
+pub fn import<S: Store>(path: &Path, store: &mut S, db: Option<u64>) -> Result<ImportStats> {
+    import_resumable(path, store, db, None)
+}
+
+/// As `import`, but if `checkpointing` is given, progress is saved every
+/// `CHECKPOINT_INTERVAL` keys so that an interrupted import can be resumed. On
+/// resume, the input must be unchanged and the store must still hold the last
+/// checkpointed key's value.
+pub fn import_resumable<S: Store>(
+    path: &Path,
+    store: &mut S,
+    db: Option<u64>,
+    checkpointing: Option<Checkpointing<S>>,
+) -> Result<ImportStats> {
+    let files = if path.is_dir() {
+        multi_part_aof_files(path)?
+    } else {
+        vec![path.to_path_buf()]
+    };
+    let resume_from = match &checkpointing {
+        Some(checkpointing) if checkpointing.resume => Checkpoint::load(&checkpointing.path)?,
+        _ => None,
+    };
+    if let Some(checkpoint) = &resume_from {
+        verify_checkpoint(checkpoint, &files, store)?;
+        log::info!(
+            "Resuming import of {:?} from offset {}.",
+            checkpoint.file,
+            checkpoint.offset
+        );
+    }
+    let mut importer = Importer {
+        store,
+        db,
+        current_db: 0,
+        stats: resume_from
+            .as_ref()
+            .map(|checkpoint| checkpoint.stats.clone())
+            .unwrap_or_default(),
+        checkpointing,
+        file_index: 0,
+        since_checkpoint: 0,
+        last_key: resume_from.as_ref().and_then(|c| c.last_key.clone()),
+    };
+    let first_file = resume_from.as_ref().map(|c| c.file_index).unwrap_or(0);
+    for (index, file) in files.iter().enumerate().skip(first_file) {
+        importer.file_index = index;
+        let resume_from = resume_from.as_ref().filter(|c| c.file_index == index);
+        importer.import_file(file, resume_from)?;
+    }
+    if let Some(checkpointing) = &importer.checkpointing {
+        match std::fs::remove_file(&checkpointing.path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+    Ok(importer.stats)
+}
+
+fn verify_checkpoint<S: Store>(
+    checkpoint: &Checkpoint,
+    files: &[PathBuf],
+    store: &S,
+) -> Result<()> {
+    if files.get(checkpoint.file_index) != Some(&checkpoint.file) {
+        bail!(
+            "Import checkpoint is for {:?}, which isn't one of the files being imported",
+            checkpoint.file
+        );
+    }
+    let metadata = checkpoint.file.metadata()?;
+    if metadata.len() != checkpoint.file_len || metadata.modified()? != checkpoint.file_modified {
+        bail!(
+            "{:?} has changed since the import checkpoint; import it again without resuming",
+            checkpoint.file
+        );
+    }
+    if let Some(key) = &checkpoint.last_key {
+        if store.get(key)? != checkpoint.last_value {
+            bail!(
+                "The store's value of {:?} doesn't match the import checkpoint, so it may \
+                 have been reset or written to since; import again without resuming",
+                key
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Counts the bytes read from an input file, so that checkpoints can record
+/// where the importer got to.
+struct Input {
+    reader: BufReader<File>,
+    position: u64,
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl BufRead for Input {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+        self.position += amt as u64;
+    }
+}
+
+/// The base and incremental files listed in a multi-part AOF directory's manifest,
+/// in replay order.
+fn multi_part_aof_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let manifest = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .find(|path| path.extension().map(|e| e == "manifest").unwrap_or(false))
+        .with_context(|| format!("No AOF manifest found in {:?}", dir))?;
+    let mut base = Vec::new();
+    let mut incremental = Vec::new();
+    for line in std::fs::read_to_string(&manifest)?.lines() {
+        // e.g. "file appendonly.aof.1.base.rdb seq 1 type b"
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let field = |name: &str| {
+            fields
+                .iter()
+                .position(|f| *f == name)
+                .and_then(|i| fields.get(i + 1))
+        };
+        match (field("file"), field("type")) {
+            (Some(file), Some(&"b")) => base.push(dir.join(file)),
+            (Some(file), Some(&"i")) => incremental.push(dir.join(file)),
+            // History files have been superseded by the base.
+            _ => {}
+        }
+    }
+    base.extend(incremental);
+    Ok(base)
+}
+
+struct Importer<'a, S: Store> {
+    store: &'a mut S,
+    db: Option<u64>,
+    current_db: u64,
+    stats: ImportStats,
+    checkpointing: Option<Checkpointing<'a, S>>,
+    file_index: usize,
+    /// Keys written or commands run since the last checkpoint.
+    since_checkpoint: u64,
+    last_key: Option<String>,
+}
+
+impl<'a, S: Store> Importer<'a, S> {
+    fn import_file(&mut self, path: &Path, resume_from: Option<&Checkpoint>) -> Result<()> {
+        log::info!("Importing Redis data from {:?}.", path);
+        let mut input = Input {
+            reader: BufReader::new(File::open(path)?),
+            position: 0,
+        };
+        let rdb_version = match resume_from {
+            Some(checkpoint) => {
+                input.reader.seek(SeekFrom::Start(checkpoint.offset))?;
+                input.position = checkpoint.offset;
+                self.current_db = checkpoint.current_db;
+                checkpoint.rdb_version
+            }
+            None if input.fill_buf()?.starts_with(b"REDIS") => {
+                let mut header = [0; 9];
+                input.read_exact(&mut header)?;
+                self.current_db = 0;
+                Some(std::str::from_utf8(&header[5..])?.parse()?)
+            }
+            None => None,
+        };
+        if let Some(version) = rdb_version {
+            self.import_rdb(path, &mut input, version)
+                .with_context(|| format!("Reading RDB data from {:?}", path))?;
+        }
+        // Anything left is AOF commands, e.g. after an RDB preamble.
+        self.import_aof(path, &mut input)
+            .with_context(|| format!("Reading AOF commands from {:?}", path))
+    }
+
+    /// Save a checkpoint at `input`'s position if checkpointing and enough has been
+    /// imported since the last one. Must only be called between entries.
+    fn maybe_checkpoint(
+        &mut self,
+        path: &Path,
+        input: &Input,
+        rdb_version: Option<u32>,
+    ) -> Result<()> {
+        let Some(checkpointing) = &self.checkpointing else {
+            return Ok(());
+        };
+        self.since_checkpoint += 1;
+        if self.since_checkpoint < CHECKPOINT_INTERVAL {
+            return Ok(());
+        }
+        self.since_checkpoint = 0;
+        (checkpointing.flush)(self.store)?;
+        let metadata = path.metadata()?;
+        let last_value = match &self.last_key {
+            Some(key) => self.store.get(key)?,
+            None => None,
+        };
+        Checkpoint {
+            file_index: self.file_index,
+            file: path.to_path_buf(),
+            file_len: metadata.len(),
+            file_modified: metadata.modified()?,
+            offset: input.position,
+            rdb_version,
+            current_db: self.current_db,
+            stats: self.stats.clone(),
+            last_key: self.last_key.clone(),
+            last_value,
+        }
+        .save(&checkpointing.path)
+    }
+
+    fn selected(&self) -> bool {
+        self.db.map(|db| db == self.current_db).unwrap_or(true)
+    }
+
+    /// Import the RDB entries following the header, up to and including the EOF
+    /// opcode.
+    fn import_rdb(&mut self, path: &Path, r: &mut Input, version: u32) -> Result<()> {
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        let mut expires_at_ms = None;
+        loop {
+            let opcode = read_u8(r)?;
+            match opcode {
+                OPCODE_EOF => {
+                    if version >= 5 {
+                        // CRC64 checksum; not verified.
+                        read_bytes(r, 8)?;
+                    }
+                    return Ok(());
+                }
+                OPCODE_SELECTDB => self.current_db = read_length(r)?,
+                OPCODE_RESIZEDB => {
+                    read_length(r)?;
+                    read_length(r)?;
+                }
+                OPCODE_AUX => {
+                    read_string(r)?;
+                    read_string(r)?;
+                }
+                OPCODE_EXPIRETIME_MS => expires_at_ms = Some(u64::from_le_bytes(read_array(r)?)),
+                OPCODE_EXPIRETIME => {
+                    expires_at_ms = Some(u32::from_le_bytes(read_array(r)?) as u64 * 1000)
+                }
+                OPCODE_FREQ => {
+                    read_u8(r)?;
+                }
+                OPCODE_IDLE => {
+                    read_length(r)?;
+                }
+                OPCODE_FUNCTION2 => {
+                    read_string(r)?;
+                }
+                OPCODE_SLOT_INFO => {
+                    for _ in 0..3 {
+                        read_length(r)?;
+                    }
+                }
+                OPCODE_MODULE_AUX => bail!("Module data is not supported"),
+                value_type => {
+                    let key = String::from_utf8_lossy(&read_string(r)?).into_owned();
+                    let value = read_value(r, value_type)
+                        .with_context(|| format!("Reading key {:?}", key))?;
+                    let expired = expires_at_ms.take().map(|t| t <= now_ms).unwrap_or(false);
+                    match value {
+                        _ if !self.selected() => {}
+                        _ if expired => self.stats.expired += 1,
+                        Some(value) => {
+                            match value {
+                                Blob::Dict(_) => self.stats.hashes += 1,
+                                _ => self.stats.strings += 1,
+                            }
+                            self.store.put(&key, value)?;
+                            self.last_key = Some(key);
+                        }
+                        None => self.stats.skipped += 1,
+                    }
+                    self.maybe_checkpoint(path, r, Some(version))?;
+                }
+            }
+        }
+    }
+
+    fn import_aof(&mut self, path: &Path, r: &mut Input) -> Result<()> {
+        while let Some(command) = read_command(r)? {
+            self.import_command(command)?;
+            self.maybe_checkpoint(path, r, None)?;
+        }
+        Ok(())
+    }
+
+    fn import_command(&mut self, command: Vec<Vec<u8>>) -> Result<()> {
+        let Some((name, args)) = command.split_first() else {
+            return Ok(());
+        };
+        let name = String::from_utf8_lossy(name).to_ascii_uppercase();
+        if name == "SELECT" {
+            self.current_db = String::from_utf8_lossy(args.first().context("SELECT")?)
+                .parse()
+                .context("SELECT")?;
+            return Ok(());
+        }
+        if !self.selected() {
+            return Ok(());
+        }
+        let key = |i: usize| -> Result<String> {
+            let arg = args.get(i).with_context(|| format!("{} arguments", name))?;
+            Ok(String::from_utf8_lossy(arg).into_owned())
+        };
+        match name.as_str() {
+            "SET" => {
+                let value = args.get(1).context("SET arguments")?;
+                let key = key(0)?;
+                self.store.put(&key, Blob::from_bytes(value.clone()))?;
+                self.stats.strings += 1;
+                self.last_key = Some(key);
+            }
+            "HSET" | "HMSET" => {
+                let key = key(0)?;
+                let mut dict = match self.store.get(&key)? {
+                    Some(Blob::Dict(dict)) => dict,
+                    _ => HashMap::new(),
+                };
+                for pair in args[1..].chunks(2) {
+                    if let [field, value] = pair {
+                        dict.insert(
+                            String::from_utf8_lossy(field).into_owned(),
+                            Blob::from_bytes(value.clone()),
+                        );
+                    }
+                }
+                self.store.put(&key, Blob::Dict(dict))?;
+                self.stats.hashes += 1;
+                self.last_key = Some(key);
+            }
+            "HDEL" => {
+                let key = key(0)?;
+                if let Some(Blob::Dict(mut dict)) = self.store.get(&key)? {
+                    for field in &args[1..] {
+                        dict.remove(String::from_utf8_lossy(field).as_ref());
+                    }
+                    if dict.is_empty() {
+                        self.store.delete(&key)?;
+                    } else {
+                        self.store.put(&key, Blob::Dict(dict))?;
+                    }
+                }
+            }
+            "DEL" | "UNLINK" => {
+                for i in 0..args.len() {
+                    self.store.delete(&key(i)?)?;
+                }
+            }
+            _ => self.stats.ignored_commands += 1,
+        }
+        Ok(())
+    }
+}
+
+fn pairs_to_dict(entries: Vec<Vec<u8>>) -> Result<Blob> {
+    if !entries.len().is_multiple_of(2) {
+        bail!("Hash has an odd number of entries");
+    }
+    let mut dict = HashMap::with_capacity(entries.len() / 2);
+    let mut entries = entries.into_iter();
+    while let (Some(field), Some(value)) = (entries.next(), entries.next()) {
+        dict.insert(
+            String::from_utf8_lossy(&field).into_owned(),
+            Blob::from_bytes(value),
+        );
+    }
+    Ok(Blob::Dict(dict))
+}
+
+/// Read a value of `value_type`; None for types that are skipped.
+fn read_value(r: &mut impl Read, value_type: u8) -> Result<Option<Blob>> {
+    Ok(match value_type {
+        TYPE_STRING => Some(Blob::from_bytes(read_string(r)?)),
+        TYPE_HASH => {
+            let len = read_length(r)?;
+            let Some(count) = len.checked_mul(2) else {
+                bail!("Hash length {} is too large", len);
+            };
+            let mut entries = Vec::new();
+            for _ in 0..count {
+                entries.push(read_string(r)?);
+            }
+            Some(pairs_to_dict(entries)?)
+        }
+        TYPE_HASH_ZIPMAP => Some(pairs_to_dict(zipmap_entries(&read_string(r)?)?)?),
+        TYPE_HASH_ZIPLIST => Some(pairs_to_dict(ziplist_entries(&read_string(r)?)?)?),
+        TYPE_HASH_LISTPACK => Some(pairs_to_dict(listpack_entries(&read_string(r)?)?)?),
+        TYPE_LIST | TYPE_SET | TYPE_LIST_QUICKLIST => {
+            for _ in 0..read_length(r)? {
+                read_string(r)?;
+            }
+            None
+        }
+        TYPE_LIST_QUICKLIST_2 => {
+            for _ in 0..read_length(r)? {
+                read_length(r)?; // Container type.
+                read_string(r)?;
+            }
+            None
+        }
+        TYPE_ZSET => {
+            for _ in 0..read_length(r)? {
+                read_string(r)?;
+                // Scores are strings with a one-byte length; 253-255 are NaN/+inf/-inf.
+                let len = read_u8(r)?;
+                if len < 253 {
+                    read_bytes(r, len as usize)?;
+                }
+            }
+            None
+        }
+        TYPE_ZSET_2 => {
+            for _ in 0..read_length(r)? {
+                read_string(r)?;
+                read_bytes(r, 8)?;
+            }
+            None
+        }
+        TYPE_LIST_ZIPLIST | TYPE_SET_INTSET | TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK
+        | TYPE_SET_LISTPACK => {
+            read_string(r)?;
+            None
+        }
+        other => bail!(
+            "Unsupported Redis value type {} (e.g. a stream or module)",
+            other
+        ),
+    })
+}
+
+fn read_u8(r: &mut impl Read) -> Result<u8> {
+    Ok(read_array::<1>(r)?[0])
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Reads up to `len` bytes before failing, rather than allocating `len` first.
+fn read_bytes(r: &mut impl Read, len: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(len.min(MAX_PREALLOCATION));
+    r.by_ref().take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        bail!(
+            "Truncated string: expected {} bytes, found {}",
+            len,
+            buf.len()
+        );
+    }
+    Ok(buf)
+}
+
+/// A length, or the kind of a specially encoded string.
+enum Length {
+    Plain(u64),
+    Encoded(u8),
+}
+
+fn read_length_or_encoding(r: &mut impl Read) -> Result<Length> {
+    let first = read_u8(r)?;
+    Ok(match first >> 6 {
+        0 => Length::Plain((first & 0x3F) as u64),
+        1 => Length::Plain(((first & 0x3F) as u64) << 8 | read_u8(r)? as u64),
+        2 => match first {
+            0x80 => Length::Plain(u32::from_be_bytes(read_array(r)?) as u64),
+            0x81 => Length::Plain(u64::from_be_bytes(read_array(r)?)),
+            _ => bail!("Invalid length encoding {:#x}", first),
+        },
+        _ => Length::Encoded(first & 0x3F),
+    })
+}
+
+fn read_length(r: &mut impl Read) -> Result<u64> {
+    match read_length_or_encoding(r)? {
+        Length::Plain(len) => Ok(len),
+        Length::Encoded(_) => bail!("Expected a length, found an encoded string"),
+    }
+}
+
+fn read_string(r: &mut impl Read) -> Result<Vec<u8>> {
+    match read_length_or_encoding(r)? {
+        Length::Plain(len) => read_bytes(r, len as usize),
+        Length::Encoded(0) => Ok((read_u8(r)? as i8).to_string().into_bytes()),
+        Length::Encoded(1) => Ok(i16::from_le_bytes(read_array(r)?).to_string().into_bytes()),
+        Length::Encoded(2) => Ok(i32::from_le_bytes(read_array(r)?).to_string().into_bytes()),
+        Length::Encoded(3) => {
+            let compressed_len = read_length(r)? as usize;
+            let len = read_length(r)? as usize;
+            lzf_decompress(&read_bytes(r, compressed_len)?, len)
+        }
+        Length::Encoded(other) => bail!("Unknown string encoding {}", other),
+    }
+}
+
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(len.min(MAX_PREALLOCATION));
+    let mut i = 0;
+    let truncated = || anyhow::anyhow!("Truncated LZF data");
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // A run of ctrl + 1 literal bytes.
+            let run = input.get(i..i + ctrl + 1).ok_or_else(truncated)?;
+            out.extend_from_slice(run);
+            i += ctrl + 1;
+        } else {
+            // A back-reference, which may overlap the bytes it produces.
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or_else(truncated)? as usize;
+                i += 1;
+            }
+            let offset = ((ctrl & 0x1F) << 8) + *input.get(i).ok_or_else(truncated)? as usize + 1;
+            i += 1;
+            let start = out
+                .len()
+                .checked_sub(offset)
+                .ok_or_else(|| anyhow::anyhow!("Invalid LZF back-reference"))?;
+            for j in 0..run + 2 {
+                out.push(out[start + j]);
+            }
+        }
+    }
+    if out.len() != len {
+        bail!(
+            "LZF data decompressed to {} bytes, expected {}",
+            out.len(),
+            len
+        );
+    }
+    Ok(out)
+}
+
+/// Bounds-checked slice of a ziplist, listpack or zipmap.
+fn slice(data: &[u8], from: usize, len: usize) -> Result<&[u8]> {
+    data.get(from..from + len)
+        .ok_or_else(|| anyhow::anyhow!("Truncated encoded value"))
+}
+
+fn le_int(bytes: &[u8]) -> i64 {
+    // Sign-extend from the top byte.
+    let mut buf = if bytes.last().map(|b| b & 0x80 != 0).unwrap_or(false) {
+        [0xFF; 8]
+    } else {
+        [0; 8]
+    };
+    buf[..bytes.len()].copy_from_slice(bytes);
+    i64::from_le_bytes(buf)
+}
+
+fn ziplist_entries(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut entries = Vec::new();
+    // zlbytes, zltail and zllen.
+    let mut pos = 10;
+    loop {
+        let prev_len_marker = *slice(data, pos, 1)?.first().unwrap_or(&0xFF);
+        if prev_len_marker == 0xFF {
+            return Ok(entries);
+        }
+        pos += if prev_len_marker < 254 { 1 } else { 5 };
+        let encoding = slice(data, pos, 1)?[0];
+        let (header, len) = match encoding >> 6 {
+            0 => (1, (encoding & 0x3F) as usize),
+            1 => (
+                2,
+                ((encoding & 0x3F) as usize) << 8 | slice(data, pos + 1, 1)?[0] as usize,
+            ),
+            2 => (
+                5,
+                u32::from_be_bytes(slice(data, pos + 1, 4)?.try_into()?) as usize,
+            ),
+            _ => {
+                let (header, value) = match encoding {
+                    0xC0 => (3, le_int(slice(data, pos + 1, 2)?)),
+                    0xD0 => (5, le_int(slice(data, pos + 1, 4)?)),
+                    0xE0 => (9, le_int(slice(data, pos + 1, 8)?)),
+                    0xF0 => (4, le_int(slice(data, pos + 1, 3)?)),
+                    0xFE => (2, le_int(slice(data, pos + 1, 1)?)),
+                    0xF1..=0xFD => (1, (encoding & 0x0F) as i64 - 1),
+                    _ => bail!("Invalid ziplist encoding {:#x}", encoding),
+                };
+                entries.push(value.to_string().into_bytes());
+                pos += header;
+                continue;
+            }
+        };
+        entries.push(slice(data, pos + header, len)?.to_vec());
+        pos += header + len;
+    }
+}
+
+fn listpack_entries(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut entries = Vec::new();
+    // Total bytes and element count.
+    let mut pos = 6;
+    loop {
+        let b = slice(data, pos, 1)?[0];
+        if b == 0xFF {
+            return Ok(entries);
+        }
+        let (entry, entry_len) = if b & 0x80 == 0 {
+            ((b & 0x7F).to_string().into_bytes(), 1)
+        } else if b & 0xC0 == 0x80 {
+            let len = (b & 0x3F) as usize;
+            (slice(data, pos + 1, len)?.to_vec(), 1 + len)
+        } else if b & 0xE0 == 0xC0 {
+            let raw = ((b & 0x1F) as i64) << 8 | slice(data, pos + 1, 1)?[0] as i64;
+            // Sign-extend from 13 bits.
+            let value = (raw << 51) >> 51;
+            (value.to_string().into_bytes(), 2)
+        } else if b & 0xF0 == 0xE0 {
+            let len = ((b & 0x0F) as usize) << 8 | slice(data, pos + 1, 1)?[0] as usize;
+            (slice(data, pos + 2, len)?.to_vec(), 2 + len)
+        } else {
+            let int_len = match b {
+                0xF0 => {
+                    let len = u32::from_le_bytes(slice(data, pos + 1, 4)?.try_into()?) as usize;
+                    entries.push(slice(data, pos + 5, len)?.to_vec());
+                    pos += 5 + len;
+                    pos += listpack_backlen_size(5 + len);
+                    continue;
+                }
+                0xF1 => 2,
+                0xF2 => 3,
+                0xF3 => 4,
+                0xF4 => 8,
+                _ => bail!("Invalid listpack encoding {:#x}", b),
+            };
+            let value = le_int(slice(data, pos + 1, int_len)?);
+            (value.to_string().into_bytes(), 1 + int_len)
+        };
+        entries.push(entry);
+        pos += entry_len;
+        pos += listpack_backlen_size(entry_len);
+    }
+}
+
+/// Size of the trailing length that follows every listpack entry.
+fn listpack_backlen_size(entry_len: usize) -> usize {
+    match entry_len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+fn zipmap_entries(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut entries = Vec::new();
+    // Element count, which is unreliable above 254.
+    let mut pos = 1;
+    let read_len = |pos: &mut usize| -> Result<usize> {
+        let b = slice(data, *pos, 1)?[0];
+        if b < 254 {
+            *pos += 1;
+            Ok(b as usize)
+        } else {
+            let len = u32::from_le_bytes(slice(data, *pos + 1, 4)?.try_into()?) as usize;
+            *pos += 5;
+            Ok(len)
+        }
+    };
+    loop {
+        if slice(data, pos, 1)?[0] == 0xFF {
+            return Ok(entries);
+        }
+        let key_len = read_len(&mut pos)?;
+        entries.push(slice(data, pos, key_len)?.to_vec());
+        pos += key_len;
+        let value_len = read_len(&mut pos)?;
+        let free = slice(data, pos, 1)?[0] as usize;
+        pos += 1;
+        entries.push(slice(data, pos, value_len)?.to_vec());
+        pos += value_len + free;
+    }
+}
+
+/// Read one RESP array command; None at the end of the input. A command cut off
+/// by a crash is dropped with a warning, as Redis does with `aof-load-truncated`.
+fn read_command(r: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    let mut line = String::new();
+    if r.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let line = line.trim_end();
+    if line.is_empty() {
+        return Ok(Some(Vec::new()));
+    }
+    let Some(count) = line.strip_prefix('*') else {
+        bail!("Expected a RESP array, found {:?}", line);
+    };
+    let count: usize = count.parse()?;
+    let mut args = Vec::with_capacity(count.min(MAX_PREALLOCATION));
+    for _ in 0..count {
+        let mut header = String::new();
+        r.read_line(&mut header)?;
+        let Some(len) = header.trim_end().strip_prefix('$') else {
+            log::warn!("Ignoring truncated AOF command at end of file.");
+            return Ok(None);
+        };
+        let len: usize = len.parse()?;
+        // The argument plus its trailing CRLF.
+        let Some(with_crlf) = len.checked_add(2) else {
+            bail!("AOF argument length {} is too large", len);
+        };
+        let mut arg = Vec::with_capacity(with_crlf.min(MAX_PREALLOCATION));
+        r.by_ref().take(with_crlf as u64).read_to_end(&mut arg)?;
+        if arg.len() != with_crlf {
+            log::warn!("Ignoring truncated AOF command at end of file.");
+            return Ok(None);
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
@@ -0,0 +1,451 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+
+use crate::server::Protocol;
+use crate::store::{Blob, Store, StoreError};
+
+/// How long a `RemoteStore` waits to connect, and for each read or write.
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const REDIS_DEFAULT_PORT: u16 = 6379;
+
+/// A Redis server to connect to, parsed from
+/// `redis://[[USERNAME]:PASSWORD@]HOST[:PORT][/DB]`. The port defaults to 6379 and
+/// the database to 0. The username and password are taken as written, without
+/// percent-decoding.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RedisUrl {
+    /// `host:port`.
+    pub addr: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub db: u64,
+}
+
+impl FromStr for RedisUrl {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let Some(rest) = s.strip_prefix("redis://") else {
+            bail!("Expected a redis:// URL, got {:?}", s);
+        };
+        let (authority, db) = match rest.split_once('/') {
+            Some((authority, "")) => (authority, 0),
+            Some((authority, db)) => (
+                authority,
+                db.parse()
+                    .with_context(|| format!("database {:?} of {:?}", db, s))?,
+            ),
+            None => (rest, 0),
+        };
+        let (credentials, host) = match authority.rsplit_once('@') {
+            Some((credentials, host)) => (Some(credentials), host),
+            None => (None, authority),
+        };
+        let (username, password) = match credentials.map(|c| c.split_once(':')) {
+            None => (None, None),
+            Some(None) => bail!("{:?} has credentials without a password", s),
+            Some(Some((username, password))) => (
+                (!username.is_empty()).then(|| username.to_string()),
+                Some(password.to_string()),
+            ),
+        };
+        if host.is_empty() {
+            bail!("{:?} has no host", s);
+        }
+        let addr = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:{}", host, REDIS_DEFAULT_PORT)
+        };
+        Ok(Self {
+            addr,
+            username,
+            password,
+            db,
+        })
+    }
+}
+
+/// A client of another process's `serve`, speaking any of its protocols. Each
+/// handle, as spawned for each load test thread, keeps its own connection open
+/// across operations, connecting on first use and reconnecting after an error.
+///
+/// The line and RESP protocols carry strings and bytes only, so putting any other
+/// value over them is unsupported; over HTTP, values are converted to and from
+/// plain JSON. None of the protocols has durable puts or soft deletes.
+pub struct RemoteStore {
+    addr: String,
+    protocol: Protocol,
+    /// RESP commands sent on every new connection, before any other request.
+    handshake: Vec<Vec<Vec<u8>>>,
+    connection: Mutex<Option<Connection>>,
+}
+
+struct Connection {
+    input: BufReader<TcpStream>,
+    output: TcpStream,
+}
+
+impl RemoteStore {
+    /// Connects lazily: nothing is checked until the first operation.
+    pub fn new(addr: &str, protocol: Protocol) -> Self {
+        Self {
+            addr: addr.to_string(),
+            protocol,
+            handshake: Vec::new(),
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// A client of a Redis server, over RESP. Each connection authenticates with
+    /// `AUTH` if the URL has a password, and picks its database with `SELECT` if
+    /// it isn't 0.
+    pub fn redis(url: &RedisUrl) -> Self {
+        let mut handshake = Vec::new();
+        match (&url.username, &url.password) {
+            (Some(username), Some(password)) => handshake.push(vec![
+                b"AUTH".to_vec(),
+                username.as_bytes().to_vec(),
+                password.as_bytes().to_vec(),
+            ]),
+            (None, Some(password)) => {
+                handshake.push(vec![b"AUTH".to_vec(), password.as_bytes().to_vec()])
+            }
+            _ => {}
+        }
+        if url.db != 0 {
+            handshake.push(vec![b"SELECT".to_vec(), url.db.to_string().into_bytes()]);
+        }
+        Self {
+            handshake,
+            ..Self::new(&url.addr, Protocol::Resp)
+        }
+    }
+
+    fn connect(&self) -> Result<Connection, StoreError> {
+        let addr = self
+            .addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| StoreError::Remote(format!("{} didn't resolve", self.addr)))?;
+        let stream = TcpStream::connect_timeout(&addr, REMOTE_TIMEOUT)?;
+        stream.set_read_timeout(Some(REMOTE_TIMEOUT))?;
+        stream.set_write_timeout(Some(REMOTE_TIMEOUT))?;
+        // Requests are written whole, so there's nothing for Nagle to coalesce.
+        stream.set_nodelay(true)?;
+        let mut connection = Connection {
+            input: BufReader::new(stream.try_clone()?),
+            output: stream,
+        };
+        for command in &self.handshake {
+            let args: Vec<&[u8]> = command.iter().map(Vec::as_slice).collect();
+            connection.output.write_all(&resp_request(&args))?;
+            if let RespReply::Error(message) = read_resp(&mut connection.input)? {
+                return Err(StoreError::Remote(format!(
+                    "{}: {}",
+                    String::from_utf8_lossy(&command[0]),
+                    message
+                )));
+            }
+        }
+        Ok(connection)
+    }
+
+    /// Send `request` and read its reply with `read`, over this handle's
+    /// connection. The connection is dropped after an I/O or protocol error, as
+    /// it may be left mid-reply, and made again by the next request.
+    fn round_trip<T>(
+        &self,
+        request: &[u8],
+        read: impl FnOnce(&mut BufReader<TcpStream>) -> Result<T, StoreError>,
+    ) -> Result<T, StoreError> {
+        let mut connection = self.connection.lock().map_err(|_| StoreError::LockError)?;
+        if connection.is_none() {
+            *connection = Some(self.connect()?);
+        }
+        let Some(open) = connection.as_mut() else {
+            return Err(StoreError::LockError);
+        };
+        let result = open
+            .output
+            .write_all(request)
+            .map_err(StoreError::from)
+            .and_then(|()| read(&mut open.input));
+        if matches!(result, Err(StoreError::Io(_) | StoreError::Remote(_))) {
+            *connection = None;
+        }
+        result
+    }
+
+    fn get_line(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        line_key(key)?;
+        let reply = self.round_trip(format!("GET {}\n", key).as_bytes(), read_line)?;
+        if let Some(value) = reply.strip_prefix(b"VALUE ") {
+            return Ok(Some(Blob::from_bytes(value.to_vec())));
+        }
+        if let Some(json) = reply.strip_prefix(b"JSON ") {
+            return Ok(Some(parse_json(json)?));
+        }
+        match reply.as_slice() {
+            b"NOT_FOUND" => Ok(None),
+            reply => Err(line_error(reply)),
+        }
+    }
+
+    fn write_line(&self, request: Vec<u8>) -> Result<(), StoreError> {
+        match self.round_trip(&request, read_line)?.as_slice() {
+            b"OK" => Ok(()),
+            reply => Err(line_error(reply)),
+        }
+    }
+
+    fn resp(&self, args: &[&[u8]]) -> Result<RespReply, StoreError> {
+        match self.round_trip(&resp_request(args), read_resp)? {
+            RespReply::Error(message) => Err(StoreError::Remote(message)),
+            reply => Ok(reply),
+        }
+    }
+
+    fn http(&self, method: &str, key: &str, body: &[u8]) -> Result<(u16, Vec<u8>), StoreError> {
+        let mut request = format!(
+            "{} /keys/{} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n",
+            method,
+            percent_encode(key),
+            self.addr,
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(body);
+        self.round_trip(&request, read_http)
+    }
+}
+
+/// What `RemoteStore::resp` read back.
+enum RespReply {
+    Status,
+    Integer,
+    Bulk(Option<Vec<u8>>),
+    Error(String),
+}
+
+/// A RESP array of bulk strings, as clients send commands.
+fn resp_request(args: &[&[u8]]) -> Vec<u8> {
+    let mut request = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        request.extend_from_slice(arg);
+        request.extend_from_slice(b"\r\n");
+    }
+    request
+}
+
+/// Strings and bytes as they are, for the protocols that carry nothing else.
+fn raw_bytes(value: &Blob) -> Result<&[u8], StoreError> {
+    match value {
+        Blob::Str(s) => Ok(s.as_bytes()),
+        Blob::Bytes(bytes) => Ok(bytes),
+        _ => Err(StoreError::Unsupported(
+            "values other than strings and bytes over the line or RESP protocols",
+        )),
+    }
+}
+
+/// The line protocol separates arguments with whitespace.
+fn line_key(key: &str) -> Result<(), StoreError> {
+    if key.is_empty() || key.chars().any(char::is_whitespace) {
+        return Err(StoreError::Unsupported(
+            "empty keys or keys with whitespace over the line protocol",
+        ));
+    }
+    Ok(())
+}
+
+fn line_error(reply: &[u8]) -> StoreError {
+    let reply = String::from_utf8_lossy(reply);
+    StoreError::Remote(reply.strip_prefix("ERR ").unwrap_or(&reply).to_string())
+}
+
+fn parse_json(json: &[u8]) -> Result<Blob, StoreError> {
+    let value: serde_json::Value = serde_json::from_slice(json)
+        .map_err(|err| StoreError::Remote(format!("reply isn't JSON: {}", err)))?;
+    Ok(value.into())
+}
+
+/// A line without its line ending.
+fn read_line(input: &mut BufReader<TcpStream>) -> Result<Vec<u8>, StoreError> {
+    let mut line = Vec::new();
+    if input.read_until(b'\n', &mut line)? == 0 {
+        return Err(StoreError::Remote(
+            "the server closed the connection".to_string(),
+        ));
+    }
+    if line.ends_with(b"\n") {
+        line.pop();
+    }
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(line)
+}
+
+fn read_resp(input: &mut BufReader<TcpStream>) -> Result<RespReply, StoreError> {
+    let line = read_line(input)?;
+    let (kind, rest) = line
+        .split_first()
+        .ok_or_else(|| StoreError::Remote("empty RESP reply".to_string()))?;
+    let rest = String::from_utf8_lossy(rest);
+    Ok(match kind {
+        b'+' => RespReply::Status,
+        b':' => RespReply::Integer,
+        b'-' => RespReply::Error(rest.strip_prefix("ERR ").unwrap_or(&rest).to_string()),
+        b'$' if rest == "-1" => RespReply::Bulk(None),
+        b'$' => {
+            let len: usize = rest
+                .parse()
+                .map_err(|_| StoreError::Remote(format!("bad bulk length {:?}", rest)))?;
+            let mut bulk = vec![0; len + 2];
+            input.read_exact(&mut bulk)?;
+            bulk.truncate(len);
+            RespReply::Bulk(Some(bulk))
+        }
+        _ => {
+            return Err(StoreError::Remote(format!(
+                "unexpected RESP reply {:?}",
+                String::from_utf8_lossy(&line)
+            )))
+        }
+    })
+}
+
+/// The status and body of a response with a Content-Length, as the server sends.
+fn read_http(input: &mut BufReader<TcpStream>) -> Result<(u16, Vec<u8>), StoreError> {
+    let status_line = read_line(input)?;
+    let status_line = String::from_utf8_lossy(&status_line);
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| StoreError::Remote(format!("bad status line {:?}", status_line)))?;
+    let mut content_length = 0;
+    loop {
+        let header = read_line(input)?;
+        if header.is_empty() {
+            break;
+        }
+        let header = String::from_utf8_lossy(&header);
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| StoreError::Remote(format!("bad Content-Length {:?}", value)))?;
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    input.read_exact(&mut body)?;
+    Ok((status, body))
+}
+
+/// The error an HTTP error response's JSON body carries, or its status.
+fn http_error(status: u16, body: &[u8]) -> StoreError {
+    let message = serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .and_then(|body| body.get("error")?.as_str().map(String::from))
+        .unwrap_or_else(|| format!("HTTP {}", status));
+    StoreError::Remote(message)
+}
+
+/// Percent-encode everything but RFC 3986's unreserved characters.
+fn percent_encode(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+impl Store for RemoteStore {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        match self.protocol {
+            Protocol::Line => self.get_line(key),
+            Protocol::Resp => match self.resp(&[b"GET", key.as_bytes()])? {
+                RespReply::Bulk(value) => Ok(value.map(Blob::from_bytes)),
+                _ => Err(StoreError::Remote(
+                    "GET didn't reply with a bulk string".to_string(),
+                )),
+            },
+            Protocol::Http => match self.http("GET", key, b"")? {
+                (200, body) => Ok(Some(parse_json(&body)?)),
+                (404, _) => Ok(None),
+                (status, body) => Err(http_error(status, &body)),
+            },
+        }
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        match self.protocol {
+            Protocol::Line => {
+                line_key(key)?;
+                let value = raw_bytes(&value)?;
+                if value.contains(&b'\n') || value.contains(&b'\r') {
+                    return Err(StoreError::Unsupported(
+                        "values with line breaks over the line protocol",
+                    ));
+                }
+                let mut request = format!("PUT {} ", key).into_bytes();
+                request.extend_from_slice(value);
+                request.push(b'\n');
+                self.write_line(request)
+            }
+            Protocol::Resp => self
+                .resp(&[b"SET", key.as_bytes(), raw_bytes(&value)?])
+                .map(|_| ()),
+            Protocol::Http => {
+                let body = serde_json::Value::from(value).to_string();
+                match self.http("PUT", key, body.as_bytes())? {
+                    (204, _) => Ok(()),
+                    (status, body) => Err(http_error(status, &body)),
+                }
+            }
+        }
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        match self.protocol {
+            Protocol::Line => {
+                line_key(key)?;
+                self.write_line(format!("DEL {}\n", key).into_bytes())
+            }
+            Protocol::Resp => match self.resp(&[b"DEL", key.as_bytes()])? {
+                RespReply::Integer => Ok(()),
+                _ => Err(StoreError::Remote(
+                    "DEL didn't reply with an integer".to_string(),
+                )),
+            },
+            Protocol::Http => match self.http("DELETE", key, b"")? {
+                (204, _) => Ok(()),
+                (status, body) => Err(http_error(status, &body)),
+            },
+        }
+    }
+
+    /// A new handle, with its own connection.
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(Self {
+            handshake: self.handshake.clone(),
+            ..Self::new(&self.addr, self.protocol)
+        })
+    }
+}
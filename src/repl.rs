@@ -0,0 +1,451 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::store::{Blob, Explanation, Store};
+
+const HELP: &str = "\
+get KEY                 print the value of KEY, or (nil)
+put KEY VALUE           store VALUE as a string (or bytes, if not valid UTF-8)
+put-json KEY JSON       store a JSON document, e.g. '{\"a\": [1, 2]}'
+delete KEY, del KEY     remove KEY
+scan [PREFIX]           print every key starting with PREFIX (or every key),
+                        and its value, sorted by key
+stats                   print the store's statistics
+flush                   persist every pending write
+get-deleted KEY         print the value KEY held before it was soft-deleted, or
+                        (nil)
+undelete KEY            restore KEY's soft-deleted value
+expect KEY VALUE        assert that KEY holds the string or bytes VALUE
+expect-json KEY JSON    assert that KEY holds the JSON document
+expect-missing KEY      assert that KEY has no value
+explain get KEY         get KEY, showing how the store found it and how long
+                        each step took
+history                 list earlier commands, numbered (interactive only)
+!N, !!                  run command N of the history again, or the last one
+                        (interactive only)
+help                    show this message
+
+Arguments are separated by whitespace. Double quotes allow spaces and the
+escapes \\\" \\\\ \\n \\r \\t \\0 and \\xHH; single quotes are taken literally.
+A # outside quotes starts a comment.";
+
+/// Commands kept in a history file; older ones are dropped when it's loaded.
+const HISTORY_LIMIT: usize = 1000;
+
+/// Result of a script run.
+#[derive(Debug, Default)]
+pub struct ScriptOutcome {
+    pub commands: usize,
+    pub failed_assertions: usize,
+}
+
+/// What a command printed, and whether it was a failed assertion.
+enum Reply {
+    Ok,
+    Value(Option<Blob>),
+    Passed,
+    Failed(String),
+    Explain(Explanation),
+    /// Sorted by key.
+    Scan(Vec<(String, Blob)>),
+    Lines(Vec<String>),
+    Help,
+}
+
+/// Commands entered at the prompt, numbered from 1, and appended to a file if one
+/// was given so that later sessions can recall them.
+pub struct History {
+    path: Option<PathBuf>,
+    lines: Vec<String>,
+}
+
+impl History {
+    /// Load the history in `path`, if it exists; a missing file is an empty history.
+    /// Without a path, commands are only kept for this session.
+    pub fn load(path: Option<PathBuf>) -> Result<Self> {
+        let mut lines = Vec::new();
+        if let Some(path) = &path {
+            match File::open(path) {
+                Ok(file) => {
+                    for line in BufReader::new(file).lines() {
+                        lines.push(line?);
+                    }
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err).with_context(|| format!("reading history {:?}", path)),
+            }
+            if lines.len() > HISTORY_LIMIT {
+                lines.drain(..lines.len() - HISTORY_LIMIT);
+                let mut file = File::create(path)?;
+                for line in &lines {
+                    writeln!(file, "{}", line)?;
+                }
+            }
+        }
+        Ok(Self { path, lines })
+    }
+
+    /// The command `!N` or `!!` recalls, or `line` itself if it's neither.
+    fn expand<'a>(&'a self, line: &'a str) -> Result<&'a str> {
+        let Some(reference) = line.strip_prefix('!') else {
+            return Ok(line);
+        };
+        let recalled = if reference == "!" {
+            self.lines.last()
+        } else {
+            let number: usize = reference
+                .parse()
+                .map_err(|_| anyhow!("expected !N or !!, not {:?}", line))?;
+            number
+                .checked_sub(1)
+                .and_then(|index| self.lines.get(index))
+        };
+        recalled
+            .map(String::as_str)
+            .ok_or_else(|| anyhow!("{} isn't in the history", line))
+    }
+
+    /// Record `line`, unless it repeats the last command.
+    fn record(&mut self, line: &str) -> Result<()> {
+        if self.lines.last().is_some_and(|last| last == line) {
+            return Ok(());
+        }
+        if let Some(path) = &self.path {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", line)?;
+        }
+        self.lines.push(line.to_string());
+        Ok(())
+    }
+}
+
+/// Run commands from `input` non-interactively. Malformed commands and store
+/// errors stop the script, with the line number; failed assertions are reported
+/// to `out` and counted, and the script carries on.
+pub fn run_script<S: Store>(
+    store: &mut S,
+    input: impl BufRead,
+    mut out: impl Write,
+) -> Result<ScriptOutcome> {
+    let mut outcome = ScriptOutcome::default();
+    for (index, line) in input.lines().enumerate() {
+        let line_number = index + 1;
+        let args = tokenize(&line?).with_context(|| format!("line {}", line_number))?;
+        if args.is_empty() {
+            continue;
+        }
+        outcome.commands += 1;
+        match execute(store, &args).with_context(|| format!("line {}", line_number))? {
+            Reply::Failed(message) => {
+                outcome.failed_assertions += 1;
+                writeln!(out, "line {}: FAILED: {}", line_number, message)?;
+            }
+            Reply::Value(value) => writeln!(out, "{}", display(value.as_ref()))?,
+            Reply::Explain(explanation) => write_explanation(&mut out, &explanation)?,
+            Reply::Scan(entries) => write_scan(&mut out, &entries)?,
+            Reply::Lines(lines) => {
+                for line in lines {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+            Reply::Ok | Reply::Passed | Reply::Help => {}
+        }
+    }
+    Ok(outcome)
+}
+
+/// Prompt for commands on `input` until it ends, printing each reply and
+/// recording each command in `history`. Errors are printed rather than ending the
+/// session.
+pub fn run_interactive<S: Store>(
+    store: &mut S,
+    mut input: impl BufRead,
+    mut out: impl Write,
+    history: &mut History,
+) -> Result<()> {
+    loop {
+        write!(out, "> ")?;
+        out.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let entered = line.trim();
+        let line = match history.expand(entered) {
+            Ok(line) if line != entered => {
+                // Show what's being run again.
+                writeln!(out, "{}", line)?;
+                line.to_string()
+            }
+            Ok(line) => line.to_string(),
+            Err(err) => {
+                writeln!(out, "error: {:#}", err)?;
+                continue;
+            }
+        };
+        if line.eq_ignore_ascii_case("history") {
+            for (index, command) in history.lines.iter().enumerate() {
+                writeln!(out, "{:5}  {}", index + 1, command)?;
+            }
+            continue;
+        }
+        if !line.is_empty() && !line.starts_with('#') {
+            history.record(&line)?;
+        }
+        let reply = tokenize(&line).and_then(|args| {
+            if args.is_empty() {
+                return Ok(None);
+            }
+            execute(store, &args).map(Some)
+        });
+        match reply {
+            Ok(None) => {}
+            Ok(Some(Reply::Ok)) => writeln!(out, "OK")?,
+            Ok(Some(Reply::Value(value))) => writeln!(out, "{}", display(value.as_ref()))?,
+            Ok(Some(Reply::Passed)) => writeln!(out, "PASSED")?,
+            Ok(Some(Reply::Failed(message))) => writeln!(out, "FAILED: {}", message)?,
+            Ok(Some(Reply::Explain(explanation))) => write_explanation(&mut out, &explanation)?,
+            Ok(Some(Reply::Scan(entries))) if entries.is_empty() => writeln!(out, "(no keys)")?,
+            Ok(Some(Reply::Scan(entries))) => write_scan(&mut out, &entries)?,
+            Ok(Some(Reply::Lines(lines))) => {
+                for line in lines {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+            Ok(Some(Reply::Help)) => writeln!(out, "{}", HELP)?,
+            Err(err) => writeln!(out, "error: {:#}", err)?,
+        }
+    }
+}
+
+fn execute<S: Store>(store: &mut S, args: &[Vec<u8>]) -> Result<Reply> {
+    let command = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    let arg = |i: usize| -> Result<&[u8]> {
+        args.get(i)
+            .map(Vec::as_slice)
+            .ok_or_else(|| anyhow!("{} is missing an argument; see help", command))
+    };
+    let key = |i: usize| -> Result<String> {
+        String::from_utf8(arg(i)?.to_vec()).context("keys must be valid UTF-8")
+    };
+    let expected_len = match command.as_str() {
+        "help" | "stats" | "flush" => 1,
+        "get" | "delete" | "del" | "get-deleted" | "undelete" | "expect-missing" | "scan" => 2,
+        "put" | "put-json" | "expect" | "expect-json" | "explain" => 3,
+        _ => bail!("unknown command {:?}; see help", command),
+    };
+    if args.len() > expected_len {
+        bail!("too many arguments to {}", command);
+    }
+    Ok(match command.as_str() {
+        "help" => Reply::Help,
+        "get" => Reply::Value(store.get(&key(1)?)?),
+        "put" => {
+            store.put(&key(1)?, Blob::from_bytes(arg(2)?.to_vec()))?;
+            Reply::Ok
+        }
+        "put-json" => {
+            store.put(&key(1)?, parse_json(arg(2)?)?)?;
+            Reply::Ok
+        }
+        "delete" | "del" => {
+            store.delete(&key(1)?)?;
+            Reply::Ok
+        }
+        "scan" => {
+            let prefix = match args.get(1) {
+                Some(_) => key(1)?,
+                None => String::new(),
+            };
+            let mut entries = store.scan(&prefix)?;
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Reply::Scan(entries)
+        }
+        "stats" => Reply::Lines(store.describe_stats()?),
+        "flush" => {
+            store.persist()?;
+            Reply::Ok
+        }
+        "get-deleted" => Reply::Value(store.get_deleted(&key(1)?)?),
+        "undelete" => {
+            let key = key(1)?;
+            if !store.undelete(&key)? {
+                bail!("{:?} has no soft-deleted value to restore", key);
+            }
+            Reply::Ok
+        }
+        "expect" => {
+            let key = key(1)?;
+            let actual = store.get(&key)?;
+            let matches = match &actual {
+                Some(Blob::Str(s)) => s.as_bytes() == arg(2)?,
+                Some(Blob::Bytes(bytes)) => bytes.as_slice() == arg(2)?,
+                _ => false,
+            };
+            assertion(&key, matches, &quote(arg(2)?), actual.as_ref())
+        }
+        "expect-json" => {
+            let key = key(1)?;
+            let expected = parse_json(arg(2)?)?;
+            let actual = store.get(&key)?;
+            let matches = actual.as_ref() == Some(&expected);
+            assertion(&key, matches, &display(Some(&expected)), actual.as_ref())
+        }
+        "expect-missing" => {
+            let key = key(1)?;
+            let actual = store.get(&key)?;
+            assertion(&key, actual.is_none(), "(nil)", actual.as_ref())
+        }
+        "explain" => {
+            if !arg(1)?.eq_ignore_ascii_case(b"get") {
+                bail!("only get can be explained");
+            }
+            Reply::Explain(store.explain_get(&key(2)?)?)
+        }
+        _ => unreachable!(),
+    })
+}
+
+fn assertion(key: &str, matches: bool, expected: &str, actual: Option<&Blob>) -> Reply {
+    if matches {
+        Reply::Passed
+    } else {
+        Reply::Failed(format!(
+            "{}: expected {}, got {}",
+            key,
+            expected,
+            display(actual)
+        ))
+    }
+}
+
+/// One line per step, then the value and the total latency.
+fn write_explanation(out: &mut impl Write, explanation: &Explanation) -> Result<()> {
+    for step in &explanation.steps {
+        writeln!(out, "{}: {} ({:?})", step.name, step.detail, step.elapsed)?;
+    }
+    writeln!(out, "value: {}", display(explanation.value.as_ref()))?;
+    writeln!(out, "total: {:?}", explanation.total)?;
+    Ok(())
+}
+
+/// One key and its value per line.
+fn write_scan(out: &mut impl Write, entries: &[(String, Blob)]) -> Result<()> {
+    for (key, value) in entries {
+        writeln!(out, "{} {}", quote(key.as_bytes()), display(Some(value)))?;
+    }
+    Ok(())
+}
+
+fn parse_json(arg: &[u8]) -> Result<Blob> {
+    let value: serde_json::Value = serde_json::from_slice(arg).context("invalid JSON")?;
+    Ok(value.into())
+}
+
+/// Strings and bytes are printed quoted with escapes, so any value can be read
+/// back unambiguously; everything else as JSON.
+fn display(value: Option<&Blob>) -> String {
+    match value {
+        None => "(nil)".to_string(),
+        Some(Blob::Str(s)) => quote(s.as_bytes()),
+        Some(Blob::Bytes(bytes)) => format!("(bytes) {}", quote(bytes)),
+        Some(blob) => serde_json::Value::from(blob.clone()).to_string(),
+    }
+}
+
+fn quote(bytes: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                '\r' => quoted.push_str("\\r"),
+                '\t' => quoted.push_str("\\t"),
+                c if c.is_control() => {
+                    let mut buf = [0; 4];
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        quoted.push_str(&format!("\\x{:02x}", b));
+                    }
+                }
+                c => quoted.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            quoted.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Split a line into arguments, honouring quotes and comments.
+fn tokenize(line: &str) -> Result<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(args);
+        };
+        let mut arg = Vec::new();
+        match first {
+            '#' => return Ok(args),
+            '"' => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        None => bail!("unterminated double quote"),
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('"') => arg.push(b'"'),
+                            Some('\\') => arg.push(b'\\'),
+                            Some('n') => arg.push(b'\n'),
+                            Some('r') => arg.push(b'\r'),
+                            Some('t') => arg.push(b'\t'),
+                            Some('0') => arg.push(0),
+                            Some('x') => {
+                                let hex: String = chars.by_ref().take(2).collect();
+                                let byte = u8::from_str_radix(&hex, 16)
+                                    .map_err(|_| anyhow!("invalid escape \\x{}", hex))?;
+                                arg.push(byte);
+                            }
+                            Some(c) => bail!("unknown escape \\{}", c),
+                            None => bail!("unterminated double quote"),
+                        },
+                        Some(c) => push_char(&mut arg, c),
+                    }
+                }
+            }
+            '\'' => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        None => bail!("unterminated single quote"),
+                        Some('\'') => break,
+                        Some(c) => push_char(&mut arg, c),
+                    }
+                }
+            }
+            _ => {
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    push_char(&mut arg, c);
+                }
+            }
+        }
+        let quoted = first == '"' || first == '\'';
+        if quoted && chars.peek().is_some_and(|c| !c.is_whitespace()) {
+            bail!("closing quote must be followed by whitespace");
+        }
+        args.push(arg);
+    }
+}
+
+fn push_char(arg: &mut Vec<u8>, c: char) {
+    let mut buf = [0; 4];
+    arg.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+}
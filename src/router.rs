@@ -0,0 +1,188 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use structopt::clap::arg_enum;
+
+/// Number of points each shard places on the consistent-hash ring. More points
+/// give a more even key distribution at the cost of a larger ring.
+const VIRTUAL_NODES_PER_SHARD: usize = 128;
+
+/// Decides which shard a given key lives in.
+pub trait ShardRouter: Send + Sync {
+    fn route(&self, key: &str) -> usize;
+
+    /// How `route` arrives at `key`'s shard, for explaining an operation.
+    fn describe_route(&self, key: &str) -> String;
+}
+
+/// Hashes keys (and ring points) for a `ShardRouter`.
+pub trait KeyHasher: Send + Sync {
+    fn hash_key(&self, key: &str) -> u64;
+}
+
+arg_enum! {
+    #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+    pub enum HashFunction {
+        Siphash,
+        Xxhash,
+        Fnv,
+        Crc32,
+    }
+}
+
+impl Default for HashFunction {
+    /// Stores created before hash functions were configurable used SipHash.
+    fn default() -> Self {
+        Self::Siphash
+    }
+}
+
+impl HashFunction {
+    pub fn build(&self) -> Arc<dyn KeyHasher> {
+        match self {
+            Self::Siphash => Arc::new(SipKeyHasher),
+            Self::Xxhash => Arc::new(XxKeyHasher),
+            Self::Fnv => Arc::new(FnvKeyHasher),
+            Self::Crc32 => Arc::new(Crc32KeyHasher),
+        }
+    }
+}
+
+/// The standard library's default hasher (currently SipHash-1-3).
+struct SipKeyHasher;
+
+impl KeyHasher for SipKeyHasher {
+    fn hash_key(&self, key: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+struct XxKeyHasher;
+
+impl KeyHasher for XxKeyHasher {
+    fn hash_key(&self, key: &str) -> u64 {
+        xxhash_rust::xxh3::xxh3_64(key.as_bytes())
+    }
+}
+
+struct FnvKeyHasher;
+
+impl KeyHasher for FnvKeyHasher {
+    fn hash_key(&self, key: &str) -> u64 {
+        let mut hasher = fnv::FnvHasher::default();
+        hasher.write(key.as_bytes());
+        hasher.finish()
+    }
+}
+
+/// Only produces 32 bits; included to show the effect of a weak hash on balance.
+struct Crc32KeyHasher;
+
+impl KeyHasher for Crc32KeyHasher {
+    fn hash_key(&self, key: &str) -> u64 {
+        crc32fast::hash(key.as_bytes()) as u64
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+    pub enum Router {
+        Modulo,
+        Consistent,
+    }
+}
+
+impl Default for Router {
+    /// Stores created before routers were configurable used modulo hashing.
+    fn default() -> Self {
+        Self::Modulo
+    }
+}
+
+impl Router {
+    pub fn build(&self, shard_count: usize, hash: HashFunction) -> Arc<dyn ShardRouter> {
+        let hasher = hash.build();
+        match self {
+            Self::Modulo => Arc::new(SimpleHasher::new(shard_count, hasher)),
+            Self::Consistent => Arc::new(ConsistentHashRing::new(shard_count, hasher)),
+        }
+    }
+}
+
+/// Simple hasher to determine the output file for a given key.
+#[derive(Clone)]
+pub struct SimpleHasher {
+    max_values: usize,
+    hasher: Arc<dyn KeyHasher>,
+}
+
+impl SimpleHasher {
+    pub fn new(max_values: usize, hasher: Arc<dyn KeyHasher>) -> Self {
+        Self { max_values, hasher }
+    }
+}
+
+impl ShardRouter for SimpleHasher {
+    fn route(&self, key: &str) -> usize {
+        let hash = self.hasher.hash_key(key);
+        // In production code, we'd use u64 everywhere to be explicit; for now
+        // we'll stick with usize for simplicity.
+        (hash as usize) % self.max_values
+    }
+
+    fn describe_route(&self, key: &str) -> String {
+        let hash = self.hasher.hash_key(key);
+        format!(
+            "hash {:#018x} modulo {} shards is shard {}",
+            hash,
+            self.max_values,
+            (hash as usize) % self.max_values
+        )
+    }
+}
+
+/// Consistent-hash ring: adding or removing a shard only moves ~1/N of the keys,
+/// rather than nearly all of them as with `SimpleHasher`.
+#[derive(Clone)]
+pub struct ConsistentHashRing {
+    /// (point on the ring, shard index), sorted by point.
+    ring: Vec<(u64, usize)>,
+    hasher: Arc<dyn KeyHasher>,
+}
+
+impl ConsistentHashRing {
+    pub fn new(shard_count: usize, hasher: Arc<dyn KeyHasher>) -> Self {
+        let mut ring = Vec::with_capacity(shard_count * VIRTUAL_NODES_PER_SHARD);
+        for shard in 0..shard_count {
+            for vnode in 0..VIRTUAL_NODES_PER_SHARD {
+                let point = hasher.hash_key(&format!("shard{}_vnode{}", shard, vnode));
+                ring.push((point, shard));
+            }
+        }
+        ring.sort_unstable();
+        Self { ring, hasher }
+    }
+}
+
+impl ShardRouter for ConsistentHashRing {
+    fn route(&self, key: &str) -> usize {
+        let hash = self.hasher.hash_key(key);
+        // The key belongs to the first point at or after its hash, wrapping around.
+        let index = self.ring.partition_point(|(point, _)| *point < hash);
+        self.ring[index % self.ring.len()].1
+    }
+
+    fn describe_route(&self, key: &str) -> String {
+        let hash = self.hasher.hash_key(key);
+        let index = self.ring.partition_point(|(point, _)| *point < hash);
+        let (point, shard) = self.ring[index % self.ring.len()];
+        format!(
+            "hash {:#018x} is owned by ring point {:#018x} of shard {}",
+            hash, point, shard
+        )
+    }
+}
@@ -0,0 +1,209 @@
+use std::ops::Range;
+use std::sync::{Arc, Mutex, RwLock};
+
+use anyhow::Result;
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+
+use crate::store::{Blob, Store, StoreError};
+
+/// Wraps a store so that short rhai procedures can run multiple gets/puts
+/// atomically, similar to Lua scripting in Redis.
+///
+/// Scripts see `get(key)`, `put(key, value)` and `delete(key)` functions. Ordinary operations
+/// through the wrapper share a read lock, while a script holds the write lock for
+/// its whole run, so no other operation can interleave with it.
+pub struct ScriptableStore<S: Store> {
+    inner: S,
+    gate: Arc<RwLock<()>>,
+    engine: Engine,
+    write_procedure: Option<Arc<AST>>,
+}
+
+impl<S: Store + 'static> ScriptableStore<S> {
+    pub fn new(mut inner: S) -> Result<Self> {
+        let engine = build_engine(inner.spawn()?);
+        Ok(Self {
+            inner,
+            gate: Arc::new(RwLock::new(())),
+            engine,
+            write_procedure: None,
+        })
+    }
+
+    /// Run `source` in place of every put. The procedure sees the put's `key` and
+    /// `value` as variables, which allows e.g. read-modify-write workloads.
+    pub fn with_write_procedure(mut self, source: &str) -> Result<Self> {
+        self.write_procedure = Some(Arc::new(self.engine.compile(source)?));
+        Ok(self)
+    }
+
+    /// Atomically evaluate a script, returning its final expression's value.
+    pub fn eval(&self, source: &str) -> Result<Blob> {
+        let ast = self.engine.compile(source)?;
+        Ok(self.eval_ast(&ast, Scope::new())?)
+    }
+
+    fn eval_ast(&self, ast: &AST, mut scope: Scope) -> Result<Blob, StoreError> {
+        let _guard = self.gate.write().map_err(|_| StoreError::LockError)?;
+        let result: Dynamic = self
+            .engine
+            .eval_ast_with_scope(&mut scope, ast)
+            .map_err(|err| StoreError::Script(err.to_string()))?;
+        dynamic_to_blob(result)
+    }
+}
+
+impl<S: Store + 'static> Store for ScriptableStore<S> {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        let _guard = self.gate.read().map_err(|_| StoreError::LockError)?;
+        self.inner.get(key)
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        if let Some(procedure) = &self.write_procedure {
+            let mut scope = Scope::new();
+            scope.push("key", key.to_string());
+            scope.push("value", blob_to_dynamic(value));
+            self.eval_ast(procedure, scope)?;
+            return Ok(());
+        }
+        let _guard = self.gate.read().map_err(|_| StoreError::LockError)?;
+        self.inner.put(key, value)
+    }
+
+    /// Bypasses the write procedure, which can only make ordinary puts; with one
+    /// set, durable puts aren't supported.
+    fn put_durable(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        if self.write_procedure.is_some() {
+            return Err(StoreError::Unsupported(
+                "durable puts with a write procedure",
+            ));
+        }
+        let _guard = self.gate.read().map_err(|_| StoreError::LockError)?;
+        self.inner.put_durable(key, value)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        let _guard = self.gate.read().map_err(|_| StoreError::LockError)?;
+        self.inner.delete(key)
+    }
+
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        let _guard = self.gate.read().map_err(|_| StoreError::LockError)?;
+        self.inner.range(range)
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(Self {
+            engine: build_engine(self.inner.spawn()?),
+            inner: self.inner.spawn()?,
+            gate: Arc::clone(&self.gate),
+            write_procedure: self.write_procedure.clone(),
+        })
+    }
+}
+
+/// The engine's functions operate on their own store handle, since they run while
+/// the script already holds the gate.
+fn build_engine<S: Store + 'static>(store: S) -> Engine {
+    let store = Arc::new(Mutex::new(store));
+    let mut engine = Engine::new();
+
+    let get_store = Arc::clone(&store);
+    engine.register_fn(
+        "get",
+        move |key: &str| -> Result<Dynamic, Box<EvalAltResult>> {
+            let store = get_store
+                .lock()
+                .map_err(|_| StoreError::LockError.to_string())?;
+            let value = store
+                .get(key)
+                .and_then(|value| value.ok_or_else(|| StoreError::KeyNotFound(key.to_string())))
+                .map_err(|err| err.to_string())?;
+            Ok(blob_to_dynamic(value))
+        },
+    );
+
+    let delete_store = Arc::clone(&store);
+    engine.register_fn(
+        "delete",
+        move |key: &str| -> Result<(), Box<EvalAltResult>> {
+            let mut store = delete_store
+                .lock()
+                .map_err(|_| StoreError::LockError.to_string())?;
+            store.delete(key).map_err(|err| err.to_string().into())
+        },
+    );
+
+    let put_store = store;
+    engine.register_fn(
+        "put",
+        move |key: &str, value: Dynamic| -> Result<(), Box<EvalAltResult>> {
+            let value = dynamic_to_blob(value).map_err(|err| err.to_string())?;
+            let mut store = put_store
+                .lock()
+                .map_err(|_| StoreError::LockError.to_string())?;
+            store.put(key, value).map_err(|err| err.to_string().into())
+        },
+    );
+
+    engine
+}
+
+fn blob_to_dynamic(blob: Blob) -> Dynamic {
+    match blob {
+        Blob::Null => Dynamic::UNIT,
+        Blob::Str(s) => s.into(),
+        Blob::Int(i) => Dynamic::from(i as rhai::INT),
+        Blob::Float(f) => Dynamic::from(f as rhai::FLOAT),
+        Blob::Bytes(bytes) => Dynamic::from_blob(bytes),
+        Blob::List(values) => {
+            let array: rhai::Array = values.into_iter().map(blob_to_dynamic).collect();
+            array.into()
+        }
+        Blob::Dict(dict) => {
+            let map: Map = dict
+                .into_iter()
+                .map(|(k, v)| (k.into(), blob_to_dynamic(v)))
+                .collect();
+            map.into()
+        }
+    }
+}
+
+fn dynamic_to_blob(value: Dynamic) -> Result<Blob, StoreError> {
+    let type_error = |type_name: &str| {
+        StoreError::Script(format!("unexpected script value of type {}", type_name))
+    };
+    if value.is_unit() {
+        Ok(Blob::Null)
+    } else if value.is_string() {
+        Ok(Blob::Str(value.into_string().map_err(type_error)?))
+    } else if value.is_int() {
+        Ok(Blob::Int(value.as_int().map_err(type_error)? as isize))
+    } else if value.is_float() {
+        Ok(Blob::Float(value.as_float().map_err(type_error)?))
+    } else if value.is_blob() {
+        Ok(Blob::Bytes(value.into_blob().map_err(type_error)?))
+    } else if value.is_array() {
+        let list = value
+            .into_array()
+            .map_err(type_error)?
+            .into_iter()
+            .map(dynamic_to_blob)
+            .collect::<Result<_, StoreError>>()?;
+        Ok(Blob::List(list))
+    } else if value.is_map() {
+        let map = value.cast::<Map>();
+        let dict = map
+            .into_iter()
+            .map(|(k, v)| Ok((k.to_string(), dynamic_to_blob(v)?)))
+            .collect::<Result<_, StoreError>>()?;
+        Ok(Blob::Dict(dict))
+    } else {
+        Err(StoreError::Script(format!(
+            "cannot store script value of type {}",
+            value.type_name()
+        )))
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::file_store::FileStore;
+use crate::store::{Blob, Store, StoreError};
+
+/// Prefix for keys written by the self-test, chosen so they can't collide with
+/// load-test keys.
+const SENTINEL_PREFIX: &str = "__self_test_";
+
+fn sentinels() -> Vec<(String, Blob)> {
+    let mut dict = HashMap::new();
+    dict.insert("nested".to_string(), Blob::Int(-1));
+    vec![
+        (format!("{}null", SENTINEL_PREFIX), Blob::Null),
+        (
+            format!("{}str", SENTINEL_PREFIX),
+            Blob::Str("sentinel".into()),
+        ),
+        (format!("{}int", SENTINEL_PREFIX), Blob::Int(42)),
+        (format!("{}float", SENTINEL_PREFIX), Blob::Float(-0.5)),
+        (
+            format!("{}list", SENTINEL_PREFIX),
+            Blob::List(vec![Blob::Int(1), Blob::Str("two".into()), Blob::Null]),
+        ),
+        (
+            format!("{}bytes", SENTINEL_PREFIX),
+            Blob::Bytes(vec![0, 1, 255]),
+        ),
+        (format!("{}dict", SENTINEL_PREFIX), Blob::Dict(dict)),
+    ]
+}
+
+fn fail(message: String) -> anyhow::Error {
+    StoreError::SelfTestFailed(message).into()
+}
+
+/// Write, read back and delete sentinel keys through `store`, failing on the first
+/// step that misbehaves.
+pub fn run<S: Store>(store: &mut S) -> Result<()> {
+    for (key, value) in sentinels() {
+        store
+            .put(&key, value.clone())
+            .map_err(|err| fail(format!("put {:?} failed: {:#}", key, err)))?;
+        let read = store
+            .get(&key)
+            .map_err(|err| fail(format!("get {:?} after put failed: {:#}", key, err)))?;
+        if read.as_ref() != Some(&value) {
+            return Err(fail(format!(
+                "get {:?} returned {:?}, expected {:?}",
+                key, read, value
+            )));
+        }
+        store
+            .delete(&key)
+            .map_err(|err| fail(format!("delete {:?} failed: {:#}", key, err)))?;
+        let read = store
+            .get(&key)
+            .map_err(|err| fail(format!("get {:?} after delete failed: {:#}", key, err)))?;
+        if let Some(read) = read {
+            return Err(fail(format!(
+                "get {:?} after delete returned {:?}",
+                key, read
+            )));
+        }
+    }
+    log::info!("Self-test passed.");
+    Ok(())
+}
+
+/// Check that a write survives a flush and a reload of its shard from disk, and
+/// that a delete does too.
+pub fn check_persistence(store: &mut FileStore) -> Result<()> {
+    let key = format!("{}persisted", SENTINEL_PREFIX);
+    let value = Blob::Str("sentinel".into());
+    store.put(&key, value.clone())?;
+    store
+        .flush()
+        .map_err(|err| fail(format!("flush failed: {:#}", err)))?;
+    let persisted = store
+        .persisted_value(&key)
+        .map_err(|err| fail(format!("reloading shard failed: {:#}", err)))?;
+    if persisted.as_ref() != Some(&value) {
+        return Err(fail(format!(
+            "reloaded shard has {:?} = {:?}, expected {:?}",
+            key, persisted, value
+        )));
+    }
+    store.delete(&key)?;
+    store.flush()?;
+    let persisted = store.persisted_value(&key)?;
+    if persisted.is_some() {
+        return Err(fail(format!(
+            "reloaded shard still has {:?} = {:?} after delete",
+            key, persisted
+        )));
+    }
+    log::info!("Persistence self-test passed.");
+    Ok(())
+}
@@ -0,0 +1,936 @@
+use std::fmt;
+use std::hash::Hasher;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use crossbeam_channel::{Receiver, TryRecvError};
+use serde::{Deserialize, Serialize};
+
+use crate::store::{Blob, Store, StoreError};
+
+/// How often idle connections check whether the server is stopping.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Longest request line accepted; a connection sending a longer one is closed.
+/// For RESP, this bounds inline commands and the headers of multibulk ones.
+pub const MAX_LINE_BYTES: usize = 1 << 20;
+
+/// Longest RESP bulk string accepted, such as a `SET` value.
+pub const MAX_BULK_BYTES: usize = 64 << 20;
+
+/// Most arguments accepted in one RESP command.
+const MAX_RESP_ARGS: usize = 1 << 20;
+
+/// Locks serializing the read-modify-write commands of RESP connections (`INCR`,
+/// and `DEL`, which counts the keys it removes) with their other writes, by key.
+const KEY_LOCKS: usize = 256;
+
+/// How clients talk to a `Server`. Parses "line", "resp" or "http".
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// See `Server`.
+    #[default]
+    Line,
+    /// The Redis serialization protocol (RESP2), so that Redis clients and
+    /// `redis-benchmark` can drive the store. Supports `GET`, `SET`, `DEL`,
+    /// `EXISTS` and `INCR`, plus enough of the connection commands (`PING`, `ECHO`,
+    /// `SELECT 0`, `QUIT`, `COMMAND`, `CONFIG GET`, `CLIENT`, `INFO`) for clients
+    /// to connect.
+    Resp,
+    /// HTTP/1.1 with JSON bodies: `GET`, `PUT` and `DELETE` on `/keys/{key}`, and
+    /// `POST /batch` with a list of operations. Values are converted to and from
+    /// plain JSON, as by `Blob`'s `serde_json::Value` conversions.
+    Http,
+}
+
+impl std::str::FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "line" => Self::Line,
+            "resp" => Self::Resp,
+            "http" => Self::Http,
+            _ => bail!("Unknown protocol {:?}; expected line, resp or http", s),
+        })
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Line => "line",
+            Self::Resp => "resp",
+            Self::Http => "http",
+        })
+    }
+}
+
+/// Serves a store over TCP. In the line protocol, which is the default, there is
+/// one line per request and one line per reply:
+///
+/// - `GET key` replies `VALUE value`, `JSON document` or `NOT_FOUND`;
+/// - `PUT key value` stores the rest of the line after the key's separating space
+///   as a string (or bytes, if not valid UTF-8), and replies `OK`;
+/// - `DEL key` replies `OK`, whether or not the key held a value;
+/// - `PING` replies `PONG`, and `QUIT` closes the connection.
+///
+/// Commands are case-insensitive and lines may end in `\r\n`. Keys can't contain
+/// whitespace. A string or bytes value without line breaks is replied as `VALUE`
+/// followed by its raw bytes; any other value as a single line of JSON. Errors
+/// reply `ERR message` and leave the connection open.
+pub struct Server {
+    listener: TcpListener,
+    max_connections: usize,
+    protocol: Protocol,
+}
+
+/// Totals over the server's lifetime.
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    pub connections: AtomicU64,
+    /// Connections turned away for exceeding `max_connections`.
+    pub rejected: AtomicU64,
+    pub commands: AtomicU64,
+    /// Commands replied to with an error: `ERR`, or an HTTP status of 400 or more
+    /// other than a missing key's 404.
+    pub errors: AtomicU64,
+}
+
+impl ServerStats {
+    pub fn log(&self) {
+        log::info!(
+            "Served {} connections ({} rejected): {} commands, {} errors.",
+            self.connections.load(Ordering::Relaxed),
+            self.rejected.load(Ordering::Relaxed),
+            self.commands.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed)
+        );
+    }
+}
+
+impl Server {
+    /// Listen on `addr` for `protocol`, serving at most `max_connections`
+    /// connections at once.
+    pub fn bind(
+        addr: impl ToSocketAddrs,
+        max_connections: usize,
+        protocol: Protocol,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        Ok(Self {
+            listener,
+            max_connections,
+            protocol,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serve `store` until `stop` receives or disconnects (e.g. `crossbeam_channel::after`
+    /// or `never`), each connection on its own thread with its own handle to the
+    /// store. Open connections are closed once their current request is replied
+    /// to, and waited for before returning.
+    pub fn run<S: Store + 'static, T: Send + 'static>(
+        self,
+        store: &mut S,
+        stop: Receiver<T>,
+    ) -> Result<ServerStats> {
+        // Accepting blocks, so that connections are taken as soon as they arrive;
+        // on stopping, a connection of the server's own wakes it up.
+        let stopping = Arc::new(AtomicBool::new(false));
+        let addr = self.listener.local_addr()?;
+        let waker_stopping = Arc::clone(&stopping);
+        std::thread::spawn(move || {
+            // Either a message or a disconnect means stop.
+            let _ = stop.recv();
+            waker_stopping.store(true, Ordering::Relaxed);
+            if let Err(err) = TcpStream::connect(addr) {
+                log::error!("Could not wake the server to stop it: {}", err);
+            }
+        });
+        let stats = Arc::new(ServerStats::default());
+        let active = Arc::new(AtomicUsize::new(0));
+        // Disconnected once the server stops, to close every connection.
+        let (closing, closed) = crossbeam_channel::bounded::<()>(0);
+        let key_locks = Arc::new(KeyLocks::new());
+        let mut connections: Vec<JoinHandle<()>> = Vec::new();
+        loop {
+            let accepted = self.listener.accept();
+            if stopping.load(Ordering::Relaxed) {
+                break;
+            }
+            match accepted {
+                Ok((stream, peer)) => {
+                    connections.retain(|handle| !handle.is_finished());
+                    if active.load(Ordering::Relaxed) >= self.max_connections {
+                        stats.rejected.fetch_add(1, Ordering::Relaxed);
+                        reject(stream, peer, self.protocol);
+                        continue;
+                    }
+                    stats.connections.fetch_add(1, Ordering::Relaxed);
+                    active.fetch_add(1, Ordering::Relaxed);
+                    let connection = Connection {
+                        store: store.spawn()?,
+                        stats: Arc::clone(&stats),
+                        closed: closed.clone(),
+                        key_locks: Arc::clone(&key_locks),
+                    };
+                    let active = Arc::clone(&active);
+                    let protocol = self.protocol;
+                    connections.push(std::thread::spawn(move || {
+                        log::debug!("Connection from {}", peer);
+                        let served = match protocol {
+                            Protocol::Line => connection.serve_lines(stream),
+                            Protocol::Resp => {
+                                connection.serve_buffered(stream, Connection::handle_resp)
+                            }
+                            Protocol::Http => {
+                                connection.serve_buffered(stream, Connection::handle_http)
+                            }
+                        };
+                        if let Err(err) = served {
+                            log::warn!("Connection from {} failed: {:#}", peer, err);
+                        }
+                        log::debug!("Connection from {} closed", peer);
+                        active.fetch_sub(1, Ordering::Relaxed);
+                    }));
+                }
+                Err(err) => log::warn!("Failed to accept a connection: {}", err),
+            }
+        }
+        drop(closing);
+        for connection in connections {
+            if connection.join().is_err() {
+                log::error!("A connection thread panicked");
+            }
+        }
+        Ok(Arc::try_unwrap(stats).unwrap_or_default())
+    }
+}
+
+fn reject(mut stream: TcpStream, peer: SocketAddr, protocol: Protocol) {
+    log::warn!("Rejecting {}: too many connections", peer);
+    let message: &[u8] = match protocol {
+        Protocol::Line => b"ERR too many connections\n",
+        Protocol::Resp => b"-ERR max number of clients reached\r\n",
+        Protocol::Http => {
+            b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        }
+    };
+    if let Err(err) = stream.write_all(message) {
+        log::debug!("Could not tell {} it was rejected: {}", peer, err);
+    }
+}
+
+/// Striped by key hash, so that commands on different keys rarely contend.
+struct KeyLocks(Vec<Mutex<()>>);
+
+impl KeyLocks {
+    fn new() -> Self {
+        Self((0..KEY_LOCKS).map(|_| Mutex::new(())).collect())
+    }
+
+    fn lock(&self, key: &str) -> Result<MutexGuard<'_, ()>, StoreError> {
+        let mut hasher = fnv::FnvHasher::default();
+        hasher.write(key.as_bytes());
+        self.0[hasher.finish() as usize % self.0.len()]
+            .lock()
+            .map_err(|_| StoreError::LockError)
+    }
+}
+
+/// What a buffered protocol made of the front of a connection's input.
+enum Frame {
+    /// The request isn't all there yet.
+    Incomplete,
+    /// A request of `len` bytes was replied to.
+    Handled { len: usize, keep_open: bool },
+}
+
+struct Connection<S> {
+    store: S,
+    stats: Arc<ServerStats>,
+    closed: Receiver<()>,
+    key_locks: Arc<KeyLocks>,
+}
+
+/// What a request line asks for.
+enum Reply {
+    Ok,
+    Pong,
+    Value(Option<Blob>),
+    Error(String),
+    Quit,
+}
+
+impl<S: Store> Connection<S> {
+    fn serve_lines(mut self, stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(STOP_CHECK_INTERVAL))?;
+        let mut out = stream.try_clone()?;
+        let mut input = BufReader::new(stream);
+        let mut line = Vec::new();
+        loop {
+            if !self.check_length(&line, &mut out)? {
+                return Ok(());
+            }
+            let limit = (MAX_LINE_BYTES + 1 - line.len()) as u64;
+            match (&mut input).take(limit).read_until(b'\n', &mut line) {
+                // The peer closed the connection.
+                Ok(0) => return Ok(()),
+                // A whole line, one cut short by the limit, or a final unterminated
+                // line.
+                Ok(_) => {}
+                // Whatever was read so far is kept in `line`.
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if self.closed.try_recv() == Err(TryRecvError::Disconnected) {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+            if !self.check_length(&line, &mut out)? {
+                return Ok(());
+            }
+            let request = line.strip_suffix(b"\n").unwrap_or(&line);
+            let request = request.strip_suffix(b"\r").unwrap_or(request);
+            if request.iter().all(u8::is_ascii_whitespace) {
+                line.clear();
+                continue;
+            }
+            self.stats.commands.fetch_add(1, Ordering::Relaxed);
+            let reply = self.execute(request);
+            line.clear();
+            match reply {
+                Reply::Ok => out.write_all(b"OK\n")?,
+                Reply::Pong => out.write_all(b"PONG\n")?,
+                Reply::Value(None) => out.write_all(b"NOT_FOUND\n")?,
+                Reply::Value(Some(value)) => write_value(&mut out, value)?,
+                Reply::Error(message) => {
+                    self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                    writeln!(out, "ERR {}", message.replace(['\r', '\n'], " "))?;
+                }
+                Reply::Quit => {
+                    out.write_all(b"OK\n")?;
+                    return Ok(());
+                }
+            }
+            if self.closed.try_recv() == Err(TryRecvError::Disconnected) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Reads whatever arrives into a buffer and has `handle` reply to every
+    /// complete request in it, in order, so that pipelined requests are answered
+    /// in one write.
+    fn serve_buffered(
+        mut self,
+        mut stream: TcpStream,
+        handle: fn(&mut Self, &[u8], &mut Vec<u8>) -> Frame,
+    ) -> Result<()> {
+        stream.set_read_timeout(Some(STOP_CHECK_INTERVAL))?;
+        let mut input = Vec::new();
+        let mut replies = Vec::new();
+        let mut chunk = vec![0; 64 << 10];
+        loop {
+            let mut parsed = 0;
+            let mut open = true;
+            while open && parsed < input.len() {
+                match handle(&mut self, &input[parsed..], &mut replies) {
+                    Frame::Handled { len, keep_open } => {
+                        parsed += len;
+                        open = keep_open;
+                    }
+                    Frame::Incomplete => break,
+                }
+            }
+            input.drain(..parsed.min(input.len()));
+            stream.write_all(&replies)?;
+            replies.clear();
+            if !open || self.closed.try_recv() == Err(TryRecvError::Disconnected) {
+                return Ok(());
+            }
+            match stream.read(&mut chunk) {
+                Ok(0) => return Ok(()),
+                Ok(len) => input.extend_from_slice(&chunk[..len]),
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn handle_resp(&mut self, input: &[u8], out: &mut Vec<u8>) -> Frame {
+        match parse_resp(input) {
+            Ok(Some((args, len))) => {
+                if args.is_empty() {
+                    return Frame::Handled {
+                        len,
+                        keep_open: true,
+                    };
+                }
+                self.stats.commands.fetch_add(1, Ordering::Relaxed);
+                let started = out.len();
+                let keep_open = self.execute_resp(&args, out);
+                if out.get(started) == Some(&b'-') {
+                    self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                }
+                Frame::Handled { len, keep_open }
+            }
+            Ok(None) => Frame::Incomplete,
+            Err(message) => {
+                self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                out.extend_from_slice(format!("-ERR Protocol error: {}\r\n", message).as_bytes());
+                Frame::Handled {
+                    len: input.len(),
+                    keep_open: false,
+                }
+            }
+        }
+    }
+
+    fn handle_http(&mut self, input: &[u8], out: &mut Vec<u8>) -> Frame {
+        let (request, len) = match parse_http(input) {
+            Ok(Some(parsed)) => parsed,
+            Ok(None) => return Frame::Incomplete,
+            Err((status, message)) => {
+                self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                http_response(out, status, &json_error(&message), false);
+                return Frame::Handled {
+                    len: input.len(),
+                    keep_open: false,
+                };
+            }
+        };
+        self.stats.commands.fetch_add(1, Ordering::Relaxed);
+        let (status, body) = self.execute_http(&request);
+        let miss = status == 404 && request.method == "GET" && request.target.starts_with("/keys/");
+        if status >= 400 && !miss {
+            self.stats.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        http_response(out, status, &body, request.keep_alive);
+        Frame::Handled {
+            len,
+            keep_open: request.keep_alive,
+        }
+    }
+
+    /// The status and JSON body replying to `request`.
+    fn execute_http(&mut self, request: &HttpRequest) -> (u16, Vec<u8>) {
+        let path = request.target.split('?').next().unwrap_or_default();
+        if path == "/batch" {
+            if request.method != "POST" {
+                return (405, json_error("use POST for /batch"));
+            }
+            let ops: Vec<BatchOp> = match serde_json::from_slice(request.body) {
+                Ok(ops) => ops,
+                Err(err) => return (400, json_error(&format!("invalid batch: {}", err))),
+            };
+            let results: Vec<BatchResult> = ops.into_iter().map(|op| self.batch_op(op)).collect();
+            return (200, serde_json::json!(results).to_string().into_bytes());
+        }
+        let Some(key) = path.strip_prefix("/keys/") else {
+            return (
+                404,
+                json_error("no such resource; use /keys/{key} or /batch"),
+            );
+        };
+        let key = match percent_decode(key) {
+            Some(key) if !key.is_empty() => key,
+            _ => {
+                return (
+                    400,
+                    json_error("keys must be non-empty, percent-encoded UTF-8"),
+                )
+            }
+        };
+        let result = match request.method {
+            "GET" => self.store.get(&key).map(|value| match value {
+                Some(value) => (200, serde_json::Value::from(value).to_string().into_bytes()),
+                None => (404, json_error("not found")),
+            }),
+            "PUT" => match serde_json::from_slice::<serde_json::Value>(request.body) {
+                Ok(value) => self
+                    .store
+                    .put(&key, value.into())
+                    .map(|()| (204, Vec::new())),
+                Err(err) => Ok((400, json_error(&format!("body must be JSON: {}", err)))),
+            },
+            "DELETE" => self.store.delete(&key).map(|()| (204, Vec::new())),
+            _ => Ok((405, json_error("use GET, PUT or DELETE for /keys/{key}"))),
+        };
+        result.unwrap_or_else(|err| (500, json_error(&err.to_string())))
+    }
+
+    fn batch_op(&mut self, op: BatchOp) -> BatchResult {
+        let result = match op {
+            BatchOp::Get { key } => self.store.get(&key).map(|value| BatchResult {
+                found: Some(value.is_some()),
+                value: value.map(serde_json::Value::from),
+                ..Default::default()
+            }),
+            BatchOp::Put { key, value } => self
+                .store
+                .put(&key, value.into())
+                .map(|()| BatchResult::default()),
+            BatchOp::Delete { key } => self.store.delete(&key).map(|()| BatchResult::default()),
+        };
+        result.unwrap_or_else(|err| BatchResult {
+            error: Some(err.to_string()),
+            ..Default::default()
+        })
+    }
+
+    /// Append the reply to `args` to `out`, returning whether to keep the
+    /// connection open.
+    fn execute_resp(&mut self, args: &[Vec<u8>], out: &mut Vec<u8>) -> bool {
+        let command = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+        let keys = &args[1..];
+        let arity_ok = match command.as_str() {
+            "get" | "incr" | "select" | "echo" => keys.len() == 1,
+            "set" => keys.len() >= 2,
+            "del" | "exists" => !keys.is_empty(),
+            "ping" => keys.len() <= 1,
+            "quit" | "info" | "command" | "config" | "client" => true,
+            _ => {
+                resp_error(
+                    out,
+                    &format!("unknown command '{}'", command.replace(['\r', '\n'], " ")),
+                );
+                return true;
+            }
+        };
+        if !arity_ok {
+            resp_error(
+                out,
+                &format!("wrong number of arguments for '{}' command", command),
+            );
+            return true;
+        }
+        let key =
+            |index: usize| std::str::from_utf8(&args[index]).map_err(|_| RespError::KeyNotUtf8);
+        let result = match command.as_str() {
+            "get" => key(1)
+                .and_then(|key| Ok(self.store.get(key)?))
+                .map(|value| match value {
+                    None => out.extend_from_slice(b"$-1\r\n"),
+                    Some(value) => resp_bulk(out, &value_bytes(value)),
+                }),
+            "set" if keys.len() > 2 => Err(RespError::Syntax),
+            "set" => key(1).and_then(|key| {
+                let _lock = self.key_locks.lock(key)?;
+                self.store.put(key, Blob::from_bytes(args[2].clone()))?;
+                out.extend_from_slice(b"+OK\r\n");
+                Ok(())
+            }),
+            "del" => (1..args.len())
+                .map(|index| {
+                    let key = key(index)?;
+                    let _lock = self.key_locks.lock(key)?;
+                    let existed = self.store.get(key)?.is_some();
+                    self.store.delete(key)?;
+                    Ok(existed as i64)
+                })
+                .sum::<Result<i64, RespError>>()
+                .map(|deleted| resp_integer(out, deleted)),
+            "exists" => (1..args.len())
+                .map(|index| Ok(self.store.get(key(index)?)?.is_some() as i64))
+                .sum::<Result<i64, RespError>>()
+                .map(|existing| resp_integer(out, existing)),
+            "incr" => key(1).and_then(|key| {
+                let _lock = self.key_locks.lock(key)?;
+                let current = match self.store.get(key)? {
+                    None => 0,
+                    Some(Blob::Int(i)) => i as i64,
+                    Some(Blob::Str(s)) => s.parse().map_err(|_| RespError::NotAnInteger)?,
+                    Some(_) => return Err(RespError::NotAnInteger),
+                };
+                let incremented = current.checked_add(1).ok_or(RespError::Overflow)?;
+                self.store.put(key, Blob::Str(incremented.to_string()))?;
+                resp_integer(out, incremented);
+                Ok(())
+            }),
+            "ping" => {
+                match args.get(1) {
+                    Some(message) => resp_bulk(out, message),
+                    None => out.extend_from_slice(b"+PONG\r\n"),
+                }
+                Ok(())
+            }
+            "echo" => {
+                resp_bulk(out, &args[1]);
+                Ok(())
+            }
+            "select" if args[1].as_slice() == b"0" => {
+                out.extend_from_slice(b"+OK\r\n");
+                Ok(())
+            }
+            "select" => Err(RespError::Database),
+            "quit" => {
+                out.extend_from_slice(b"+OK\r\n");
+                return false;
+            }
+            "info" => {
+                let info = format!(
+                    "# Server\r\nkey_value_store_version:{}\r\n",
+                    env!("CARGO_PKG_VERSION")
+                );
+                resp_bulk(out, info.as_bytes());
+                Ok(())
+            }
+            // Clients look these up on connecting; an empty answer means there is
+            // nothing to configure or document.
+            "command" | "config" => {
+                out.extend_from_slice(b"*0\r\n");
+                Ok(())
+            }
+            "client" => {
+                out.extend_from_slice(b"+OK\r\n");
+                Ok(())
+            }
+            _ => unreachable!(),
+        };
+        if let Err(err) = result {
+            resp_error(out, &err.to_string());
+        }
+        true
+    }
+
+    /// Whether `line` is within `MAX_LINE_BYTES`; if not, tells the peer, which is
+    /// then disconnected.
+    fn check_length(&self, line: &[u8], out: &mut TcpStream) -> Result<bool> {
+        if line.len() <= MAX_LINE_BYTES {
+            return Ok(true);
+        }
+        self.stats.errors.fetch_add(1, Ordering::Relaxed);
+        writeln!(out, "ERR line longer than {} bytes", MAX_LINE_BYTES)?;
+        Ok(false)
+    }
+
+    fn execute(&mut self, request: &[u8]) -> Reply {
+        let request = request.trim_ascii_start();
+        let (command, rest) = split_token(request);
+        let (key, value) = split_token(rest);
+        let key = match std::str::from_utf8(key) {
+            Ok(key) => key,
+            Err(_) => return Reply::Error("keys must be valid UTF-8".to_string()),
+        };
+        let command = String::from_utf8_lossy(command).to_ascii_uppercase();
+        let expects_key = matches!(command.as_str(), "GET" | "PUT" | "DEL");
+        if expects_key && key.is_empty() {
+            return Reply::Error(format!("{} needs a key", command));
+        }
+        let extra = match command.as_str() {
+            "PUT" => false,
+            "GET" | "DEL" => !value.trim_ascii().is_empty(),
+            _ => !rest.trim_ascii().is_empty(),
+        };
+        if extra {
+            return Reply::Error(format!("too many arguments to {}", command));
+        }
+        let result = match command.as_str() {
+            "GET" => self.store.get(key).map(Reply::Value),
+            "PUT" => self
+                .store
+                .put(key, Blob::from_bytes(value.to_vec()))
+                .map(|()| Reply::Ok),
+            "DEL" => self.store.delete(key).map(|()| Reply::Ok),
+            "PING" => Ok(Reply::Pong),
+            "QUIT" => Ok(Reply::Quit),
+            _ => return Reply::Error(format!("unknown command {:?}", command)),
+        };
+        result.unwrap_or_else(|err| Reply::Error(err.to_string()))
+    }
+}
+
+/// The first token of `line` and what follows its separating space or tab.
+fn split_token(line: &[u8]) -> (&[u8], &[u8]) {
+    match line.iter().position(|&b| b == b' ' || b == b'\t') {
+        Some(end) => (&line[..end], &line[end + 1..]),
+        None => (line, &[]),
+    }
+}
+
+fn write_value(out: &mut impl Write, value: Blob) -> Result<()> {
+    let raw = match &value {
+        Blob::Str(s) => Some(s.as_bytes()),
+        Blob::Bytes(bytes) => Some(bytes.as_slice()),
+        _ => None,
+    };
+    match raw {
+        Some(raw) if !raw.contains(&b'\n') && !raw.contains(&b'\r') => {
+            out.write_all(b"VALUE ")?;
+            out.write_all(raw)?;
+            out.write_all(b"\n")?;
+        }
+        _ => writeln!(out, "JSON {}", serde_json::Value::from(value))?,
+    }
+    Ok(())
+}
+
+/// Why a RESP command failed, as its error reply puts it.
+#[derive(Debug, thiserror::Error)]
+enum RespError {
+    #[error("syntax error")]
+    Syntax,
+    #[error("keys must be valid UTF-8")]
+    KeyNotUtf8,
+    #[error("value is not an integer or out of range")]
+    NotAnInteger,
+    #[error("increment or decrement would overflow")]
+    Overflow,
+    #[error("DB index is out of range")]
+    Database,
+    #[error("{0}")]
+    Store(#[from] StoreError),
+}
+
+/// Strings and bytes as they are; any other value as JSON.
+fn value_bytes(value: Blob) -> Vec<u8> {
+    match value {
+        Blob::Str(s) => s.into_bytes(),
+        Blob::Bytes(bytes) => bytes,
+        value => serde_json::Value::from(value).to_string().into_bytes(),
+    }
+}
+
+fn resp_bulk(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+    out.extend_from_slice(bytes);
+    out.extend_from_slice(b"\r\n");
+}
+
+fn resp_integer(out: &mut Vec<u8>, value: i64) {
+    out.extend_from_slice(format!(":{}\r\n", value).as_bytes());
+}
+
+fn resp_error(out: &mut Vec<u8>, message: &str) {
+    out.extend_from_slice(format!("-ERR {}\r\n", message.replace(['\r', '\n'], " ")).as_bytes());
+}
+
+/// A command's arguments, and the bytes of input they took up.
+type Parsed = (Vec<Vec<u8>>, usize);
+
+/// Parse one command from the front of `input`: a multibulk array of bulk
+/// strings, as clients send, or an inline command, as typed into telnet. Returns
+/// the arguments and the bytes they took up, or `None` if the command isn't
+/// complete yet. A blank inline line parses as no arguments.
+fn parse_resp(input: &[u8]) -> Result<Option<Parsed>, String> {
+    let Some((header, mut pos)) = resp_line(input)? else {
+        return Ok(None);
+    };
+    let Some(count) = header.strip_prefix(b"*") else {
+        let args = header
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some((args, pos)));
+    };
+    let count = resp_length(count, MAX_RESP_ARGS, "multibulk length")?;
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let Some((header, header_len)) = resp_line(&input[pos..])? else {
+            return Ok(None);
+        };
+        let Some(len) = header.strip_prefix(b"$") else {
+            return Err(format!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&header[..header.len().min(1)])
+            ));
+        };
+        let len = resp_length(len, MAX_BULK_BYTES, "bulk length")?;
+        let start = pos + header_len;
+        let end = start + len;
+        if input.len() < end + 2 {
+            return Ok(None);
+        }
+        if &input[end..end + 2] != b"\r\n" {
+            return Err("bulk string not terminated by CRLF".to_string());
+        }
+        args.push(input[start..end].to_vec());
+        pos = end + 2;
+    }
+    Ok(Some((args, pos)))
+}
+
+/// The first line of `input` without its line ending, and its length with it.
+fn resp_line(input: &[u8]) -> Result<Option<(&[u8], usize)>, String> {
+    match input.iter().position(|&b| b == b'\n') {
+        Some(end) => {
+            let line = &input[..end];
+            Ok(Some((line.strip_suffix(b"\r").unwrap_or(line), end + 1)))
+        }
+        None if input.len() > MAX_LINE_BYTES => Err("too big inline request".to_string()),
+        None => Ok(None),
+    }
+}
+
+fn resp_length(digits: &[u8], max: usize, what: &str) -> Result<usize, String> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| format!("invalid {}", what))
+}
+
+/// One operation of a `POST /batch` body, e.g.
+/// `{"op": "put", "key": "a", "value": {"n": 1}}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchOp {
+    Get {
+        key: String,
+    },
+    Put {
+        key: String,
+        value: serde_json::Value,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+/// The result of a `BatchOp`, in the same position of the reply: whether a get
+/// found a value and what it was, or the error. Empty for a successful put or
+/// delete.
+#[derive(Debug, Default, Serialize)]
+struct BatchResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    found: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+struct HttpRequest<'a> {
+    method: &'a str,
+    target: &'a str,
+    keep_alive: bool,
+    body: &'a [u8],
+}
+
+/// A status and message to reply with before closing the connection.
+type HttpError = (u16, String);
+
+/// Parse one request from the front of `input`, returning it and the bytes it
+/// took up, or `None` if it isn't complete yet. Bodies need a Content-Length;
+/// chunked request bodies aren't supported.
+fn parse_http(input: &[u8]) -> Result<Option<(HttpRequest<'_>, usize)>, HttpError> {
+    let Some(head_len) = input.windows(4).position(|w| w == b"\r\n\r\n") else {
+        if input.len() > MAX_LINE_BYTES {
+            return Err((431, "request header fields too large".to_string()));
+        }
+        return Ok(None);
+    };
+    let head = std::str::from_utf8(&input[..head_len])
+        .map_err(|_| (400, "request head isn't valid UTF-8".to_string()))?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let (method, target, version) = match request_line.split(' ').collect::<Vec<_>>()[..] {
+        [method, target, version] if version.starts_with("HTTP/1.") => (method, target, version),
+        _ => return Err((400, format!("bad request line {:?}", request_line))),
+    };
+    let mut keep_alive = version != "HTTP/1.0";
+    let mut content_length = 0;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            return Err((400, format!("bad header {:?}", line)));
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .map_err(|_| (400, format!("bad Content-Length {:?}", value)))?;
+            }
+            "transfer-encoding" => {
+                return Err((
+                    411,
+                    "chunked bodies aren't supported; send a Content-Length".to_string(),
+                ));
+            }
+            "connection" if value.eq_ignore_ascii_case("close") => keep_alive = false,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => keep_alive = true,
+            _ => {}
+        }
+    }
+    if content_length > MAX_BULK_BYTES {
+        return Err((
+            413,
+            format!("bodies are limited to {} bytes", MAX_BULK_BYTES),
+        ));
+    }
+    let body_start = head_len + 4;
+    let Some(body) = input.get(body_start..body_start + content_length) else {
+        return Ok(None);
+    };
+    let request = HttpRequest {
+        method,
+        target,
+        keep_alive,
+        body,
+    };
+    Ok(Some((request, body_start + content_length)))
+}
+
+fn http_response(out: &mut Vec<u8>, status: u16, body: &[u8], keep_alive: bool) {
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    };
+    out.extend_from_slice(format!("HTTP/1.1 {} {}\r\n", status, reason).as_bytes());
+    if !body.is_empty() {
+        out.extend_from_slice(b"Content-Type: application/json\r\n");
+    }
+    out.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+    if !keep_alive {
+        out.extend_from_slice(b"Connection: close\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+    out.extend_from_slice(body);
+}
+
+fn json_error(message: &str) -> Vec<u8> {
+    serde_json::json!({ "error": message })
+        .to_string()
+        .into_bytes()
+}
+
+/// Decode `%XX` escapes, or `None` if one is malformed or the result isn't
+/// UTF-8. `+` is left alone, as it is in a path.
+fn percent_decode(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail
+                .get(..2)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
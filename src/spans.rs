@@ -0,0 +1,166 @@
+use std::cell::Cell;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use crate::store::StoreError;
+
+/// Most spans kept; later ones are counted but dropped.
+pub const MAX_SPANS: u64 = 1_000_000;
+
+/// Buffers spans are spread over by thread, so that threads rarely share a lock.
+const BUFFERS: usize = 64;
+
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+static NEXT_THREAD: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// Small sequential ids are easier to read in a trace viewer than OS ids.
+    static THREAD: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+fn thread_id() -> u64 {
+    THREAD.with(|id| {
+        id.get().unwrap_or_else(|| {
+            let next = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+            id.set(Some(next));
+            next
+        })
+    })
+}
+
+/// A finished span, as a Chrome trace "complete" event.
+#[derive(Debug, Serialize)]
+struct Event {
+    name: &'static str,
+    cat: &'static str,
+    ph: &'static str,
+    /// Start and duration, in microseconds since the recorder was installed.
+    ts: f64,
+    dur: f64,
+    pid: u32,
+    tid: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<serde_json::Value>,
+}
+
+struct Recorder {
+    started: Instant,
+    buffers: Vec<Mutex<Vec<Event>>>,
+    /// Spans recorded or dropped so far.
+    spans: AtomicU64,
+}
+
+/// Start recording spans from every thread of the process, until `write_chrome_trace`.
+/// Until then, `span` does nothing but return an inert guard.
+pub fn install() -> Result<()> {
+    let recorder = Recorder {
+        started: Instant::now(),
+        buffers: (0..BUFFERS).map(|_| Mutex::new(Vec::new())).collect(),
+        spans: AtomicU64::new(0),
+    };
+    if RECORDER.set(recorder).is_err() {
+        bail!("Span recording is already installed");
+    }
+    Ok(())
+}
+
+/// Time from now until the returned span is dropped, if recording is installed.
+/// `category` groups spans for filtering in a trace viewer, e.g. "store".
+pub fn span(name: &'static str, category: &'static str) -> Span {
+    Span {
+        open: RECORDER.get().map(|_| OpenSpan {
+            name,
+            category,
+            started: Instant::now(),
+            arg: None,
+        }),
+    }
+}
+
+struct OpenSpan {
+    name: &'static str,
+    category: &'static str,
+    started: Instant,
+    arg: Option<(&'static str, u64)>,
+}
+
+/// Records its span when dropped.
+pub struct Span {
+    open: Option<OpenSpan>,
+}
+
+impl Span {
+    /// Attach a number, shown with the span in a trace viewer.
+    pub fn arg(mut self, key: &'static str, value: u64) -> Self {
+        self.set_arg(key, value);
+        self
+    }
+
+    /// As `arg`, for values only known once the span is under way.
+    pub fn set_arg(&mut self, key: &'static str, value: u64) {
+        if let Some(open) = &mut self.open {
+            open.arg = Some((key, value));
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let (Some(open), Some(recorder)) = (self.open.take(), RECORDER.get()) else {
+            return;
+        };
+        if recorder.spans.fetch_add(1, Ordering::Relaxed) >= MAX_SPANS {
+            return;
+        }
+        let tid = thread_id();
+        let event = Event {
+            name: open.name,
+            cat: open.category,
+            ph: "X",
+            ts: open
+                .started
+                .saturating_duration_since(recorder.started)
+                .as_secs_f64()
+                * 1e6,
+            dur: open.started.elapsed().as_secs_f64() * 1e6,
+            pid: std::process::id(),
+            tid,
+            args: open
+                .arg
+                .map(|(key, value)| serde_json::json!({ key: value })),
+        };
+        if let Ok(mut buffer) = recorder.buffers[tid as usize % BUFFERS].lock() {
+            buffer.push(event);
+        }
+    }
+}
+
+/// Write every span recorded so far as a Chrome trace (the JSON trace event
+/// format read by chrome://tracing and Perfetto), returning how many were written
+/// and how many were dropped past `MAX_SPANS`. Spans still open are left out.
+pub fn write_chrome_trace(mut out: impl Write) -> Result<(usize, u64)> {
+    let Some(recorder) = RECORDER.get() else {
+        bail!("Span recording isn't installed");
+    };
+    let mut events = Vec::new();
+    for buffer in &recorder.buffers {
+        events.append(&mut *buffer.lock().map_err(|_| StoreError::LockError)?);
+    }
+    events.sort_by(|a, b| a.ts.total_cmp(&b.ts));
+    let recorded = recorder.spans.load(Ordering::Relaxed);
+    serde_json::to_writer(
+        &mut out,
+        &serde_json::json!({
+            "traceEvents": events,
+            "displayTimeUnit": "ms",
+        }),
+    )?;
+    writeln!(out)?;
+    out.flush()?;
+    Ok((events.len(), recorded.saturating_sub(MAX_SPANS)))
+}
@@ -0,0 +1,548 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+
+use crate::store::{Blob, Store, StoreError};
+
+/// Changes a primary may get ahead of its standby by before its writes block,
+/// which bounds how much a promotion has to drain.
+pub const DEFAULT_FEED_CAPACITY: usize = 4096;
+
+/// Number of independently locked key stripes, so that keeping each key's
+/// changes in order doesn't funnel every write through a single lock.
+const FEED_STRIPES: usize = 16;
+
+/// One write to a primary, as shipped to its standby.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    Put(String, Blob),
+    Delete(String),
+}
+
+/// Wraps a primary store, sending every write that succeeds to a change feed for
+/// a `StandbyStore` to apply. The feed is bounded and never drops changes: once
+/// it's full, writes wait for the standby to catch up.
+pub struct ReplicatedStore<S: Store> {
+    inner: S,
+    feed: Sender<Change>,
+    /// Held from a key's write until its change is queued, so that changes to one
+    /// key reach the feed in the order they were made.
+    stripes: Arc<Vec<Mutex<()>>>,
+}
+
+impl<S: Store> ReplicatedStore<S> {
+    /// Returns the store and the receiving end of its change feed, which holds up
+    /// to `capacity` changes.
+    pub fn new(inner: S, capacity: usize) -> (Self, Receiver<Change>) {
+        let (feed, changes) = crossbeam_channel::bounded(capacity);
+        let store = Self {
+            inner,
+            feed,
+            stripes: Arc::new((0..FEED_STRIPES).map(|_| Mutex::new(())).collect()),
+        };
+        (store, changes)
+    }
+
+    fn replicate(
+        &mut self,
+        key: &str,
+        write: impl FnOnce(&mut S) -> Result<(), StoreError>,
+        change: impl FnOnce() -> Change,
+    ) -> Result<(), StoreError> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let _stripe = self.stripes[(hasher.finish() as usize) % FEED_STRIPES]
+            .lock()
+            .map_err(|_| StoreError::LockError)?;
+        write(&mut self.inner)?;
+        // The standby stops listening once it's promoted.
+        self.feed.send(change()).map_err(|_| StoreError::Demoted)
+    }
+}
+
+impl<S: Store> Store for ReplicatedStore<S> {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        self.inner.get(key)
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let change = value.clone();
+        self.replicate(
+            key,
+            |inner| inner.put(key, value),
+            || Change::Put(key.to_string(), change),
+        )
+    }
+
+    /// Durable on the primary only: the standby applies it as an ordinary put.
+    fn put_durable(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let change = value.clone();
+        self.replicate(
+            key,
+            |inner| inner.put_durable(key, value),
+            || Change::Put(key.to_string(), change),
+        )
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        self.replicate(
+            key,
+            |inner| inner.delete(key),
+            || Change::Delete(key.to_string()),
+        )
+    }
+
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        self.inner.range(range)
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(Self {
+            inner: self.inner.spawn()?,
+            feed: self.feed.clone(),
+            stripes: Arc::clone(&self.stripes),
+        })
+    }
+}
+
+/// What a promotion did.
+#[derive(Clone, Debug)]
+pub struct Promotion {
+    /// From the promotion being requested to the standby accepting writes.
+    pub elapsed: Duration,
+    /// Changes still in the feed when the promotion was requested, and applied
+    /// before the standby took over.
+    pub drained: u64,
+    /// Changes left in the feed when the timeout ran out, which the new primary
+    /// never got.
+    pub abandoned: usize,
+    pub timed_out: bool,
+}
+
+/// Counters shared by every handle of a `StandbyStore`.
+#[derive(Debug, Default)]
+pub struct StandbyStats {
+    pub applied: AtomicU64,
+    pub apply_errors: AtomicU64,
+    /// Client writes refused while the store was still a standby.
+    pub rejected_writes: AtomicU64,
+}
+
+struct StandbyShared {
+    primary: AtomicBool,
+    stats: StandbyStats,
+    /// Asks the applier thread to drain the feed within a timeout, then take over.
+    promotions: Sender<(Duration, Sender<Promotion>)>,
+}
+
+/// Wraps a store as a warm standby: a background thread continuously applies a
+/// primary's change feed to it, and client writes are refused with
+/// `StoreError::Standby` until `promote` makes it the primary. Reads are served
+/// all along, as of the changes applied so far.
+pub struct StandbyStore<S: Store> {
+    inner: S,
+    shared: Arc<StandbyShared>,
+}
+
+impl<S: Store + 'static> StandbyStore<S> {
+    /// Start applying `feed`, e.g. from `ReplicatedStore::new`, to `inner`. The
+    /// applier stops once it's promoted, or if the primary goes away.
+    pub fn new(mut inner: S, feed: Receiver<Change>) -> Result<Self> {
+        let (promotions, requests) = crossbeam_channel::bounded(1);
+        let shared = Arc::new(StandbyShared {
+            primary: AtomicBool::new(false),
+            stats: StandbyStats::default(),
+            promotions,
+        });
+        let mut applier = Applier {
+            store: inner.spawn()?,
+            shared: Arc::clone(&shared),
+        };
+        std::thread::spawn(move || applier.run(feed, requests));
+        Ok(Self { inner, shared })
+    }
+}
+
+impl<S: Store> StandbyStore<S> {
+    pub fn is_primary(&self) -> bool {
+        self.shared.primary.load(Ordering::Acquire)
+    }
+
+    pub fn stats(&self) -> &StandbyStats {
+        &self.shared.stats
+    }
+
+    /// Make this store the primary: apply whatever is left in the change feed,
+    /// then start accepting writes. Stop writing to the old primary first, or the
+    /// drain may never finish. Takes at most about `timeout`; if the feed isn't
+    /// drained by then, the store takes over anyway and the rest of the feed is
+    /// abandoned. Promoting a primary does nothing.
+    pub fn promote(&self, timeout: Duration) -> Promotion {
+        let started = Instant::now();
+        let (reply, promoted) = crossbeam_channel::bounded(1);
+        if self.shared.promotions.send((timeout, reply)).is_ok() {
+            if let Ok(promotion) = promoted.recv() {
+                // Including the wait for the applier to pick the request up.
+                return Promotion {
+                    elapsed: started.elapsed(),
+                    ..promotion
+                };
+            }
+        }
+        // The applier already stopped, because the primary went away or this
+        // store was promoted before, so there's nothing left to drain.
+        self.shared.primary.store(true, Ordering::Release);
+        Promotion {
+            elapsed: started.elapsed(),
+            drained: 0,
+            abandoned: 0,
+            timed_out: false,
+        }
+    }
+
+    fn write(
+        &mut self,
+        write: impl FnOnce(&mut S) -> Result<(), StoreError>,
+    ) -> Result<(), StoreError> {
+        if !self.is_primary() {
+            self.shared
+                .stats
+                .rejected_writes
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(StoreError::Standby);
+        }
+        write(&mut self.inner)
+    }
+}
+
+impl<S: Store> Store for StandbyStore<S> {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        self.inner.get(key)
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        self.write(|inner| inner.put(key, value))
+    }
+
+    fn put_durable(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        self.write(|inner| inner.put_durable(key, value))
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        self.write(|inner| inner.delete(key))
+    }
+
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        self.inner.range(range)
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(Self {
+            inner: self.inner.spawn()?,
+            shared: Arc::clone(&self.shared),
+        })
+    }
+}
+
+/// The background thread applying a standby's change feed.
+struct Applier<S: Store> {
+    store: S,
+    shared: Arc<StandbyShared>,
+}
+
+impl<S: Store> Applier<S> {
+    fn run(&mut self, feed: Receiver<Change>, requests: Receiver<(Duration, Sender<Promotion>)>) {
+        loop {
+            crossbeam_channel::select! {
+                recv(feed) -> change => match change {
+                    Ok(change) => self.apply(change),
+                    Err(_) => {
+                        log::info!("The primary's change feed closed; the standby stopped applying it");
+                        return;
+                    }
+                },
+                recv(requests) -> request => {
+                    if let Ok((timeout, reply)) = request {
+                        let _ = reply.send(self.promote(&feed, timeout));
+                    }
+                    // Dropping the feed disconnects it, so that the old primary's
+                    // writes fail rather than wait for a standby that's gone.
+                    return;
+                },
+            }
+        }
+    }
+
+    fn promote(&mut self, feed: &Receiver<Change>, timeout: Duration) -> Promotion {
+        let started = Instant::now();
+        let mut drained = 0;
+        let mut timed_out = false;
+        loop {
+            if started.elapsed() >= timeout {
+                timed_out = !feed.is_empty();
+                break;
+            }
+            match feed.try_recv() {
+                Ok(change) => {
+                    self.apply(change);
+                    drained += 1;
+                }
+                Err(_) => break,
+            }
+        }
+        let abandoned = feed.len();
+        self.shared.primary.store(true, Ordering::Release);
+        Promotion {
+            elapsed: started.elapsed(),
+            drained,
+            abandoned,
+            timed_out,
+        }
+    }
+
+    fn apply(&mut self, change: Change) {
+        let applied = match &change {
+            Change::Put(key, value) => self.store.put(key, value.clone()),
+            Change::Delete(key) => self.store.delete(key),
+        };
+        let stats = &self.shared.stats;
+        match applied {
+            Ok(()) => stats.applied.fetch_add(1, Ordering::Relaxed),
+            Err(err) => {
+                log::error!("The standby failed to apply {:?}: {:?}", change, err);
+                stats.apply_errors.fetch_add(1, Ordering::Relaxed)
+            }
+        };
+    }
+}
+
+/// How a failover under load went, as seen by `FailoverStore`'s clients.
+#[derive(Clone, Debug)]
+pub struct FailoverReport {
+    pub promotion: Promotion,
+    /// From the primary failing to the first write the new primary accepted:
+    /// how long clients couldn't write.
+    pub write_outage: Option<Duration>,
+    pub rejected_writes: u64,
+    /// Changes the standby applied over the whole run, including the drain.
+    pub applied: u64,
+    pub apply_errors: u64,
+}
+
+impl FailoverReport {
+    pub fn log(&self) {
+        log::info!(
+            "failover_promotion_ms: {:.3}",
+            self.promotion.elapsed.as_secs_f64() * 1000.0
+        );
+        match self.write_outage {
+            Some(outage) => log::info!(
+                "failover_write_outage_ms: {:.3}",
+                outage.as_secs_f64() * 1000.0
+            ),
+            None => log::info!("failover_write_outage_ms: no writes after the failover"),
+        }
+        log::info!(
+            "failover_changes: {} applied, {} drained at promotion, {} abandoned",
+            self.applied,
+            self.promotion.drained,
+            self.promotion.abandoned
+        );
+        log::info!("failover_rejected_writes: {}", self.rejected_writes);
+        if self.promotion.timed_out {
+            log::warn!(
+                "The promotion timed out; {} acknowledged changes were lost",
+                self.promotion.abandoned
+            );
+        }
+        if self.apply_errors > 0 {
+            log::warn!("failover_apply_errors: {}", self.apply_errors);
+        }
+    }
+}
+
+struct FailoverShared {
+    started: Instant,
+    /// Set when the primary fails; from then on every operation goes to the
+    /// standby.
+    failed: AtomicBool,
+    /// When the primary failed, and when the new primary first accepted a write,
+    /// in microseconds since `started`; 0 until then.
+    failed_at_us: AtomicU64,
+    first_write_us: AtomicU64,
+    promotion: Mutex<Option<Promotion>>,
+}
+
+impl FailoverShared {
+    fn since_start_us(&self) -> u64 {
+        (self.started.elapsed().as_micros() as u64).max(1)
+    }
+}
+
+/// Sends operations to a replicated primary until `spawn_failover` fails it,
+/// then to its standby: reads carry on straight away, and writes are refused
+/// until the standby has been promoted. For measuring failover under load.
+pub struct FailoverStore<S: Store, T: Store> {
+    primary: ReplicatedStore<S>,
+    standby: StandbyStore<T>,
+    shared: Arc<FailoverShared>,
+}
+
+impl<S: Store, T: Store + 'static> FailoverStore<S, T> {
+    /// `standby` should start out empty, or hold the same data as `primary`.
+    pub fn new(primary: S, standby: T) -> Result<Self> {
+        let (primary, feed) = ReplicatedStore::new(primary, DEFAULT_FEED_CAPACITY);
+        Ok(Self {
+            primary,
+            standby: StandbyStore::new(standby, feed)?,
+            shared: Arc::new(FailoverShared {
+                started: Instant::now(),
+                failed: AtomicBool::new(false),
+                failed_at_us: AtomicU64::new(0),
+                first_write_us: AtomicU64::new(0),
+                promotion: Mutex::new(None),
+            }),
+        })
+    }
+
+    /// After `after`, fail the primary and promote the standby, giving it
+    /// `timeout` to drain the feed. Dropping the returned handle before then
+    /// cancels the failover.
+    pub fn spawn_failover(&mut self, after: Duration, timeout: Duration) -> Result<Failover> {
+        let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+        let standby = self.standby.spawn()?;
+        let shared = Arc::clone(&self.shared);
+        let handle = std::thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(after) {
+                shared.failed.store(true, Ordering::Release);
+                shared
+                    .failed_at_us
+                    .store(shared.since_start_us(), Ordering::Relaxed);
+                log::info!("Failing the primary over to the standby");
+                let promotion = standby.promote(timeout);
+                if let Ok(mut slot) = shared.promotion.lock() {
+                    *slot = Some(promotion);
+                }
+            }
+        });
+        Ok(Failover {
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+
+    /// None if the failover hasn't happened yet.
+    pub fn report(&self) -> Result<Option<FailoverReport>, StoreError> {
+        let promotion = self
+            .shared
+            .promotion
+            .lock()
+            .map_err(|_| StoreError::LockError)?
+            .clone();
+        let failed_at = self.shared.failed_at_us.load(Ordering::Relaxed);
+        let first_write = self.shared.first_write_us.load(Ordering::Relaxed);
+        let stats = self.standby.stats();
+        Ok(promotion.map(|promotion| FailoverReport {
+            promotion,
+            write_outage: (first_write > 0)
+                .then(|| Duration::from_micros(first_write.saturating_sub(failed_at))),
+            rejected_writes: stats.rejected_writes.load(Ordering::Relaxed),
+            applied: stats.applied.load(Ordering::Relaxed),
+            apply_errors: stats.apply_errors.load(Ordering::Relaxed),
+        }))
+    }
+}
+
+impl<S: Store, T: Store> FailoverStore<S, T> {
+    fn failed(&self) -> bool {
+        self.shared.failed.load(Ordering::Acquire)
+    }
+
+    fn write(
+        &mut self,
+        to_primary: impl FnOnce(&mut ReplicatedStore<S>) -> Result<(), StoreError>,
+        to_standby: impl FnOnce(&mut StandbyStore<T>) -> Result<(), StoreError>,
+    ) -> Result<(), StoreError> {
+        if !self.failed() {
+            return to_primary(&mut self.primary);
+        }
+        to_standby(&mut self.standby)?;
+        if self.shared.first_write_us.load(Ordering::Relaxed) == 0 {
+            let _ = self.shared.first_write_us.compare_exchange(
+                0,
+                self.shared.since_start_us(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<S: Store, T: Store> Store for FailoverStore<S, T> {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        if self.failed() {
+            self.standby.get(key)
+        } else {
+            self.primary.get(key)
+        }
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let standby_value = value.clone();
+        self.write(
+            |primary| primary.put(key, value),
+            |standby| standby.put(key, standby_value),
+        )
+    }
+
+    fn put_durable(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let standby_value = value.clone();
+        self.write(
+            |primary| primary.put_durable(key, value),
+            |standby| standby.put_durable(key, standby_value),
+        )
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        self.write(|primary| primary.delete(key), |standby| standby.delete(key))
+    }
+
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        if self.failed() {
+            self.standby.range(range)
+        } else {
+            self.primary.range(range)
+        }
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(Self {
+            primary: self.primary.spawn()?,
+            standby: self.standby.spawn()?,
+            shared: Arc::clone(&self.shared),
+        })
+    }
+}
+
+/// Background thread failing a `FailoverStore` over; cancels it if dropped first.
+pub struct Failover {
+    stop: Option<Sender<()>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for Failover {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the thread up.
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
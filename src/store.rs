@@ -1,29 +1,250 @@
 use std::collections::HashMap;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub enum Blob {
     Null,
     Str(String),
     Int(isize),
+    Float(f64),
+    /// Stored as a byte string by serializers that have one (e.g. CBOR), rather than
+    /// as a list of integers.
+    Bytes(#[serde(with = "serde_bytes")] Vec<u8>),
+    List(Vec<Blob>),
     Dict(HashMap<String, Blob>),
 }
 
+impl Blob {
+    /// `Str` if the bytes are valid UTF-8, otherwise `Bytes`.
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(s) => Blob::Str(s),
+            Err(err) => Blob::Bytes(err.into_bytes()),
+        }
+    }
+}
+
+/// Converts plain (untagged) JSON: integers that fit become `Int`, other numbers
+/// `Float`.
+impl From<serde_json::Value> for Blob {
+    fn from(value: serde_json::Value) -> Self {
+        use serde_json::Value;
+        match value {
+            Value::Null => Blob::Null,
+            Value::Bool(b) => Blob::Int(b as isize),
+            Value::Number(n) => match n.as_i64() {
+                Some(i) => Blob::Int(i as isize),
+                None => Blob::Float(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            Value::String(s) => Blob::Str(s),
+            Value::Array(values) => Blob::List(values.into_iter().map(Blob::from).collect()),
+            Value::Object(map) => Blob::Dict(map.into_iter().map(|(k, v)| (k, v.into())).collect()),
+        }
+    }
+}
+
+/// Converts to plain JSON. Lossy: `Bytes` become a list of integers, and non-finite
+/// floats become null.
+impl From<Blob> for serde_json::Value {
+    fn from(blob: Blob) -> Self {
+        use serde_json::Value;
+        match blob {
+            Blob::Null => Value::Null,
+            Blob::Str(s) => Value::String(s),
+            Blob::Int(i) => Value::from(i as i64),
+            Blob::Float(f) => serde_json::Number::from_f64(f)
+                .map(Value::Number)
+                .unwrap_or(Value::Null),
+            Blob::Bytes(bytes) => Value::Array(bytes.into_iter().map(Value::from).collect()),
+            Blob::List(values) => Value::Array(values.into_iter().map(Value::from).collect()),
+            Blob::Dict(dict) => {
+                Value::Object(dict.into_iter().map(|(k, v)| (k, v.into())).collect())
+            }
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum StoreError {
+    #[error("key not found: {0}")]
+    KeyNotFound(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("serialization error: {0}")]
+    Serialization(String),
+    #[error("background writer has stopped")]
+    WriterDisconnected,
+    #[error("background writer's queue is full")]
+    QueueFull,
+    #[error("store has crashed")]
+    Crashed,
+    #[error("store failed after a background error: {0}")]
+    BackgroundFailure(String),
+    #[error("script error: {0}")]
+    Script(String),
+    #[error("upstream error: {0}")]
+    Upstream(String),
+    #[error("remote store error: {0}")]
+    Remote(String),
+    #[error("store is a standby; writes go to its primary")]
+    Standby,
+    #[error("store is no longer the primary")]
+    Demoted,
+    #[error("store was opened read-only")]
+    ReadOnly,
+    #[error("{0} is not supported")]
+    Unsupported(&'static str),
     #[error("lock failed to acquire")]
     LockError,
     #[error("bad file hash: {0}")]
     BadFileHash(usize),
     #[error("no threads completed")]
     NoThreadsCompleted,
+    #[error("no store manifest found in {0:?}")]
+    MissingManifest(PathBuf),
+    #[error("a store already exists in {0:?}")]
+    AlreadyExists(PathBuf),
+    #[error(
+        "store in {path:?} was written with {field} = {stored}, but {requested} was requested"
+    )]
+    ManifestMismatch {
+        path: PathBuf,
+        field: &'static str,
+        stored: String,
+        requested: String,
+    },
+    #[error("unsupported store format version: {0}")]
+    UnsupportedFormatVersion(u32),
+    #[error("write rejected by hook: {0}")]
+    HookRejected(String),
+    #[error("self-test failed: {0}")]
+    SelfTestFailed(String),
+    #[error("invalid key {key:?}: {violation}")]
+    InvalidKey {
+        key: String,
+        violation: KeyViolation,
+    },
+}
+
+/// The rule of a `keys::KeyPolicy` that a key breaks.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum KeyViolation {
+    #[error("empty")]
+    Empty,
+    #[error("{len} bytes long, more than the maximum of {max_len}")]
+    TooLong { len: usize, max_len: usize },
+    #[error("contains disallowed character {0:?}")]
+    DisallowedChar(char),
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Serialization(err.to_string())
+    }
+}
+
+impl<E: std::fmt::Debug> From<ciborium::ser::Error<E>> for StoreError {
+    fn from(err: ciborium::ser::Error<E>) -> Self {
+        Self::Serialization(err.to_string())
+    }
+}
+
+impl<E: std::fmt::Debug> From<ciborium::de::Error<E>> for StoreError {
+    fn from(err: ciborium::de::Error<E>) -> Self {
+        Self::Serialization(err.to_string())
+    }
+}
+
+impl<T> From<crossbeam_channel::SendError<T>> for StoreError {
+    fn from(_: crossbeam_channel::SendError<T>) -> Self {
+        Self::WriterDisconnected
+    }
 }
 
 pub trait Store: Sized + Send {
-    fn get(&self, key: &str) -> Result<Blob>;
-    fn put(&mut self, key: &str, value: Blob) -> Result<()>;
-    fn spawn(&mut self) -> Result<Self>;
+    /// Returns None if the key has no value; errors are reserved for failures.
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError>;
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError>;
+    /// Remove a key. Deleting a missing key is not an error.
+    fn delete(&mut self, key: &str) -> Result<(), StoreError>;
+    fn spawn(&mut self) -> Result<Self, StoreError>;
+
+    /// As `put`, but only returns once the write is durable: flushed and fsynced to
+    /// disk, not just handed to a writer. Stores with nothing to persist to don't
+    /// support it.
+    fn put_durable(&mut self, _key: &str, _value: Blob) -> Result<(), StoreError> {
+        Err(StoreError::Unsupported("durable puts"))
+    }
+
+    /// The value `key` held before it was soft-deleted, if its tombstone hasn't been
+    /// purged. Only stores with soft deletes enabled support it.
+    fn get_deleted(&self, _key: &str) -> Result<Option<Blob>, StoreError> {
+        Err(StoreError::Unsupported("soft deletes"))
+    }
+
+    /// Restore `key`'s soft-deleted value, returning whether there was one to
+    /// restore.
+    fn undelete(&mut self, _key: &str) -> Result<bool, StoreError> {
+        Err(StoreError::Unsupported("soft deletes"))
+    }
+
+    /// Every key starting with `prefix`, and its value, in no particular order.
+    fn scan(&self, _prefix: &str) -> Result<Vec<(String, Blob)>, StoreError> {
+        Err(StoreError::Unsupported("scans"))
+    }
+
+    /// Every key from `range.start` up to but not including `range.end`, and its
+    /// value, in key order. An empty or backwards range finds nothing.
+    fn range(&self, _range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        Err(StoreError::Unsupported("range scans"))
+    }
+
+    /// Point-in-time statistics, as `name: value` lines for display.
+    fn describe_stats(&self) -> Result<Vec<String>, StoreError> {
+        Err(StoreError::Unsupported("stats"))
+    }
+
+    /// Persist every pending write. Stores with nothing to persist to have nothing
+    /// to do.
+    fn persist(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Get `key`, recording each step the store takes to find it. Stores that
+    /// don't break a get down report it as a single step.
+    fn explain_get(&self, key: &str) -> Result<Explanation, StoreError> {
+        let started = Instant::now();
+        let value = self.get(key)?;
+        let elapsed = started.elapsed();
+        Ok(Explanation {
+            value,
+            steps: vec![ExplainStep {
+                name: "get",
+                detail: "no breakdown for this store".to_string(),
+                elapsed,
+            }],
+            total: elapsed,
+        })
+    }
+}
+
+/// How a store served one get, from `Store::explain_get`.
+#[derive(Debug)]
+pub struct Explanation {
+    pub value: Option<Blob>,
+    /// In the order they were taken.
+    pub steps: Vec<ExplainStep>,
+    pub total: Duration,
+}
+
+#[derive(Debug)]
+pub struct ExplainStep {
+    pub name: &'static str,
+    pub detail: String,
+    pub elapsed: Duration,
 }
@@ -0,0 +1,578 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+
+use crate::chaos::Crash;
+use crate::lru::LruMemoryStore;
+use crate::store::{Blob, ExplainStep, Explanation, Store, StoreError};
+
+/// Longest a background thread sleeps before checking whether it should stop.
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// When a `TieredStore` writes puts to its backing store. Parses "write-through"
+/// or "write-back".
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WriteMode {
+    /// Write every put to the backing store, then the cache.
+    #[default]
+    WriteThrough,
+    /// Write puts to the cache only, and to the backing store once they're evicted
+    /// or the store is persisted.
+    WriteBack,
+}
+
+impl std::str::FromStr for WriteMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim() {
+            "write-through" => Self::WriteThrough,
+            "write-back" => Self::WriteBack,
+            other => bail!(
+                "Unknown cache write mode {:?}; expected write-through or write-back",
+                other
+            ),
+        })
+    }
+}
+
+/// A store that can sit in front of a `TieredStore`'s backing store: one that
+/// says what it evicts, so that unwritten values aren't lost.
+pub trait Cache: Store {
+    /// As `put`, returning the keys and values evicted to make room, which may
+    /// include this one.
+    fn put_evicting(&mut self, key: &str, value: Blob) -> Result<Vec<(String, Blob)>, StoreError>;
+
+    /// Whether it holds `key`, without counting as a use.
+    fn contains(&self, key: &str) -> Result<bool, StoreError>;
+
+    /// Up to `limit` of its keys, the most valuable to keep first.
+    fn hottest(&self, limit: usize) -> Result<Vec<String>, StoreError>;
+}
+
+impl Cache for LruMemoryStore {
+    fn put_evicting(&mut self, key: &str, value: Blob) -> Result<Vec<(String, Blob)>, StoreError> {
+        self.put_returning_evicted(key, value)
+    }
+
+    fn contains(&self, key: &str) -> Result<bool, StoreError> {
+        LruMemoryStore::contains(self, key)
+    }
+
+    /// Most recently used first.
+    fn hottest(&self, limit: usize) -> Result<Vec<String>, StoreError> {
+        LruMemoryStore::hottest(self, limit)
+    }
+}
+
+/// How a `TieredStore`'s gets were served, and what it wrote back.
+#[derive(Debug, Default)]
+pub struct TieredStats {
+    pub cache_hits: AtomicU64,
+    /// Gets the cache missed, served by the backing store.
+    pub cache_misses: AtomicU64,
+    /// Of the cache misses, those the backing store didn't have either.
+    pub backing_misses: AtomicU64,
+    /// Dirty values written to the backing store, on eviction or persist.
+    pub write_backs: AtomicU64,
+    /// Saved hot keys copied from the backing store into the cache after opening.
+    pub rehydrated: AtomicU64,
+}
+
+impl TieredStats {
+    pub fn summarize(&self) {
+        let hits = self.cache_hits.load(Ordering::Relaxed);
+        let misses = self.cache_misses.load(Ordering::Relaxed);
+        log::info!("tiered_cache_hits: {}", hits);
+        log::info!("tiered_cache_misses: {}", misses);
+        log::info!(
+            "tiered_cache_hit_rate: {:.2}%",
+            if hits + misses > 0 {
+                hits as f64 / (hits + misses) as f64 * 100.0
+            } else {
+                0.0
+            }
+        );
+        log::info!(
+            "tiered_backing_misses: {}",
+            self.backing_misses.load(Ordering::Relaxed)
+        );
+        log::info!(
+            "tiered_write_backs: {}",
+            self.write_backs.load(Ordering::Relaxed)
+        );
+        log::info!(
+            "tiered_rehydrated: {}",
+            self.rehydrated.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Where a `TieredStore` saves its cache's hottest keys, and how often.
+#[derive(Clone, Debug)]
+pub struct HotKeyLog {
+    /// A JSON list of keys, hottest first, replaced whole by each save.
+    pub path: PathBuf,
+    pub interval: Duration,
+    /// Most keys saved at once.
+    pub limit: usize,
+}
+
+impl HotKeyLog {
+    /// The keys last saved at `path`; none if nothing has been saved there yet.
+    pub fn load(path: &Path) -> Result<Vec<String>> {
+        match File::open(path) {
+            Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Write via a temporary file, so that a reader never sees half a list. The
+    /// keys are only a hint, so they aren't fsynced.
+    fn save(&self, keys: &[String]) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, keys)?;
+        writer.flush()?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Paces rehydration: `rate` tokens a second, up to `burst` saved up.
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        let burst = (rate / 10.0).max(1.0);
+        Self {
+            rate,
+            burst,
+            tokens: 1.0,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Wait for a token and take it; false if `stop` was set first.
+    fn take(&mut self, stop: &AtomicBool) -> bool {
+        loop {
+            if stop.load(Ordering::Relaxed) {
+                return false;
+            }
+            let now = Instant::now();
+            let earned = (now - self.refilled).as_secs_f64() * self.rate;
+            self.tokens = (self.tokens + earned).min(self.burst);
+            self.refilled = now;
+            if self.tokens >= 1.0 {
+                self.tokens -= 1.0;
+                return true;
+            }
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+            std::thread::sleep(wait.min(STOP_CHECK_INTERVAL));
+        }
+    }
+}
+
+/// Sleep for `duration`, or until `stop` is set. Returns false if it was.
+fn sleep_unless_stopped(stop: &AtomicBool, duration: Duration) -> bool {
+    let until = Instant::now() + duration;
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= until {
+            return true;
+        }
+        std::thread::sleep((until - now).min(STOP_CHECK_INTERVAL));
+    }
+}
+
+/// A thread working for a `TieredStore` until the store crashes or its last
+/// handle is dropped.
+struct Background {
+    stop: Arc<AtomicBool>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Background {
+    fn start(work: impl FnOnce(&AtomicBool) + Send + 'static) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let handle = std::thread::spawn(move || work(&thread_stop));
+        Self {
+            stop,
+            handle: Mutex::new(Some(handle)),
+        }
+    }
+
+    fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let handle = match self.handle.lock() {
+            Ok(mut handle) => handle.take(),
+            Err(_) => None,
+        };
+        if let Some(handle) = handle {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// A cache in front of a backing store. Gets are served by the cache if it has
+/// the key, and otherwise by the backing store, whose value is then cached.
+/// Deletes go to both. Puts depend on the `WriteMode`: written through to both,
+/// or kept in the cache, dirty, until it evicts them.
+///
+/// Everything but a cache hit takes one lock shared by every handle, so that a
+/// miss can't cache a value older than one being put, nor read the backing store
+/// while a newer value is on its way there.
+pub struct TieredStore<C: Cache, B: Store> {
+    /// In `RefCell`s so that gets can cache what they fetch, and write back what
+    /// that evicts.
+    cache: RefCell<C>,
+    backing: RefCell<B>,
+    mode: WriteMode,
+    /// Keys whose cached value the backing store doesn't have yet.
+    dirty: Arc<Mutex<HashSet<String>>>,
+    stats: Arc<TieredStats>,
+    /// Hot-key saving and rehydration, shared by every handle but theirs.
+    background: Vec<Arc<Background>>,
+}
+
+impl<C: Cache, B: Store> TieredStore<C, B> {
+    pub fn new(cache: C, backing: B, mode: WriteMode) -> Self {
+        Self {
+            cache: RefCell::new(cache),
+            backing: RefCell::new(backing),
+            mode,
+            dirty: Arc::new(Mutex::new(HashSet::new())),
+            stats: Arc::new(TieredStats::default()),
+            background: Vec::new(),
+        }
+    }
+
+    /// Count into `stats` instead of the store's own, e.g. to carry counts across
+    /// reopening the store.
+    pub fn with_stats(self, stats: Arc<TieredStats>) -> Self {
+        Self { stats, ..self }
+    }
+
+    /// Shared by every handle spawned from this store.
+    pub fn stats(&self) -> Arc<TieredStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// A handle on the backing store.
+    pub fn spawn_backing(&self) -> Result<B, StoreError> {
+        self.backing.borrow_mut().spawn()
+    }
+
+    /// Copy `key` from the backing store into the cache, unless the cache already
+    /// has it or the backing store doesn't. Returns whether it was copied. Takes
+    /// the lock a miss does, so it can't cache a value older than one being put.
+    fn warm(&self, key: &str) -> Result<bool, StoreError> {
+        let mut dirty = self.dirty.lock().map_err(|_| StoreError::LockError)?;
+        if self.cache.borrow().contains(key)? {
+            return Ok(false);
+        }
+        let Some(value) = self.backing.borrow().get(key)? else {
+            return Ok(false);
+        };
+        let evicted = self.cache.borrow_mut().put_evicting(key, value)?;
+        self.write_back_evicted(&mut dirty, evicted)?;
+        Ok(true)
+    }
+
+    /// Write every dirty value to the backing store, returning how many there were.
+    /// A key stays dirty until its write succeeds.
+    pub fn write_back(&self) -> Result<u64, StoreError> {
+        let mut dirty = self.dirty.lock().map_err(|_| StoreError::LockError)?;
+        let keys: Vec<String> = dirty.iter().cloned().collect();
+        let mut written = 0;
+        for key in keys {
+            if let Some(value) = self.cache.borrow().get(&key)? {
+                self.backing.borrow_mut().put(&key, value)?;
+                self.stats.write_backs.fetch_add(1, Ordering::Relaxed);
+                written += 1;
+            }
+            dirty.remove(&key);
+        }
+        Ok(written)
+    }
+
+    /// Write back whichever of `evicted` are dirty. Called with `dirty` locked.
+    fn write_back_evicted(
+        &self,
+        dirty: &mut HashSet<String>,
+        evicted: Vec<(String, Blob)>,
+    ) -> Result<(), StoreError> {
+        for (key, value) in evicted {
+            if dirty.remove(&key) {
+                self.backing.borrow_mut().put(&key, value)?;
+                self.stats.write_backs.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<C: Cache + Send + 'static, B: Store + Send + 'static> TieredStore<C, B> {
+    /// Save the cache's hottest keys to `log.path` every `log.interval`, for
+    /// `with_rehydration` to replay after the store is next opened.
+    pub fn with_hot_key_log(mut self, log: HotKeyLog) -> Result<Self> {
+        let cache = self.cache.get_mut().spawn()?;
+        self.background
+            .push(Arc::new(Background::start(move |stop| {
+                while sleep_unless_stopped(stop, log.interval) {
+                    let saved = cache
+                        .hottest(log.limit)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|keys| log.save(&keys));
+                    if let Err(err) = saved {
+                        log::warn!("Saving hot keys to {:?} failed: {:#}", log.path, err);
+                    }
+                }
+            })));
+        Ok(self)
+    }
+
+    /// Copy the keys saved at `path` from the backing store into the cache, hottest
+    /// first, at up to `rate` keys a second, in the background. Gets and puts carry
+    /// on meanwhile, and keys they've already cached are skipped. Replayed keys
+    /// count as used, so a cache too small for them all keeps the coldest.
+    pub fn with_rehydration(mut self, path: &Path, rate: f64) -> Result<Self> {
+        if !(rate > 0.0 && rate.is_finite()) {
+            bail!("The rehydration rate must be positive, not {}", rate);
+        }
+        let keys = match HotKeyLog::load(path) {
+            Ok(keys) => keys,
+            Err(err) => {
+                // The keys are only a hint; the cache just starts cold without them.
+                log::warn!("Ignoring unreadable hot keys in {:?}: {:#}", path, err);
+                Vec::new()
+            }
+        };
+        if keys.is_empty() {
+            log::info!("No hot keys saved in {:?}; the cache starts cold.", path);
+            return Ok(self);
+        }
+        let worker = self.spawn()?;
+        self.background
+            .push(Arc::new(Background::start(move |stop| {
+                let started = Instant::now();
+                let mut bucket = TokenBucket::new(rate);
+                let mut rehydrated = 0;
+                for key in &keys {
+                    if !bucket.take(stop) {
+                        break;
+                    }
+                    match worker.warm(key) {
+                        Ok(true) => rehydrated += 1,
+                        Ok(false) => {}
+                        Err(err) => {
+                            log::warn!("Rehydrating the cache stopped: {}", err);
+                            break;
+                        }
+                    }
+                }
+                worker
+                    .stats
+                    .rehydrated
+                    .fetch_add(rehydrated, Ordering::Relaxed);
+                log::info!(
+                    "Rehydrated {} of {} saved hot keys into the cache in {:.2?}.",
+                    rehydrated,
+                    keys.len(),
+                    started.elapsed()
+                );
+            })));
+        Ok(self)
+    }
+}
+
+impl<C: Cache, B: Store> Store for TieredStore<C, B> {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        if let Some(value) = self.cache.borrow().get(key)? {
+            self.stats.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(value));
+        }
+        let mut dirty = self.dirty.lock().map_err(|_| StoreError::LockError)?;
+        // Another handle may have cached it while this one waited for the lock.
+        if let Some(value) = self.cache.borrow().get(key)? {
+            self.stats.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(value));
+        }
+        self.stats.cache_misses.fetch_add(1, Ordering::Relaxed);
+        let Some(value) = self.backing.borrow().get(key)? else {
+            self.stats.backing_misses.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        };
+        let evicted = self.cache.borrow_mut().put_evicting(key, value.clone())?;
+        self.write_back_evicted(&mut dirty, evicted)?;
+        Ok(Some(value))
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let mut dirty = self.dirty.lock().map_err(|_| StoreError::LockError)?;
+        match self.mode {
+            WriteMode::WriteThrough => self.backing.get_mut().put(key, value.clone())?,
+            WriteMode::WriteBack => {
+                dirty.insert(key.to_string());
+            }
+        }
+        let evicted = self.cache.get_mut().put_evicting(key, value)?;
+        self.write_back_evicted(&mut dirty, evicted)
+    }
+
+    /// Durable in either mode: written through, and no longer dirty.
+    fn put_durable(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        let mut dirty = self.dirty.lock().map_err(|_| StoreError::LockError)?;
+        self.backing.get_mut().put_durable(key, value.clone())?;
+        dirty.remove(key);
+        let evicted = self.cache.get_mut().put_evicting(key, value)?;
+        self.write_back_evicted(&mut dirty, evicted)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        let mut dirty = self.dirty.lock().map_err(|_| StoreError::LockError)?;
+        dirty.remove(key);
+        self.cache.get_mut().delete(key)?;
+        self.backing.get_mut().delete(key)
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(Self {
+            cache: RefCell::new(self.cache.get_mut().spawn()?),
+            backing: RefCell::new(self.backing.get_mut().spawn()?),
+            mode: self.mode,
+            dirty: Arc::clone(&self.dirty),
+            stats: Arc::clone(&self.stats),
+            background: self.background.clone(),
+        })
+    }
+
+    /// Writes back every dirty value first, then scans the backing store.
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Blob)>, StoreError> {
+        self.write_back()?;
+        self.backing.borrow().scan(prefix)
+    }
+
+    /// As `scan`, writing back first.
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        self.write_back()?;
+        self.backing.borrow().range(range)
+    }
+
+    /// The cache's statistics, prefixed with "cache ", then the backing store's.
+    fn describe_stats(&self) -> Result<Vec<String>, StoreError> {
+        let mut lines: Vec<String> = self
+            .cache
+            .borrow()
+            .describe_stats()?
+            .into_iter()
+            .map(|line| format!("cache {}", line))
+            .collect();
+        lines.extend(self.backing.borrow().describe_stats()?);
+        Ok(lines)
+    }
+
+    fn persist(&self) -> Result<(), StoreError> {
+        self.write_back()?;
+        self.backing.borrow().persist()
+    }
+
+    /// A cache hit is a single step. Otherwise the miss is followed by the backing
+    /// store's own steps, and, if it had the key, by caching its value, as a get
+    /// would.
+    fn explain_get(&self, key: &str) -> Result<Explanation, StoreError> {
+        let started = Instant::now();
+        let hit = |value| {
+            self.stats.cache_hits.fetch_add(1, Ordering::Relaxed);
+            let elapsed = started.elapsed();
+            Explanation {
+                value: Some(value),
+                steps: vec![ExplainStep {
+                    name: "cache",
+                    detail: "hit".to_string(),
+                    elapsed,
+                }],
+                total: elapsed,
+            }
+        };
+        if let Some(value) = self.cache.borrow().get(key)? {
+            return Ok(hit(value));
+        }
+        let mut dirty = self.dirty.lock().map_err(|_| StoreError::LockError)?;
+        if let Some(value) = self.cache.borrow().get(key)? {
+            return Ok(hit(value));
+        }
+        self.stats.cache_misses.fetch_add(1, Ordering::Relaxed);
+        let mut steps = vec![ExplainStep {
+            name: "cache",
+            detail: "miss; falling through to the backing store".to_string(),
+            elapsed: started.elapsed(),
+        }];
+        let backing = self.backing.borrow().explain_get(key)?;
+        steps.extend(backing.steps);
+        match &backing.value {
+            Some(value) => {
+                let fill_started = Instant::now();
+                let evicted = self.cache.borrow_mut().put_evicting(key, value.clone())?;
+                let detail = format!("cached the value, evicting {} keys", evicted.len());
+                self.write_back_evicted(&mut dirty, evicted)?;
+                steps.push(ExplainStep {
+                    name: "fill",
+                    detail,
+                    elapsed: fill_started.elapsed(),
+                });
+            }
+            None => {
+                self.stats.backing_misses.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(Explanation {
+            value: backing.value,
+            steps,
+            total: started.elapsed(),
+        })
+    }
+}
+
+/// Stops saving hot keys and rehydrating, as a killed process would, then crashes
+/// the backing store. The cache is lost with the process; the store reopened after
+/// starts with an empty one.
+impl<C: Cache, B: Store + Crash> Crash for TieredStore<C, B> {
+    fn crash(&self) -> Result<(), StoreError> {
+        for background in &self.background {
+            background.stop();
+        }
+        self.backing.borrow().crash()
+    }
+
+    /// Read from the backing store, so that checking what survived doesn't warm the
+    /// cache.
+    fn recovered(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        self.backing.borrow().get(key)
+    }
+}
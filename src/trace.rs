@@ -0,0 +1,266 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::store::{Blob, StoreError};
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceOp {
+    Get,
+    Put,
+    Delete,
+}
+
+/// What an operation returned, as recorded by a `TraceRecorder` with results.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceResult {
+    /// A write that succeeded.
+    Ok,
+    Error,
+    /// A get of a key with no value.
+    Miss,
+    /// A get that found a value, with its `value_hash`.
+    Hit(u64),
+}
+
+impl TraceResult {
+    pub fn of_get(result: &Result<Option<Blob>, StoreError>) -> Self {
+        match result {
+            Ok(Some(value)) => Self::Hit(value_hash(value)),
+            Ok(None) => Self::Miss,
+            Err(_) => Self::Error,
+        }
+    }
+
+    pub fn of_write(result: &Result<(), StoreError>) -> Self {
+        match result {
+            Ok(()) => Self::Ok,
+            Err(_) => Self::Error,
+        }
+    }
+}
+
+impl std::fmt::Display for TraceResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ok => f.write_str("ok"),
+            Self::Error => f.write_str("an error"),
+            Self::Miss => f.write_str("no value"),
+            Self::Hit(hash) => write!(f, "a value with hash {:016x}", hash),
+        }
+    }
+}
+
+/// A hash of `value` that two equal blobs always share, whatever order their
+/// dicts iterate in.
+pub fn value_hash(value: &Blob) -> u64 {
+    let mut hasher = Xxh3::new();
+    hash_into(&mut hasher, value);
+    hasher.digest()
+}
+
+fn hash_into(hasher: &mut Xxh3, value: &Blob) {
+    // A tag per variant, and lengths ahead of contents, so that different values
+    // can't feed the hasher the same bytes.
+    match value {
+        Blob::Null => hasher.update(&[0]),
+        Blob::Str(s) => {
+            hasher.update(&[1]);
+            hash_bytes(hasher, s.as_bytes());
+        }
+        Blob::Int(i) => {
+            hasher.update(&[2]);
+            hasher.update(&(*i as i64).to_le_bytes());
+        }
+        Blob::Float(f) => {
+            hasher.update(&[3]);
+            hasher.update(&f.to_bits().to_le_bytes());
+        }
+        Blob::Bytes(bytes) => {
+            hasher.update(&[4]);
+            hash_bytes(hasher, bytes);
+        }
+        Blob::List(values) => {
+            hasher.update(&[5]);
+            hasher.update(&(values.len() as u64).to_le_bytes());
+            for value in values {
+                hash_into(hasher, value);
+            }
+        }
+        Blob::Dict(dict) => {
+            hasher.update(&[6]);
+            hasher.update(&(dict.len() as u64).to_le_bytes());
+            let mut entries: Vec<_> = dict.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            for (key, value) in entries {
+                hash_bytes(hasher, key.as_bytes());
+                hash_into(hasher, value);
+            }
+        }
+    }
+}
+
+fn hash_bytes(hasher: &mut Xxh3, bytes: &[u8]) {
+    hasher.update(&(bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+/// One operation of a recorded workload: a line of a trace file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TraceEvent {
+    /// When the operation started, in microseconds since recording began.
+    pub at_us: u64,
+    /// Index of the load-test thread that ran it.
+    pub thread: usize,
+    pub op: TraceOp,
+    pub key: String,
+    /// Approximate payload size of a put's value; see `value_sizes::blob_size`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_size: Option<u64>,
+    /// A put's exact value, if recorded with results, so that a replay writes
+    /// the same one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<Blob>,
+    /// What the operation returned, if recorded with results.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<TraceResult>,
+}
+
+#[derive(Debug)]
+struct TraceWriter {
+    out: BufWriter<File>,
+    events: u64,
+    /// The first write error, reported by `finish`; later events are dropped.
+    error: Option<std::io::Error>,
+}
+
+/// Appends every operation of a load test to a trace file, as JSON lines. Each
+/// thread's events are in order, but threads' events are interleaved.
+#[derive(Debug)]
+pub struct TraceRecorder {
+    started: Instant,
+    /// Whether each event also records its put's value and its result.
+    results: bool,
+    writer: Mutex<TraceWriter>,
+}
+
+impl TraceRecorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(Self {
+            started: Instant::now(),
+            results: false,
+            writer: Mutex::new(TraceWriter {
+                out: BufWriter::new(
+                    File::create(path).with_context(|| format!("Creating trace {:?}", path))?,
+                ),
+                events: 0,
+                error: None,
+            }),
+        })
+    }
+
+    /// Also record each put's value and each operation's result, for replaying
+    /// with `LoadParams::compare_results`.
+    pub fn with_results(mut self) -> Self {
+        self.results = true;
+        self
+    }
+
+    pub fn records_results(&self) -> bool {
+        self.results
+    }
+
+    /// Microseconds from the start of recording to `at`.
+    pub fn offset_us(&self, at: Instant) -> u64 {
+        at.saturating_duration_since(self.started).as_micros() as u64
+    }
+
+    pub fn record(&self, event: &TraceEvent) {
+        let Ok(mut writer) = self.writer.lock() else {
+            return;
+        };
+        if writer.error.is_some() {
+            return;
+        }
+        let result = serde_json::to_writer(&mut writer.out, event)
+            .map_err(std::io::Error::from)
+            .and_then(|()| writer.out.write_all(b"\n"));
+        match result {
+            Ok(()) => writer.events += 1,
+            Err(err) => writer.error = Some(err),
+        }
+    }
+
+    /// Flush the trace, returning the number of events recorded, or the first
+    /// error writing it.
+    pub fn finish(&self) -> Result<u64> {
+        let mut writer = self.writer.lock().map_err(|_| StoreError::LockError)?;
+        if let Some(err) = writer.error.take() {
+            return Err(err).context("Writing trace");
+        }
+        writer.out.flush()?;
+        Ok(writer.events)
+    }
+}
+
+/// A recorded trace, loaded for replay: each thread's events in order.
+#[derive(Debug, Default)]
+pub struct Trace {
+    threads: Vec<Vec<TraceEvent>>,
+}
+
+impl Trace {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Opening trace {:?}", path))?;
+        let mut trace = Self::default();
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event: TraceEvent = serde_json::from_str(&line)
+                .with_context(|| format!("{:?} line {}", path, index + 1))?;
+            if trace.threads.len() <= event.thread {
+                trace.threads.resize_with(event.thread + 1, Vec::new);
+            }
+            trace.threads[event.thread].push(event);
+        }
+        for events in &mut trace.threads {
+            events.sort_by_key(|event| event.at_us);
+        }
+        Ok(trace)
+    }
+
+    /// Number of threads needed to replay the trace, one per recorded thread.
+    pub fn threads(&self) -> usize {
+        self.threads.len()
+    }
+
+    pub fn events(&self) -> usize {
+        self.threads.iter().map(Vec::len).sum()
+    }
+
+    /// Whether the trace was recorded with results.
+    pub fn has_results(&self) -> bool {
+        self.threads
+            .iter()
+            .flatten()
+            .any(|event| event.result.is_some())
+    }
+
+    /// The events recorded by thread `index`, in order.
+    pub fn thread_events(&self, index: usize) -> &[TraceEvent] {
+        self.threads
+            .get(index)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use rand::prelude::*;
+use structopt::clap::arg_enum;
+
+use crate::store::{Blob, Store, StoreError};
+
+/// Number of independently locked deadline indexes, so that tracking TTLs doesn't
+/// funnel every put through a single lock.
+const INDEX_STRIPES: usize = 16;
+
+/// How often active expiry wakes up to sample keys.
+const ACTIVE_EXPIRY_PERIOD: Duration = Duration::from_millis(100);
+
+/// Keys sampled per active expiry round.
+const ACTIVE_EXPIRY_SAMPLE_SIZE: usize = 20;
+
+/// If more than this fraction of a sample had expired, sample again immediately
+/// rather than waiting for the next period (the same heuristic Redis uses).
+const ACTIVE_EXPIRY_REPEAT_THRESHOLD: f64 = 0.25;
+
+/// Width of the windows expired-key counts are bucketed into.
+const METRICS_WINDOW: Duration = Duration::from_secs(1);
+
+arg_enum! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum ExpiryMode {
+        // Expired keys are only removed when they're next read.
+        Lazy,
+        // As lazy, plus a background thread that samples and removes expired keys.
+        Active,
+    }
+}
+
+/// Why a key changed. Expiry is reported separately from client deletes, so
+/// subscribers can tell policy-driven removal apart.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChangeKind {
+    Written,
+    Deleted,
+    Expired,
+}
+
+/// One change to a `TtlStore`, as delivered to subscribers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeEvent {
+    pub key: String,
+    pub kind: ChangeKind,
+}
+
+/// Deadlines for the keys in one stripe, laid out so that random sampling is O(1).
+#[derive(Default)]
+struct DeadlineIndex {
+    keys: Vec<(String, Instant)>,
+    positions: HashMap<String, usize>,
+}
+
+impl DeadlineIndex {
+    fn get(&self, key: &str) -> Option<Instant> {
+        self.positions.get(key).map(|&i| self.keys[i].1)
+    }
+
+    fn insert(&mut self, key: &str, deadline: Instant) {
+        if let Some(&i) = self.positions.get(key) {
+            self.keys[i].1 = deadline;
+        } else {
+            self.positions.insert(key.to_owned(), self.keys.len());
+            self.keys.push((key.to_owned(), deadline));
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(i) = self.positions.remove(key) {
+            self.keys.swap_remove(i);
+            if let Some((moved, _)) = self.keys.get(i) {
+                self.positions.insert(moved.clone(), i);
+            }
+        }
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> Option<(String, Instant)> {
+        self.keys.choose(rng).cloned()
+    }
+}
+
+/// Expiry counters shared by every handle of a `TtlStore`.
+#[derive(Debug)]
+pub struct ExpiryMetrics {
+    start: Instant,
+    pub lazy_expired: AtomicU64,
+    pub active_expired: AtomicU64,
+    /// Time reads spent removing expired keys.
+    pub lazy_expiry_ns: AtomicU64,
+    pub max_lazy_expiry_ns: AtomicU64,
+    pub active_rounds: AtomicU64,
+    /// Time the background thread spent sampling and removing expired keys.
+    pub active_expiry_ns: AtomicU64,
+    /// Change events not delivered because a subscriber's channel was full.
+    pub dropped_change_events: AtomicU64,
+    /// Expired keys (by either mode) per METRICS_WINDOW since the store was created.
+    windows: Mutex<Vec<u64>>,
+}
+
+impl ExpiryMetrics {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            lazy_expired: AtomicU64::new(0),
+            active_expired: AtomicU64::new(0),
+            lazy_expiry_ns: AtomicU64::new(0),
+            max_lazy_expiry_ns: AtomicU64::new(0),
+            active_rounds: AtomicU64::new(0),
+            active_expiry_ns: AtomicU64::new(0),
+            dropped_change_events: AtomicU64::new(0),
+            windows: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn record_window(&self, expired: u64) {
+        let window = (self.start.elapsed().as_nanos() / METRICS_WINDOW.as_nanos()) as usize;
+        if let Ok(mut windows) = self.windows.lock() {
+            if windows.len() <= window {
+                windows.resize(window + 1, 0);
+            }
+            windows[window] += expired;
+        }
+    }
+
+    fn record_lazy(&self, elapsed: Duration) {
+        let ns = elapsed.as_nanos() as u64;
+        self.lazy_expired.fetch_add(1, Ordering::Relaxed);
+        self.lazy_expiry_ns.fetch_add(ns, Ordering::Relaxed);
+        self.max_lazy_expiry_ns.fetch_max(ns, Ordering::Relaxed);
+        self.record_window(1);
+    }
+
+    fn record_active(&self, expired: u64, elapsed: Duration) {
+        self.active_expired.fetch_add(expired, Ordering::Relaxed);
+        self.active_rounds.fetch_add(1, Ordering::Relaxed);
+        self.active_expiry_ns
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        if expired > 0 {
+            self.record_window(expired);
+        }
+    }
+
+    pub fn summarize(&self) {
+        let lazy_expired = self.lazy_expired.load(Ordering::Relaxed);
+        let lazy_ns = self.lazy_expiry_ns.load(Ordering::Relaxed);
+        let mean_lazy_us = if lazy_expired > 0 {
+            lazy_ns as f64 / lazy_expired as f64 / 1000.0
+        } else {
+            0.0
+        };
+        log::info!("lazy_expired_keys: {}", lazy_expired);
+        log::info!("lazy_expiry_mean_us: {:.2}", mean_lazy_us);
+        log::info!(
+            "lazy_expiry_max_us: {:.2}",
+            self.max_lazy_expiry_ns.load(Ordering::Relaxed) as f64 / 1000.0
+        );
+        log::info!(
+            "active_expired_keys: {}",
+            self.active_expired.load(Ordering::Relaxed)
+        );
+        log::info!(
+            "active_expiry_rounds: {}",
+            self.active_rounds.load(Ordering::Relaxed)
+        );
+        log::info!(
+            "active_expiry_total_ms: {:.2}",
+            self.active_expiry_ns.load(Ordering::Relaxed) as f64 / 1e6
+        );
+        if let Ok(windows) = self.windows.lock() {
+            log::info!("expired_keys_per_{:?}: {:?}", METRICS_WINDOW, windows);
+        }
+        let dropped = self.dropped_change_events.load(Ordering::Relaxed);
+        if dropped > 0 {
+            log::warn!("dropped_change_events: {}", dropped);
+        }
+    }
+}
+
+/// State shared by every handle of a `TtlStore`.
+struct Shared<S: Store> {
+    stripes: Vec<Mutex<DeadlineIndex>>,
+    /// Handle used to remove expired keys, from reads or the background thread.
+    reaper: Mutex<S>,
+    metrics: Arc<ExpiryMetrics>,
+    stop: AtomicBool,
+    subscribers: Mutex<Vec<Sender<ChangeEvent>>>,
+    /// Mirrors `subscribers.len()`, so that publishing costs nothing without any.
+    subscriber_count: AtomicUsize,
+}
+
+impl<S: Store> Shared<S> {
+    fn stripe(&self, key: &str) -> &Mutex<DeadlineIndex> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.stripes[(hasher.finish() as usize) % INDEX_STRIPES]
+    }
+
+    /// Send an event to every subscriber, dropping those that have gone away.
+    /// Called with the key's stripe locked, so each key's events are in order.
+    fn publish(&self, key: &str, kind: ChangeKind) {
+        if self.subscriber_count.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let Ok(mut subscribers) = self.subscribers.lock() else {
+            return;
+        };
+        subscribers.retain(|subscriber| {
+            let event = ChangeEvent {
+                key: key.to_string(),
+                kind,
+            };
+            match subscriber.try_send(event) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    self.metrics
+                        .dropped_change_events
+                        .fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+        self.subscriber_count
+            .store(subscribers.len(), Ordering::Relaxed);
+    }
+
+    /// Remove `key` if its deadline has passed. Holds the stripe lock throughout, so
+    /// a concurrent put to the same key can't be lost.
+    fn expire_if_due(&self, key: &str, now: Instant) -> Result<bool, StoreError> {
+        let mut stripe = self.stripe(key).lock().map_err(|_| StoreError::LockError)?;
+        match stripe.get(key) {
+            Some(deadline) if deadline <= now => {
+                self.reaper
+                    .lock()
+                    .map_err(|_| StoreError::LockError)?
+                    .delete(key)?;
+                stripe.remove(key);
+                self.publish(key, ChangeKind::Expired);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// One round of active expiry: sample keys across stripes and remove those that
+    /// have expired. Returns the number of keys sampled and expired.
+    fn active_round(&self, rng: &mut impl Rng) -> Result<(usize, usize)> {
+        let now = Instant::now();
+        let mut sampled = 0;
+        let mut expired = 0;
+        for _ in 0..ACTIVE_EXPIRY_SAMPLE_SIZE {
+            let stripe = &self.stripes[rng.gen_range(0..INDEX_STRIPES)];
+            let candidate = stripe
+                .lock()
+                .map_err(|_| StoreError::LockError)?
+                .sample(rng);
+            if let Some((key, _)) = candidate {
+                sampled += 1;
+                if self.expire_if_due(&key, now)? {
+                    expired += 1;
+                }
+            }
+        }
+        Ok((sampled, expired))
+    }
+}
+
+/// Runs active expiry until the last `TtlStore` handle is dropped.
+struct ActiveExpirer<S: Store + 'static> {
+    shared: Arc<Shared<S>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl<S: Store + 'static> ActiveExpirer<S> {
+    fn start(shared: Arc<Shared<S>>) -> Self {
+        let thread_shared = Arc::clone(&shared);
+        let handle = std::thread::spawn(move || {
+            let mut rng = rand::thread_rng();
+            while !thread_shared.stop.load(Ordering::Relaxed) {
+                let start = Instant::now();
+                let mut expired = 0;
+                loop {
+                    match thread_shared.active_round(&mut rng) {
+                        Ok((sampled, round_expired)) => {
+                            expired += round_expired;
+                            let ratio = round_expired as f64 / sampled.max(1) as f64;
+                            if ratio <= ACTIVE_EXPIRY_REPEAT_THRESHOLD {
+                                break;
+                            }
+                        }
+                        Err(err) => {
+                            log::error!("active expiry error: {:?}", err);
+                            break;
+                        }
+                    }
+                }
+                thread_shared
+                    .metrics
+                    .record_active(expired as u64, start.elapsed());
+                std::thread::sleep(ACTIVE_EXPIRY_PERIOD);
+            }
+        });
+        Self {
+            shared,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl<S: Store + 'static> Drop for ActiveExpirer<S> {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Wraps any store, expiring keys a fixed time after they were last written.
+pub struct TtlStore<S: Store + 'static> {
+    inner: S,
+    default_ttl: Option<Duration>,
+    shared: Arc<Shared<S>>,
+    _expirer: Option<Arc<ActiveExpirer<S>>>,
+}
+
+impl<S: Store + 'static> TtlStore<S> {
+    /// `default_ttl` applies to every `put`; use `put_with_ttl` to override it.
+    pub fn new(mut inner: S, default_ttl: Option<Duration>, mode: ExpiryMode) -> Result<Self> {
+        let shared = Arc::new(Shared {
+            stripes: (0..INDEX_STRIPES).map(|_| Mutex::default()).collect(),
+            reaper: Mutex::new(inner.spawn()?),
+            metrics: Arc::new(ExpiryMetrics::new()),
+            stop: AtomicBool::new(false),
+            subscribers: Mutex::new(Vec::new()),
+            subscriber_count: AtomicUsize::new(0),
+        });
+        let expirer = match mode {
+            ExpiryMode::Lazy => None,
+            ExpiryMode::Active => Some(Arc::new(ActiveExpirer::start(Arc::clone(&shared)))),
+        };
+        Ok(Self {
+            inner,
+            default_ttl,
+            shared,
+            _expirer: expirer,
+        })
+    }
+
+    pub fn metrics(&self) -> Arc<ExpiryMetrics> {
+        Arc::clone(&self.shared.metrics)
+    }
+
+    /// Receive an event for every later write, delete and expiry through any
+    /// handle. Up to `capacity` events are buffered; once the buffer is full,
+    /// further events to this subscriber are dropped and counted in
+    /// `ExpiryMetrics::dropped_change_events`. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self, capacity: usize) -> Result<Receiver<ChangeEvent>> {
+        let (sender, receiver) = crossbeam_channel::bounded(capacity);
+        let mut subscribers = self
+            .shared
+            .subscribers
+            .lock()
+            .map_err(|_| StoreError::LockError)?;
+        subscribers.push(sender);
+        self.shared
+            .subscriber_count
+            .store(subscribers.len(), Ordering::Relaxed);
+        Ok(receiver)
+    }
+
+    /// Write `value`, expiring it after `ttl` (or never, if None).
+    pub fn put_with_ttl(
+        &mut self,
+        key: &str,
+        value: Blob,
+        ttl: Option<Duration>,
+    ) -> Result<(), StoreError> {
+        self.put_with(key, value, ttl, S::put)
+    }
+
+    /// Write `value` with `put`, holding the key's index stripe until it returns.
+    fn put_with(
+        &mut self,
+        key: &str,
+        value: Blob,
+        ttl: Option<Duration>,
+        put: fn(&mut S, &str, Blob) -> Result<(), StoreError>,
+    ) -> Result<(), StoreError> {
+        let mut stripe = self
+            .shared
+            .stripe(key)
+            .lock()
+            .map_err(|_| StoreError::LockError)?;
+        put(&mut self.inner, key, value)?;
+        match ttl {
+            Some(ttl) => stripe.insert(key, Instant::now() + ttl),
+            None => stripe.remove(key),
+        }
+        self.shared.publish(key, ChangeKind::Written);
+        Ok(())
+    }
+}
+
+impl<S: Store + 'static> Store for TtlStore<S> {
+    fn get(&self, key: &str) -> Result<Option<Blob>, StoreError> {
+        let start = Instant::now();
+        if self.shared.expire_if_due(key, start)? {
+            self.shared.metrics.record_lazy(start.elapsed());
+            return Ok(None);
+        }
+        self.inner.get(key)
+    }
+
+    fn put(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        self.put_with_ttl(key, value, self.default_ttl)
+    }
+
+    /// The key's index stripe stays locked until the put is durable, so durable
+    /// puts of keys in the same stripe don't share syncs.
+    fn put_durable(&mut self, key: &str, value: Blob) -> Result<(), StoreError> {
+        self.put_with(key, value, self.default_ttl, S::put_durable)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), StoreError> {
+        let mut stripe = self
+            .shared
+            .stripe(key)
+            .lock()
+            .map_err(|_| StoreError::LockError)?;
+        self.inner.delete(key)?;
+        stripe.remove(key);
+        self.shared.publish(key, ChangeKind::Deleted);
+        Ok(())
+    }
+
+    /// Expired keys are left out, and removed as a get would remove them.
+    fn range(&self, range: Range<&str>) -> Result<Vec<(String, Blob)>, StoreError> {
+        let start = Instant::now();
+        let mut live = Vec::new();
+        for (key, value) in self.inner.range(range)? {
+            if self.shared.expire_if_due(&key, start)? {
+                self.shared.metrics.record_lazy(start.elapsed());
+            } else {
+                live.push((key, value));
+            }
+        }
+        Ok(live)
+    }
+
+    fn spawn(&mut self) -> Result<Self, StoreError> {
+        Ok(Self {
+            inner: self.inner.spawn()?,
+            default_ttl: self.default_ttl,
+            shared: Arc::clone(&self.shared),
+            _expirer: self._expirer.clone(),
+        })
+    }
+}
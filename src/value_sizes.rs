@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use hdrhistogram::Histogram;
+use serde::Serialize;
+
+use crate::store::Blob;
+
+/// Most namespaces tracked by one `ValueSizes`; values of any others are counted
+/// under `OTHER_NAMESPACE`, so that keys without a recognizable namespace can't
+/// grow it without bound.
+const MAX_NAMESPACES: usize = 64;
+
+const OTHER_NAMESPACE: &str = "(other)";
+
+/// Significant figures of the size histograms.
+const SIZE_PRECISION: u8 = 2;
+
+/// The namespace of `key`: everything before the first `:` ("user:42" is in
+/// "user"), or if there's no `:`, the key without its trailing digits ("Key42" is
+/// in "Key", as are all the load test's keys).
+pub fn namespace(key: &str) -> &str {
+    match key.split_once(':') {
+        Some((namespace, _)) => namespace,
+        None => key.trim_end_matches(|c: char| c.is_ascii_digit()),
+    }
+}
+
+/// Approximate payload size of `value` in bytes, independent of the serializer:
+/// the length of strings, bytes and dict keys, and 8 bytes per number.
+pub fn blob_size(value: &Blob) -> u64 {
+    match value {
+        Blob::Null => 0,
+        Blob::Str(s) => s.len() as u64,
+        Blob::Int(_) | Blob::Float(_) => 8,
+        Blob::Bytes(bytes) => bytes.len() as u64,
+        Blob::List(values) => values.iter().map(blob_size).sum(),
+        Blob::Dict(dict) => dict
+            .iter()
+            .map(|(key, value)| key.len() as u64 + blob_size(value))
+            .sum(),
+    }
+}
+
+/// Streaming distribution of value sizes per key namespace. Each namespace keeps
+/// an HDR histogram, so quantiles are accurate to two significant figures without
+/// storing every size.
+#[derive(Clone, Debug, Default)]
+pub struct ValueSizes {
+    namespaces: HashMap<String, Histogram<u64>>,
+}
+
+/// Size statistics for one namespace, in bytes.
+#[derive(Debug, Serialize)]
+pub struct NamespaceSizes {
+    pub namespace: String,
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+}
+
+impl ValueSizes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, key: &str, value: &Blob) {
+        let mut namespace = namespace(key);
+        if !self.namespaces.contains_key(namespace) {
+            if self.namespaces.len() >= MAX_NAMESPACES {
+                namespace = OTHER_NAMESPACE;
+            }
+            // Auto-resizing histograms with valid precision can always be created,
+            // and only fail to record values too large to index.
+            let Ok(histogram) = Histogram::new(SIZE_PRECISION) else {
+                return;
+            };
+            self.namespaces
+                .entry(namespace.to_string())
+                .or_insert(histogram);
+        }
+        if let Some(histogram) = self.namespaces.get_mut(namespace) {
+            let _ = histogram.record(blob_size(value));
+        }
+    }
+
+    /// Add `other`'s sizes, e.g. to combine shards.
+    pub fn merge(&mut self, other: &Self) -> Result<()> {
+        for (namespace, sizes) in &other.namespaces {
+            match self.namespaces.get_mut(namespace) {
+                Some(histogram) => histogram.add(sizes)?,
+                None => {
+                    self.namespaces.insert(namespace.clone(), sizes.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Statistics for each namespace, largest total size first.
+    pub fn summaries(&self) -> Vec<NamespaceSizes> {
+        let mut summaries: Vec<NamespaceSizes> = self
+            .namespaces
+            .iter()
+            .map(|(namespace, sizes)| NamespaceSizes {
+                namespace: namespace.clone(),
+                count: sizes.len(),
+                min: sizes.min(),
+                max: sizes.max(),
+                mean: sizes.mean(),
+                p50: sizes.value_at_percentile(50.0),
+                p90: sizes.value_at_percentile(90.0),
+                p99: sizes.value_at_percentile(99.0),
+            })
+            .collect();
+        summaries.sort_by(|a, b| {
+            (b.mean * b.count as f64)
+                .total_cmp(&(a.mean * a.count as f64))
+                .then_with(|| a.namespace.cmp(&b.namespace))
+        });
+        summaries
+    }
+
+    /// Log one `label` line per namespace.
+    pub fn log(&self, label: &str) {
+        for sizes in self.summaries() {
+            log::info!(
+                "{} {:?}: count={} min={} mean={:.1} p50={} p90={} p99={} max={}",
+                label,
+                sizes.namespace,
+                sizes.count,
+                sizes.min,
+                sizes.mean,
+                sizes.p50,
+                sizes.p90,
+                sizes.p99,
+                sizes.max
+            );
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{bail, Result};
+use rand::Rng;
+
+use crate::load_test::{KeyDistribution, WorkloadContext, WorkloadOp, DEFAULT_ZIPF_EXPONENT};
+use crate::store::StoreError;
+
+/// The core YCSB workloads this store can run. E is missing because its scans
+/// read a count of records from a start key, while `Store::range` takes an end
+/// key, and no end key picks out a fixed count of unpadded numbered keys.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum YcsbWorkload {
+    /// Update heavy: 50% reads, 50% updates.
+    A,
+    /// Read mostly: 95% reads, 5% updates.
+    B,
+    /// Read only.
+    C,
+    /// Read latest: 95% reads, 5% inserts, reading recently inserted keys most.
+    D,
+    /// Read-modify-write: 50% reads, 50% reads followed by an update of the same
+    /// key.
+    F,
+}
+
+impl std::str::FromStr for YcsbWorkload {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim().to_ascii_lowercase().as_str() {
+            "ycsb-a" => Self::A,
+            "ycsb-b" => Self::B,
+            "ycsb-c" => Self::C,
+            "ycsb-d" => Self::D,
+            "ycsb-f" => Self::F,
+            other => bail!(
+                "Unknown workload {:?}; expected ycsb-a, ycsb-b, ycsb-c, ycsb-d or ycsb-f",
+                other
+            ),
+        })
+    }
+}
+
+/// Fractions of a workload's operations of each kind; the rest are
+/// read-modify-writes.
+#[derive(Clone, Copy, Debug)]
+struct Mix {
+    read: f64,
+    update: f64,
+    insert: f64,
+}
+
+impl YcsbWorkload {
+    fn mix(self) -> Mix {
+        let mix = |read, update, insert| Mix {
+            read,
+            update,
+            insert,
+        };
+        match self {
+            Self::A => mix(0.5, 0.5, 0.0),
+            Self::B => mix(0.95, 0.05, 0.0),
+            Self::C => mix(1.0, 0.0, 0.0),
+            Self::D => mix(0.95, 0.0, 0.05),
+            Self::F => mix(0.5, 0.0, 0.0),
+        }
+    }
+
+    /// How the workload picks existing keys: zipfian with YCSB's constant. For D
+    /// it's the distance back from the newest key, so the latest inserts are the
+    /// most popular.
+    pub fn key_distribution(self) -> KeyDistribution {
+        KeyDistribution::Zipfian {
+            exponent: DEFAULT_ZIPF_EXPONENT,
+        }
+    }
+}
+
+/// A YCSB workload over `LoadParams::key_count` records, which it loads before
+/// the run, one put per record spread across the threads. Updates and inserts
+/// write values of the configured shape and size; YCSB's own records are 10
+/// fields of 100 bytes.
+///
+/// Unlike YCSB's scrambled zipfian, the most popular keys are the first ones,
+/// which only matters to stores that keep nearby keys together.
+#[derive(Debug)]
+pub struct Ycsb {
+    workload: YcsbWorkload,
+    mix: Mix,
+    /// Index of the next record to load.
+    next_load: AtomicU64,
+    /// Records inserted so far; they follow the loaded ones.
+    inserted: AtomicU64,
+}
+
+impl Ycsb {
+    pub fn new(workload: YcsbWorkload) -> Self {
+        Self {
+            workload,
+            mix: workload.mix(),
+            next_load: AtomicU64::new(0),
+            inserted: AtomicU64::new(0),
+        }
+    }
+
+    /// An existing key to read or update.
+    fn existing_key(&self, ctx: &mut WorkloadContext) -> String {
+        let drawn = ctx.random_key_index();
+        let index = match self.workload {
+            YcsbWorkload::D => {
+                let newest = ctx.params().key_count + self.inserted.load(Ordering::Relaxed);
+                newest.saturating_sub(drawn + 1)
+            }
+            _ => drawn,
+        };
+        ctx.key(index)
+    }
+}
+
+impl WorkloadOp for Ycsb {
+    fn run(&self, ctx: &mut WorkloadContext) -> Result<(), StoreError> {
+        let Mix {
+            read,
+            update,
+            insert,
+        } = self.mix;
+        let choice = ctx.rng().gen::<f64>();
+        if choice < read {
+            let key = self.existing_key(ctx);
+            ctx.get(&key)?;
+        } else if choice < read + update {
+            let key = self.existing_key(ctx);
+            let value = ctx.random_value();
+            ctx.put(&key, value)?;
+        } else if choice < read + update + insert {
+            let index = ctx.params().key_count + self.inserted.fetch_add(1, Ordering::Relaxed);
+            let key = ctx.key(index);
+            let value = ctx.random_value();
+            ctx.put(&key, value)?;
+        } else {
+            let key = self.existing_key(ctx);
+            ctx.get(&key)?;
+            let value = ctx.random_value();
+            ctx.put(&key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Loads only once per `Ycsb`, so runs that share one (e.g. the layer-cost
+    /// preset's) also share the loaded records.
+    fn load(&self, ctx: &mut WorkloadContext) -> Result<(), StoreError> {
+        let records = ctx.params().key_count;
+        loop {
+            let index = self.next_load.fetch_add(1, Ordering::Relaxed);
+            if index >= records {
+                return Ok(());
+            }
+            let key = ctx.key(index);
+            let value = ctx.random_value();
+            // Carry on past failures, so one fault doesn't leave most records
+            // unloaded; the misses will show in the results.
+            let _ = ctx.put(&key, value);
+        }
+    }
+}